@@ -3,9 +3,12 @@
     windows_subsystem = "windows"
 )]
 
+use std::cell::Cell;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, OnceLock, mpsc};
 use std::{
     collections::{HashMap, HashSet, VecDeque},
@@ -18,14 +21,16 @@ use anyhow::{Context as _, Result};
 use base64::Engine as _;
 use eframe::egui;
 use eframe::egui::TextStyle;
-use egui_commonmark::{CommonMarkCache, CommonMarkViewer};
+use egui_commonmark::{Alert, AlertBundle, CommonMarkCache, CommonMarkViewer};
 use rand::Rng as _;
 use regex::{Captures, Regex};
 use scraper::{Html, node::Node};
 use serde::{Deserialize, Serialize};
 
+const APP_NAME: &str = "markdownviewer";
 const STATE_KEY: &str = "markdownviewer_state_v1";
 const MAX_RECENT_FILES: usize = 20;
+const MARKDOWN_EXTENSIONS: &[&str] = &["md", "markdown", "mdown", "mkd", "mkdn", "mdtxt"];
 
 fn main() -> eframe::Result {
     let native_options = eframe::NativeOptions {
@@ -37,7 +42,7 @@ fn main() -> eframe::Result {
     };
 
     eframe::run_native(
-        "markdownviewer",
+        APP_NAME,
         native_options,
         Box::new(|cc| Ok(Box::new(MarkdownViewerApp::new(cc)))),
     )
@@ -227,6 +232,37 @@ fn outline_item_label(level: usize, title: &str) -> egui::RichText {
     }
 }
 
+fn show_workspace_dir_contents(
+    ui: &mut egui::Ui,
+    dir: &WorkspaceDir,
+    active_path: Option<&Path>,
+    open_path: &mut Option<PathBuf>,
+) {
+    for sub in &dir.dirs {
+        let contains_active = active_path.is_some_and(|p| p.starts_with(&sub.path));
+        egui::CollapsingHeader::new(format!("📁 {}", sub.name))
+            .id_salt(&sub.path)
+            .default_open(contains_active)
+            .show(ui, |ui| {
+                show_workspace_dir_contents(ui, sub, active_path, open_path);
+            });
+    }
+    for file in &dir.files {
+        let name = file
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let is_active = active_path == Some(file.as_path());
+        if ui
+            .selectable_label(is_active, name)
+            .on_hover_text(file.display().to_string())
+            .clicked()
+        {
+            *open_path = Some(file.clone());
+        }
+    }
+}
+
 fn set_pixel(rgba: &mut [u8], size: u32, x: i32, y: i32, r: u8, g: u8, b: u8, a: u8) {
     if x < 0 || y < 0 {
         return;
@@ -284,6 +320,334 @@ enum SvgState {
     Error(String),
 }
 
+impl SvgState {
+    fn heap_bytes(&self) -> usize {
+        match self {
+            SvgState::Pending => 0,
+            SvgState::Ready(bytes) => bytes.len(),
+            SvgState::Error(err) => err.len(),
+        }
+    }
+}
+
+const DEFAULT_RENDER_MEMORY_LIMIT_MB: u64 = 64;
+const RENDER_CACHE_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+const RENDER_DEBOUNCE: Duration = Duration::from_millis(300);
+
+struct SvgCache<K> {
+    ctx: egui::Context,
+    entries: HashMap<K, SvgCacheEntry>,
+    slots: HashMap<SvgSlotId, SvgSlot<K>>,
+    bytes: usize,
+    limit_bytes: usize,
+    grown: bool,
+}
+
+struct SvgCacheEntry {
+    state: SvgState,
+    uri: String,
+    bytes: usize,
+    last_used: u64,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+struct SvgSlotId {
+    doc: u64,
+    block: usize,
+    index: usize,
+}
+
+struct SvgSlot<K> {
+    key: K,
+    key_bytes: usize,
+    changed_at: f64,
+    shown: Option<(K, usize)>,
+    last_used: u64,
+}
+
+impl<K> SvgSlot<K> {
+    fn bytes(&self) -> usize {
+        self.key_bytes + self.shown.as_ref().map_or(0, |(_, bytes)| *bytes)
+    }
+}
+
+impl<K: Clone + Eq + std::hash::Hash> SvgCache<K> {
+    fn new(ctx: egui::Context, limit_bytes: usize) -> Self {
+        Self {
+            ctx,
+            entries: HashMap::new(),
+            slots: HashMap::new(),
+            bytes: 0,
+            limit_bytes,
+            grown: false,
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<SvgState> {
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = self.ctx.cumulative_pass_nr();
+        Some(entry.state.clone())
+    }
+
+    fn insert_pending(&mut self, key: &K, key_bytes: usize, uri: &str) {
+        let frame = self.ctx.cumulative_pass_nr();
+        let bytes = key_bytes + uri.len();
+        self.entries.insert(
+            key.clone(),
+            SvgCacheEntry {
+                state: SvgState::Pending,
+                uri: uri.to_owned(),
+                bytes,
+                last_used: frame,
+            },
+        );
+        self.bytes += bytes;
+        self.grown = true;
+    }
+
+    fn show_slot(
+        &mut self,
+        slot_id: SvgSlotId,
+        key: &K,
+        key_bytes: usize,
+        uri: &str,
+        now: f64,
+        request: impl FnOnce(K),
+    ) -> (String, SvgState, Option<f64>) {
+        let frame = self.ctx.cumulative_pass_nr();
+        let mut replaced = None;
+        let changed_at = match self.slots.get_mut(&slot_id) {
+            Some(slot) => {
+                slot.last_used = frame;
+                if slot.key != *key {
+                    let before = slot.bytes();
+                    replaced = Some(std::mem::replace(&mut slot.key, key.clone()));
+                    slot.key_bytes = key_bytes;
+                    slot.changed_at = now;
+                    self.bytes = self.bytes + slot.bytes() - before;
+                }
+                slot.changed_at
+            }
+            None => {
+                let slot = SvgSlot {
+                    key: key.clone(),
+                    key_bytes,
+                    changed_at: f64::NEG_INFINITY,
+                    shown: None,
+                    last_used: frame,
+                };
+                self.bytes += slot.bytes();
+                self.slots.insert(slot_id, slot);
+                f64::NEG_INFINITY
+            }
+        };
+        if let Some(replaced) = replaced {
+            self.cancel(&replaced);
+        }
+
+        let mut wait = None;
+        let state = self.get(key).unwrap_or_else(|| {
+            let remaining = RENDER_DEBOUNCE.as_secs_f64() - (now - changed_at);
+            if remaining > 0.0 {
+                wait = Some(remaining);
+            } else {
+                self.insert_pending(key, key_bytes, uri);
+                request(key.clone());
+            }
+            SvgState::Pending
+        });
+
+        match state {
+            SvgState::Ready(bytes) => {
+                if let Some(slot) = self.slots.get_mut(&slot_id)
+                    && slot.shown.as_ref().is_none_or(|(shown, _)| shown != key)
+                {
+                    let before = slot.bytes();
+                    slot.shown = Some((key.clone(), key_bytes));
+                    self.bytes = self.bytes + slot.bytes() - before;
+                }
+                (uri.to_owned(), SvgState::Ready(bytes), wait)
+            }
+            SvgState::Pending => {
+                let stale = self
+                    .slots
+                    .get(&slot_id)
+                    .and_then(|slot| slot.shown.as_ref())
+                    .and_then(|(shown, _)| self.entries.get_mut(shown))
+                    .and_then(|entry| match &entry.state {
+                        SvgState::Ready(bytes) => {
+                            entry.last_used = frame;
+                            Some((entry.uri.clone(), bytes.clone()))
+                        }
+                        _ => None,
+                    });
+                match stale {
+                    Some((stale_uri, bytes)) => (stale_uri, SvgState::Ready(bytes), wait),
+                    None => (uri.to_owned(), SvgState::Pending, wait),
+                }
+            }
+            SvgState::Error(err) => (uri.to_owned(), SvgState::Error(err), wait),
+        }
+    }
+
+    fn wants(&self, key: &K) -> bool {
+        matches!(self.entries.get(key), Some(entry) if matches!(entry.state, SvgState::Pending))
+    }
+
+    fn cancel(&mut self, key: &K) {
+        if self.wants(key) {
+            self.remove(key);
+        }
+    }
+
+    fn finish(&mut self, key: &K, state: SvgState) {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return;
+        };
+        self.bytes = self.bytes - entry.state.heap_bytes() + state.heap_bytes();
+        entry.bytes = entry.bytes - entry.state.heap_bytes() + state.heap_bytes();
+        entry.state = state;
+        self.evict();
+    }
+
+    fn set_limit(&mut self, limit_bytes: usize) {
+        self.limit_bytes = limit_bytes;
+        self.evict();
+    }
+
+    fn usage(&self) -> (usize, usize) {
+        (self.entries.len(), self.bytes)
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&K) -> bool) {
+        let dropped_slots: Vec<SvgSlotId> = self
+            .slots
+            .iter()
+            .filter(|(_, slot)| !keep(&slot.key))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in dropped_slots {
+            self.remove_slot(id);
+        }
+        let shown: HashSet<&K> = self
+            .slots
+            .values()
+            .filter_map(|slot| slot.shown.as_ref().map(|(shown, _)| shown))
+            .collect();
+        let dropped: Vec<K> = self
+            .entries
+            .keys()
+            .filter(|key| !shown.contains(key) && !keep(key))
+            .cloned()
+            .collect();
+        for key in dropped {
+            self.remove(&key);
+        }
+        self.grown = false;
+    }
+
+    fn clear(&mut self) {
+        for (_, entry) in self.entries.drain() {
+            self.ctx.forget_image(&entry.uri);
+        }
+        self.slots.clear();
+        self.bytes = 0;
+        self.grown = false;
+    }
+
+    fn remove_slot(&mut self, id: SvgSlotId) {
+        if let Some(slot) = self.slots.remove(&id) {
+            self.bytes -= slot.bytes();
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.bytes;
+            self.ctx.forget_image(&entry.uri);
+        }
+    }
+
+    fn evict(&mut self) {
+        if self.bytes <= self.limit_bytes {
+            return;
+        }
+        // What's on screen stays, even if that alone exceeds the budget; pending entries
+        // stay too, or they'd be requested again on the next frame. Workers evict between
+        // passes, when the pass counter is already one past what the last frame drew.
+        let frame = self.ctx.cumulative_pass_nr();
+        let stale_slots: Vec<SvgSlotId> = self
+            .slots
+            .iter()
+            .filter(|(_, slot)| slot.last_used + 1 < frame)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in stale_slots {
+            self.remove_slot(id);
+        }
+        let mut candidates: Vec<(u64, K)> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| {
+                entry.last_used + 1 < frame && !matches!(entry.state, SvgState::Pending)
+            })
+            .map(|(key, entry)| (entry.last_used, key.clone()))
+            .collect();
+        candidates.sort_by_key(|(last_used, _)| *last_used);
+        for (_, key) in candidates {
+            if self.bytes <= self.limit_bytes {
+                break;
+            }
+            self.remove(&key);
+        }
+    }
+}
+
+fn svg_cache_limit(settings: &ViewerSettings) -> usize {
+    (settings.render_memory_limit_mb * 1024 * 1024 / 2) as usize
+}
+
+struct RenderReferences<'a> {
+    documents: Vec<&'a str>,
+    lines: HashSet<&'a str>,
+}
+
+impl<'a> RenderReferences<'a> {
+    fn new(documents: impl IntoIterator<Item = &'a str>) -> Self {
+        let documents: Vec<&str> = documents.into_iter().collect();
+        let lines = documents
+            .iter()
+            .flat_map(|text| text.lines())
+            .map(Self::normalize_line)
+            .collect();
+        Self { documents, lines }
+    }
+
+    fn normalize_line(line: &str) -> &str {
+        line.trim_start_matches(|c: char| c == '>' || c.is_whitespace())
+            .trim_end()
+    }
+
+    fn has_lines(&self, source: &str) -> bool {
+        source
+            .lines()
+            .map(Self::normalize_line)
+            .filter(|line| !line.is_empty())
+            .all(|line| self.lines.contains(line))
+    }
+
+    fn has_math(&self, tex: &str) -> bool {
+        let delimited = format!("${tex}$");
+        self.documents.iter().any(|text| text.contains(&delimited))
+            || (tex.contains('\n') && self.has_lines(tex))
+    }
+
+    fn has_diagram(&self, source: &str) -> bool {
+        self.has_lines(source)
+    }
+}
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 struct MathKey {
     tex: String,
@@ -291,9 +655,72 @@ struct MathKey {
     color: egui::Color32,
 }
 
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+enum DiagramEngine {
+    Mermaid,
+    PlantUml,
+    Graphviz,
+    D2,
+    Bpmn,
+    VegaLite,
+    Ditaa,
+}
+
+impl DiagramEngine {
+    const ALL: [Self; 7] = [
+        Self::Mermaid,
+        Self::PlantUml,
+        Self::Graphviz,
+        Self::D2,
+        Self::Bpmn,
+        Self::VegaLite,
+        Self::Ditaa,
+    ];
+
+    fn from_fence_lang(lang: &str) -> Option<Self> {
+        match lang.to_ascii_lowercase().as_str() {
+            "mermaid" => Some(Self::Mermaid),
+            "plantuml" | "puml" => Some(Self::PlantUml),
+            "dot" | "graphviz" => Some(Self::Graphviz),
+            "d2" => Some(Self::D2),
+            "bpmn" => Some(Self::Bpmn),
+            "vega-lite" | "vegalite" => Some(Self::VegaLite),
+            "ditaa" => Some(Self::Ditaa),
+            _ => None,
+        }
+    }
+
+    fn kroki_name(self) -> &'static str {
+        match self {
+            Self::Mermaid => "mermaid",
+            Self::PlantUml => "plantuml",
+            Self::Graphviz => "graphviz",
+            Self::D2 => "d2",
+            Self::Bpmn => "bpmn",
+            Self::VegaLite => "vegalite",
+            Self::Ditaa => "ditaa",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Mermaid => "Mermaid",
+            Self::PlantUml => "PlantUML",
+            Self::Graphviz => "Graphviz",
+            Self::D2 => "D2",
+            Self::Bpmn => "BPMN",
+            Self::VegaLite => "Vega-Lite",
+            Self::Ditaa => "ditaa",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
-struct MermaidKey {
+struct DiagramKey {
+    engine: DiagramEngine,
     source: String,
+    palette: DiagramPalette,
+    remote: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -332,6 +759,10 @@ fn slugify_heading(title: &str) -> String {
     out
 }
 
+fn content_hash(text: &str) -> u64 {
+    egui::Id::new(text).value()
+}
+
 fn hex_val(byte: u8) -> Option<u8> {
     match byte {
         b'0'..=b'9' => Some(byte - b'0'),
@@ -411,50 +842,281 @@ fn push_outline_item(
     });
 }
 
+fn markdown_parser_options() -> pulldown_cmark::Options {
+    use pulldown_cmark::Options;
+
+    Options::ENABLE_TABLES
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_DEFINITION_LIST
+        | Options::ENABLE_MATH
+}
+
 fn build_outline(markdown: &str) -> Vec<OutlineItem> {
-    let lines: Vec<&str> = markdown.lines().collect();
+    use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+
+    let options = markdown_parser_options() | Options::ENABLE_YAML_STYLE_METADATA_BLOCKS;
+
     let mut outline = Vec::new();
     let mut used_slugs = HashSet::<String>::new();
-
-    for (idx, line) in lines.iter().enumerate() {
-        let line = line.trim_end_matches(['\r']);
-        let trimmed = line.trim_start_matches([' ', '\t']);
-
-        let hash_count = trimmed.chars().take_while(|c| *c == '#').count();
-        if (1..=6).contains(&hash_count) {
-            let after_hashes = &trimmed[hash_count..];
-            if after_hashes.starts_with([' ', '\t']) {
-                let title = after_hashes.trim().trim_end_matches('#').trim().to_string();
-                if !title.is_empty() {
-                    push_outline_item(&mut outline, &mut used_slugs, hash_count, title, idx);
+    let mut heading: Option<(usize, usize, String)> = None;
+    let mut line = 0usize;
+    let mut counted = 0usize;
+
+    for (event, range) in Parser::new_ext(markdown, options).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Heading { level, .. }) => {
+                line += markdown[counted..range.start].matches('\n').count();
+                counted = range.start;
+                heading = Some((level as usize, line, String::new()));
+            }
+            Event::End(TagEnd::Heading(_)) => {
+                if let Some((level, line, title)) = heading.take() {
+                    let title = title.trim().to_string();
+                    if !title.is_empty() {
+                        push_outline_item(&mut outline, &mut used_slugs, level, title, line);
+                    }
                 }
             }
-            continue;
+            Event::Text(text) | Event::Code(text) | Event::InlineMath(text) => {
+                if let Some((_, _, title)) = &mut heading {
+                    title.push_str(&text);
+                }
+            }
+            Event::SoftBreak | Event::HardBreak => {
+                if let Some((_, _, title)) = &mut heading {
+                    title.push(' ');
+                }
+            }
+            _ => {}
         }
+    }
 
-        let underline = trimmed.trim();
-        let is_h1 = !underline.is_empty() && underline.chars().all(|c| c == '=');
-        let is_h2 = !underline.is_empty() && underline.chars().all(|c| c == '-');
-        if (is_h1 || is_h2) && idx > 0 {
-            let prev = lines[idx - 1].trim();
-            if !prev.is_empty() {
-                push_outline_item(
-                    &mut outline,
-                    &mut used_slugs,
-                    if is_h1 { 1 } else { 2 },
-                    prev.to_string(),
-                    idx - 1,
-                );
+    outline
+}
+
+fn outline_has_children(outline: &[OutlineItem], idx: usize) -> bool {
+    outline
+        .get(idx + 1)
+        .is_some_and(|next| next.level > outline[idx].level)
+}
+
+fn outline_section_at(outline: &[OutlineItem], line: usize) -> Option<usize> {
+    outline.iter().rposition(|item| item.line <= line)
+}
+
+fn outline_visible_rows(
+    outline: &[OutlineItem],
+    collapsed: &HashSet<String>,
+    filter: &str,
+) -> Vec<usize> {
+    let filter: String = filter.trim().chars().map(fold_case).collect();
+    if filter.is_empty() {
+        let mut rows = Vec::with_capacity(outline.len());
+        let mut hidden_below = None::<usize>;
+        for (idx, item) in outline.iter().enumerate() {
+            if hidden_below.is_some_and(|level| item.level > level) {
+                continue;
+            }
+            hidden_below = None;
+            rows.push(idx);
+            if collapsed.contains(&item.slug) && outline_has_children(outline, idx) {
+                hidden_below = Some(item.level);
             }
         }
+        return rows;
     }
 
-    outline
+    let mut keep = vec![false; outline.len()];
+    let mut ancestors: Vec<usize> = Vec::new();
+    for (idx, item) in outline.iter().enumerate() {
+        while ancestors
+            .last()
+            .is_some_and(|&parent| outline[parent].level >= item.level)
+        {
+            ancestors.pop();
+        }
+        let title: String = item.title.chars().map(fold_case).collect();
+        if title.contains(&filter) {
+            keep[idx] = true;
+            for &parent in &ancestors {
+                keep[parent] = true;
+            }
+        }
+        ancestors.push(idx);
+    }
+    (0..outline.len()).filter(|&idx| keep[idx]).collect()
+}
+
+fn outline_collapsed_to_level(outline: &[OutlineItem], level: usize) -> HashSet<String> {
+    (0..outline.len())
+        .filter(|&idx| outline[idx].level >= level && outline_has_children(outline, idx))
+        .map(|idx| outline[idx].slug.clone())
+        .collect()
+}
+
+fn outline_section_items(outline: &[OutlineItem], idx: usize) -> std::ops::Range<usize> {
+    let level = outline[idx].level;
+    let end = outline[idx + 1..]
+        .iter()
+        .position(|item| item.level <= level)
+        .map_or(outline.len(), |offset| idx + 1 + offset);
+    idx..end
+}
+
+fn outline_section_lines(
+    outline: &[OutlineItem],
+    idx: usize,
+    line_count: usize,
+) -> std::ops::Range<usize> {
+    let items = outline_section_items(outline, idx);
+    let end = outline.get(items.end).map_or(line_count, |item| item.line);
+    outline[idx].line..end
+}
+
+fn split_section_lines(text: &str) -> (Vec<String>, bool) {
+    let trailing_newline = text.is_empty() || text.ends_with('\n');
+    let mut lines: Vec<String> = text.split_inclusive('\n').map(str::to_string).collect();
+    if let Some(last) = lines.last_mut()
+        && !trailing_newline
+    {
+        last.push('\n');
+    }
+    (lines, trailing_newline)
+}
+
+fn join_section_lines(lines: Vec<String>, trailing_newline: bool) -> String {
+    let mut text = lines.concat();
+    if !trailing_newline {
+        text.pop();
+    }
+    text
+}
+
+fn move_outline_section(
+    markdown: &str,
+    outline: &[OutlineItem],
+    idx: usize,
+    dest_line: usize,
+) -> Option<String> {
+    let (mut lines, trailing_newline) = split_section_lines(markdown);
+    let section = outline_section_lines(outline, idx, lines.len());
+    if section.contains(&dest_line) || dest_line == section.end || dest_line > lines.len() {
+        return None;
+    }
+
+    let moved: Vec<String> = lines.drain(section.clone()).collect();
+    let dest = if dest_line > section.start {
+        dest_line - moved.len()
+    } else {
+        dest_line
+    };
+    lines.splice(dest..dest, moved);
+    Some(join_section_lines(lines, trailing_newline))
+}
+
+fn heading_ranges(markdown: &str) -> HashMap<usize, std::ops::Range<usize>> {
+    use pulldown_cmark::{Event, Options, Parser, Tag};
+
+    let options = markdown_parser_options() | Options::ENABLE_YAML_STYLE_METADATA_BLOCKS;
+    let mut ranges = HashMap::new();
+    let mut line = 0usize;
+    let mut counted = 0usize;
+    for (event, range) in Parser::new_ext(markdown, options).into_offset_iter() {
+        if let Event::Start(Tag::Heading { .. }) = event {
+            line += markdown[counted..range.start].matches('\n').count();
+            counted = range.start;
+            ranges.insert(line, range);
+        }
+    }
+    ranges
+}
+
+fn shift_outline_section(
+    markdown: &str,
+    outline: &[OutlineItem],
+    idx: usize,
+    delta: isize,
+) -> Option<String> {
+    let items = outline_section_items(outline, idx);
+    let shifted = |level: usize| {
+        level
+            .checked_add_signed(delta)
+            .filter(|level| (1..=6).contains(level))
+    };
+    if outline[items.clone()]
+        .iter()
+        .any(|item| shifted(item.level).is_none())
+    {
+        return None;
+    }
+
+    let ranges = heading_ranges(markdown);
+    let mut text = markdown.to_string();
+    // Back to front, so earlier byte offsets stay valid.
+    for item in outline[items].iter().rev() {
+        let level = shifted(item.level)?;
+        let range = ranges.get(&item.line)?;
+        let source = markdown[range.clone()].trim_end_matches(['\n', '\r']);
+        let end = range.start + source.len();
+
+        if source.starts_with('#') {
+            let content = source
+                .trim_start_matches('#')
+                .trim_start_matches([' ', '\t']);
+            let marker = format!("{} ", "#".repeat(level));
+            text.replace_range(range.start..end - content.len(), &marker);
+            continue;
+        }
+
+        let (heading, underline) = source.rsplit_once('\n')?;
+        let underline = underline.trim_end();
+        let underline_char = underline
+            .chars()
+            .last()
+            .filter(|c| matches!(c, '=' | '-'))?;
+        let width = underline.len() - underline.trim_end_matches(underline_char).len();
+        if level <= 2 {
+            let run_start = range.start + heading.len() + 1 + underline.len() - width;
+            let marker = if level == 1 { "=" } else { "-" };
+            text.replace_range(run_start..run_start + width, &marker.repeat(width));
+        } else {
+            let line_start = markdown[..range.start].rfind('\n').map_or(0, |i| i + 1);
+            let quotes = markdown[line_start..range.start].matches('>').count();
+            let title = heading
+                .lines()
+                .enumerate()
+                .map(|(i, line)| {
+                    let line = if i == 0 {
+                        line
+                    } else {
+                        strip_quote_markers(line, quotes)
+                    };
+                    line.trim()
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            text.replace_range(range.start..end, &format!("{} {title}", "#".repeat(level)));
+        }
+    }
+    Some(text)
+}
+
+fn strip_quote_markers(mut line: &str, depth: usize) -> &str {
+    for _ in 0..depth {
+        match line.trim_start().strip_prefix('>') {
+            Some(rest) => line = rest,
+            None => break,
+        }
+    }
+    line
 }
 
 #[derive(Debug)]
 enum WatchCommand {
     SetWatchedFiles(Vec<PathBuf>),
+    SetWorkspace(Option<PathBuf>),
 }
 
 fn spawn_file_watcher(
@@ -485,6 +1147,7 @@ fn spawn_file_watcher(
             };
 
         let mut watched_dirs = HashSet::<PathBuf>::new();
+        let mut workspace_root = None::<PathBuf>;
         while let Ok(cmd) = rx.recv() {
             match cmd {
                 WatchCommand::SetWatchedFiles(files) => {
@@ -504,72 +1167,603 @@ fn spawn_file_watcher(
 
                     watched_dirs = next_dirs;
                 }
+                WatchCommand::SetWorkspace(root) => {
+                    if let Some(old_root) = workspace_root.take() {
+                        let _ = watcher.unwatch(&old_root);
+                    }
+                    if let Some(root) = root {
+                        match watcher.watch(&root, notify::RecursiveMode::Recursive) {
+                            Ok(()) => workspace_root = Some(root),
+                            Err(err) => eprintln!("workspace watcher disabled: {err}"),
+                        }
+                    }
+                }
             }
         }
     });
 }
 
-struct Document {
-    id: u64,
-    raw_markdown: String,
-    markdown: String,
-    file_path: Option<PathBuf>,
-    github_repo: Option<GithubRepo>,
-    outline: Vec<OutlineItem>,
-    scroll_to_line: Option<usize>,
-    commonmark_cache: CommonMarkCache,
+fn is_markdown_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|ext| {
+            MARKDOWN_EXTENSIONS
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(ext))
+        })
 }
 
-impl Document {
-    fn welcome(id: u64, settings: &ViewerSettings) -> Self {
-        let raw_markdown = String::from(
-            "# markdownviewer\n\nOpen a `.md` file to view it.\n\n- Use **Open…** or drag and drop a file.\n- Use **Reload** to re-read the current file.\n",
-        );
-        Self::from_content(id, raw_markdown, None, settings)
+#[derive(Debug, Clone, Default)]
+struct WorkspaceDir {
+    name: String,
+    path: PathBuf,
+    dirs: Vec<WorkspaceDir>,
+    files: Vec<PathBuf>,
+}
+
+impl WorkspaceDir {
+    fn is_empty(&self) -> bool {
+        self.dirs.is_empty() && self.files.is_empty()
     }
 
-    fn from_path(id: u64, path: PathBuf, settings: &ViewerSettings) -> Result<Self> {
-        let raw_markdown = read_markdown(&path)?;
-        Ok(Self::from_content(id, raw_markdown, Some(path), settings))
+    fn collect_files(&self, out: &mut Vec<PathBuf>) {
+        out.extend(self.files.iter().cloned());
+        for dir in &self.dirs {
+            dir.collect_files(out);
+        }
     }
+}
 
-    fn from_content(
-        id: u64,
-        raw_markdown: String,
-        file_path: Option<PathBuf>,
-        settings: &ViewerSettings,
-    ) -> Self {
-        let github_repo = file_path.as_deref().and_then(|p| discover_github_repo(p));
+struct Workspace {
+    root: PathBuf,
+    tree: WorkspaceDir,
+    files: HashSet<PathBuf>,
+    rescan_at: Option<Instant>,
+    scanning: bool,
+}
 
-        let mut doc = Self {
-            id,
-            raw_markdown,
-            markdown: String::new(),
-            file_path,
-            github_repo,
-            outline: Vec::new(),
+impl Workspace {
+    fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            tree: WorkspaceDir::default(),
+            files: HashSet::new(),
+            rescan_at: None,
+            scanning: false,
+        }
+    }
+
+    fn set_tree(&mut self, tree: WorkspaceDir) {
+        let mut files = Vec::new();
+        tree.collect_files(&mut files);
+        self.files = files.into_iter().collect();
+        self.tree = tree;
+        self.scanning = false;
+    }
+
+    fn markdown_files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        self.tree.collect_files(&mut files);
+        files
+    }
+
+    fn name(&self) -> String {
+        self.root
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| self.root.display().to_string())
+    }
+
+    fn affected_by(&self, path: &Path) -> bool {
+        if !path.starts_with(&self.root) {
+            return false;
+        }
+        if self.files.contains(path) {
+            return !path.exists();
+        }
+        is_markdown_path(path) || path.is_dir() || !path.exists()
+    }
+}
+
+fn spawn_workspace_scanner(
+    ctx: egui::Context,
+    rx: mpsc::Receiver<PathBuf>,
+    tx: mpsc::Sender<(PathBuf, WorkspaceDir)>,
+) {
+    thread::spawn(move || {
+        while let Ok(mut root) = rx.recv() {
+            while let Ok(newer) = rx.try_recv() {
+                root = newer;
+            }
+            let tree = scan_workspace_dir(&root, 0);
+            if tx.send((root, tree)).is_err() {
+                return;
+            }
+            ctx.request_repaint();
+        }
+    });
+}
+
+fn scan_workspace_dir(dir: &Path, depth: usize) -> WorkspaceDir {
+    const MAX_DEPTH: usize = 32;
+    const SKIPPED_DIRS: &[&str] = &["node_modules", "target"];
+
+    let mut out = WorkspaceDir {
+        name: dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        path: dir.to_path_buf(),
+        ..WorkspaceDir::default()
+    };
+    if depth > MAX_DEPTH {
+        return out;
+    }
+    let Ok(entries) = fs::read_dir(dir) else {
+        return out;
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        // `file_type` does not follow symlinks, which keeps link cycles out of the walk.
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let path = entry.path();
+        if file_type.is_dir() {
+            if SKIPPED_DIRS.contains(&name.as_str()) {
+                continue;
+            }
+            let sub = scan_workspace_dir(&path, depth + 1);
+            if !sub.is_empty() {
+                out.dirs.push(sub);
+            }
+        } else if is_markdown_path(&path) {
+            out.files.push(path);
+        }
+    }
+
+    out.dirs.sort_by_key(|d| d.name.to_lowercase());
+    out.files.sort_by_key(|p| {
+        p.file_name()
+            .map(|n| n.to_string_lossy().to_lowercase())
+            .unwrap_or_default()
+    });
+    out
+}
+
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(5);
+const RECOVERY_EXTENSION: &str = "mdrecovery";
+const RECOVERY_HEADER: &str = "markdownviewer-recovery 1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RecoverySnapshot {
+    file_path: Option<PathBuf>,
+    saved_at: u64,
+    content: String,
+}
+
+#[derive(Debug, Clone)]
+struct RecoveredBuffer {
+    recovery_file: PathBuf,
+    snapshot: RecoverySnapshot,
+}
+
+#[derive(Debug)]
+enum AutosaveCommand {
+    Write {
+        file_name: String,
+        snapshot: RecoverySnapshot,
+    },
+    Remove(String),
+}
+
+struct Autosave {
+    dir: PathBuf,
+    session: String,
+    tx: mpsc::Sender<AutosaveCommand>,
+    worker: thread::JoinHandle<()>,
+    lock: Option<fs::File>,
+    last_run: Instant,
+    snapshots: HashMap<u64, u64>,
+}
+
+impl Autosave {
+    fn start() -> Option<Self> {
+        let dir = recovery_dir()?;
+        let session = format!("{}-{}", chrono_like_timestamp(), std::process::id());
+        let lock = fs::create_dir_all(&dir)
+            .and_then(|()| fs::File::create(dir.join(recovery_lock_name(&session))))
+            .and_then(|file| file.try_lock().map(|()| file).map_err(std::io::Error::from));
+        if let Err(err) = &lock {
+            eprintln!("failed to lock recovery session: {err}");
+        }
+        let (tx, rx) = mpsc::channel::<AutosaveCommand>();
+        let worker = spawn_autosave_worker(dir.clone(), rx);
+        Some(Self {
+            dir,
+            session,
+            tx,
+            worker,
+            lock: lock.ok(),
+            last_run: Instant::now(),
+            snapshots: HashMap::new(),
+        })
+    }
+
+    fn file_name(&self, doc_id: u64) -> String {
+        format!("{}-{doc_id}.{RECOVERY_EXTENSION}", self.session)
+    }
+
+    fn finish(self) {
+        for id in self.snapshots.keys() {
+            let _ = self.tx.send(AutosaveCommand::Remove(self.file_name(*id)));
+        }
+        drop(self.tx);
+        let _ = self.worker.join();
+        if self.lock.is_some() {
+            let _ = fs::remove_file(self.dir.join(recovery_lock_name(&self.session)));
+        }
+    }
+}
+
+fn recovery_lock_name(session: &str) -> String {
+    format!("{session}.lock")
+}
+
+fn recovery_session_is_live(recovery_file: &Path) -> bool {
+    let Some((session, _doc_id)) = recovery_file
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.rsplit_once('-'))
+    else {
+        return false;
+    };
+    let Some(dir) = recovery_file.parent() else {
+        return false;
+    };
+    fs::File::open(dir.join(recovery_lock_name(session)))
+        .is_ok_and(|file| matches!(file.try_lock(), Err(fs::TryLockError::WouldBlock)))
+}
+
+fn recovery_dir() -> Option<PathBuf> {
+    eframe::storage_dir(APP_NAME).map(|dir| dir.join("recovery"))
+}
+
+fn encode_recovery_snapshot(snapshot: &RecoverySnapshot) -> String {
+    let mut out = String::with_capacity(snapshot.content.len() + 128);
+    out.push_str(RECOVERY_HEADER);
+    out.push('\n');
+    if let Some(path) = &snapshot.file_path {
+        out.push_str(&format!("path {}\n", path.display()));
+    }
+    out.push_str(&format!("saved {}\n\n", snapshot.saved_at));
+    out.push_str(&snapshot.content);
+    out
+}
+
+fn decode_recovery_snapshot(text: &str) -> Option<RecoverySnapshot> {
+    let rest = text.strip_prefix(RECOVERY_HEADER)?.strip_prefix('\n')?;
+    let (header, content) = rest.split_once("\n\n")?;
+
+    let mut file_path = None;
+    let mut saved_at = 0;
+    for line in header.lines() {
+        if let Some(path) = line.strip_prefix("path ") {
+            file_path = Some(PathBuf::from(path));
+        } else if let Some(secs) = line.strip_prefix("saved ") {
+            saved_at = secs.trim().parse().unwrap_or(0);
+        }
+    }
+
+    Some(RecoverySnapshot {
+        file_path,
+        saved_at,
+        content: content.to_string(),
+    })
+}
+
+fn load_recovered_buffers(dir: &Path) -> Vec<RecoveredBuffer> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut out = Vec::new();
+    for entry in entries.flatten() {
+        let recovery_file = entry.path();
+        let extension = recovery_file.extension().and_then(|e| e.to_str());
+        if extension == Some("lock")
+            && fs::File::open(&recovery_file).is_ok_and(|file| file.try_lock().is_ok())
+        {
+            let _ = fs::remove_file(&recovery_file);
+            continue;
+        }
+        if extension != Some(RECOVERY_EXTENSION) || recovery_session_is_live(&recovery_file) {
+            continue;
+        }
+        let Some(snapshot) = fs::read_to_string(&recovery_file)
+            .ok()
+            .and_then(|text| decode_recovery_snapshot(&text))
+        else {
+            continue;
+        };
+        let unchanged = snapshot
+            .file_path
+            .as_deref()
+            .and_then(|path| read_markdown(path).ok())
+            .is_some_and(|disk| disk == snapshot.content);
+        if unchanged {
+            let _ = fs::remove_file(&recovery_file);
+            continue;
+        }
+        out.push(RecoveredBuffer {
+            recovery_file,
+            snapshot,
+        });
+    }
+    out.sort_by_key(|entry| std::cmp::Reverse(entry.snapshot.saved_at));
+    out
+}
+
+fn spawn_autosave_worker(
+    dir: PathBuf,
+    rx: mpsc::Receiver<AutosaveCommand>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while let Ok(cmd) = rx.recv() {
+            match cmd {
+                AutosaveCommand::Write {
+                    file_name,
+                    snapshot,
+                } => {
+                    if let Err(err) = fs::create_dir_all(&dir) {
+                        eprintln!("autosave disabled: {err}");
+                        continue;
+                    }
+                    let target = dir.join(&file_name);
+                    let tmp = dir.join(format!("{file_name}.tmp"));
+                    let written = fs::write(&tmp, encode_recovery_snapshot(&snapshot))
+                        .and_then(|()| fs::rename(&tmp, &target));
+                    if let Err(err) = written {
+                        eprintln!("autosave failed for `{}`: {err}", target.display());
+                    }
+                }
+                AutosaveCommand::Remove(file_name) => {
+                    let _ = fs::remove_file(dir.join(file_name));
+                }
+            }
+        }
+    })
+}
+
+struct Document {
+    id: u64,
+    raw_markdown: String,
+    markdown: String,
+    file_path: Option<PathBuf>,
+    github_repo: Option<GithubRepo>,
+    outline: Vec<OutlineItem>,
+    preview_blocks: Vec<PreviewBlock>,
+    footnotes: Vec<Footnote>,
+    preview_layout: LineLayout,
+    viewed_line: Option<usize>,
+    outline_collapsed: HashSet<String>,
+    scroll_to_line: Option<usize>,
+    commonmark_cache: CommonMarkCache,
+    dirty: bool,
+    saved_hash: Option<u64>,
+    history: EditHistory,
+    disk_conflict: Option<DiskConflict>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EditKind {
+    Typing,
+    Command,
+}
+
+#[derive(Debug, Default)]
+struct EditHistory {
+    undo: VecDeque<String>,
+    redo: Vec<String>,
+    baseline: String,
+    last_typing: Option<Instant>,
+}
+
+impl EditHistory {
+    const MAX_STEPS: usize = 200;
+    const MAX_BYTES: usize = 64 * 1024 * 1024;
+    const TYPING_MERGE_WINDOW: Duration = Duration::from_millis(1000);
+
+    fn new(text: &str) -> Self {
+        Self {
+            baseline: text.to_string(),
+            ..Self::default()
+        }
+    }
+
+    fn record(&mut self, current: &str, kind: EditKind) -> bool {
+        if current == self.baseline {
+            return false;
+        }
+
+        let merge = kind == EditKind::Typing
+            && self
+                .last_typing
+                .is_some_and(|at| at.elapsed() < Self::TYPING_MERGE_WINDOW)
+            && !self.undo.is_empty();
+        let before = std::mem::replace(&mut self.baseline, current.to_string());
+        if !merge {
+            self.undo.push_back(before);
+            self.trim();
+        }
+        self.redo.clear();
+        self.last_typing = (kind == EditKind::Typing).then(Instant::now);
+        true
+    }
+
+    fn undo(&mut self) -> Option<String> {
+        let previous = self.undo.pop_back()?;
+        self.redo
+            .push(std::mem::replace(&mut self.baseline, previous.clone()));
+        self.last_typing = None;
+        Some(previous)
+    }
+
+    fn redo(&mut self) -> Option<String> {
+        let next = self.redo.pop()?;
+        self.undo
+            .push_back(std::mem::replace(&mut self.baseline, next.clone()));
+        self.last_typing = None;
+        Some(next)
+    }
+
+    fn trim(&mut self) {
+        let mut bytes: usize = self.undo.iter().map(String::len).sum();
+        while self.undo.len() > Self::MAX_STEPS || (bytes > Self::MAX_BYTES && self.undo.len() > 1)
+        {
+            if let Some(dropped) = self.undo.pop_front() {
+                bytes -= dropped.len();
+            }
+        }
+    }
+
+    fn reset(&mut self, text: &str) {
+        *self = Self::new(text);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DiskConflict {
+    disk_markdown: String,
+    show_diff: bool,
+}
+
+impl Document {
+    fn welcome(id: u64, settings: &ViewerSettings) -> Self {
+        let raw_markdown = String::from(
+            "# markdownviewer\n\nOpen a `.md` file to view it.\n\n- Use **Open…** or drag and drop a file.\n- Use **Reload** to re-read the current file.\n",
+        );
+        Self::from_content(id, raw_markdown, None, settings)
+    }
+
+    fn from_path(id: u64, path: PathBuf, settings: &ViewerSettings) -> Result<Self> {
+        let raw_markdown = read_markdown(&path)?;
+        Ok(Self::from_content(id, raw_markdown, Some(path), settings))
+    }
+
+    fn from_content(
+        id: u64,
+        raw_markdown: String,
+        file_path: Option<PathBuf>,
+        settings: &ViewerSettings,
+    ) -> Self {
+        let github_repo = file_path.as_deref().and_then(|p| discover_github_repo(p));
+
+        let mut doc = Self {
+            id,
+            saved_hash: Some(content_hash(&raw_markdown)),
+            history: EditHistory::new(&raw_markdown),
+            raw_markdown,
+            markdown: String::new(),
+            file_path,
+            github_repo,
+            outline: Vec::new(),
+            preview_blocks: Vec::new(),
+            footnotes: Vec::new(),
+            preview_layout: LineLayout::default(),
+            viewed_line: None,
+            outline_collapsed: HashSet::new(),
             scroll_to_line: None,
             commonmark_cache: CommonMarkCache::default(),
+            dirty: false,
+            disk_conflict: None,
         };
         doc.rebuild_markdown(settings);
         doc
     }
 
     fn rebuild_markdown(&mut self, settings: &ViewerSettings) {
-        self.markdown =
-            preprocess_markdown(&self.raw_markdown, settings, self.github_repo.as_ref());
+        let markdown = preprocess_markdown(&self.raw_markdown, settings, self.github_repo.as_ref());
+        (self.markdown, self.footnotes) = link_footnotes(&markdown);
         self.outline = build_outline(&self.raw_markdown);
+        self.preview_blocks = split_preview_blocks(&self.markdown);
+        expand_toc_markers(&mut self.preview_blocks, &self.outline);
         self.commonmark_cache = CommonMarkCache::default();
     }
 
+    fn record_edit(&mut self, settings: &ViewerSettings, kind: EditKind) {
+        if self.history.record(&self.raw_markdown, kind) {
+            self.mark_edited(settings);
+        }
+    }
+
+    fn mark_edited(&mut self, settings: &ViewerSettings) {
+        self.dirty = self.saved_hash != Some(content_hash(&self.raw_markdown));
+        self.rebuild_markdown(settings);
+    }
+
+    fn mark_clean(&mut self) {
+        self.saved_hash = Some(content_hash(&self.raw_markdown));
+        self.dirty = false;
+    }
+
+    fn undo(&mut self, settings: &ViewerSettings) -> bool {
+        let Some(text) = self.history.undo() else {
+            return false;
+        };
+        self.raw_markdown = text;
+        self.mark_edited(settings);
+        true
+    }
+
+    fn redo(&mut self, settings: &ViewerSettings) -> bool {
+        let Some(text) = self.history.redo() else {
+            return false;
+        };
+        self.raw_markdown = text;
+        self.mark_edited(settings);
+        true
+    }
+
     fn reload(&mut self, settings: &ViewerSettings) -> Result<()> {
         let path = self.file_path.clone().context("no file to reload")?;
         self.raw_markdown = read_markdown(&path)?;
         self.github_repo = discover_github_repo(&path);
+        self.mark_clean();
+        self.history.reset(&self.raw_markdown);
+        self.disk_conflict = None;
         self.rebuild_markdown(settings);
         Ok(())
     }
 
+    fn take_disk_version(&mut self, settings: &ViewerSettings) {
+        let Some(conflict) = self.disk_conflict.take() else {
+            return;
+        };
+        self.raw_markdown = conflict.disk_markdown;
+        self.mark_clean();
+        self.record_edit(settings, EditKind::Command);
+    }
+
+    fn save_to(&mut self, path: PathBuf, settings: &ViewerSettings) -> Result<()> {
+        fs::write(&path, self.raw_markdown.as_bytes())
+            .with_context(|| format!("failed to write `{}`", path.display()))?;
+        self.mark_clean();
+        self.disk_conflict = None;
+
+        let path = normalize_path(path);
+        if self.file_path.as_ref() != Some(&path) {
+            self.github_repo = discover_github_repo(&path);
+            self.file_path = Some(path);
+            self.rebuild_markdown(settings);
+        }
+        Ok(())
+    }
+
     fn display_name(&self) -> String {
         match &self.file_path {
             Some(path) => path
@@ -607,6 +1801,9 @@ impl Document {
         if let Some(item) = self.outline.iter().find(|item| item.slug == decoded_lc) {
             return Some(item.line);
         }
+        if let Some(line) = footnote_line_for_fragment(&self.footnotes, decoded) {
+            return Some(line);
+        }
 
         let slug = slugify_heading(decoded);
         if slug.is_empty() {
@@ -624,7 +1821,18 @@ impl Document {
 struct FindMatch {
     line: usize,
     col: usize,
+    span: std::ops::Range<usize>,
+    line_span: std::ops::Range<usize>,
     preview: String,
+    highlight: std::ops::Range<usize>,
+    captures: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct FindOptions {
+    case_sensitive: bool,
+    whole_word: bool,
+    regex: bool,
 }
 
 #[derive(Debug, Default)]
@@ -632,53 +1840,155 @@ struct FindState {
     open: bool,
     focus_query: bool,
     query: String,
-    case_sensitive: bool,
+    options: FindOptions,
+    replace_open: bool,
+    replacement: String,
+    replaced: Option<usize>,
     matches: Vec<FindMatch>,
+    total: usize,
     selected: usize,
+    error: Option<String>,
     last_doc_id: Option<u64>,
+    last_text_hash: u64,
     last_query: String,
-    last_case_sensitive: bool,
+    last_options: FindOptions,
+    reveal_selected: bool,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
-enum SlashCategory {
-    Headings,
-    Lists,
-    Blocks,
-    Inline,
-    Tables,
-    Media,
-    Transforms,
-    Custom,
+struct PreviewHighlight {
+    regex: Regex,
+    whole_word: bool,
+    selected: Option<(usize, usize)>,
+    reveal: bool,
 }
 
-#[derive(Debug, Clone)]
-struct SlashCommand {
-    id: &'static str,
-    name: &'static str,
-    description: &'static str,
-    category: SlashCategory,
-    aliases: &'static [&'static str],
-    keywords: &'static [&'static str],
-    insert_text: &'static str,
-    when_has_selection: Option<bool>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum QuickOpenSource {
+    OpenTab,
+    Recent,
+    Workspace,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(default)]
-struct SlashCommandConfig {
-    enabled: bool,
-    trigger_char: char,
-    show_descriptions: bool,
-    show_recently_used: bool,
-    recently_used_count: usize,
+impl QuickOpenSource {
+    fn label(self) -> &'static str {
+        match self {
+            QuickOpenSource::OpenTab => "open",
+            QuickOpenSource::Recent => "recent",
+            QuickOpenSource::Workspace => "folder",
+        }
+    }
 }
 
-impl Default for SlashCommandConfig {
-    fn default() -> Self {
-        Self {
-            enabled: true,
-            trigger_char: '/',
+#[derive(Debug, Clone)]
+struct QuickOpenItem {
+    path: PathBuf,
+    label: String,
+    source: QuickOpenSource,
+    score: f32,
+    positions: Vec<usize>,
+}
+
+#[derive(Debug, Default)]
+struct QuickOpenState {
+    open: bool,
+    focus_query: bool,
+    query: String,
+    selected: usize,
+    scroll_to_selected: bool,
+    items: Vec<QuickOpenItem>,
+    last_query: Option<String>,
+}
+
+#[derive(Debug)]
+struct SearchSource {
+    doc_id: Option<u64>,
+    path: Option<PathBuf>,
+    title: String,
+    text: Option<String>,
+}
+
+#[derive(Debug)]
+struct SearchRequest {
+    generation: u64,
+    regex: Regex,
+    whole_word: bool,
+    sources: Vec<SearchSource>,
+}
+
+#[derive(Debug, Clone)]
+struct SearchFileResult {
+    doc_id: Option<u64>,
+    path: Option<PathBuf>,
+    title: String,
+    matches: Vec<FindMatch>,
+}
+
+#[derive(Debug)]
+enum SearchEvent {
+    File {
+        generation: u64,
+        result: SearchFileResult,
+    },
+    Done {
+        generation: u64,
+        files_searched: usize,
+    },
+}
+
+#[derive(Debug, Default)]
+struct SearchState {
+    open: bool,
+    focus_query: bool,
+    query: String,
+    options: FindOptions,
+    tabs_only: bool,
+    error: Option<String>,
+    results: Vec<SearchFileResult>,
+    files_searched: usize,
+    running: bool,
+    search_at: Option<Instant>,
+    generation: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum SlashCategory {
+    Headings,
+    Lists,
+    Blocks,
+    Inline,
+    Tables,
+    Media,
+    Transforms,
+    Custom,
+}
+
+#[derive(Debug, Clone)]
+struct SlashCommand {
+    id: &'static str,
+    name: &'static str,
+    description: &'static str,
+    category: SlashCategory,
+    aliases: &'static [&'static str],
+    keywords: &'static [&'static str],
+    insert_text: &'static str,
+    when_has_selection: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct SlashCommandConfig {
+    enabled: bool,
+    trigger_char: char,
+    show_descriptions: bool,
+    show_recently_used: bool,
+    recently_used_count: usize,
+}
+
+impl Default for SlashCommandConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            trigger_char: '/',
             show_descriptions: true,
             show_recently_used: true,
             recently_used_count: 3,
@@ -901,75 +2211,394 @@ fn slash_registry() -> Vec<SlashCommand> {
     ]
 }
 
-fn fuzzy_score(query: &str, haystack: &str) -> Option<f32> {
+#[derive(Debug, Clone, PartialEq)]
+struct FuzzyMatch {
+    score: f32,
+    positions: Vec<usize>,
+}
+
+fn fold_case(ch: char) -> char {
+    ch.to_lowercase().next().unwrap_or(ch)
+}
+
+fn fuzzy_match(query: &str, haystack: &str) -> Option<FuzzyMatch> {
+    const MATCH: i32 = 16;
+    const BOUNDARY: i32 = 10;
+    const CONSECUTIVE: i32 = 12;
+    const GAP: i32 = 2;
+    const MAX_LEADING_PENALTY: usize = 12;
+    const MAX_HAYSTACK_CHARS: usize = 512;
+
+    let query: Vec<char> = query
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(fold_case)
+        .collect();
     if query.is_empty() {
-        return Some(1.0);
-    }
-    let mut q = query.chars().map(|c| c.to_ascii_lowercase());
-    let mut current = q.next()?;
-    let mut matched = 0usize;
-    for ch in haystack.chars().map(|c| c.to_ascii_lowercase()) {
-        if ch == current {
-            matched += 1;
-            if let Some(next) = q.next() {
-                current = next;
-            } else {
-                let density = matched as f32 / haystack.len().max(1) as f32;
-                return Some((matched as f32 / query.len().max(1) as f32) + density);
+        return Some(FuzzyMatch {
+            score: 1.0,
+            positions: Vec::new(),
+        });
+    }
+    let original: Vec<char> = haystack.chars().take(MAX_HAYSTACK_CHARS).collect();
+    let hay: Vec<char> = original.iter().copied().map(fold_case).collect();
+    let (m, n) = (query.len(), hay.len());
+    if m > n {
+        return None;
+    }
+
+    let bonus = |j: usize| -> i32 {
+        if j == 0 {
+            return BOUNDARY;
+        }
+        let (prev, cur) = (original[j - 1], original[j]);
+        let boundary = matches!(prev, '/' | '\\' | '_' | '-' | ' ' | '.')
+            || (prev.is_lowercase() && cur.is_uppercase())
+            || (!prev.is_alphanumeric() && cur.is_alphanumeric());
+        if boundary { BOUNDARY } else { 0 }
+    };
+
+    // best[i * n + j]: best score for query[..=i] with query[i] matched at hay[j],
+    // together with the position query[i - 1] was matched at.
+    let mut best: Vec<Option<(i32, usize)>> = vec![None; m * n];
+    for j in 0..n {
+        if hay[j] == query[0] {
+            let leading = j.min(MAX_LEADING_PENALTY) as i32;
+            best[j] = Some((MATCH + bonus(j) - leading, usize::MAX));
+        }
+    }
+    for i in 1..m {
+        let mut gapped: Option<(i32, usize)> = None;
+        for j in 1..n {
+            if hay[j] == query[i] {
+                let adjacent = best[(i - 1) * n + j - 1].map(|(s, _)| (s + CONSECUTIVE, j - 1));
+                let prev = match (adjacent, gapped) {
+                    (Some(a), Some(g)) => Some(if g.0 > a.0 { g } else { a }),
+                    (a, g) => a.or(g),
+                };
+                if let Some((score, k)) = prev {
+                    best[i * n + j] = Some((score + MATCH + bonus(j), k));
+                }
             }
+
+            let candidate = best[(i - 1) * n + j - 1].map(|(s, _)| (s - GAP, j - 1));
+            gapped = match (gapped.map(|(s, k)| (s - GAP, k)), candidate) {
+                (Some(g), Some(c)) => Some(if c.0 > g.0 { c } else { g }),
+                (g, c) => g.or(c),
+            };
         }
     }
-    None
-}
 
-fn find_matches(text: &str, query: &str, case_sensitive: bool) -> Vec<FindMatch> {
-    const MAX_MATCHES: usize = 500;
-    if query.is_empty() {
-        return Vec::new();
+    let (mut j, (raw, _)) = (0..n)
+        .filter_map(|j| best[(m - 1) * n + j].map(|entry| (j, entry)))
+        .max_by_key(|(_, (score, _))| *score)?;
+
+    let mut positions = vec![0usize; m];
+    for i in (0..m).rev() {
+        positions[i] = j;
+        if i > 0 {
+            j = best[i * n + j].map(|(_, k)| k)?;
+        }
     }
 
-    let mut matches = Vec::new();
-    let query_cmp = if case_sensitive {
+    let max = (m as i32 * (MATCH + BOUNDARY + CONSECUTIVE)) as f32;
+    let density = m as f32 / n as f32;
+    Some(FuzzyMatch {
+        score: raw as f32 / max + 0.1 * density,
+        positions,
+    })
+}
+
+fn fuzzy_score(query: &str, haystack: &str) -> Option<f32> {
+    fuzzy_match(query, haystack).map(|m| m.score)
+}
+
+fn rank_quick_open(
+    query: &str,
+    candidates: Vec<(PathBuf, QuickOpenSource)>,
+    root: Option<&Path>,
+) -> Vec<QuickOpenItem> {
+    const MAX_RESULTS: usize = 200;
+    const NAME_BONUS: f32 = 0.25;
+
+    let mut items: Vec<QuickOpenItem> = candidates
+        .into_iter()
+        .filter_map(|(path, source)| {
+            let label = match root.and_then(|root| path.strip_prefix(root).ok()) {
+                Some(relative) => relative.to_string_lossy().replace('\\', "/"),
+                None => path.display().to_string(),
+            };
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let name_offset = label.chars().count().saturating_sub(name.chars().count());
+
+            let by_name = fuzzy_match(query, &name).map(|m| FuzzyMatch {
+                score: m.score + NAME_BONUS,
+                positions: m.positions.iter().map(|p| p + name_offset).collect(),
+            });
+            let by_path = fuzzy_match(query, &label);
+            let best = match (by_name, by_path) {
+                (Some(a), Some(b)) => {
+                    if b.score > a.score {
+                        b
+                    } else {
+                        a
+                    }
+                }
+                (a, b) => a.or(b)?,
+            };
+            Some(QuickOpenItem {
+                path,
+                label,
+                source,
+                score: best.score,
+                positions: best.positions,
+            })
+        })
+        .collect();
+
+    items.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.source.cmp(&b.source)));
+    items.truncate(MAX_RESULTS);
+    items
+}
+
+fn build_find_regex(query: &str, options: FindOptions) -> Result<Regex, String> {
+    let pattern = if options.regex {
         query.to_string()
     } else {
-        query.to_lowercase()
+        regex::escape(query)
     };
+    regex::RegexBuilder::new(&pattern)
+        .case_insensitive(!options.case_sensitive)
+        .build()
+        .map_err(|err| err.to_string())
+}
 
-    for (line_no, line) in text.lines().enumerate() {
-        let hay = if case_sensitive {
-            line.to_string()
-        } else {
-            line.to_lowercase()
-        };
+fn is_word_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
 
-        let mut start = 0usize;
-        while start <= hay.len() {
-            let Some(rel) = hay[start..].find(&query_cmp) else {
-                break;
+fn is_whole_word(line: &str, start: usize, end: usize) -> bool {
+    !line[..start].chars().next_back().is_some_and(is_word_char)
+        && !line[end..].chars().next().is_some_and(is_word_char)
+}
+
+fn match_preview(line: &str, start: usize, end: usize) -> (String, std::ops::Range<usize>) {
+    const CONTEXT_BEFORE: usize = 40;
+    const MAX_LEN: usize = 180;
+
+    let content_start = (line.len() - line.trim_start().len()).min(start);
+    let content_end = line.trim_end().len().max(end);
+    let mut from = content_start.max(start.saturating_sub(CONTEXT_BEFORE));
+    while !line.is_char_boundary(from) {
+        from -= 1;
+    }
+    let mut to = content_end.min(from + MAX_LEN).max(end);
+    while !line.is_char_boundary(to) {
+        to += 1;
+    }
+
+    let prefix = if from > content_start { "…" } else { "" };
+    let mut preview = format!("{prefix}{}", &line[from..to]);
+    if to < content_end {
+        preview.push('…');
+    }
+    let highlight_start = prefix.len() + start - from;
+    (preview, highlight_start..highlight_start + (end - start))
+}
+
+const FIND_MAX_MATCHES: usize = 500;
+
+fn find_matches(text: &str, re: &Regex, whole_word: bool) -> Vec<FindMatch> {
+    find_matches_up_to(text, re, whole_word, FIND_MAX_MATCHES)
+}
+
+fn count_find_matches(text: &str, re: &Regex, whole_word: bool) -> usize {
+    text.lines()
+        .map(|line| {
+            re.find_iter(line)
+                .filter(|m| {
+                    !m.is_empty() && (!whole_word || is_whole_word(line, m.start(), m.end()))
+                })
+                .count()
+        })
+        .sum()
+}
+
+fn find_matches_up_to(text: &str, re: &Regex, whole_word: bool, limit: usize) -> Vec<FindMatch> {
+    let mut matches = Vec::new();
+    let mut line_start = 0usize;
+    for (line_no, raw_line) in text.split_inclusive('\n').enumerate() {
+        let line = raw_line.strip_suffix('\n').unwrap_or(raw_line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let offset = line_start;
+        line_start += raw_line.len();
+
+        for caps in re.captures_iter(line) {
+            let Some(whole) = caps.get(0) else {
+                continue;
             };
-            let col = start + rel;
-            let mut preview = line.trim().to_string();
-            if preview.len() > 180 {
-                preview.truncate(180);
-                preview.push('…');
+            if whole.is_empty() || (whole_word && !is_whole_word(line, whole.start(), whole.end()))
+            {
+                continue;
             }
 
+            let (preview, highlight) = match_preview(line, whole.start(), whole.end());
             matches.push(FindMatch {
                 line: line_no,
-                col,
+                col: line[..whole.start()].chars().count(),
+                span: offset + whole.start()..offset + whole.end(),
+                line_span: offset..offset + line.len(),
                 preview,
+                highlight,
+                captures: caps
+                    .iter()
+                    .skip(1)
+                    .map(|group| group.map(|g| g.as_str().to_string()).unwrap_or_default())
+                    .collect(),
             });
-            if matches.len() >= MAX_MATCHES {
+            if matches.len() >= limit {
                 return matches;
             }
-
-            start = col + query_cmp.len().max(1);
         }
     }
 
     matches
 }
 
+fn expand_replacements(
+    text: &str,
+    matches: &[FindMatch],
+    re: &Regex,
+    template: &str,
+    regex_mode: bool,
+) -> Vec<String> {
+    matches
+        .iter()
+        .map(|m| {
+            if !regex_mode {
+                return template.to_string();
+            }
+            let line = &text[m.line_span.clone()];
+            let mut out = String::new();
+            match re.captures_at(line, m.span.start - m.line_span.start) {
+                Some(caps) => caps.expand(template, &mut out),
+                None => out.push_str(template),
+            }
+            out
+        })
+        .collect()
+}
+
+fn apply_replacements(text: &str, matches: &[FindMatch], replacements: &[String]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for (m, replacement) in matches.iter().zip(replacements) {
+        out.push_str(&text[last..m.span.start]);
+        out.push_str(replacement);
+        last = m.span.end;
+    }
+    out.push_str(&text[last..]);
+    out
+}
+
+fn replacement_preview(
+    text: &str,
+    matches: &[FindMatch],
+    replacements: &[String],
+) -> Vec<(usize, String, String)> {
+    let mut rows = Vec::new();
+    let mut idx = 0;
+    while idx < matches.len() {
+        let line_span = matches[idx].line_span.clone();
+        let end = idx
+            + matches[idx..]
+                .iter()
+                .take_while(|m| m.line_span == line_span)
+                .count();
+        let local: Vec<FindMatch> = matches[idx..end]
+            .iter()
+            .map(|m| FindMatch {
+                span: m.span.start - line_span.start..m.span.end - line_span.start,
+                ..m.clone()
+            })
+            .collect();
+        let before = &text[line_span];
+        rows.push((
+            matches[idx].line,
+            before.to_string(),
+            apply_replacements(before, &local, &replacements[idx..end]),
+        ));
+        idx = end;
+    }
+    rows
+}
+
+fn search_text(text: &str, query: &str, options: FindOptions) -> Result<Vec<FindMatch>, String> {
+    if query.is_empty() {
+        return Ok(Vec::new());
+    }
+    let re = build_find_regex(query, options)?;
+    Ok(find_matches(text, &re, options.whole_word))
+}
+
+fn find_options_ui(ui: &mut egui::Ui, options: &mut FindOptions) -> bool {
+    let mut changed = ui
+        .toggle_value(&mut options.case_sensitive, "Aa")
+        .on_hover_text("Match case")
+        .changed();
+    changed |= ui
+        .toggle_value(&mut options.whole_word, "ab")
+        .on_hover_text("Match whole word")
+        .changed();
+    changed |= ui
+        .toggle_value(&mut options.regex, ".*")
+        .on_hover_text("Use regular expression")
+        .changed();
+    changed
+}
+
+fn find_match_job(ui: &egui::Ui, prefix: &str, m: &FindMatch) -> egui::text::LayoutJob {
+    let font = egui::TextStyle::Body.resolve(ui.style());
+    let small = egui::TextStyle::Small.resolve(ui.style());
+    let normal = ui.visuals().text_color();
+    let weak = ui.visuals().weak_text_color();
+
+    let mut job = egui::text::LayoutJob::default();
+    job.append(prefix, 0.0, egui::TextFormat::simple(font.clone(), weak));
+    let highlight = m.highlight.start.min(m.preview.len())..m.highlight.end.min(m.preview.len());
+    job.append(
+        &m.preview[..highlight.start],
+        0.0,
+        egui::TextFormat::simple(font.clone(), normal),
+    );
+    job.append(
+        &m.preview[highlight.clone()],
+        0.0,
+        egui::TextFormat {
+            font_id: font.clone(),
+            color: ui.visuals().strong_text_color(),
+            background: ui.visuals().selection.bg_fill,
+            ..Default::default()
+        },
+    );
+    job.append(
+        &m.preview[highlight.end..],
+        0.0,
+        egui::TextFormat::simple(font, normal),
+    );
+    for (idx, capture) in m.captures.iter().enumerate() {
+        job.append(
+            &format!("${}={capture}", idx + 1),
+            8.0,
+            egui::TextFormat::simple(small.clone(), weak),
+        );
+    }
+    job
+}
+
 struct MarkdownViewerApp {
     documents: Vec<Document>,
     active_doc: usize,
@@ -977,13 +2606,21 @@ struct MarkdownViewerApp {
     persisted: PersistedState,
     next_doc_id: u64,
     find: FindState,
+    quick_open: QuickOpenState,
+    search: SearchState,
+    search_tx: mpsc::Sender<SearchRequest>,
+    search_event_rx: mpsc::Receiver<SearchEvent>,
     watch_cmd_tx: mpsc::Sender<WatchCommand>,
     watch_event_rx: mpsc::Receiver<PathBuf>,
+    workspace_scan_tx: mpsc::Sender<PathBuf>,
+    workspace_scan_rx: mpsc::Receiver<(PathBuf, WorkspaceDir)>,
     pending_reloads: HashMap<PathBuf, Instant>,
-    math_cache: Arc<Mutex<HashMap<MathKey, SvgState>>>,
-    math_tx: mpsc::Sender<MathKey>,
-    mermaid_cache: Arc<Mutex<HashMap<MermaidKey, SvgState>>>,
-    mermaid_tx: mpsc::Sender<MermaidKey>,
+    math_cache: Arc<Mutex<SvgCache<MathKey>>>,
+    math_pool: Arc<MathPool>,
+    diagram_cache: Arc<Mutex<SvgCache<DiagramKey>>>,
+    diagram_tx: mpsc::Sender<DiagramKey>,
+    render_disk_cache: Option<Arc<RenderDiskCache>>,
+    render_cache_swept: Instant,
     error: Option<String>,
     editor_has_focus: bool,
     drop_zone_visible: bool,
@@ -995,6 +2632,30 @@ struct MarkdownViewerApp {
     slash_config: SlashCommandConfig,
     slash_tracker: SlashCommandTracker,
     inline_state: InlineSurfaceState,
+    split_sync: SplitScrollSync,
+    outline_panel: OutlinePanelState,
+    pending_close: Option<PendingClose>,
+    allow_exit: bool,
+    autosave: Option<Autosave>,
+    recovered: Vec<RecoveredBuffer>,
+    workspace: Option<Workspace>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PendingClose {
+    Tab(u64),
+    Exit,
+}
+
+impl PendingClose {
+    fn dirty_documents(self, documents: &[Document]) -> Vec<usize> {
+        (0..documents.len())
+            .filter(|&idx| {
+                let doc = &documents[idx];
+                doc.dirty && (self == Self::Exit || self == Self::Tab(doc.id))
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -1009,6 +2670,9 @@ struct EditorUiState {
     cursor_line: usize,
     has_selection: bool,
     open_slash_args: Option<(usize, usize, String, egui::Pos2)>,
+    scroll_offset: f32,
+    viewport_height: f32,
+    scrolled_by_sync: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -1090,21 +2754,49 @@ impl MarkdownViewerApp {
         let settings = persisted.settings.clone();
         let slash_tracker = persisted.slash_tracker.clone();
 
-        let math_cache: Arc<Mutex<HashMap<MathKey, SvgState>>> =
-            Arc::new(Mutex::new(HashMap::new()));
-        let mermaid_cache: Arc<Mutex<HashMap<MermaidKey, SvgState>>> =
-            Arc::new(Mutex::new(HashMap::new()));
+        let memory_limit = svg_cache_limit(&settings);
+        let math_cache = Arc::new(Mutex::new(SvgCache::new(cc.egui_ctx.clone(), memory_limit)));
+        let diagram_cache = Arc::new(Mutex::new(SvgCache::new(cc.egui_ctx.clone(), memory_limit)));
+
+        let (diagram_tx, diagram_rx) = mpsc::channel::<DiagramKey>();
 
-        let (math_tx, math_rx) = mpsc::channel::<MathKey>();
-        let (mermaid_tx, mermaid_rx) = mpsc::channel::<MermaidKey>();
+        let render_disk_cache = render_cache_dir().map(|dir| {
+            Arc::new(RenderDiskCache::new(
+                dir,
+                settings.render_cache_limit_mb * 1024 * 1024,
+            ))
+        });
+        let math_pool = MathPool::start(
+            cc.egui_ctx.clone(),
+            math_cache.clone(),
+            render_disk_cache.clone(),
+            settings.math_workers,
+        );
+        spawn_diagram_worker(
+            cc.egui_ctx.clone(),
+            diagram_cache.clone(),
+            render_disk_cache.clone(),
+            diagram_rx,
+        );
 
-        spawn_math_worker(cc.egui_ctx.clone(), math_cache.clone(), math_rx);
-        spawn_mermaid_worker(cc.egui_ctx.clone(), mermaid_cache.clone(), mermaid_rx);
+        let search = SearchState::default();
+        let (search_tx, search_rx) = mpsc::channel::<SearchRequest>();
+        let (search_event_tx, search_event_rx) = mpsc::channel::<SearchEvent>();
+        spawn_search_worker(
+            cc.egui_ctx.clone(),
+            search.generation.clone(),
+            search_rx,
+            search_event_tx,
+        );
 
         let (watch_cmd_tx, watch_cmd_rx) = mpsc::channel::<WatchCommand>();
         let (watch_event_tx, watch_event_rx) = mpsc::channel::<PathBuf>();
         spawn_file_watcher(cc.egui_ctx.clone(), watch_cmd_rx, watch_event_tx);
 
+        let (workspace_scan_tx, scan_rx) = mpsc::channel::<PathBuf>();
+        let (scan_tx, workspace_scan_rx) = mpsc::channel::<(PathBuf, WorkspaceDir)>();
+        spawn_workspace_scanner(cc.egui_ctx.clone(), scan_rx, scan_tx);
+
         let mut app = Self {
             documents: Vec::new(),
             active_doc: 0,
@@ -1112,13 +2804,21 @@ impl MarkdownViewerApp {
             persisted,
             next_doc_id: 1,
             find: FindState::default(),
+            quick_open: QuickOpenState::default(),
+            search,
+            search_tx,
+            search_event_rx,
             watch_cmd_tx,
             watch_event_rx,
+            workspace_scan_tx,
+            workspace_scan_rx,
             pending_reloads: HashMap::new(),
             math_cache,
-            math_tx,
-            mermaid_cache,
-            mermaid_tx,
+            math_pool,
+            diagram_cache,
+            diagram_tx,
+            render_disk_cache,
+            render_cache_swept: Instant::now(),
             error: None,
             editor_has_focus: false,
             drop_zone_visible: false,
@@ -1130,10 +2830,21 @@ impl MarkdownViewerApp {
             slash_config: SlashCommandConfig::default(),
             slash_tracker,
             inline_state: InlineSurfaceState::default(),
+            split_sync: SplitScrollSync::default(),
+            outline_panel: OutlinePanelState::default(),
+            pending_close: None,
+            allow_exit: false,
+            autosave: Autosave::start(),
+            recovered: Vec::new(),
+            workspace: None,
         };
 
         apply_app_theme(&cc.egui_ctx, app.settings.theme);
 
+        if let Some(root) = app.persisted.workspace_root.clone().filter(|p| p.is_dir()) {
+            app.open_workspace(root);
+        }
+
         let startup_paths: Vec<PathBuf> = std::env::args_os().skip(1).map(PathBuf::from).collect();
         if !startup_paths.is_empty() {
             for path in startup_paths {
@@ -1163,6 +2874,10 @@ impl MarkdownViewerApp {
 
         app.update_watched_paths();
 
+        if let Some(autosave) = &app.autosave {
+            app.recovered = load_recovered_buffers(&autosave.dir);
+        }
+
         app
     }
 
@@ -1175,25 +2890,44 @@ impl MarkdownViewerApp {
     fn refresh_find_cache(&mut self) {
         let Some(doc) = self.active_document() else {
             self.find.matches.clear();
+            self.find.total = 0;
             self.find.selected = 0;
             self.find.last_doc_id = None;
             return;
         };
 
         let doc_id = doc.id;
+        let text_hash = content_hash(&doc.raw_markdown);
         if self.find.last_doc_id != Some(doc_id)
+            || self.find.last_text_hash != text_hash
             || self.find.last_query != self.find.query
-            || self.find.last_case_sensitive != self.find.case_sensitive
+            || self.find.last_options != self.find.options
         {
-            self.find.matches = find_matches(
-                &doc.raw_markdown,
-                &self.find.query,
-                self.find.case_sensitive,
-            );
+            let result = search_text(&doc.raw_markdown, &self.find.query, self.find.options);
+            let total = match &result {
+                Ok(matches) if matches.len() >= FIND_MAX_MATCHES => {
+                    build_find_regex(&self.find.query, self.find.options).map_or(0, |re| {
+                        count_find_matches(&doc.raw_markdown, &re, self.find.options.whole_word)
+                    })
+                }
+                Ok(matches) => matches.len(),
+                Err(_) => 0,
+            };
+            if self.find.last_query != self.find.query
+                || self.find.last_options != self.find.options
+            {
+                self.find.replaced = None;
+            }
+            (self.find.matches, self.find.error) = match result {
+                Ok(matches) => (matches, None),
+                Err(err) => (Vec::new(), Some(err)),
+            };
+            self.find.total = total;
             self.find.selected = 0;
             self.find.last_doc_id = Some(doc_id);
+            self.find.last_text_hash = text_hash;
             self.find.last_query = self.find.query.clone();
-            self.find.last_case_sensitive = self.find.case_sensitive;
+            self.find.last_options = self.find.options;
         }
 
         if !self.find.matches.is_empty() && self.find.selected >= self.find.matches.len() {
@@ -1227,11 +2961,39 @@ impl MarkdownViewerApp {
         let Some(line) = self.find.matches.get(self.find.selected).map(|m| m.line) else {
             return;
         };
+        self.find.reveal_selected = true;
         if let Some(doc) = self.active_document_mut() {
             doc.scroll_to_line = Some(line);
         }
     }
 
+    fn preview_find_highlight(&self) -> Option<PreviewHighlight> {
+        if !self.find.open || self.find.query.is_empty() || self.find.error.is_some() {
+            return None;
+        }
+        let regex = build_find_regex(&self.find.query, self.find.options).ok()?;
+        let doc = self.active_document()?;
+
+        let selected = self.find.matches.get(self.find.selected).and_then(|m| {
+            let block = doc
+                .preview_blocks
+                .iter()
+                .position(|b| b.lines.contains(&m.line))?;
+            let lines = &doc.preview_blocks[block].lines;
+            let nth = self.find.matches[..self.find.selected]
+                .iter()
+                .filter(|other| lines.contains(&other.line))
+                .count();
+            Some((block, nth))
+        });
+        Some(PreviewHighlight {
+            regex,
+            whole_word: self.find.options.whole_word,
+            selected,
+            reveal: self.find.reveal_selected,
+        })
+    }
+
     fn show_find_window(&mut self, ctx: &egui::Context) {
         if !self.find.open {
             return;
@@ -1240,18 +3002,35 @@ impl MarkdownViewerApp {
         self.refresh_find_cache();
         let matches = self.find.matches.clone();
         let selected = self.find.selected;
+        let preview = self.replacement_preview_rows();
 
         let mut jump_to_line = None::<usize>;
         let mut do_next = false;
         let mut do_prev = false;
+        let mut do_replace = false;
+        let mut do_replace_all = false;
 
-        egui::Window::new("Find")
+        let title = if self.find.replace_open {
+            "Find and Replace"
+        } else {
+            "Find"
+        };
+        egui::Window::new(title)
+            .id(egui::Id::new("find_window"))
             .open(&mut self.find.open)
             .resizable(false)
             .collapsible(false)
             .show(ctx, |ui| {
+                // Alt+Enter only means Replace All while one of the window's fields has
+                // focus; consuming it first also keeps that field from losing focus.
+                let query_id = egui::Id::new("find_query");
+                let replacement_id = egui::Id::new("find_replacement");
+                let alt_enter = self.find.replace_open
+                    && ui.memory(|m| m.has_focus(query_id) || m.has_focus(replacement_id))
+                    && ui.input_mut(|i| i.consume_key(egui::Modifiers::ALT, egui::Key::Enter));
                 ui.horizontal(|ui| {
-                    let resp = ui.text_edit_singleline(&mut self.find.query);
+                    let resp =
+                        ui.add(egui::TextEdit::singleline(&mut self.find.query).id(query_id));
                     if self.find.focus_query {
                         resp.request_focus();
                         self.find.focus_query = false;
@@ -1261,9 +3040,8 @@ impl MarkdownViewerApp {
                         do_next = true;
                     }
 
-                    if ui.checkbox(&mut self.find.case_sensitive, "Aa").changed() {
-                        // Updated below after the window closes.
-                    }
+                    // Results are refreshed below, after the window closes.
+                    find_options_ui(ui, &mut self.find.options);
 
                     if ui.button("Prev").clicked() {
                         do_prev = true;
@@ -1271,10 +3049,66 @@ impl MarkdownViewerApp {
                     if ui.button("Next").clicked() {
                         do_next = true;
                     }
+                    ui.toggle_value(&mut self.find.replace_open, "Replace")
+                        .on_hover_text("Show replace (Ctrl+H)");
                 });
 
-                if self.find.query.is_empty() {
+                if self.find.replace_open {
+                    ui.horizontal(|ui| {
+                        let hint = if self.find.options.regex {
+                            "Replace with ($1 for groups)"
+                        } else {
+                            "Replace with"
+                        };
+                        let resp = ui.add(
+                            egui::TextEdit::singleline(&mut self.find.replacement)
+                                .id(replacement_id)
+                                .hint_text(hint),
+                        );
+                        if resp.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                            do_replace = true;
+                        }
+
+                        let can_replace = !matches.is_empty();
+                        if ui
+                            .add_enabled(can_replace, egui::Button::new("Replace"))
+                            .clicked()
+                        {
+                            do_replace = true;
+                        }
+                        if ui
+                            .add_enabled(can_replace, egui::Button::new("Replace All"))
+                            .on_hover_text(format!(
+                                "Replace all {} occurrences (Alt+Enter)",
+                                self.find.total
+                            ))
+                            .clicked()
+                        {
+                            do_replace_all = true;
+                        }
+                    });
+                    do_replace_all |= alt_enter;
+                }
+
+                if let Some(err) = &self.find.error {
+                    ui.label(
+                        egui::RichText::new(format!("Invalid pattern:\n{err}"))
+                            .monospace()
+                            .color(ui.visuals().error_fg_color),
+                    );
+                } else if self.find.query.is_empty() {
                     ui.weak("Type to search…");
+                } else if let Some(count) = self.find.replaced {
+                    ui.label(format!(
+                        "Replaced {count} occurrence{}",
+                        if count == 1 { "" } else { "s" }
+                    ));
+                } else if self.find.total > matches.len() {
+                    ui.label(format!(
+                        "{} matches, first {} listed",
+                        self.find.total,
+                        matches.len()
+                    ));
                 } else {
                     ui.label(format!(
                         "{} match{}",
@@ -1283,11 +3117,49 @@ impl MarkdownViewerApp {
                     ));
                 }
 
+                if !preview.is_empty() {
+                    let partial = if self.find.total > matches.len() {
+                        format!(", first {} of {} matches", matches.len(), self.find.total)
+                    } else {
+                        String::new()
+                    };
+                    egui::CollapsingHeader::new(format!(
+                        "Preview ({} line{}{partial})",
+                        preview.len(),
+                        if preview.len() == 1 { "" } else { "s" }
+                    ))
+                    .id_salt("replace_preview")
+                    .show(ui, |ui| {
+                        egui::ScrollArea::vertical()
+                            .id_salt("replace_preview_rows")
+                            .max_height(200.0)
+                            .show(ui, |ui| {
+                                let removed = ui.visuals().error_fg_color;
+                                let added = ui.visuals().hyperlink_color;
+                                for (line, before, after) in &preview {
+                                    ui.label(
+                                        egui::RichText::new(format!("{:>5} - {before}", line + 1))
+                                            .monospace()
+                                            .strikethrough()
+                                            .color(removed),
+                                    );
+                                    ui.label(
+                                        egui::RichText::new(format!("{:>5} + {after}", line + 1))
+                                            .monospace()
+                                            .color(added),
+                                    );
+                                }
+                            });
+                    });
+                }
+
                 egui::ScrollArea::vertical()
+                    .id_salt("find_matches")
                     .max_height(260.0)
                     .show(ui, |ui| {
                         for (idx, m) in matches.iter().enumerate() {
-                            let label = format!("{}:{}  {}", m.line + 1, m.col + 1, m.preview);
+                            let label =
+                                find_match_job(ui, &format!("{}:{}  ", m.line + 1, m.col + 1), m);
                             if ui.selectable_label(idx == selected, label).clicked() {
                                 self.find.selected = idx;
                                 jump_to_line = Some(m.line);
@@ -1300,2934 +3172,8835 @@ impl MarkdownViewerApp {
         self.refresh_find_cache();
 
         if let Some(line) = jump_to_line {
+            self.find.reveal_selected = true;
             if let Some(doc) = self.active_document_mut() {
                 doc.scroll_to_line = Some(line);
             }
         }
-        if do_prev {
+        if do_replace_all {
+            self.replace_in_active_document(true);
+        } else if do_replace {
+            self.replace_in_active_document(false);
+        } else if do_prev {
             self.find_prev();
         } else if do_next {
             self.find_next();
         }
     }
 
-    fn update_watched_paths(&mut self) {
-        if !self.settings.auto_reload {
-            self.pending_reloads.clear();
-            let _ = self
-                .watch_cmd_tx
-                .send(WatchCommand::SetWatchedFiles(Vec::new()));
-            return;
-        }
-
-        let files: Vec<PathBuf> = self
-            .documents
-            .iter()
-            .filter_map(|doc| doc.file_path.clone())
-            .collect();
-        let _ = self.watch_cmd_tx.send(WatchCommand::SetWatchedFiles(files));
-    }
-
-    fn pump_watch_events(&mut self) {
-        while let Ok(path) = self.watch_event_rx.try_recv() {
-            let normalized = normalize_path(path.clone());
-            for doc_path in self
-                .documents
-                .iter()
-                .filter_map(|doc| doc.file_path.clone())
-            {
-                if doc_path == path || doc_path == normalized {
-                    self.pending_reloads.insert(doc_path, Instant::now());
-                }
-            }
+    fn replacement_preview_rows(&self) -> Vec<(usize, String, String)> {
+        if !self.find.replace_open || self.find.matches.is_empty() {
+            return Vec::new();
         }
+        let (Some(doc), Ok(re)) = (
+            self.active_document(),
+            build_find_regex(&self.find.query, self.find.options),
+        ) else {
+            return Vec::new();
+        };
+        let replacements = expand_replacements(
+            &doc.raw_markdown,
+            &self.find.matches,
+            &re,
+            &self.find.replacement,
+            self.find.options.regex,
+        );
+        replacement_preview(&doc.raw_markdown, &self.find.matches, &replacements)
     }
 
-    fn process_pending_reloads(&mut self) {
-        const DEBOUNCE: Duration = Duration::from_millis(250);
-        let now = Instant::now();
-
-        let ready: Vec<PathBuf> = self
-            .pending_reloads
-            .iter()
-            .filter_map(|(path, last_change)| {
-                if now.duration_since(*last_change) >= DEBOUNCE {
-                    Some(path.clone())
-                } else {
-                    None
-                }
-            })
-            .collect();
-
-        for path in ready {
-            self.pending_reloads.remove(&path);
-            self.reload_open_file_silently(&path);
+    fn replace_in_active_document(&mut self, all: bool) {
+        self.refresh_find_cache();
+        let Ok(re) = build_find_regex(&self.find.query, self.find.options) else {
+            return;
+        };
+        let Some(doc) = self.active_document() else {
+            return;
+        };
+        let matches = if all {
+            find_matches_up_to(
+                &doc.raw_markdown,
+                &re,
+                self.find.options.whole_word,
+                usize::MAX,
+            )
+        } else {
+            self.find
+                .matches
+                .get(self.find.selected)
+                .cloned()
+                .into_iter()
+                .collect()
+        };
+        if self.find.query.is_empty() || matches.is_empty() {
+            return;
         }
-    }
+        let replacements = expand_replacements(
+            &doc.raw_markdown,
+            &matches,
+            &re,
+            &self.find.replacement,
+            self.find.options.regex,
+        );
+        let text = apply_replacements(&doc.raw_markdown, &matches, &replacements);
+        let resume_at = matches[0].span.start + replacements[0].len();
 
-    fn reload_open_file_silently(&mut self, path: &Path) {
         let settings = self.settings.clone();
-        let Some(idx) = self
-            .documents
-            .iter()
-            .position(|doc| doc.file_path.as_deref() == Some(path))
-        else {
+        let Some(doc) = self.active_document_mut() else {
             return;
         };
+        doc.raw_markdown = text;
+        doc.record_edit(&settings, EditKind::Command);
 
-        if self.documents[idx].reload(&settings).is_ok() && idx == self.active_doc {
-            self.error = None;
+        self.refresh_find_cache();
+        if all {
+            self.find.replaced = Some(matches.len());
+        } else if !self.find.matches.is_empty() {
+            self.find.selected = self
+                .find
+                .matches
+                .iter()
+                .position(|m| m.span.start >= resume_at)
+                .unwrap_or(0);
+            self.jump_to_find_selected();
         }
     }
 
-    fn open_dialog(&mut self) {
-        let mut dialog = rfd::FileDialog::new().add_filter(
-            "Markdown",
-            &["md", "markdown", "mdown", "mkd", "mkdn", "mdtxt"],
-        );
-        if let Some(path) = self.active_document().and_then(|d| d.file_path.as_ref()) {
-            if let Some(parent) = path.parent() {
-                dialog = dialog.set_directory(parent);
-            }
-        }
-        if let Some(paths) = dialog.pick_files() {
-            for path in paths {
-                let _ = self.open_file(path);
-            }
-        }
+    fn open_quick_open(&mut self) {
+        self.quick_open.open = true;
+        self.quick_open.focus_query = true;
+        self.quick_open.query.clear();
+        self.quick_open.selected = 0;
+        self.quick_open.last_query = None;
     }
 
-    fn open_file(&mut self, path: PathBuf) -> Result<()> {
-        let path = normalize_path(path);
-        if let Some(idx) = self
+    fn quick_open_candidates(&self) -> Vec<(PathBuf, QuickOpenSource)> {
+        let mut seen = HashSet::new();
+        let mut candidates = Vec::new();
+        let tabs = self
             .documents
             .iter()
-            .position(|doc| doc.file_path.as_ref() == Some(&path))
-        {
-            self.active_doc = idx;
-            if let Err(err) = self.documents[idx].reload(&self.settings) {
-                self.error = Some(err.to_string());
-            } else {
-                self.error = None;
-                self.persisted.remember_file(path);
-                self.update_watched_paths();
+            .filter_map(|doc| doc.file_path.clone())
+            .map(|p| (p, QuickOpenSource::OpenTab));
+        let recent = self
+            .persisted
+            .recent_files
+            .iter()
+            .filter(|p| p.is_file())
+            .cloned()
+            .map(|p| (p, QuickOpenSource::Recent));
+        let workspace = self
+            .workspace
+            .iter()
+            .flat_map(|w| w.markdown_files())
+            .map(|p| (p, QuickOpenSource::Workspace));
+        for (path, source) in tabs.chain(recent).chain(workspace) {
+            if seen.insert(path.clone()) {
+                candidates.push((path, source));
             }
-            return Ok(());
         }
+        candidates
+    }
 
-        match Document::from_path(self.alloc_doc_id(), path, &self.settings) {
-            Ok(doc) => {
-                self.documents.push(doc);
-                self.active_doc = self.documents.len().saturating_sub(1);
-                self.error = None;
-                if let Some(path) = self.documents[self.active_doc].file_path.clone() {
-                    self.persisted.remember_file(path);
-                }
-                self.update_watched_paths();
+    fn refresh_quick_open(&mut self) {
+        if self.quick_open.last_query.as_deref() == Some(self.quick_open.query.as_str()) {
+            return;
+        }
+        let root = self.workspace.as_ref().map(|w| w.root.clone());
+        self.quick_open.items = rank_quick_open(
+            &self.quick_open.query,
+            self.quick_open_candidates(),
+            root.as_deref(),
+        );
+        self.quick_open.selected = 0;
+        self.quick_open.last_query = Some(self.quick_open.query.clone());
+    }
+
+    fn handle_quick_open_keys(&mut self, ctx: &egui::Context) {
+        if !self.quick_open.open {
+            return;
+        }
+        let (up, down, enter, escape) = ctx.input_mut(|i| {
+            (
+                i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowUp),
+                i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowDown),
+                i.consume_key(egui::Modifiers::NONE, egui::Key::Enter),
+                i.consume_key(egui::Modifiers::NONE, egui::Key::Escape),
+            )
+        });
+        if escape {
+            self.quick_open.open = false;
+            return;
+        }
+        let len = self.quick_open.items.len();
+        if len > 0 && (up || down) {
+            self.quick_open.scroll_to_selected = true;
+            if down {
+                self.quick_open.selected = (self.quick_open.selected + 1) % len;
             }
-            Err(err) => {
-                self.error = Some(err.to_string());
+            if up {
+                self.quick_open.selected = (self.quick_open.selected + len - 1) % len;
             }
         }
-        Ok(())
+        if enter {
+            self.open_quick_open_selection();
+        }
     }
 
-    fn reload_active(&mut self) {
-        let settings = self.settings.clone();
-        let Some(doc) = self.active_document_mut() else {
+    fn open_quick_open_selection(&mut self) {
+        let Some(item) = self.quick_open.items.get(self.quick_open.selected) else {
             return;
         };
-        if let Err(e) = doc.reload(&settings) {
-            self.error = Some(e.to_string());
-        } else {
-            self.error = None;
+        let path = item.path.clone();
+        self.quick_open.open = false;
+        if let Err(err) = self.open_file(path) {
+            self.push_error(format!("Failed to open file: {err}"));
         }
     }
 
-    fn rebuild_all_markdown(&mut self) {
-        for doc in &mut self.documents {
-            doc.rebuild_markdown(&self.settings);
+    fn show_quick_open(&mut self, ctx: &egui::Context) {
+        if !self.quick_open.open {
+            return;
         }
-    }
+        self.refresh_quick_open();
 
-    fn clear_render_caches(&mut self) {
-        for doc in &mut self.documents {
-            doc.commonmark_cache = CommonMarkCache::default();
-        }
-        if let Ok(mut map) = self.math_cache.lock() {
-            map.clear();
+        let mut clicked = None::<usize>;
+        egui::Window::new("Quick Open")
+            .title_bar(false)
+            .resizable(false)
+            .collapsible(false)
+            .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 60.0))
+            .default_width(520.0)
+            .show(ctx, |ui| {
+                let scroll_to_selected = std::mem::take(&mut self.quick_open.scroll_to_selected);
+                let resp = ui.add(
+                    egui::TextEdit::singleline(&mut self.quick_open.query)
+                        .hint_text("Go to file…")
+                        .desired_width(f32::INFINITY),
+                );
+                if self.quick_open.focus_query {
+                    resp.request_focus();
+                    self.quick_open.focus_query = false;
+                }
+
+                if self.quick_open.items.is_empty() {
+                    ui.weak("No matching files");
+                    return;
+                }
+
+                let font = egui::TextStyle::Body.resolve(ui.style());
+                let normal = ui.visuals().text_color();
+                let highlight = ui.visuals().hyperlink_color;
+                let weak = ui.visuals().weak_text_color();
+                egui::ScrollArea::vertical()
+                    .max_height(360.0)
+                    .show(ui, |ui| {
+                        for (idx, item) in self.quick_open.items.iter().enumerate() {
+                            let mut job = egui::text::LayoutJob::default();
+                            let mut positions = item.positions.iter().peekable();
+                            for (i, ch) in item.label.chars().enumerate() {
+                                let matched = positions.next_if_eq(&&i).is_some();
+                                let format = egui::TextFormat {
+                                    font_id: font.clone(),
+                                    color: if matched { highlight } else { normal },
+                                    underline: if matched {
+                                        egui::Stroke::new(1.0, highlight)
+                                    } else {
+                                        egui::Stroke::NONE
+                                    },
+                                    ..Default::default()
+                                };
+                                job.append(ch.encode_utf8(&mut [0; 4]), 0.0, format);
+                            }
+                            job.append(
+                                item.source.label(),
+                                12.0,
+                                egui::TextFormat::simple(font.clone(), weak),
+                            );
+
+                            let selected = idx == self.quick_open.selected;
+                            let row = ui
+                                .selectable_label(selected, job)
+                                .on_hover_text(item.path.display().to_string());
+                            if selected && scroll_to_selected {
+                                row.scroll_to_me(None);
+                            }
+                            if row.clicked() {
+                                clicked = Some(idx);
+                            }
+                        }
+                    });
+            });
+
+        if let Some(idx) = clicked {
+            self.quick_open.selected = idx;
+            self.open_quick_open_selection();
         }
-        if let Ok(mut map) = self.mermaid_cache.lock() {
-            map.clear();
+    }
+
+    fn open_search_panel(&mut self) {
+        self.search.open = true;
+        self.search.focus_query = true;
+        if self.search.query.is_empty() && !self.find.query.is_empty() {
+            self.search.query = self.find.query.clone();
+            self.search.search_at = Some(Instant::now());
         }
     }
 
-    fn active_document(&self) -> Option<&Document> {
-        self.documents.get(self.active_doc)
+    fn start_search(&mut self) {
+        let generation = self.search.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        self.search.results.clear();
+        self.search.files_searched = 0;
+        self.search.error = None;
+        self.search.running = false;
+        if self.search.query.is_empty() {
+            return;
+        }
+        let regex = match build_find_regex(&self.search.query, self.search.options) {
+            Ok(regex) => regex,
+            Err(err) => {
+                self.search.error = Some(err);
+                return;
+            }
+        };
+
+        let workspace = self.workspace.as_ref().filter(|_| !self.search.tabs_only);
+        let sources = search_sources(&self.documents, workspace);
+
+        self.search.running = true;
+        let _ = self.search_tx.send(SearchRequest {
+            generation,
+            regex,
+            whole_word: self.search.options.whole_word,
+            sources,
+        });
     }
 
-    fn active_document_mut(&mut self) -> Option<&mut Document> {
-        self.documents.get_mut(self.active_doc)
+    fn pump_search_events(&mut self, ctx: &egui::Context) {
+        if let Some(at) = self.search.search_at {
+            let now = Instant::now();
+            if now >= at {
+                self.search.search_at = None;
+                self.start_search();
+            } else {
+                ctx.request_repaint_after(at - now);
+            }
+        }
+
+        let current = self.search.generation.load(Ordering::Relaxed);
+        while let Ok(event) = self.search_event_rx.try_recv() {
+            match event {
+                SearchEvent::File { generation, result } if generation == current => {
+                    self.search.results.push(result);
+                }
+                SearchEvent::Done {
+                    generation,
+                    files_searched,
+                } if generation == current => {
+                    self.search.running = false;
+                    self.search.files_searched = files_searched;
+                }
+                _ => {}
+            }
+        }
     }
 
-    fn close_tab(&mut self, idx: usize) {
-        if idx >= self.documents.len() {
+    fn open_search_result(&mut self, doc_id: Option<u64>, path: Option<PathBuf>, line: usize) {
+        if let Some(idx) = doc_id.and_then(|id| self.documents.iter().position(|d| d.id == id)) {
+            self.active_doc = idx;
+        } else if let Some(path) = path {
+            if let Err(err) = self.open_file(path) {
+                self.push_error(format!("Failed to open file: {err}"));
+                return;
+            }
+        } else {
             return;
         }
-        self.documents.remove(idx);
-        if self.documents.is_empty() {
-            let id = self.alloc_doc_id();
-            self.documents.push(Document::welcome(id, &self.settings));
-            self.active_doc = 0;
-        } else if self.active_doc >= self.documents.len() {
-            self.active_doc = self.documents.len() - 1;
+        if let Some(doc) = self.active_document_mut() {
+            doc.scroll_to_line = Some(line);
         }
-        self.update_watched_paths();
     }
 
-    fn show_tab_bar(&mut self, ui: &mut egui::Ui) {
-        let mut close_tab = None::<usize>;
-        egui::ScrollArea::horizontal()
-            .id_salt("tab_bar_scroll")
-            .show(ui, |ui| {
+    fn show_search_panel(&mut self, ctx: &egui::Context) {
+        const SEARCH_DEBOUNCE: Duration = Duration::from_millis(250);
+
+        if !self.search.open {
+            return;
+        }
+
+        let mut changed = false;
+        let mut close = false;
+        let mut open_result = None::<(Option<u64>, Option<PathBuf>, usize)>;
+        let has_workspace = self.workspace.is_some();
+
+        egui::SidePanel::right("search_panel")
+            .resizable(true)
+            .default_width(300.0)
+            .frame(egui::Frame::new().inner_margin(egui::Margin::symmetric(8, 6)))
+            .show(ctx, |ui| {
+                ui.spacing_mut().item_spacing = egui::vec2(8.0, 6.0);
                 ui.horizontal(|ui| {
-                    for (idx, doc) in self.documents.iter().enumerate() {
-                        let is_active = idx == self.active_doc;
-                        let fill = if is_active {
-                            ui.visuals().selection.bg_fill
+                    ui.label(egui::RichText::new("Search").heading());
+                    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                        if ui.button("×").on_hover_text("Close search").clicked() {
+                            close = true;
+                        }
+                    });
+                });
+
+                let resp = ui.add(
+                    egui::TextEdit::singleline(&mut self.search.query)
+                        .hint_text("Search in files…")
+                        .desired_width(f32::INFINITY),
+                );
+                if self.search.focus_query {
+                    resp.request_focus();
+                    self.search.focus_query = false;
+                }
+                changed |= resp.changed();
+                ui.horizontal(|ui| {
+                    changed |= find_options_ui(ui, &mut self.search.options);
+                    if has_workspace {
+                        changed |= ui
+                            .checkbox(&mut self.search.tabs_only, "Open tabs only")
+                            .changed();
+                    }
+                });
+
+                if let Some(err) = &self.search.error {
+                    ui.label(
+                        egui::RichText::new(format!("Invalid pattern:\n{err}"))
+                            .monospace()
+                            .color(ui.visuals().error_fg_color),
+                    );
+                    return;
+                }
+                if self.search.query.is_empty() {
+                    ui.weak("Type to search across files…");
+                    return;
+                }
+                let total: usize = self.search.results.iter().map(|r| r.matches.len()).sum();
+                ui.horizontal(|ui| {
+                    ui.label(format!(
+                        "{total} match{} in {} file{}",
+                        if total == 1 { "" } else { "es" },
+                        self.search.results.len(),
+                        if self.search.results.len() == 1 {
+                            ""
                         } else {
-                            ui.visuals().faint_bg_color
-                        };
-                        let stroke_color = ui.visuals().widgets.noninteractive.bg_stroke.color;
-                        let response = egui::Frame::new()
-                            .fill(fill)
-                            .stroke(egui::Stroke::new(1.0, stroke_color))
-                            .corner_radius(egui::CornerRadius::same(6))
-                            .inner_margin(egui::Margin::symmetric(10, 4))
+                            "s"
+                        }
+                    ));
+                    if self.search.running {
+                        ui.spinner();
+                    }
+                });
+                ui.separator();
+
+                egui::ScrollArea::vertical()
+                    .id_salt("search_results")
+                    .show(ui, |ui| {
+                        for result in &self.search.results {
+                            let header = egui::CollapsingHeader::new(format!(
+                                "{} ({})",
+                                result.title,
+                                result.matches.len()
+                            ))
+                            .id_salt(("search_result", result.doc_id, &result.path))
+                            .default_open(true)
                             .show(ui, |ui| {
-                                ui.horizontal(|ui| {
-                                    let label = doc.display_name();
-                                    let mut label_resp =
-                                        ui.selectable_label(is_active, label.clone());
-                                    if let Some(tt) = doc.tooltip() {
-                                        label_resp = label_resp.on_hover_text(tt);
+                                for m in &result.matches {
+                                    let label = find_match_job(ui, &format!("{}  ", m.line + 1), m);
+                                    if ui.selectable_label(false, label).clicked() {
+                                        open_result =
+                                            Some((result.doc_id, result.path.clone(), m.line));
                                     }
-                                    if label_resp.clicked() {
-                                        self.active_doc = idx;
-                                    }
-                                    if is_active || label_resp.hovered() {
-                                        if ui
-                                            .add(
-                                                egui::Button::new("×")
-                                                    .small()
-                                                    .fill(egui::Color32::TRANSPARENT),
-                                            )
-                                            .on_hover_text("Close tab")
-                                            .clicked()
-                                        {
-                                            close_tab = Some(idx);
-                                        }
-                                    }
-                                });
-                            })
-                            .response;
-                        if response.clicked() {
-                            self.active_doc = idx;
+                                }
+                            });
+                            if let Some(path) = &result.path {
+                                header
+                                    .header_response
+                                    .on_hover_text(path.display().to_string());
+                            }
                         }
-                    }
-                });
+                    });
             });
-        if let Some(idx) = close_tab {
-            self.close_tab(idx);
+
+        if close {
+            self.search.open = false;
+            self.search.generation.fetch_add(1, Ordering::Relaxed);
+            self.search.running = false;
+        } else if changed {
+            self.search.search_at = Some(Instant::now() + SEARCH_DEBOUNCE);
+            ctx.request_repaint_after(SEARCH_DEBOUNCE);
+        }
+        if let Some((doc_id, path, line)) = open_result {
+            self.open_search_result(doc_id, path, line);
         }
     }
 
-    fn handle_internal_anchor_links(&mut self, ctx: &egui::Context) {
-        let mut fragments = Vec::<String>::new();
-        ctx.output_mut(|o| {
-            o.commands.retain(|cmd| {
-                let egui::OutputCommand::OpenUrl(open) = cmd else {
-                    return true;
-                };
-                let Some(fragment) = open.url.strip_prefix('#') else {
-                    return true;
-                };
-                fragments.push(fragment.to_string());
-                false
-            });
-        });
-
-        let Some(fragment) = fragments.pop() else {
-            return;
-        };
-        let Some(doc) = self.active_document_mut() else {
-            return;
-        };
-        let Some(line) = doc.heading_line_for_fragment(&fragment) else {
+    fn update_watched_paths(&mut self) {
+        if !self.settings.auto_reload {
+            self.pending_reloads.clear();
+            let _ = self
+                .watch_cmd_tx
+                .send(WatchCommand::SetWatchedFiles(Vec::new()));
             return;
-        };
+        }
 
-        doc.scroll_to_line = Some(line);
-        ctx.request_repaint();
+        let files: Vec<PathBuf> = self
+            .documents
+            .iter()
+            .filter_map(|doc| doc.file_path.clone())
+            .collect();
+        let _ = self.watch_cmd_tx.send(WatchCommand::SetWatchedFiles(files));
     }
 
-    fn push_error(&mut self, message: impl Into<String>) {
-        self.notifications.push(Notification {
-            message: message.into(),
-            created_at: Instant::now(),
-        });
-    }
+    fn pump_watch_events(&mut self) {
+        const WORKSPACE_DEBOUNCE: Duration = Duration::from_millis(300);
 
-    fn process_images_for_active_doc(&mut self, files: Vec<PendingImage>) {
-        if files.is_empty() {
-            return;
-        }
-        let settings = self.settings.clone();
-        let image_config = self.image_config.clone();
-        for file in files {
-            if !image_config.allowed_types.iter().any(|t| *t == file.mime) {
-                self.push_error("Only image files are supported.");
-                continue;
+        while let Ok(path) = self.watch_event_rx.try_recv() {
+            if let Some(workspace) = &mut self.workspace
+                && workspace.affected_by(&path)
+            {
+                workspace.rescan_at = Some(Instant::now() + WORKSPACE_DEBOUNCE);
             }
-            let max_bytes = image_config.max_file_size_mb * 1024 * 1024;
-            if file.bytes.len() > max_bytes {
-                self.push_error(format!(
-                    "Image exceeds maximum size of {}MB.",
-                    image_config.max_file_size_mb
-                ));
+            if !self.settings.auto_reload {
                 continue;
             }
 
-            if self.documents.get(self.active_doc).is_none() {
-                continue;
-            }
-            let placeholder_id = rand::rng().random::<u32>();
-            let placeholder = format!("![Uploading image...](placeholder-{placeholder_id})");
+            let normalized = normalize_path(path.clone());
+            for doc_path in self
+                .documents
+                .iter()
+                .filter_map(|doc| doc.file_path.clone())
             {
-                let doc = &mut self.documents[self.active_doc];
-                if !doc.raw_markdown.ends_with('\n') {
-                    doc.raw_markdown.push('\n');
+                if doc_path == path || doc_path == normalized {
+                    self.pending_reloads.insert(doc_path, Instant::now());
                 }
-                doc.raw_markdown.push_str(&placeholder);
-                doc.raw_markdown.push('\n');
             }
-
-            let store_result = {
-                let doc = &self.documents[self.active_doc];
-                store_image_for_doc(doc, &image_config, &file)
-            };
-
-            let final_markdown = match store_result {
-                Ok(markdown) => markdown,
-                Err(err) => {
-                    self.push_error(err.to_string());
-                    format!("<!-- image upload failed: {} -->", file.name)
-                }
-            };
-
-            let doc = &mut self.documents[self.active_doc];
-            doc.raw_markdown = doc.raw_markdown.replacen(&placeholder, &final_markdown, 1);
-            doc.rebuild_markdown(&settings);
         }
     }
 
-    fn show_notifications(&mut self, ctx: &egui::Context) {
-        self.notifications
-            .retain(|n| n.created_at.elapsed() < Duration::from_secs(5));
-        if self.notifications.is_empty() {
+    fn process_workspace_rescan(&mut self, ctx: &egui::Context) {
+        while let Ok((root, tree)) = self.workspace_scan_rx.try_recv() {
+            if let Some(workspace) = &mut self.workspace
+                && workspace.root == root
+            {
+                workspace.set_tree(tree);
+            }
+        }
+
+        let Some(workspace) = &mut self.workspace else {
+            return;
+        };
+        let Some(at) = workspace.rescan_at else {
+            return;
+        };
+        let now = Instant::now();
+        if now < at {
+            ctx.request_repaint_after(at - now);
             return;
         }
-        egui::Area::new("error_toasts".into())
-            .anchor(egui::Align2::RIGHT_BOTTOM, egui::vec2(-16.0, -16.0))
-            .show(ctx, |ui| {
-                ui.vertical(|ui| {
-                    for note in &self.notifications {
-                        egui::Frame::new()
-                            .fill(egui::Color32::from_rgb(90, 20, 20))
-                            .corner_radius(egui::CornerRadius::same(6))
-                            .inner_margin(egui::Margin::same(8))
-                            .show(ui, |ui| {
-                                ui.colored_label(
-                                    egui::Color32::from_rgb(255, 220, 220),
-                                    &note.message,
-                                );
-                            });
-                    }
-                });
-            });
+        workspace.rescan_at = None;
+        workspace.scanning = true;
+        let _ = self.workspace_scan_tx.send(workspace.root.clone());
     }
 
-    fn handle_smart_paste_shortcuts(&mut self, ctx: &egui::Context) {
-        if !self.smart_paste_config.enabled || !self.editor_has_focus {
-            return;
+    fn open_workspace_dialog(&mut self) {
+        let mut dialog = rfd::FileDialog::new();
+        if let Some(root) = self.workspace.as_ref().map(|w| w.root.clone()).or_else(|| {
+            self.active_document()
+                .and_then(|d| d.file_path.as_ref())
+                .and_then(|p| p.parent().map(Path::to_path_buf))
+        }) {
+            dialog = dialog.set_directory(root);
         }
+        if let Some(root) = dialog.pick_folder() {
+            self.open_workspace(root);
+        }
+    }
 
-        let plain_shortcut = ctx.input(|i| {
-            i.modifiers.command
-                && i.modifiers.shift
-                && !i.modifiers.alt
-                && i.key_pressed(egui::Key::V)
-        });
-        let raw_shortcut = ctx.input(|i| {
-            i.modifiers.command
-                && i.modifiers.alt
-                && !i.modifiers.shift
-                && i.key_pressed(egui::Key::V)
-        });
-        let smart_shortcut = ctx.input(|i| {
-            i.modifiers.command
-                && !i.modifiers.shift
-                && !i.modifiers.alt
-                && i.key_pressed(egui::Key::V)
-        });
+    fn open_workspace(&mut self, root: PathBuf) {
+        let root = normalize_path(root);
+        let mut workspace = Workspace::new(root.clone());
+        workspace.scanning = true;
+        let _ = self.workspace_scan_tx.send(root.clone());
+        self.workspace = Some(workspace);
+        self.persisted.workspace_root = Some(root.clone());
+        let _ = self
+            .watch_cmd_tx
+            .send(WatchCommand::SetWorkspace(Some(root)));
+    }
 
-        if !(smart_shortcut || plain_shortcut || raw_shortcut) {
-            return;
-        }
+    fn close_workspace(&mut self) {
+        self.workspace = None;
+        self.persisted.workspace_root = None;
+        let _ = self.watch_cmd_tx.send(WatchCommand::SetWorkspace(None));
+    }
 
-        let Ok(mut clipboard) = arboard::Clipboard::new() else {
+    fn show_workspace_panel(&mut self, ctx: &egui::Context) {
+        let Some(workspace) = &self.workspace else {
             return;
         };
+        let active_path = self.active_document().and_then(|d| d.file_path.clone());
+        let mut open_path = None::<PathBuf>;
+        let mut close = false;
 
-        if smart_shortcut {
-            if let Ok(image) = clipboard.get_image() {
-                let _ = ctx.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND, egui::Key::V));
-                self.process_images_for_active_doc(vec![PendingImage {
-                    name: "pasted-image.png".to_string(),
-                    mime: "image/png".to_string(),
-                    bytes: image.bytes.into_owned(),
-                }]);
-                return;
-            }
-        }
-
-        let html = clipboard.get().html().ok().unwrap_or_default();
-        if html.trim().is_empty() {
-            return;
-        }
+        egui::SidePanel::left("workspace_panel")
+            .resizable(true)
+            .default_width(220.0)
+            .frame(egui::Frame::new().inner_margin(egui::Margin::symmetric(8, 6)))
+            .show(ctx, |ui| {
+                ui.spacing_mut().item_spacing = egui::vec2(8.0, 6.0);
+                ui.horizontal(|ui| {
+                    ui.label(egui::RichText::new(workspace.name()).heading())
+                        .on_hover_text(workspace.root.display().to_string());
+                    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                        if ui.button("×").on_hover_text("Close folder").clicked() {
+                            close = true;
+                        }
+                    });
+                });
+                ui.separator();
 
-        if raw_shortcut {
-            let _ = clipboard.set_text(html);
-            self.push_error("Pasted as raw HTML");
-            return;
-        }
+                if workspace.tree.is_empty() {
+                    if workspace.scanning {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.weak("Scanning…");
+                        });
+                    } else {
+                        ui.weak("No Markdown files");
+                    }
+                    return;
+                }
 
-        if plain_shortcut {
-            self.push_error("Pasted as plain text");
-            return;
-        }
+                egui::ScrollArea::vertical()
+                    .id_salt("workspace_tree")
+                    .show(ui, |ui| {
+                        show_workspace_dir_contents(
+                            ui,
+                            &workspace.tree,
+                            active_path.as_deref(),
+                            &mut open_path,
+                        );
+                    });
+            });
 
-        let source = detect_paste_source(&html);
-        let preprocessed = preprocess_pasted_html(&html, source);
-        let markdown = convert_html_to_markdown(&preprocessed, &self.smart_paste_config);
-        if markdown.is_empty() {
-            return;
+        if close {
+            self.close_workspace();
+        } else if let Some(path) = open_path {
+            let _ = self.open_file(path);
         }
-
-        let _ = clipboard.set_text(markdown);
-        self.push_error("Pasted as Markdown");
     }
-}
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum PasteSource {
-    GoogleDocs,
-    Word,
-    Notion,
-    Confluence,
-    Generic,
-}
+    fn show_outline_panel(&mut self, ctx: &egui::Context) {
+        let Some(doc) = self.documents.get_mut(self.active_doc) else {
+            return;
+        };
+        let doc_id = doc.id;
+        let line_count = doc.raw_markdown.split_inclusive('\n').count();
+        let outline = &doc.outline;
+        let collapsed = &mut doc.outline_collapsed;
+        let current = doc
+            .viewed_line
+            .and_then(|line| outline_section_at(outline, line));
+        let state = &mut self.outline_panel;
+        let mut jump_to_line = None::<usize>;
+        let mut edit = None::<OutlineEdit>;
+        let mut hide = false;
 
-fn detect_paste_source(html: &str) -> PasteSource {
-    if html.contains("docs-internal-guid") {
-        return PasteSource::GoogleDocs;
-    }
-    if html.contains("class=\"Mso") || html.contains("mso-") {
-        return PasteSource::Word;
-    }
-    if html.contains("data-block-id") {
-        return PasteSource::Notion;
-    }
-    if html.contains("confluenceTd") || html.contains("confluenceTh") {
-        return PasteSource::Confluence;
-    }
-    PasteSource::Generic
-}
-
-fn preprocess_pasted_html(html: &str, source: PasteSource) -> String {
-    let mut out = html.to_string();
-    static WORD_COMMENTS_RE: OnceLock<Regex> = OnceLock::new();
-    static O_P_TAG_RE: OnceLock<Regex> = OnceLock::new();
-
-    if source == PasteSource::Word {
-        out = WORD_COMMENTS_RE
-            .get_or_init(|| Regex::new(r"(?is)<!--\[if.*?<!\[endif\]-->").expect("valid"))
-            .replace_all(&out, "")
-            .to_string();
-        out = O_P_TAG_RE
-            .get_or_init(|| Regex::new(r"(?is)</?o:p[^>]*>").expect("valid"))
-            .replace_all(&out, "")
-            .to_string();
-    }
-    out
-}
-
-fn convert_html_to_markdown(html: &str, config: &SmartPasteConfig) -> String {
-    if html.trim().is_empty() {
-        return String::new();
-    }
-    let fragment = Html::parse_fragment(html);
-    let mut out = String::new();
-    for child in fragment.tree.root().children() {
-        out.push_str(&convert_node(&child, config, 0));
-    }
-    post_process_markdown(&out)
-}
-
-fn convert_node(
-    node: &ego_tree::NodeRef<'_, Node>,
-    config: &SmartPasteConfig,
-    depth: usize,
-) -> String {
-    match node.value() {
-        Node::Text(text) => text.text.to_string(),
-        Node::Comment(_) => String::new(),
-        Node::Element(el) => {
-            let tag = el.name.local.to_string();
-            let children = || {
-                let mut s = String::new();
-                for child in node.children() {
-                    s.push_str(&convert_node(&child, config, depth));
-                }
-                s
-            };
-
-            match tag.as_str() {
-                "script" | "style" | "noscript" | "iframe" | "object" | "embed" | "form"
-                | "button" | "select" | "textarea" | "nav" | "footer" | "header" => String::new(),
-                "strong" | "b" => format!("**{}**", children().trim()),
-                "em" | "i" => format!("*{}*", children().trim()),
-                "s" | "strike" | "del" => format!("~~{}~~", children().trim()),
-                "u" => format!("<u>{}</u>", children().trim()),
-                "code" if tag != "pre" => {
-                    let content = children();
-                    if content.contains('`') {
-                        format!("``{content}``")
-                    } else {
-                        format!("`{content}`")
-                    }
-                }
-                "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
-                    let level = tag[1..].parse::<usize>().unwrap_or(1).clamp(1, 6);
-                    format!("\n{} {}\n\n", "#".repeat(level), children().trim())
-                }
-                "p" | "div" | "section" | "article" | "main" | "aside" => {
-                    let text = children().trim().to_string();
-                    if text.is_empty() {
-                        String::new()
-                    } else {
-                        format!("{text}\n\n")
+        egui::SidePanel::left("outline_panel")
+            .resizable(true)
+            .default_width(220.0)
+            .frame(egui::Frame::new().inner_margin(egui::Margin::symmetric(8, 6)))
+            .show(ctx, |ui| {
+                ui.spacing_mut().item_spacing = egui::vec2(8.0, 6.0);
+                ui.horizontal(|ui| {
+                    let heading = ui
+                        .add(
+                            egui::Label::new(egui::RichText::new("Outline").heading())
+                                .sense(egui::Sense::click()),
+                        )
+                        .on_hover_text("Scroll to top");
+                    if heading.clicked() {
+                        jump_to_line = Some(0);
                     }
-                }
-                "br" => "  \n".to_string(),
-                "hr" => "\n---\n\n".to_string(),
-                "a" => {
-                    let text = children().trim().to_string();
-                    if let Some(href) = el.attr("href") {
-                        let href = clean_url(href, config);
-                        if text == href {
-                            format!("<{href}>")
-                        } else {
-                            format!("[{text}]({href})")
+                    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                        if ui.button("×").on_hover_text("Hide outline").clicked() {
+                            hide = true;
                         }
-                    } else {
-                        text
-                    }
+                        let max_level = outline.iter().map(|item| item.level).max().unwrap_or(0);
+                        ui.add_enabled_ui(!outline.is_empty(), |ui| {
+                            ui.menu_button("⏷", |ui| {
+                                if ui.button("Expand all").clicked() {
+                                    collapsed.clear();
+                                    ui.close();
+                                }
+                                for level in 1..max_level {
+                                    if ui.button(format!("Collapse to level {level}")).clicked() {
+                                        *collapsed = outline_collapsed_to_level(outline, level);
+                                        ui.close();
+                                    }
+                                }
+                            })
+                            .response
+                            .on_hover_text("Expand or collapse headings");
+                        });
+                    });
+                });
+                ui.add(
+                    egui::TextEdit::singleline(&mut state.filter)
+                        .hint_text("Filter headings…")
+                        .desired_width(f32::INFINITY),
+                );
+                ui.separator();
+
+                if outline.is_empty() {
+                    ui.weak("No headings");
+                    return;
                 }
-                "img" => {
-                    if let Some(src) = el.attr("src") {
-                        let alt = el.attr("alt").unwrap_or_default();
-                        format!("![{alt}]({src})")
-                    } else {
-                        String::new()
-                    }
+
+                let filtering = !state.filter.trim().is_empty();
+                let rows = outline_visible_rows(outline, collapsed, &state.filter);
+                if rows.is_empty() {
+                    ui.weak("No matching headings");
+                    return;
                 }
-                "ul" => convert_list(node, config, false, depth),
-                "ol" => convert_list(node, config, true, depth),
-                "blockquote" => {
-                    let inner = children();
-                    inner
-                        .lines()
-                        .map(|line| format!("> {line}"))
-                        .collect::<Vec<_>>()
-                        .join("\n")
-                        + "\n\n"
+
+                let highlighted =
+                    current.and_then(|current| rows.iter().rev().find(|&&row| row <= current));
+                let reveal = highlighted.is_some_and(|&row| state.revealed != Some((doc_id, row)));
+                if reveal {
+                    state.revealed = highlighted.map(|&row| (doc_id, row));
                 }
-                "pre" => {
-                    let mut code = String::new();
-                    for child in node.children() {
-                        if let Node::Element(code_el) = child.value()
-                            && code_el.name.local.to_string() == "code"
-                        {
-                            for grandchild in child.children() {
-                                code.push_str(&convert_node(&grandchild, config, depth + 1));
+
+                egui::ScrollArea::vertical()
+                    .id_salt("outline_tree")
+                    .show(ui, |ui| {
+                        let toggle_width = ui.spacing().icon_width;
+                        for &idx in &rows {
+                            let item = &outline[idx];
+                            let row = ui.horizontal(|ui| {
+                                ui.spacing_mut().item_spacing.x = 2.0;
+                                ui.add_space(((item.level.saturating_sub(1)) as f32) * 12.0);
+                                if !filtering && outline_has_children(outline, idx) {
+                                    let is_collapsed = collapsed.contains(&item.slug);
+                                    let icon = if is_collapsed { "⏵" } else { "⏷" };
+                                    let toggle = ui.add_sized(
+                                        [toggle_width, toggle_width],
+                                        egui::Button::new(icon).frame(false),
+                                    );
+                                    if toggle.clicked() {
+                                        if is_collapsed {
+                                            collapsed.remove(&item.slug);
+                                        } else {
+                                            collapsed.insert(item.slug.clone());
+                                        }
+                                    }
+                                } else {
+                                    ui.add_space(toggle_width);
+                                }
+
+                                let is_current = highlighted == Some(&idx);
+                                let label = |ui: &mut egui::Ui| {
+                                    ui.selectable_label(
+                                        is_current,
+                                        outline_item_label(item.level, &item.title),
+                                    )
+                                };
+                                let resp = if filtering {
+                                    label(ui)
+                                } else {
+                                    ui.dnd_drag_source(
+                                        egui::Id::new(("outline_drag", doc_id, idx)),
+                                        OutlineDrag(idx),
+                                        label,
+                                    )
+                                    .inner
+                                };
+                                let resp = resp.on_hover_text(format!(
+                                    "Line {}\n#{}\nDrag to move the section",
+                                    item.line + 1,
+                                    item.slug
+                                ));
+                                if resp.clicked() {
+                                    jump_to_line = Some(item.line);
+                                }
+                                if is_current && reveal {
+                                    resp.scroll_to_me(None);
+                                }
+
+                                let section = &outline[outline_section_items(outline, idx)];
+                                let can_promote = section.iter().all(|item| item.level > 1);
+                                let can_demote = section.iter().all(|item| item.level < 6);
+                                resp.context_menu(|ui| {
+                                    if ui
+                                        .add_enabled(
+                                            can_promote,
+                                            egui::Button::new("Promote section"),
+                                        )
+                                        .clicked()
+                                    {
+                                        edit = Some(OutlineEdit::Shift { idx, delta: -1 });
+                                        ui.close();
+                                    }
+                                    if ui
+                                        .add_enabled(
+                                            can_demote,
+                                            egui::Button::new("Demote section"),
+                                        )
+                                        .clicked()
+                                    {
+                                        edit = Some(OutlineEdit::Shift { idx, delta: 1 });
+                                        ui.close();
+                                    }
+                                });
+                            });
+
+                            // Dropping on the upper half of a row inserts the dragged section
+                            // before that heading, on the lower half right after it: as its
+                            // first subsection when expanded, after its whole section otherwise.
+                            let rect = row.response.rect;
+                            let pointer = ui.ctx().pointer_interact_pos();
+                            let below = pointer.is_some_and(|pos| pos.y > rect.center().y);
+                            if let Some(dragged) = row.response.dnd_hover_payload::<OutlineDrag>()
+                                && dragged.0 != idx
+                            {
+                                let y = if below { rect.bottom() } else { rect.top() };
+                                ui.painter().hline(
+                                    rect.x_range(),
+                                    y,
+                                    ui.visuals().selection.stroke,
+                                );
                             }
-                            break;
+                            if let Some(dragged) = row.response.dnd_release_payload::<OutlineDrag>()
+                            {
+                                let expanded = outline_has_children(outline, idx)
+                                    && !collapsed.contains(&item.slug);
+                                let dest_line = if !below {
+                                    item.line
+                                } else if expanded {
+                                    outline[idx + 1].line
+                                } else {
+                                    outline_section_lines(outline, idx, line_count).end
+                                };
+                                edit = Some(OutlineEdit::Move {
+                                    idx: dragged.0,
+                                    dest_line,
+                                });
+                            }
+                            ui.add_space(2.0);
                         }
-                        code.push_str(&convert_node(&child, config, depth + 1));
-                    }
-                    format!("\n```\n{}\n```\n\n", code.trim_end())
-                }
-                "table" => convert_table(node, config),
-                _ => children(),
-            }
+                    });
+            });
+
+        if hide {
+            self.settings.show_outline = false;
+        }
+        if let Some(edit) = edit {
+            self.apply_outline_edit(edit);
+        } else if let Some(line) = jump_to_line
+            && let Some(doc) = self.active_document_mut()
+        {
+            doc.scroll_to_line = Some(line);
         }
-        _ => String::new(),
     }
-}
 
-fn convert_list(
-    node: &ego_tree::NodeRef<'_, Node>,
-    config: &SmartPasteConfig,
-    ordered: bool,
-    depth: usize,
-) -> String {
-    let mut lines = Vec::new();
-    let mut index = 1usize;
-    let indent = "  ".repeat(depth.min(config.max_nesting_depth));
-    for child in node.children() {
-        if let Node::Element(el) = child.value()
-            && el.name.local.to_string() == "li"
-        {
-            let mut has_checkbox = None;
-            for li_child in child.children() {
-                if let Node::Element(input) = li_child.value()
-                    && input.name.local.to_string() == "input"
-                    && input.attr("type") == Some("checkbox")
-                {
-                    has_checkbox = Some(input.attr("checked").is_some());
-                }
+    fn apply_outline_edit(&mut self, edit: OutlineEdit) {
+        let settings = self.settings.clone();
+        let Some(doc) = self.active_document_mut() else {
+            return;
+        };
+        let (text, line) = match edit {
+            OutlineEdit::Move { idx, dest_line } => {
+                let line_count = doc.raw_markdown.split_inclusive('\n').count();
+                let section = outline_section_lines(&doc.outline, idx, line_count);
+                let Some(text) =
+                    move_outline_section(&doc.raw_markdown, &doc.outline, idx, dest_line)
+                else {
+                    return;
+                };
+                let line = if dest_line > section.start {
+                    dest_line - section.len()
+                } else {
+                    dest_line
+                };
+                (text, line)
             }
-
-            let mut text = String::new();
-            for li_child in child.children() {
-                if let Node::Element(input) = li_child.value()
-                    && input.name.local.to_string() == "input"
-                {
-                    continue;
-                }
-                text.push_str(&convert_node(&li_child, config, depth + 1));
+            OutlineEdit::Shift { idx, delta } => {
+                let Some(text) = shift_outline_section(&doc.raw_markdown, &doc.outline, idx, delta)
+                else {
+                    return;
+                };
+                (text, doc.outline[idx].line)
             }
-            let text = text.trim().to_string();
-
-            let marker = if ordered {
-                let m = format!("{index}.");
-                index += 1;
-                m
-            } else {
-                "-".to_string()
-            };
-            let item = if let Some(checked) = has_checkbox {
-                format!(
-                    "{indent}{marker} [{}] {text}",
-                    if checked { "x" } else { " " }
-                )
-            } else {
-                format!("{indent}{marker} {text}")
-            };
-            lines.push(item);
-        }
+        };
+        doc.raw_markdown = text;
+        doc.record_edit(&settings, EditKind::Command);
+        doc.scroll_to_line = Some(line);
     }
-    format!("{}\n\n", lines.join("\n"))
-}
 
-fn convert_table(node: &ego_tree::NodeRef<'_, Node>, config: &SmartPasteConfig) -> String {
-    let mut rows: Vec<Vec<String>> = Vec::new();
-    for child in node.children() {
-        collect_table_rows(&child, config, &mut rows);
-    }
-    if rows.is_empty() {
-        return String::new();
-    }
-    let width = rows.iter().map(|r| r.len()).max().unwrap_or(0);
-    if width == 0 {
-        return String::new();
-    }
-    for row in &mut rows {
-        while row.len() < width {
-            row.push(String::new());
+    fn process_pending_reloads(&mut self) {
+        const DEBOUNCE: Duration = Duration::from_millis(250);
+        let now = Instant::now();
+
+        let ready: Vec<PathBuf> = self
+            .pending_reloads
+            .iter()
+            .filter_map(|(path, last_change)| {
+                if now.duration_since(*last_change) >= DEBOUNCE {
+                    Some(path.clone())
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        for path in ready {
+            self.pending_reloads.remove(&path);
+            self.reload_open_file_silently(&path);
         }
     }
-    let mut out = String::new();
-    let header = &rows[0];
-    out.push_str(&format!("| {} |\n", header.join(" | ")));
-    out.push_str(&format!("|{}|\n", vec!["---"; width].join("|")));
-    for row in rows.iter().skip(1) {
-        out.push_str(&format!("| {} |\n", row.join(" | ")));
-    }
-    format!("\n{out}\n")
-}
 
-fn collect_table_rows(
-    node: &ego_tree::NodeRef<'_, Node>,
-    config: &SmartPasteConfig,
-    rows: &mut Vec<Vec<String>>,
-) {
-    if let Node::Element(el) = node.value() {
-        let tag = el.name.local.to_string();
-        if tag == "tr" {
-            let mut row = Vec::new();
-            for cell in node.children() {
-                if let Node::Element(cell_el) = cell.value() {
-                    let ctag = cell_el.name.local.to_string();
-                    if ctag == "th" || ctag == "td" {
-                        let mut text = String::new();
-                        for gc in cell.children() {
-                            text.push_str(&convert_node(&gc, config, 0));
-                        }
-                        row.push(text.trim().replace('|', "\\|"));
-                    }
-                }
-            }
-            rows.push(row);
+    fn reload_open_file_silently(&mut self, path: &Path) {
+        let settings = self.settings.clone();
+        let Some(idx) = self
+            .documents
+            .iter()
+            .position(|doc| doc.file_path.as_deref() == Some(path))
+        else {
+            return;
+        };
+
+        let doc = &mut self.documents[idx];
+        let Ok(disk_markdown) = read_markdown(path) else {
+            return;
+        };
+        if disk_markdown == doc.raw_markdown {
+            doc.mark_clean();
+            doc.disk_conflict = None;
             return;
         }
-    }
-    for child in node.children() {
-        collect_table_rows(&child, config, rows);
-    }
-}
 
-fn clean_url(url: &str, config: &SmartPasteConfig) -> String {
-    let escaped = url.replace('(', r"\(").replace(')', r"\)");
-    if !config.strip_tracking_params {
-        return escaped;
+        if doc.dirty {
+            let show_diff = doc.disk_conflict.as_ref().is_some_and(|c| c.show_diff);
+            doc.disk_conflict = Some(DiskConflict {
+                disk_markdown,
+                show_diff,
+            });
+            return;
+        }
+
+        if doc.reload(&settings).is_ok() && idx == self.active_doc {
+            self.error = None;
+        }
     }
 
-    let Some((base, query)) = url.split_once('?') else {
-        return escaped;
-    };
+    fn show_conflict_banner(&mut self, ui: &mut egui::Ui) {
+        let settings = self.settings.clone();
+        let Some(doc) = self.documents.get_mut(self.active_doc) else {
+            return;
+        };
+        let name = doc.display_name();
+        let Some(conflict) = doc.disk_conflict.as_mut() else {
+            return;
+        };
 
-    let stripped = query
-        .split('&')
-        .filter(|entry| {
-            let key = entry.split('=').next().unwrap_or_default();
-            !matches!(
-                key,
-                "utm_source" | "utm_medium" | "utm_campaign" | "utm_content" | "utm_term"
-            )
-        })
-        .collect::<Vec<_>>()
-        .join("&");
+        let mut keep_mine = false;
+        let mut take_theirs = false;
+        egui::Frame::new()
+            .fill(with_alpha(ui.visuals().warn_fg_color, 24))
+            .stroke(egui::Stroke::new(1.0, ui.visuals().warn_fg_color))
+            .corner_radius(egui::CornerRadius::same(6))
+            .inner_margin(egui::Margin::same(8))
+            .show(ui, |ui| {
+                ui.horizontal_wrapped(|ui| {
+                    ui.colored_label(
+                        ui.visuals().warn_fg_color,
+                        format!("“{name}” changed on disk while you have unsaved edits."),
+                    );
+                    keep_mine = ui
+                        .button("Keep mine")
+                        .on_hover_text("Keep the editor contents; saving overwrites the file")
+                        .clicked();
+                    take_theirs = ui
+                        .button("Take theirs")
+                        .on_hover_text("Discard your edits and load the file from disk")
+                        .clicked();
+                    ui.toggle_value(&mut conflict.show_diff, "Show diff");
+                });
 
-    let rebuilt = if stripped.is_empty() {
-        base.to_string()
-    } else {
-        format!("{base}?{stripped}")
-    };
-    rebuilt.replace('(', r"\(").replace(')', r"\)")
-}
+                if conflict.show_diff {
+                    ui.separator();
+                    show_side_by_side_diff(ui, &doc.raw_markdown, &conflict.disk_markdown);
+                }
+            });
+        ui.add_space(4.0);
 
-fn post_process_markdown(markdown: &str) -> String {
-    static MANY_BREAKS_RE: OnceLock<Regex> = OnceLock::new();
-    static ZW_RE: OnceLock<Regex> = OnceLock::new();
-    let mut out = markdown.to_string();
-    out = ZW_RE
-        .get_or_init(|| Regex::new(r"[\u{200B}\u{FEFF}]").expect("valid"))
-        .replace_all(&out, "")
-        .replace('\u{00A0}', " ")
-        .to_string();
-    out = out
-        .lines()
-        .map(|line| {
-            if line.ends_with("  ") {
-                line.to_string()
-            } else {
-                line.trim_end().to_string()
-            }
-        })
-        .collect::<Vec<_>>()
-        .join("\n");
-    out = MANY_BREAKS_RE
-        .get_or_init(|| Regex::new(r"\n{3,}").expect("valid"))
-        .replace_all(&out, "\n\n")
-        .to_string();
-    out.trim().to_string()
-}
+        if take_theirs {
+            doc.take_disk_version(&settings);
+        } else if keep_mine {
+            doc.disk_conflict = None;
+        }
+    }
 
-fn store_image_for_doc(doc: &Document, cfg: &ImageConfig, file: &PendingImage) -> Result<String> {
-    let ext = Path::new(&file.name)
-        .extension()
-        .and_then(|e| e.to_str())
-        .unwrap_or("png");
-    let random = format!("{:04x}", rand::rng().random::<u16>());
-    let mut filename = format!("image-{}-{random}.{ext}", chrono_like_timestamp());
-    match cfg.storage_mode {
-        ImageStorageMode::Local => {
-            let doc_dir = doc
-                .file_path
-                .as_ref()
-                .and_then(|p| p.parent())
-                .unwrap_or_else(|| Path::new("."));
-            let base_dir = doc_dir.join(cfg.local_path.trim_start_matches("./"));
-            fs::create_dir_all(&base_dir)?;
-            let mut output = base_dir.join(&filename);
-            while output.exists() {
-                filename = format!(
-                    "image-{}-{:06x}.{ext}",
-                    chrono_like_timestamp(),
-                    rand::rng().random::<u32>() & 0x00ff_ffff
-                );
-                output = base_dir.join(&filename);
+    fn open_dialog(&mut self) {
+        let mut dialog = rfd::FileDialog::new().add_filter("Markdown", MARKDOWN_EXTENSIONS);
+        if let Some(path) = self.active_document().and_then(|d| d.file_path.as_ref()) {
+            if let Some(parent) = path.parent() {
+                dialog = dialog.set_directory(parent);
             }
-            let mut f = fs::File::create(output)?;
-            f.write_all(&file.bytes)?;
-            Ok(format!("![](./assets/images/{filename})"))
         }
-        ImageStorageMode::Base64 => {
-            if file.bytes.len() > cfg.base64_max_size_kb * 1024 {
-                anyhow::bail!("Image too large for base64 mode.")
+        if let Some(paths) = dialog.pick_files() {
+            for path in paths {
+                let _ = self.open_file(path);
             }
-            let encoded = base64::engine::general_purpose::STANDARD.encode(&file.bytes);
-            Ok(format!("![](data:{};base64,{encoded})", file.mime))
-        }
-        ImageStorageMode::Remote => {
-            let endpoint = cfg
-                .remote_endpoint
-                .as_ref()
-                .context("remote endpoint is not configured")?;
-            let resp = reqwest::blocking::Client::new()
-                .post(endpoint)
-                .body(file.bytes.clone())
-                .send()?;
-            let url = resp.text()?;
-            Ok(format!("![]({})", url.trim()))
         }
     }
-}
-
-fn chrono_like_timestamp() -> u64 {
-    std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .map(|d| d.as_secs())
-        .unwrap_or(0)
-}
 
-impl MarkdownViewerApp {
-    fn byte_index_from_char_index(text: &str, char_index: usize) -> usize {
-        text.char_indices()
-            .nth(char_index)
-            .map(|(idx, _)| idx)
-            .unwrap_or(text.len())
-    }
+    fn open_file(&mut self, path: PathBuf) -> Result<()> {
+        let path = normalize_path(path);
+        if let Some(idx) = self
+            .documents
+            .iter()
+            .position(|doc| doc.file_path.as_ref() == Some(&path))
+        {
+            self.active_doc = idx;
+            if self.documents[idx].dirty {
+                return Ok(());
+            }
+            if let Err(err) = self.documents[idx].reload(&self.settings) {
+                self.error = Some(err.to_string());
+            } else {
+                self.error = None;
+                self.persisted.remember_file(path);
+                self.update_watched_paths();
+            }
+            return Ok(());
+        }
 
-    fn detect_inline_code(line_prefix: &str) -> bool {
-        line_prefix.chars().filter(|c| *c == '`').count() % 2 == 1
+        match Document::from_path(self.alloc_doc_id(), path, &self.settings) {
+            Ok(doc) => {
+                self.documents.push(doc);
+                self.active_doc = self.documents.len().saturating_sub(1);
+                self.error = None;
+                if let Some(path) = self.documents[self.active_doc].file_path.clone() {
+                    self.persisted.remember_file(path);
+                }
+                self.update_watched_paths();
+            }
+            Err(err) => {
+                self.error = Some(err.to_string());
+            }
+        }
+        Ok(())
     }
 
-    fn maybe_open_slash_menu(
-        &mut self,
-        cursor_char_index: usize,
-        cursor_line: usize,
-        text: &str,
-        anchor: egui::Pos2,
-    ) {
-        if !self.slash_config.enabled || !self.editor_has_focus {
+    fn reload_active(&mut self) {
+        let settings = self.settings.clone();
+        let Some(doc) = self.active_document_mut() else {
             return;
+        };
+        if let Err(e) = doc.reload(&settings) {
+            self.error = Some(e.to_string());
+        } else {
+            self.error = None;
         }
-        let before = &text[..Self::byte_index_from_char_index(text, cursor_char_index)];
-        let mut line_start = 0usize;
-        if let Some(last_nl) = before.rfind('\n') {
-            line_start = last_nl + 1;
-        }
-        let line_prefix = &before[line_start..];
-        if Self::detect_inline_code(line_prefix) {
-            return;
+    }
+
+    fn rebuild_all_markdown(&mut self) {
+        for doc in &mut self.documents {
+            doc.rebuild_markdown(&self.settings);
         }
-        if line_prefix.ends_with("```") || before.matches("\n```").count() % 2 == 1 {
-            return;
+    }
+
+    fn clear_render_caches(&mut self) {
+        for doc in &mut self.documents {
+            doc.commonmark_cache = CommonMarkCache::default();
         }
-        let Some(ch) = before.chars().last() else {
-            return;
-        };
-        if ch != self.slash_config.trigger_char {
-            return;
+        if let Ok(mut cache) = self.math_cache.lock() {
+            cache.clear();
         }
-        let prev = line_prefix.chars().rev().nth(1);
-        let allowed = prev.is_none() || prev == Some(' ') || line_prefix.ends_with("> /");
-        if !allowed {
-            return;
+        if let Ok(mut cache) = self.diagram_cache.lock() {
+            cache.clear();
         }
+    }
 
-        self.slash_menu.open = true;
-        self.slash_menu.trigger_char_index = cursor_char_index.saturating_sub(1);
-        self.slash_menu.trigger_line = cursor_line;
-        self.slash_menu.query.clear();
-        self.slash_menu.selected_idx = 0;
-        self.slash_menu.anchor = anchor;
+    fn active_document(&self) -> Option<&Document> {
+        self.documents.get(self.active_doc)
     }
 
-    fn slash_matches(&self, has_selection: bool) -> Vec<SlashMatch> {
-        let query = self.slash_menu.query.trim();
-        let mut out = Vec::new();
-        for (idx, cmd) in self.slash_commands.iter().enumerate() {
-            if let Some(needs_sel) = cmd.when_has_selection
-                && needs_sel != has_selection
-            {
-                continue;
+    fn active_document_mut(&mut self) -> Option<&mut Document> {
+        self.documents.get_mut(self.active_doc)
+    }
+
+    fn save_document(&mut self, idx: usize) -> bool {
+        let Some(doc) = self.documents.get(idx) else {
+            return false;
+        };
+        match doc.file_path.clone() {
+            Some(path) => self.write_document(idx, path),
+            None => self.save_document_as(idx),
+        }
+    }
+
+    fn save_document_as(&mut self, idx: usize) -> bool {
+        let Some(doc) = self.documents.get(idx) else {
+            return false;
+        };
+        let mut dialog = rfd::FileDialog::new().add_filter("Markdown", MARKDOWN_EXTENSIONS);
+        match &doc.file_path {
+            Some(path) => {
+                if let Some(parent) = path.parent() {
+                    dialog = dialog.set_directory(parent);
+                }
+                dialog = dialog.set_file_name(doc.display_name());
             }
-            let score = fuzzy_score(query, cmd.name)
-                .or_else(|| cmd.aliases.iter().find_map(|a| fuzzy_score(query, a)))
-                .or_else(|| cmd.keywords.iter().find_map(|k| fuzzy_score(query, k)));
-            if let Some(score) = score {
-                out.push(SlashMatch {
-                    cmd_idx: idx,
-                    score,
-                });
+            None => {
+                dialog = dialog.set_file_name("untitled.md");
             }
         }
-        out.sort_by(|a, b| b.score.total_cmp(&a.score));
-        if query.is_empty() && self.slash_config.show_recently_used {
-            let recent = self
-                .slash_tracker
-                .top_recent(self.slash_config.recently_used_count);
-            out.sort_by_key(|m| {
-                recent
-                    .iter()
-                    .position(|id| id == self.slash_commands[m.cmd_idx].id)
-                    .unwrap_or(usize::MAX)
-            });
-        }
-        out
+        let Some(path) = dialog.save_file() else {
+            return false;
+        };
+        self.write_document(idx, path)
     }
 
-    fn execute_slash_command(&mut self, cmd: &SlashCommand, cursor_char_index: usize) {
+    fn write_document(&mut self, idx: usize, path: PathBuf) -> bool {
         let settings = self.settings.clone();
-        let mut applied = false;
-        let trigger_char_index = self.slash_menu.trigger_char_index;
-        if let Some(doc) = self.active_document_mut() {
-            let start = Self::byte_index_from_char_index(&doc.raw_markdown, trigger_char_index);
-            let end = Self::byte_index_from_char_index(&doc.raw_markdown, cursor_char_index);
-            if start <= end && end <= doc.raw_markdown.len() {
-                doc.raw_markdown.replace_range(start..end, cmd.insert_text);
-                doc.rebuild_markdown(&settings);
-                applied = true;
+        let Some(doc) = self.documents.get_mut(idx) else {
+            return false;
+        };
+        match doc.save_to(path, &settings) {
+            Ok(()) => {
+                if let Some(path) = doc.file_path.clone() {
+                    self.persisted.remember_file(path);
+                }
+                self.error = None;
+                self.update_watched_paths();
+                true
+            }
+            Err(err) => {
+                self.error = Some(format!("{err:#}"));
+                false
             }
         }
-        if applied {
-            self.slash_tracker.record_use(cmd.id);
-        }
-        self.slash_menu.open = false;
     }
 
-    fn show_inline_surface(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
-        let line_height = ui.text_style_height(&TextStyle::Body) + ui.spacing().item_spacing.y;
-        let (doc_id, scroll_to_line) = {
-            let doc = &mut self.documents[self.active_doc];
-            (doc.id, doc.scroll_to_line.take())
+    fn close_tab(&mut self, idx: usize) {
+        let Some(doc) = self.documents.get(idx) else {
+            return;
         };
-
-        let mut scroll_area = egui::ScrollArea::vertical().id_salt(("inline", doc_id));
-        if let Some(line) = scroll_to_line {
-            scroll_area = scroll_area.vertical_scroll_offset((line as f32) * line_height);
+        if doc.dirty {
+            self.pending_close = Some(PendingClose::Tab(doc.id));
+            return;
         }
-
-        scroll_area.show(ui, |ui| {
-            let mut changed = false;
-            let mut new_editing = self.inline_state.editing_line;
-            let doc = &mut self.documents[self.active_doc];
-            let mut lines: Vec<String> = doc.raw_markdown.lines().map(ToOwned::to_owned).collect();
-            if lines.is_empty() {
-                lines.push(String::new());
-            }
-
-            for (idx, line) in lines.iter_mut().enumerate() {
-                let is_editing = new_editing == Some(idx);
-                let row_id = ui.make_persistent_id((doc.id, idx));
-                let rendered_t = if self.settings.inline_render.animate_transitions {
-                    ctx.animate_bool(row_id, !is_editing)
-                } else if is_editing {
-                    0.0
-                } else {
-                    1.0
-                };
-
-                if is_editing {
-                    let resp =
-                        ui.add(egui::TextEdit::singleline(line).desired_width(f32::INFINITY));
-                    changed |= resp.changed();
-                    if resp.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
-                        new_editing = None;
-                    }
-                } else {
-                    let rich = render_line_preview(line);
-                    let text = if rendered_t > 0.99 {
-                        rich
-                    } else {
-                        egui::RichText::new(line.as_str()).monospace()
-                    };
-                    let resp = ui.add(egui::Label::new(text).sense(egui::Sense::click()));
-                    if resp.clicked() {
-                        new_editing = Some(idx);
-                    }
-                }
-            }
-
-            if changed || new_editing != self.inline_state.editing_line {
-                self.inline_state.editing_line = new_editing;
-                doc.raw_markdown = lines.join(
-                    "
-",
-                );
-                doc.rebuild_markdown(&self.settings);
-            }
-        });
+        self.remove_tab(idx);
     }
 
-    fn show_editor_pane(&mut self, ui: &mut egui::Ui) -> EditorUiState {
-        let mut state = EditorUiState::default();
-        let doc_id = self.documents[self.active_doc].id;
+    fn remove_tab(&mut self, idx: usize) {
+        if idx >= self.documents.len() {
+            return;
+        }
+        self.documents.remove(idx);
+        if self.documents.is_empty() {
+            let id = self.alloc_doc_id();
+            self.documents.push(Document::welcome(id, &self.settings));
+            self.active_doc = 0;
+        } else if self.active_doc >= self.documents.len() {
+            self.active_doc = self.documents.len() - 1;
+        }
+        self.update_watched_paths();
+    }
 
-        egui::ScrollArea::vertical()
-            .id_salt(("editor", doc_id))
+    fn show_tab_bar(&mut self, ui: &mut egui::Ui) {
+        let mut close_tab = None::<usize>;
+        egui::ScrollArea::horizontal()
+            .id_salt("tab_bar_scroll")
             .show(ui, |ui| {
-                let doc = &mut self.documents[self.active_doc];
-                let output = egui::TextEdit::multiline(&mut doc.raw_markdown)
-                    .code_editor()
-                    .desired_width(f32::INFINITY)
-                    .desired_rows(12)
-                    .show(ui);
-                state.has_focus = output.response.has_focus();
-                if let Some(range) = output.cursor_range {
-                    state.cursor_char_index = Some(range.primary.index);
-                    state.has_selection = range.primary.index != range.secondary.index;
-                    let before = &doc.raw_markdown[..Self::byte_index_from_char_index(
-                        &doc.raw_markdown,
-                        range.primary.index,
-                    )];
-                    state.cursor_line = before.bytes().filter(|b| *b == b'\n').count();
-                }
-                if output.response.changed() {
-                    doc.rebuild_markdown(&self.settings);
-                    if let Some(index) = state.cursor_char_index {
-                        state.open_slash_args = Some((
-                            index,
-                            state.cursor_line,
-                            doc.raw_markdown.clone(),
-                            output.response.rect.left_bottom(),
-                        ));
+                ui.horizontal(|ui| {
+                    for (idx, doc) in self.documents.iter().enumerate() {
+                        let is_active = idx == self.active_doc;
+                        let fill = if is_active {
+                            ui.visuals().selection.bg_fill
+                        } else {
+                            ui.visuals().faint_bg_color
+                        };
+                        let stroke_color = ui.visuals().widgets.noninteractive.bg_stroke.color;
+                        let response = egui::Frame::new()
+                            .fill(fill)
+                            .stroke(egui::Stroke::new(1.0, stroke_color))
+                            .corner_radius(egui::CornerRadius::same(6))
+                            .inner_margin(egui::Margin::symmetric(10, 4))
+                            .show(ui, |ui| {
+                                ui.horizontal(|ui| {
+                                    let label = if doc.dirty {
+                                        format!("● {}", doc.display_name())
+                                    } else {
+                                        doc.display_name()
+                                    };
+                                    let mut label_resp =
+                                        ui.selectable_label(is_active, label.clone());
+                                    if let Some(tt) = doc.tooltip() {
+                                        label_resp = label_resp.on_hover_text(tt);
+                                    }
+                                    if doc.dirty {
+                                        label_resp = label_resp.on_hover_text("Unsaved changes");
+                                    }
+                                    if label_resp.clicked() {
+                                        self.active_doc = idx;
+                                    }
+                                    if is_active || label_resp.hovered() {
+                                        if ui
+                                            .add(
+                                                egui::Button::new("×")
+                                                    .small()
+                                                    .fill(egui::Color32::TRANSPARENT),
+                                            )
+                                            .on_hover_text("Close tab")
+                                            .clicked()
+                                        {
+                                            close_tab = Some(idx);
+                                        }
+                                    }
+                                });
+                            })
+                            .response;
+                        if response.clicked() {
+                            self.active_doc = idx;
+                        }
                     }
-                }
+                });
             });
-
-        state
+        if let Some(idx) = close_tab {
+            self.close_tab(idx);
+        }
     }
 
-    fn show_preview_pane(&mut self, ui: &mut egui::Ui) {
-        let math_cache = self.math_cache.clone();
-        let math_tx = self.math_tx.clone();
-        let mermaid_cache = self.mermaid_cache.clone();
-        let mermaid_tx = self.mermaid_tx.clone();
-        let render_math_enabled = self.settings.render_math;
-        let render_mermaid_enabled = self.settings.render_mermaid;
+    fn handle_internal_anchor_links(&mut self, ctx: &egui::Context) {
+        let mut fragments = Vec::<String>::new();
+        ctx.output_mut(|o| {
+            o.commands.retain(|cmd| {
+                let egui::OutputCommand::OpenUrl(open) = cmd else {
+                    return true;
+                };
+                let Some(fragment) = open.url.strip_prefix('#') else {
+                    return true;
+                };
+                fragments.push(fragment.to_string());
+                false
+            });
+        });
 
-        let line_height = ui.text_style_height(&TextStyle::Body) + ui.spacing().item_spacing.y;
-        let (doc_id, scroll_to_line) = {
-            let doc = &mut self.documents[self.active_doc];
-            (doc.id, doc.scroll_to_line.take())
+        let Some(fragment) = fragments.pop() else {
+            return;
+        };
+        let Some(doc) = self.active_document_mut() else {
+            return;
+        };
+        let Some(line) = doc.heading_line_for_fragment(&fragment) else {
+            return;
         };
-        let mut scroll_area = egui::ScrollArea::vertical().id_salt(("preview", doc_id));
-        if let Some(line) = scroll_to_line {
-            scroll_area = scroll_area.vertical_scroll_offset((line as f32) * line_height);
-        }
-
-        scroll_area.show(ui, |ui| {
-            let render_math_fn = move |ui: &mut egui::Ui, tex: &str, inline: bool| {
-                render_math(ui, tex, inline, &math_cache, &math_tx);
-            };
-            let render_html_fn = move |ui: &mut egui::Ui, html: &str| {
-                render_html(
-                    ui,
-                    html,
-                    render_mermaid_enabled,
-                    &mermaid_cache,
-                    &mermaid_tx,
-                );
-            };
-
-            let mut viewer = CommonMarkViewer::new();
-            if render_math_enabled {
-                viewer = viewer.render_math_fn(Some(&render_math_fn));
-            }
-            if render_mermaid_enabled {
-                viewer = viewer.render_html_fn(Some(&render_html_fn));
-            }
 
-            let doc = &mut self.documents[self.active_doc];
-            viewer.show(ui, &mut doc.commonmark_cache, &doc.markdown);
-        });
+        doc.scroll_to_line = Some(line);
+        ctx.request_repaint();
     }
 
-    fn dismiss_slash_menu(&mut self, remove_typed: bool, cursor_char_index: usize) {
-        let settings = self.settings.clone();
-        if remove_typed {
-            let trigger_char_index = self.slash_menu.trigger_char_index;
-            if let Some(doc) = self.active_document_mut() {
-                let start = Self::byte_index_from_char_index(&doc.raw_markdown, trigger_char_index);
-                let end = Self::byte_index_from_char_index(&doc.raw_markdown, cursor_char_index);
-                if start <= end && end <= doc.raw_markdown.len() {
-                    doc.raw_markdown.replace_range(start..end, "");
-                    doc.rebuild_markdown(&settings);
-                }
-            }
-        }
-        self.slash_menu.open = false;
-        self.slash_menu.query.clear();
-    }
-}
-
-impl eframe::App for MarkdownViewerApp {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        let dropped_paths: Vec<PathBuf> = ctx.input(|i| {
-            i.raw
-                .dropped_files
-                .iter()
-                .filter_map(|f| f.path.clone())
-                .collect()
-        });
-        let dropped_images: Vec<PendingImage> = ctx.input(|i| {
-            i.raw
-                .dropped_files
-                .iter()
-                .filter_map(|f| {
-                    let mime = f.mime.clone();
-                    let bytes = f.bytes.as_ref()?.to_vec();
-                    Some(PendingImage {
-                        name: if f.name.is_empty() {
-                            f.path
-                                .as_ref()
-                                .and_then(|p| {
-                                    p.file_name().map(|n| n.to_string_lossy().into_owned())
-                                })
-                                .unwrap_or_else(|| "image.png".to_string())
-                        } else {
-                            f.name.clone()
-                        },
-                        mime,
-                        bytes,
-                    })
-                })
-                .collect()
+    fn push_error(&mut self, message: impl Into<String>) {
+        self.notifications.push(Notification {
+            message: message.into(),
+            created_at: Instant::now(),
         });
+    }
 
-        self.drop_zone_visible = ctx.input(|i| !i.raw.hovered_files.is_empty());
-        if ctx.input(|i| i.key_pressed(egui::Key::Escape)) {
-            self.drop_zone_visible = false;
+    fn process_images_for_active_doc(&mut self, files: Vec<PendingImage>) {
+        if files.is_empty() {
+            return;
         }
-        if self.editor_has_focus {
-            self.process_images_for_active_doc(dropped_images);
-            for path in dropped_paths {
-                if path.is_file() {
-                    match fs::read(&path) {
-                        Ok(bytes) => {
-                            let mime = infer_mime_from_path(&path);
-                            self.process_images_for_active_doc(vec![PendingImage {
-                                name: path
-                                    .file_name()
-                                    .map(|n| n.to_string_lossy().into_owned())
-                                    .unwrap_or_else(|| "image.png".to_string()),
-                                mime,
-                                bytes,
-                            }]);
-                        }
-                        Err(err) => self.push_error(format!("Failed to read dropped file: {err}")),
-                    }
-                }
+        let settings = self.settings.clone();
+        let image_config = self.image_config.clone();
+        for file in files {
+            if !image_config.allowed_types.iter().any(|t| *t == file.mime) {
+                self.push_error("Only image files are supported.");
+                continue;
             }
-        } else {
-            for path in dropped_paths {
-                let _ = self.open_file(path);
+            let max_bytes = image_config.max_file_size_mb * 1024 * 1024;
+            if file.bytes.len() > max_bytes {
+                self.push_error(format!(
+                    "Image exceeds maximum size of {}MB.",
+                    image_config.max_file_size_mb
+                ));
+                continue;
             }
-        }
 
-        if ctx.input(|i| i.modifiers.command && i.modifiers.shift && i.key_pressed(egui::Key::I)) {
-            if let Some(files) = rfd::FileDialog::new()
-                .add_filter("Images", &["png", "jpg", "jpeg", "gif", "webp", "svg"])
-                .pick_files()
+            if self.documents.get(self.active_doc).is_none() {
+                continue;
+            }
+            let placeholder_id = rand::rng().random::<u32>();
+            let placeholder = format!("![Uploading image...](placeholder-{placeholder_id})");
             {
-                let mut pending = Vec::new();
-                for path in files {
-                    if let Ok(bytes) = fs::read(&path) {
-                        pending.push(PendingImage {
-                            name: path
-                                .file_name()
-                                .map(|n| n.to_string_lossy().into_owned())
-                                .unwrap_or_default(),
-                            mime: infer_mime_from_path(&path),
-                            bytes,
-                        });
-                    }
+                let doc = &mut self.documents[self.active_doc];
+                if !doc.raw_markdown.ends_with('\n') {
+                    doc.raw_markdown.push('\n');
                 }
-                self.process_images_for_active_doc(pending);
+                doc.raw_markdown.push_str(&placeholder);
+                doc.raw_markdown.push('\n');
             }
-        }
 
-        if ctx.input(|i| i.key_pressed(egui::Key::F) && i.modifiers.command) {
-            self.find.open = true;
-            self.find.focus_query = true;
-        }
-        if self.find.open && ctx.input(|i| i.key_pressed(egui::Key::Escape)) {
-            self.find.open = false;
-        }
+            let store_result = {
+                let doc = &self.documents[self.active_doc];
+                store_image_for_doc(doc, &image_config, &file)
+            };
 
-        if self.settings.auto_reload {
-            self.pump_watch_events();
-            self.process_pending_reloads();
-        } else {
-            while self.watch_event_rx.try_recv().is_ok() {}
-            self.pending_reloads.clear();
+            let final_markdown = match store_result {
+                Ok(markdown) => markdown,
+                Err(err) => {
+                    self.push_error(err.to_string());
+                    format!("<!-- image upload failed: {} -->", file.name)
+                }
+            };
+
+            let doc = &mut self.documents[self.active_doc];
+            doc.raw_markdown = doc.raw_markdown.replacen(&placeholder, &final_markdown, 1);
+            doc.record_edit(&settings, EditKind::Command);
         }
+    }
 
-        egui::TopBottomPanel::top("top_bar")
-            .frame(egui::Frame::new().inner_margin(egui::Margin::symmetric(8, 4)))
+    fn show_notifications(&mut self, ctx: &egui::Context) {
+        self.notifications
+            .retain(|n| n.created_at.elapsed() < Duration::from_secs(5));
+        if self.notifications.is_empty() {
+            return;
+        }
+        egui::Area::new("error_toasts".into())
+            .anchor(egui::Align2::RIGHT_BOTTOM, egui::vec2(-16.0, -16.0))
             .show(ctx, |ui| {
-                ui.spacing_mut().item_spacing = egui::vec2(8.0, 6.0);
                 ui.vertical(|ui| {
-                    let mut auto_reload_changed = false;
-                    egui::menu::bar(ui, |ui| {
-                        ui.menu_button("File", |ui| {
-                            if ui
-                                .add(
-                                    egui::Button::new("Open…")
-                                        .shortcut_text("Ctrl+O"),
-                                )
-                                .clicked()
-                            {
-                                self.open_dialog();
-                                ui.close();
-                            }
+                    for note in &self.notifications {
+                        egui::Frame::new()
+                            .fill(egui::Color32::from_rgb(90, 20, 20))
+                            .corner_radius(egui::CornerRadius::same(6))
+                            .inner_margin(egui::Margin::same(8))
+                            .show(ui, |ui| {
+                                ui.colored_label(
+                                    egui::Color32::from_rgb(255, 220, 220),
+                                    &note.message,
+                                );
+                            });
+                    }
+                });
+            });
+    }
 
-                            if ui
-                                .add(
-                                    egui::Button::new("Upload image…")
-                                        .shortcut_text("Ctrl+Shift+I"),
-                                )
-                                .clicked()
-                            {
-                                if let Some(files) = rfd::FileDialog::new()
-                                    .add_filter(
-                                        "Images",
-                                        &["png", "jpg", "jpeg", "gif", "webp", "svg"],
-                                    )
-                                    .pick_files()
-                                {
-                                    let mut pending = Vec::new();
-                                    for path in files {
-                                        if let Ok(bytes) = fs::read(&path) {
-                                            pending.push(PendingImage {
-                                                name: path
-                                                    .file_name()
-                                                    .map(|n| n.to_string_lossy().into_owned())
-                                                    .unwrap_or_default(),
-                                                mime: infer_mime_from_path(&path),
-                                                bytes,
-                                            });
-                                        }
-                                    }
-                                    self.process_images_for_active_doc(pending);
-                                }
-                                ui.close();
-                            }
+    fn render_memory_usage(&self) -> (usize, usize) {
+        let math = self
+            .math_cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .usage();
+        let diagrams = self
+            .diagram_cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .usage();
+        (math.0 + diagrams.0, math.1 + diagrams.1)
+    }
+
+    fn apply_render_memory_limit(&mut self) {
+        let limit = svg_cache_limit(&self.settings);
+        self.math_cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .set_limit(limit);
+        self.diagram_cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .set_limit(limit);
+    }
+
+    fn sweep_render_caches(&mut self) {
+        if self.render_cache_swept.elapsed() < RENDER_CACHE_SWEEP_INTERVAL {
+            return;
+        }
+        self.render_cache_swept = Instant::now();
 
-                            ui.separator();
+        let mut math = self.math_cache.lock().unwrap_or_else(|e| e.into_inner());
+        let mut diagrams = self.diagram_cache.lock().unwrap_or_else(|e| e.into_inner());
+        if !math.grown && !diagrams.grown {
+            return;
+        }
+        let references =
+            RenderReferences::new(self.documents.iter().map(|doc| doc.markdown.as_str()));
+        math.retain(|key| references.has_math(&key.tex));
+        diagrams.retain(|key| references.has_diagram(&key.source));
+    }
 
-                            ui.menu_button("Recent", |ui| {
-                                let recent = self.persisted.recent_files.clone();
-                                if recent.is_empty() {
-                                    ui.weak("No recent files");
-                                    return;
-                                }
+    fn autosave_dirty_documents(&mut self, ctx: &egui::Context) {
+        let Some(autosave) = &mut self.autosave else {
+            return;
+        };
+        let any_dirty = self.documents.iter().any(|doc| doc.dirty);
+        if any_dirty {
+            ctx.request_repaint_after(AUTOSAVE_INTERVAL);
+        }
+        if autosave.last_run.elapsed() < AUTOSAVE_INTERVAL {
+            return;
+        }
+        autosave.last_run = Instant::now();
 
-                                for path in recent {
-                                    let exists = path.is_file();
-                                    let label = path
-                                        .file_name()
-                                        .map(|n| n.to_string_lossy().into_owned())
-                                        .unwrap_or_else(|| path.display().to_string());
+        let mut live = HashSet::<u64>::new();
+        for doc in self.documents.iter().filter(|doc| doc.dirty) {
+            live.insert(doc.id);
+            let hash = egui::Id::new((&doc.raw_markdown, &doc.file_path)).value();
+            if autosave.snapshots.insert(doc.id, hash) == Some(hash) {
+                continue;
+            }
+            let _ = autosave.tx.send(AutosaveCommand::Write {
+                file_name: autosave.file_name(doc.id),
+                snapshot: RecoverySnapshot {
+                    file_path: doc.file_path.clone(),
+                    saved_at: chrono_like_timestamp(),
+                    content: doc.raw_markdown.clone(),
+                },
+            });
+        }
 
-                                    if ui
-                                        .add_enabled(exists, egui::Button::new(label))
-                                        .on_hover_text(path.display().to_string())
-                                        .clicked()
-                                    {
-                                        let _ = self.open_file(path);
-                                        ui.close();
-                                    }
-                                }
+        let stale: Vec<u64> = autosave
+            .snapshots
+            .keys()
+            .filter(|id| !live.contains(id))
+            .copied()
+            .collect();
+        for id in stale {
+            autosave.snapshots.remove(&id);
+            let _ = autosave
+                .tx
+                .send(AutosaveCommand::Remove(autosave.file_name(id)));
+        }
+    }
 
-                                ui.separator();
-                                if ui.button("Clear recent").clicked() {
-                                    self.persisted.recent_files.clear();
-                                    ui.close();
-                                }
-                            });
-
-                            ui.separator();
+    fn restore_recovered_buffers(&mut self) {
+        let settings = self.settings.clone();
+        for entry in std::mem::take(&mut self.recovered) {
+            let RecoverySnapshot {
+                file_path, content, ..
+            } = entry.snapshot;
 
-                            let reload_enabled = self
-                                .active_document()
-                                .and_then(|d| d.file_path.as_ref())
-                                .is_some();
-                            if ui
-                                .add_enabled(reload_enabled, egui::Button::new("Reload"))
-                                .clicked()
-                            {
-                                self.reload_active();
-                                ui.close();
-                            }
+            if let Some(path) = file_path.as_ref().filter(|p| p.is_file()) {
+                let _ = self.open_file(path.clone());
+            }
+            let existing = file_path.as_ref().and_then(|path| {
+                self.documents
+                    .iter()
+                    .position(|doc| doc.file_path.as_ref() == Some(path))
+            });
+            match existing {
+                Some(idx) => {
+                    let doc = &mut self.documents[idx];
+                    doc.raw_markdown = content;
+                    doc.record_edit(&settings, EditKind::Command);
+                    self.active_doc = idx;
+                }
+                None => {
+                    let id = self.alloc_doc_id();
+                    let mut doc = Document::from_content(id, content, file_path, &settings);
+                    doc.saved_hash = None;
+                    doc.dirty = true;
+                    self.documents.push(doc);
+                    self.active_doc = self.documents.len() - 1;
+                }
+            }
+            let _ = fs::remove_file(&entry.recovery_file);
+        }
+        self.update_watched_paths();
+    }
 
-                            auto_reload_changed |= ui
-                                .checkbox(
-                                    &mut self.settings.auto_reload,
-                                    "Auto-reload changed files",
-                                )
-                                .changed();
-                        });
+    fn discard_recovered_buffers(&mut self) {
+        for entry in std::mem::take(&mut self.recovered) {
+            let _ = fs::remove_file(&entry.recovery_file);
+        }
+    }
 
-                        ui.menu_button("Edit", |ui| {
-                            if ui
-                                .add(
-                                    egui::Button::new("Find…")
-                                        .shortcut_text("Ctrl+F"),
-                                )
-                                .clicked()
-                            {
-                                self.find.open = true;
-                                self.find.focus_query = true;
-                                ui.close();
-                            }
-                        });
+    fn show_recovery_prompt(&mut self, ctx: &egui::Context) {
+        if self.recovered.is_empty() || self.pending_close.is_some() {
+            return;
+        }
 
-                        ui.menu_button("View", |ui| {
-                            let view_mode_before = self.settings.view_mode;
-                            ui.selectable_value(
-                                &mut self.settings.view_mode,
-                                ViewMode::EditorOnly,
-                                "Editor only",
-                            );
-                            ui.selectable_value(
-                                &mut self.settings.view_mode,
-                                ViewMode::Split,
-                                "Split view",
-                            );
-                            ui.selectable_value(
-                                &mut self.settings.view_mode,
-                                ViewMode::PreviewOnly,
-                                "Preview only",
-                            );
-                            if view_mode_before != self.settings.view_mode {
-                                ui.close();
-                            }
+        let now = chrono_like_timestamp();
+        let mut restore = false;
+        let mut discard = false;
+        let mut later = false;
+        let modal = egui::Modal::new(egui::Id::new("recover_unsaved")).show(ctx, |ui| {
+            ui.set_max_width(420.0);
+            ui.heading("Recover unsaved changes?");
+            ui.add_space(4.0);
+            ui.label("markdownviewer did not shut down cleanly. These buffers had unsaved edits:");
+            for entry in &self.recovered {
+                let name = entry
+                    .snapshot
+                    .file_path
+                    .as_ref()
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_else(|| "Untitled".to_string());
+                let age_min = now.saturating_sub(entry.snapshot.saved_at) / 60;
+                ui.label(format!("• {name} ({age_min} min ago)"));
+            }
+            ui.add_space(8.0);
+            ui.horizontal(|ui| {
+                restore = ui.button("Restore").clicked();
+                discard = ui.button("Discard").clicked();
+                later = ui
+                    .button("Not now")
+                    .on_hover_text("Keep the snapshots and ask again next start")
+                    .clicked();
+            });
+        });
 
-                            ui.separator();
-                            ui.checkbox(&mut self.settings.show_outline, "Show outline panel");
-                            ui.checkbox(
-                                &mut self.settings.inline_render.enabled,
-                                "Inline live rendering (experimental)",
-                            );
+        if restore {
+            self.restore_recovered_buffers();
+        } else if discard {
+            self.discard_recovered_buffers();
+        } else if later || modal.should_close() {
+            self.recovered.clear();
+        }
+    }
 
-                            ui.separator();
-                            ui.menu_button("Theme", |ui| {
-                                let theme_before = self.settings.theme;
-                                ui.selectable_value(
-                                    &mut self.settings.theme,
-                                    AppTheme::System,
-                                    "💻 System",
-                                )
-                                .on_hover_text("Follow the system theme");
-                                ui.selectable_value(
-                                    &mut self.settings.theme,
-                                    AppTheme::Dark,
-                                    "🌙 Dark",
-                                )
-                                .on_hover_text("Dark theme");
-                                ui.selectable_value(
-                                    &mut self.settings.theme,
-                                    AppTheme::Light,
-                                    "☀ Light",
-                                )
-                                .on_hover_text("Light theme");
-                                ui.selectable_value(
-                                    &mut self.settings.theme,
-                                    AppTheme::TerminalGreen,
-                                    egui::RichText::new("G> Terminal Green")
-                                        .monospace()
-                                        .color(egui::Color32::from_rgb(0x00, 0xff, 0x7a)),
-                                )
-                                .on_hover_text("Green terminal theme");
-                                ui.selectable_value(
-                                    &mut self.settings.theme,
-                                    AppTheme::TerminalAmber,
-                                    egui::RichText::new("A> Terminal Amber")
-                                        .monospace()
-                                        .color(egui::Color32::from_rgb(0xff, 0xb0, 0x36)),
-                                )
-                                .on_hover_text("Amber terminal theme");
+    fn undo_active(&mut self) {
+        let settings = self.settings.clone();
+        if let Some(doc) = self.active_document_mut() {
+            doc.undo(&settings);
+        }
+    }
 
-                                if theme_before != self.settings.theme {
-                                    apply_app_theme(ctx, self.settings.theme);
-                                    self.clear_render_caches();
-                                    ui.close();
-                                }
-                            });
-                        });
+    fn redo_active(&mut self) {
+        let settings = self.settings.clone();
+        if let Some(doc) = self.active_document_mut() {
+            doc.redo(&settings);
+        }
+    }
 
-                        ui.menu_button("Options", |ui| {
-                            let mut changed = false;
-                            ui.label(egui::RichText::new("Rendering").strong());
-                            changed |= ui
-                                .checkbox(
-                                    &mut self.settings.render_math,
-                                    "Render math ($...$ / $$...$$)",
-                                )
-                                .changed();
-                            changed |= ui
-                                .checkbox(
-                                    &mut self.settings.render_mermaid,
-                                    "Render Mermaid diagrams",
-                                )
-                                .changed();
-                            changed |= ui
-                                .checkbox(
-                                    &mut self.settings.auto_detect_code_lang,
-                                    "Auto-detect code languages",
-                                )
-                                .changed();
+    fn handle_undo_shortcuts(&mut self, ctx: &egui::Context) {
+        let mut editors = Vec::new();
+        if let Some(doc) = self.active_document() {
+            editors.push(editor_text_id(doc.id));
+            if let Some(line) = self.inline_state.editing_line {
+                editors.push(inline_line_text_id(doc.id, line));
+            }
+        }
+        let focused = ctx.memory(|m| m.focused());
+        if !document_undo_has_keys(focused, &editors, ctx.wants_keyboard_input()) {
+            return;
+        }
+        let redo = ctx.input_mut(|i| {
+            i.consume_shortcut(&egui::KeyboardShortcut::new(
+                egui::Modifiers::COMMAND | egui::Modifiers::SHIFT,
+                egui::Key::Z,
+            )) || i.consume_shortcut(&egui::KeyboardShortcut::new(
+                egui::Modifiers::COMMAND,
+                egui::Key::Y,
+            ))
+        });
+        let undo = ctx.input_mut(|i| {
+            i.consume_shortcut(&egui::KeyboardShortcut::new(
+                egui::Modifiers::COMMAND,
+                egui::Key::Z,
+            ))
+        });
+        if redo {
+            self.redo_active();
+        } else if undo {
+            self.undo_active();
+        }
+    }
 
-                            ui.separator();
-                            ui.label(egui::RichText::new("Formatting").strong());
-                            changed |= ui
-                                .checkbox(&mut self.settings.autolink_urls, "Autolink plain URLs")
-                                .changed();
-                            changed |= ui
-                                .checkbox(
-                                    &mut self.settings.github_links,
-                                    "GitHub issue/PR links (#123)",
-                                )
-                                .changed();
-                            changed |= ui
-                                .checkbox(
-                                    &mut self.settings.replace_emoji,
-                                    "Emoji shortcodes (:smile:)",
-                                )
-                                .changed();
-                            changed |= ui
-                                .checkbox(
-                                    &mut self.settings.smart_typography,
-                                    "Smart typography (off by default)",
-                                )
-                                .changed();
+    fn handle_close_request(&mut self, ctx: &egui::Context) {
+        if !ctx.input(|i| i.viewport().close_requested()) || self.allow_exit {
+            return;
+        }
+        if self.documents.iter().any(|doc| doc.dirty) {
+            ctx.send_viewport_cmd(egui::ViewportCommand::CancelClose);
+            self.pending_close = Some(PendingClose::Exit);
+        }
+    }
 
-                            ui.separator();
-                            ui.label(egui::RichText::new("Images").strong());
-                            ui.label("Image storage mode");
-                            changed |= ui
-                                .selectable_value(
-                                    &mut self.image_config.storage_mode,
-                                    ImageStorageMode::Local,
-                                    "Local",
-                                )
-                                .changed();
-                            changed |= ui
-                                .selectable_value(
-                                    &mut self.image_config.storage_mode,
-                                    ImageStorageMode::Base64,
-                                    "Base64",
-                                )
-                                .changed();
-                            changed |= ui
-                                .selectable_value(
-                                    &mut self.image_config.storage_mode,
-                                    ImageStorageMode::Remote,
-                                    "Remote",
-                                )
-                                .changed();
+    fn show_unsaved_changes_prompt(&mut self, ctx: &egui::Context) {
+        let Some(pending) = self.pending_close else {
+            return;
+        };
 
-                            if changed {
-                                self.rebuild_all_markdown();
-                            }
-                        });
+        let dirty = pending.dirty_documents(&self.documents);
+        let dirty_names: Vec<String> = dirty
+            .iter()
+            .map(|&idx| self.documents[idx].display_name())
+            .collect();
+        if dirty.is_empty() {
+            self.pending_close = None;
+            if pending == PendingClose::Exit {
+                self.allow_exit = true;
+                ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+            }
+            return;
+        }
 
-                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                            match self
-                                .active_document()
-                                .and_then(|doc| doc.file_path.as_ref())
-                            {
-                                Some(path) => {
-                                    let full_path = path.display().to_string();
-                                    let truncated = left_truncate(&full_path, 72);
-                                    ui.label(truncated).on_hover_text(full_path);
-                                }
-                                None => {
-                                    ui.weak("No file loaded");
-                                }
-                            }
-                        });
-                    });
-                    if auto_reload_changed {
-                        self.update_watched_paths();
+        let mut save = false;
+        let mut discard = false;
+        let mut cancel = false;
+        let modal = egui::Modal::new(egui::Id::new("unsaved_changes")).show(ctx, |ui| {
+            ui.set_max_width(360.0);
+            ui.heading("Unsaved changes");
+            ui.add_space(4.0);
+            match pending {
+                PendingClose::Tab(_) => {
+                    ui.label(format!(
+                        "Save changes to “{}” before closing?",
+                        dirty_names[0]
+                    ));
+                }
+                PendingClose::Exit => {
+                    ui.label("The following documents have unsaved changes:");
+                    for name in &dirty_names {
+                        ui.label(format!("• {name}"));
                     }
+                }
+            }
+            ui.add_space(8.0);
+            ui.horizontal(|ui| {
+                let save_label = match pending {
+                    PendingClose::Tab(_) => "Save",
+                    PendingClose::Exit => "Save all",
+                };
+                save = ui.button(save_label).clicked();
+                discard = ui.button("Don't save").clicked();
+                cancel = ui.button("Cancel").clicked();
+            });
+        });
+        if modal.should_close() {
+            cancel = true;
+        }
 
-                    ui.horizontal(|ui| {
-                        ui.spacing_mut().item_spacing = egui::vec2(2.0, 0.0);
-                        if ui.button("📂").on_hover_text("Open file (Ctrl+O)").clicked() {
-                            self.open_dialog();
-                        }
-
-                        let reload_enabled = self
-                            .active_document()
-                            .and_then(|d| d.file_path.as_ref())
-                            .is_some();
-                        if ui
-                            .add_enabled(reload_enabled, egui::Button::new("🔄"))
-                            .on_hover_text("Reload file")
-                            .clicked()
-                        {
-                            self.reload_active();
-                        }
+        if cancel {
+            self.pending_close = None;
+            return;
+        }
+        if !(save || discard) {
+            return;
+        }
+        self.pending_close = None;
 
-                        if ui.button("🔍").on_hover_text("Find (Ctrl+F)").clicked() {
-                            self.find.open = true;
-                            self.find.focus_query = true;
+        match pending {
+            PendingClose::Tab(_) => {
+                let idx = dirty[0];
+                if discard || self.save_document(idx) {
+                    self.remove_tab(idx);
+                }
+            }
+            PendingClose::Exit => {
+                if save {
+                    for idx in dirty {
+                        if !self.save_document(idx) {
+                            return;
                         }
+                    }
+                }
+                self.allow_exit = true;
+                ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+            }
+        }
+    }
 
-                        ui.separator();
-
-                        ui.selectable_value(
-                            &mut self.settings.view_mode,
-                            ViewMode::EditorOnly,
-                            "Editor",
-                        );
-                        ui.selectable_value(&mut self.settings.view_mode, ViewMode::Split, "Split");
-                        ui.selectable_value(
-                            &mut self.settings.view_mode,
-                            ViewMode::PreviewOnly,
-                            "Preview",
-                        );
+    fn handle_smart_paste_shortcuts(&mut self, ctx: &egui::Context) {
+        if !self.smart_paste_config.enabled || !self.editor_has_focus {
+            return;
+        }
 
-                        ui.separator();
+        let plain_shortcut = ctx.input(|i| {
+            i.modifiers.command
+                && i.modifiers.shift
+                && !i.modifiers.alt
+                && i.key_pressed(egui::Key::V)
+        });
+        let raw_shortcut = ctx.input(|i| {
+            i.modifiers.command
+                && i.modifiers.alt
+                && !i.modifiers.shift
+                && i.key_pressed(egui::Key::V)
+        });
+        let smart_shortcut = ctx.input(|i| {
+            i.modifiers.command
+                && !i.modifiers.shift
+                && !i.modifiers.alt
+                && i.key_pressed(egui::Key::V)
+        });
 
-                        let theme_before = self.settings.theme;
-                        ui.selectable_value(&mut self.settings.theme, AppTheme::System, "💻")
-                            .on_hover_text("System theme");
-                        ui.selectable_value(&mut self.settings.theme, AppTheme::Dark, "🌙")
-                            .on_hover_text("Dark theme");
-                        ui.selectable_value(&mut self.settings.theme, AppTheme::Light, "☀")
-                            .on_hover_text("Light theme");
-                        ui.selectable_value(
-                            &mut self.settings.theme,
-                            AppTheme::TerminalGreen,
-                            egui::RichText::new("G>")
-                                .monospace()
-                                .color(egui::Color32::from_rgb(0x00, 0xff, 0x7a)),
-                        )
-                        .on_hover_text("Green terminal theme");
-                        ui.selectable_value(
-                            &mut self.settings.theme,
-                            AppTheme::TerminalAmber,
-                            egui::RichText::new("A>")
-                                .monospace()
-                                .color(egui::Color32::from_rgb(0xff, 0xb0, 0x36)),
-                        )
-                        .on_hover_text("Amber terminal theme");
-                        if theme_before != self.settings.theme {
-                            apply_app_theme(ctx, self.settings.theme);
-                            self.clear_render_caches();
-                        }
-                    });
+        if !(smart_shortcut || plain_shortcut || raw_shortcut) {
+            return;
+        }
 
-                    ui.add_space(4.0);
-                    self.show_tab_bar(ui);
-                });
-            });
+        let Ok(mut clipboard) = arboard::Clipboard::new() else {
+            return;
+        };
 
-        if self.settings.show_outline {
-            let outline = self
-                .active_document()
-                .map(|doc| doc.outline.clone())
-                .unwrap_or_default();
-            let mut jump_to_line = None::<usize>;
-
-            egui::SidePanel::left("outline_panel")
-                .resizable(true)
-                .default_width(220.0)
-                .frame(egui::Frame::new().inner_margin(egui::Margin::symmetric(8, 6)))
-                .show(ctx, |ui| {
-                    ui.spacing_mut().item_spacing = egui::vec2(8.0, 6.0);
-                    ui.horizontal(|ui| {
-                        let heading = ui
-                            .add(
-                                egui::Label::new(egui::RichText::new("Outline").heading())
-                                    .sense(egui::Sense::click()),
-                            )
-                            .on_hover_text("Scroll to top");
-                        if heading.clicked() {
-                            jump_to_line = Some(0);
-                        }
-                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                            if ui.button("×").on_hover_text("Hide outline").clicked() {
-                                self.settings.show_outline = false;
-                            }
-                        });
-                    });
-                    ui.separator();
+        if smart_shortcut {
+            if let Ok(image) = clipboard.get_image() {
+                let _ = ctx.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND, egui::Key::V));
+                self.process_images_for_active_doc(vec![PendingImage {
+                    name: "pasted-image.png".to_string(),
+                    mime: "image/png".to_string(),
+                    bytes: image.bytes.into_owned(),
+                }]);
+                return;
+            }
+        }
 
-                    if outline.is_empty() {
-                        ui.weak("No headings");
-                        return;
-                    }
+        let html = clipboard.get().html().ok().unwrap_or_default();
+        if html.trim().is_empty() {
+            return;
+        }
 
-                    egui::ScrollArea::vertical().show(ui, |ui| {
-                        for item in &outline {
-                            ui.horizontal(|ui| {
-                                ui.add_space(((item.level.saturating_sub(1)) as f32) * 12.0);
-                                if ui
-                                    .selectable_label(
-                                        false,
-                                        outline_item_label(item.level, &item.title),
-                                    )
-                                    .on_hover_text(format!(
-                                        "Line {}\n#{}",
-                                        item.line + 1,
-                                        item.slug
-                                    ))
-                                    .clicked()
-                                {
-                                    jump_to_line = Some(item.line);
-                                }
-                            });
-                            ui.add_space(2.0);
-                        }
-                    });
-                });
+        if raw_shortcut {
+            let _ = clipboard.set_text(html);
+            self.push_error("Pasted as raw HTML");
+            return;
+        }
 
-            if let Some(line) = jump_to_line {
-                if let Some(doc) = self.active_document_mut() {
-                    doc.scroll_to_line = Some(line);
-                }
-            }
+        if plain_shortcut {
+            self.push_error("Pasted as plain text");
+            return;
         }
 
-        self.handle_smart_paste_shortcuts(ctx);
+        let source = detect_paste_source(&html);
+        let preprocessed = preprocess_pasted_html(&html, source);
+        let markdown = convert_html_to_markdown(&preprocessed, &self.smart_paste_config);
+        if markdown.is_empty() {
+            return;
+        }
 
-        egui::CentralPanel::default()
-            .frame(egui::Frame::new().inner_margin(egui::Margin::symmetric(8, 6)))
-            .show(ctx, |ui| {
-                ui.spacing_mut().item_spacing = egui::vec2(8.0, 6.0);
-                if let Some(err) = &self.error {
-                    ui.colored_label(ui.visuals().error_fg_color, err);
-                    ui.separator();
-                }
+        let _ = clipboard.set_text(markdown);
+        self.push_error("Pasted as Markdown");
+    }
+}
 
-                if self.documents.get(self.active_doc).is_none() {
-                    ui.weak("No documents open");
-                    return;
-                }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PasteSource {
+    GoogleDocs,
+    Word,
+    Notion,
+    Confluence,
+    Generic,
+}
 
-                if self.settings.inline_render.enabled
-                    && matches!(self.settings.view_mode, ViewMode::EditorOnly)
-                {
-                    self.show_inline_surface(ui, ctx);
-                    return;
-                }
+fn detect_paste_source(html: &str) -> PasteSource {
+    if html.contains("docs-internal-guid") {
+        return PasteSource::GoogleDocs;
+    }
+    if html.contains("class=\"Mso") || html.contains("mso-") {
+        return PasteSource::Word;
+    }
+    if html.contains("data-block-id") {
+        return PasteSource::Notion;
+    }
+    if html.contains("confluenceTd") || html.contains("confluenceTh") {
+        return PasteSource::Confluence;
+    }
+    PasteSource::Generic
+}
 
-                let show_editor = matches!(
-                    self.settings.view_mode,
-                    ViewMode::EditorOnly | ViewMode::Split
-                );
-                let mut editor_state = EditorUiState::default();
+fn preprocess_pasted_html(html: &str, source: PasteSource) -> String {
+    let mut out = html.to_string();
+    static WORD_COMMENTS_RE: OnceLock<Regex> = OnceLock::new();
+    static O_P_TAG_RE: OnceLock<Regex> = OnceLock::new();
 
-                match self.settings.view_mode {
-                    ViewMode::EditorOnly => {
-                        editor_state = self.show_editor_pane(ui);
-                    }
-                    ViewMode::Split => {
-                        ui.columns(2, |cols| {
-                            cols[0].spacing_mut().item_spacing = egui::vec2(8.0, 6.0);
-                            cols[1].spacing_mut().item_spacing = egui::vec2(8.0, 6.0);
-                            editor_state = self.show_editor_pane(&mut cols[0]);
-                            self.show_preview_pane(&mut cols[1]);
-                        });
-                    }
-                    ViewMode::PreviewOnly => {
-                        self.show_preview_pane(ui);
-                    }
-                }
+    if source == PasteSource::Word {
+        out = WORD_COMMENTS_RE
+            .get_or_init(|| Regex::new(r"(?is)<!--\[if.*?<!\[endif\]-->").expect("valid"))
+            .replace_all(&out, "")
+            .to_string();
+        out = O_P_TAG_RE
+            .get_or_init(|| Regex::new(r"(?is)</?o:p[^>]*>").expect("valid"))
+            .replace_all(&out, "")
+            .to_string();
+    }
+    out
+}
 
-                self.editor_has_focus = show_editor && editor_state.has_focus;
+fn convert_html_to_markdown(html: &str, config: &SmartPasteConfig) -> String {
+    if html.trim().is_empty() {
+        return String::new();
+    }
+    let fragment = Html::parse_fragment(html);
+    let mut out = String::new();
+    for child in fragment.tree.root().children() {
+        out.push_str(&convert_node(&child, config, 0));
+    }
+    post_process_markdown(&out)
+}
 
-                if show_editor {
-                    if let Some((index, line, text, anchor)) = editor_state.open_slash_args {
-                        self.maybe_open_slash_menu(index, line, &text, anchor);
-                    }
+fn convert_node(
+    node: &ego_tree::NodeRef<'_, Node>,
+    config: &SmartPasteConfig,
+    depth: usize,
+) -> String {
+    match node.value() {
+        Node::Text(text) => text.text.to_string(),
+        Node::Comment(_) => String::new(),
+        Node::Element(el) => {
+            let tag = el.name.local.to_string();
+            let children = || {
+                let mut s = String::new();
+                for child in node.children() {
+                    s.push_str(&convert_node(&child, config, depth));
+                }
+                s
+            };
 
-                    if self.slash_menu.open {
-                        if let Some(index) = editor_state.cursor_char_index {
-                            if editor_state.cursor_line != self.slash_menu.trigger_line {
-                                self.dismiss_slash_menu(false, index);
-                            } else if let Some(doc) = self.active_document() {
-                                let start = Self::byte_index_from_char_index(
-                                    &doc.raw_markdown,
-                                    self.slash_menu.trigger_char_index + 1,
-                                );
-                                let end =
-                                    Self::byte_index_from_char_index(&doc.raw_markdown, index);
-                                if start <= end && end <= doc.raw_markdown.len() {
-                                    self.slash_menu.query =
-                                        doc.raw_markdown[start..end].to_string();
-                                }
-                            }
-                        }
+            match tag.as_str() {
+                "script" | "style" | "noscript" | "iframe" | "object" | "embed" | "form"
+                | "button" | "select" | "textarea" | "nav" | "footer" | "header" => String::new(),
+                "strong" | "b" => format!("**{}**", children().trim()),
+                "em" | "i" => format!("*{}*", children().trim()),
+                "s" | "strike" | "del" => format!("~~{}~~", children().trim()),
+                "u" => format!("<u>{}</u>", children().trim()),
+                "code" if tag != "pre" => {
+                    let content = children();
+                    if content.contains('`') {
+                        format!("``{content}``")
+                    } else {
+                        format!("`{content}`")
                     }
-
-                    if self.slash_menu.open {
-                        let matches = self.slash_matches(editor_state.has_selection);
-                        if matches.len() == 1 {
-                            self.slash_menu.selected_idx = 0;
-                        } else if self.slash_menu.selected_idx >= matches.len() {
-                            self.slash_menu.selected_idx = 0;
-                        }
-
-                        let mut dismiss_escape = false;
-                        let mut execute = false;
-                        let mut move_next = false;
-                        let mut move_prev = false;
-                        let mut dismiss_on_space = false;
-                        ctx.input(|i| {
-                            dismiss_escape = i.key_pressed(egui::Key::Escape);
-                            execute = i.key_pressed(egui::Key::Enter);
-                            move_next = i.key_pressed(egui::Key::ArrowDown)
-                                || i.key_pressed(egui::Key::Tab) && !i.modifiers.shift;
-                            move_prev = i.key_pressed(egui::Key::ArrowUp)
-                                || (i.key_pressed(egui::Key::Tab) && i.modifiers.shift);
-                            dismiss_on_space =
-                                i.key_pressed(egui::Key::Space) && matches.is_empty();
-                        });
-                        if move_next && !matches.is_empty() {
-                            self.slash_menu.selected_idx =
-                                (self.slash_menu.selected_idx + 1) % matches.len();
-                        }
-                        if move_prev && !matches.is_empty() {
-                            self.slash_menu.selected_idx = if self.slash_menu.selected_idx == 0 {
-                                matches.len() - 1
-                            } else {
-                                self.slash_menu.selected_idx - 1
-                            };
-                        }
-                        if dismiss_escape {
-                            if let Some(index) = editor_state.cursor_char_index {
-                                self.dismiss_slash_menu(true, index);
-                            }
-                        } else if dismiss_on_space {
-                            self.slash_menu.open = false;
-                        } else if execute {
-                            if let (Some(index), Some(entry)) = (
-                                editor_state.cursor_char_index,
-                                matches.get(self.slash_menu.selected_idx),
-                            ) {
-                                let cmd = self.slash_commands[entry.cmd_idx].clone();
-                                self.execute_slash_command(&cmd, index);
-                            }
+                }
+                "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                    let level = tag[1..].parse::<usize>().unwrap_or(1).clamp(1, 6);
+                    format!("\n{} {}\n\n", "#".repeat(level), children().trim())
+                }
+                "p" | "div" | "section" | "article" | "main" | "aside" => {
+                    let text = children().trim().to_string();
+                    if text.is_empty() {
+                        String::new()
+                    } else {
+                        format!("{text}\n\n")
+                    }
+                }
+                "br" => "  \n".to_string(),
+                "hr" => "\n---\n\n".to_string(),
+                "a" => {
+                    let text = children().trim().to_string();
+                    if let Some(href) = el.attr("href") {
+                        let href = clean_url(href, config);
+                        if text == href {
+                            format!("<{href}>")
+                        } else {
+                            format!("[{text}]({href})")
                         }
-
-                        if self.slash_menu.open {
-                            let mut clicked_cmd: Option<SlashCommand> = None;
-                            let frame = egui::Frame::popup(ui.style())
-                                .corner_radius(egui::CornerRadius::same(4))
-                                .shadow(egui::epaint::Shadow {
-                                    offset: [0, 4],
-                                    blur: 12,
-                                    spread: 0,
-                                    color: egui::Color32::from_black_alpha(26),
-                                });
-                            egui::Area::new("slash_menu".into())
-                                .order(egui::Order::Foreground)
-                                .fixed_pos(self.slash_menu.anchor + egui::vec2(0.0, 4.0))
-                                .show(ctx, |ui| {
-                                    frame.show(ui, |ui| {
-                                        ui.set_min_width(280.0);
-                                        ui.set_max_width(360.0);
-                                        ui.set_max_height(340.0);
-                                        if matches.is_empty() {
-                                            ui.weak("No commands found");
-                                        } else {
-                                            egui::ScrollArea::vertical().max_height(340.0).show(
-                                                ui,
-                                                |ui| {
-                                                    for (i, m) in matches.iter().enumerate() {
-                                                        let cmd =
-                                                            self.slash_commands[m.cmd_idx].clone();
-                                                        let selected =
-                                                            i == self.slash_menu.selected_idx;
-                                                        let mut label =
-                                                            egui::RichText::new(cmd.name);
-                                                        if selected {
-                                                            label = label.strong();
-                                                        }
-                                                        let row =
-                                                            ui.selectable_label(selected, label);
-                                                        if row.clicked() {
-                                                            clicked_cmd = Some(cmd.clone());
-                                                        }
-                                                        if self.slash_config.show_descriptions {
-                                                            ui.small(
-                                                                egui::RichText::new(
-                                                                    cmd.description,
-                                                                )
-                                                                .weak(),
-                                                            );
-                                                        }
-                                                        ui.add_space(2.0);
-                                                    }
-                                                },
-                                            );
-                                        }
-                                    });
-                                });
-                            if let (Some(index), Some(cmd)) =
-                                (editor_state.cursor_char_index, clicked_cmd)
-                            {
-                                self.execute_slash_command(&cmd, index);
+                    } else {
+                        text
+                    }
+                }
+                "img" => {
+                    if let Some(src) = el.attr("src") {
+                        let alt = el.attr("alt").unwrap_or_default();
+                        format!("![{alt}]({src})")
+                    } else {
+                        String::new()
+                    }
+                }
+                "ul" => convert_list(node, config, false, depth),
+                "ol" => convert_list(node, config, true, depth),
+                "blockquote" => {
+                    let inner = children();
+                    inner
+                        .lines()
+                        .map(|line| format!("> {line}"))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                        + "\n\n"
+                }
+                "pre" => {
+                    let mut code = String::new();
+                    for child in node.children() {
+                        if let Node::Element(code_el) = child.value()
+                            && code_el.name.local.to_string() == "code"
+                        {
+                            for grandchild in child.children() {
+                                code.push_str(&convert_node(&grandchild, config, depth + 1));
                             }
+                            break;
                         }
+                        code.push_str(&convert_node(&child, config, depth + 1));
                     }
-                } else {
-                    self.slash_menu.open = false;
-                    self.slash_menu.query.clear();
+                    format!("\n```\n{}\n```\n\n", code.trim_end())
                 }
+                "table" => convert_table(node, config),
+                _ => children(),
+            }
+        }
+        _ => String::new(),
+    }
+}
 
-                if self.drop_zone_visible && self.editor_has_focus {
-                    let rect = ui.max_rect();
-                    let painter = ui.painter();
-                    painter.rect_filled(
-                        rect,
-                        egui::CornerRadius::same(8),
-                        egui::Color32::from_rgba_unmultiplied(59, 130, 246, 20),
-                    );
-                    painter.rect_stroke(
-                        rect.shrink(4.0),
-                        egui::CornerRadius::same(8),
-                        egui::Stroke::new(2.0, egui::Color32::from_rgb(59, 130, 246)),
-                        egui::StrokeKind::Middle,
-                    );
-                    painter.text(
-                        rect.center(),
-                        egui::Align2::CENTER_CENTER,
-                        "🖼 Drop image here",
-                        egui::TextStyle::Heading.resolve(ui.style()),
-                        egui::Color32::from_rgb(59, 130, 246),
-                    );
+fn convert_list(
+    node: &ego_tree::NodeRef<'_, Node>,
+    config: &SmartPasteConfig,
+    ordered: bool,
+    depth: usize,
+) -> String {
+    let mut lines = Vec::new();
+    let mut index = 1usize;
+    let indent = "  ".repeat(depth.min(config.max_nesting_depth));
+    for child in node.children() {
+        if let Node::Element(el) = child.value()
+            && el.name.local.to_string() == "li"
+        {
+            let mut has_checkbox = None;
+            for li_child in child.children() {
+                if let Node::Element(input) = li_child.value()
+                    && input.name.local.to_string() == "input"
+                    && input.attr("type") == Some("checkbox")
+                {
+                    has_checkbox = Some(input.attr("checked").is_some());
+                }
+            }
+
+            let mut text = String::new();
+            for li_child in child.children() {
+                if let Node::Element(input) = li_child.value()
+                    && input.name.local.to_string() == "input"
+                {
+                    continue;
+                }
+                text.push_str(&convert_node(&li_child, config, depth + 1));
+            }
+            let text = text.trim().to_string();
+
+            let marker = if ordered {
+                let m = format!("{index}.");
+                index += 1;
+                m
+            } else {
+                "-".to_string()
+            };
+            let item = if let Some(checked) = has_checkbox {
+                format!(
+                    "{indent}{marker} [{}] {text}",
+                    if checked { "x" } else { " " }
+                )
+            } else {
+                format!("{indent}{marker} {text}")
+            };
+            lines.push(item);
+        }
+    }
+    format!("{}\n\n", lines.join("\n"))
+}
+
+fn convert_table(node: &ego_tree::NodeRef<'_, Node>, config: &SmartPasteConfig) -> String {
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    for child in node.children() {
+        collect_table_rows(&child, config, &mut rows);
+    }
+    if rows.is_empty() {
+        return String::new();
+    }
+    let width = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+    if width == 0 {
+        return String::new();
+    }
+    for row in &mut rows {
+        while row.len() < width {
+            row.push(String::new());
+        }
+    }
+    let mut out = String::new();
+    let header = &rows[0];
+    out.push_str(&format!("| {} |\n", header.join(" | ")));
+    out.push_str(&format!("|{}|\n", vec!["---"; width].join("|")));
+    for row in rows.iter().skip(1) {
+        out.push_str(&format!("| {} |\n", row.join(" | ")));
+    }
+    format!("\n{out}\n")
+}
+
+fn collect_table_rows(
+    node: &ego_tree::NodeRef<'_, Node>,
+    config: &SmartPasteConfig,
+    rows: &mut Vec<Vec<String>>,
+) {
+    if let Node::Element(el) = node.value() {
+        let tag = el.name.local.to_string();
+        if tag == "tr" {
+            let mut row = Vec::new();
+            for cell in node.children() {
+                if let Node::Element(cell_el) = cell.value() {
+                    let ctag = cell_el.name.local.to_string();
+                    if ctag == "th" || ctag == "td" {
+                        let mut text = String::new();
+                        for gc in cell.children() {
+                            text.push_str(&convert_node(&gc, config, 0));
+                        }
+                        row.push(text.trim().replace('|', "\\|"));
+                    }
+                }
+            }
+            rows.push(row);
+            return;
+        }
+    }
+    for child in node.children() {
+        collect_table_rows(&child, config, rows);
+    }
+}
+
+fn clean_url(url: &str, config: &SmartPasteConfig) -> String {
+    let escaped = url.replace('(', r"\(").replace(')', r"\)");
+    if !config.strip_tracking_params {
+        return escaped;
+    }
+
+    let Some((base, query)) = url.split_once('?') else {
+        return escaped;
+    };
+
+    let stripped = query
+        .split('&')
+        .filter(|entry| {
+            let key = entry.split('=').next().unwrap_or_default();
+            !matches!(
+                key,
+                "utm_source" | "utm_medium" | "utm_campaign" | "utm_content" | "utm_term"
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let rebuilt = if stripped.is_empty() {
+        base.to_string()
+    } else {
+        format!("{base}?{stripped}")
+    };
+    rebuilt.replace('(', r"\(").replace(')', r"\)")
+}
+
+fn post_process_markdown(markdown: &str) -> String {
+    static MANY_BREAKS_RE: OnceLock<Regex> = OnceLock::new();
+    static ZW_RE: OnceLock<Regex> = OnceLock::new();
+    let mut out = markdown.to_string();
+    out = ZW_RE
+        .get_or_init(|| Regex::new(r"[\u{200B}\u{FEFF}]").expect("valid"))
+        .replace_all(&out, "")
+        .replace('\u{00A0}', " ")
+        .to_string();
+    out = out
+        .lines()
+        .map(|line| {
+            if line.ends_with("  ") {
+                line.to_string()
+            } else {
+                line.trim_end().to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    out = MANY_BREAKS_RE
+        .get_or_init(|| Regex::new(r"\n{3,}").expect("valid"))
+        .replace_all(&out, "\n\n")
+        .to_string();
+    out.trim().to_string()
+}
+
+fn store_image_for_doc(doc: &Document, cfg: &ImageConfig, file: &PendingImage) -> Result<String> {
+    let ext = Path::new(&file.name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("png");
+    let random = format!("{:04x}", rand::rng().random::<u16>());
+    let mut filename = format!("image-{}-{random}.{ext}", chrono_like_timestamp());
+    match cfg.storage_mode {
+        ImageStorageMode::Local => {
+            let doc_dir = doc
+                .file_path
+                .as_ref()
+                .and_then(|p| p.parent())
+                .unwrap_or_else(|| Path::new("."));
+            let base_dir = doc_dir.join(cfg.local_path.trim_start_matches("./"));
+            fs::create_dir_all(&base_dir)?;
+            let mut output = base_dir.join(&filename);
+            while output.exists() {
+                filename = format!(
+                    "image-{}-{:06x}.{ext}",
+                    chrono_like_timestamp(),
+                    rand::rng().random::<u32>() & 0x00ff_ffff
+                );
+                output = base_dir.join(&filename);
+            }
+            let mut f = fs::File::create(output)?;
+            f.write_all(&file.bytes)?;
+            Ok(format!("![](./assets/images/{filename})"))
+        }
+        ImageStorageMode::Base64 => {
+            if file.bytes.len() > cfg.base64_max_size_kb * 1024 {
+                anyhow::bail!("Image too large for base64 mode.")
+            }
+            let encoded = base64::engine::general_purpose::STANDARD.encode(&file.bytes);
+            Ok(format!("![](data:{};base64,{encoded})", file.mime))
+        }
+        ImageStorageMode::Remote => {
+            let endpoint = cfg
+                .remote_endpoint
+                .as_ref()
+                .context("remote endpoint is not configured")?;
+            let resp = reqwest::blocking::Client::new()
+                .post(endpoint)
+                .body(file.bytes.clone())
+                .send()?;
+            let url = resp.text()?;
+            Ok(format!("![]({})", url.trim()))
+        }
+    }
+}
+
+fn chrono_like_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl MarkdownViewerApp {
+    fn byte_index_from_char_index(text: &str, char_index: usize) -> usize {
+        text.char_indices()
+            .nth(char_index)
+            .map(|(idx, _)| idx)
+            .unwrap_or(text.len())
+    }
+
+    fn detect_inline_code(line_prefix: &str) -> bool {
+        line_prefix.chars().filter(|c| *c == '`').count() % 2 == 1
+    }
+
+    fn maybe_open_slash_menu(
+        &mut self,
+        cursor_char_index: usize,
+        cursor_line: usize,
+        text: &str,
+        anchor: egui::Pos2,
+    ) {
+        if !self.slash_config.enabled || !self.editor_has_focus {
+            return;
+        }
+        let before = &text[..Self::byte_index_from_char_index(text, cursor_char_index)];
+        let mut line_start = 0usize;
+        if let Some(last_nl) = before.rfind('\n') {
+            line_start = last_nl + 1;
+        }
+        let line_prefix = &before[line_start..];
+        if Self::detect_inline_code(line_prefix) {
+            return;
+        }
+        if line_prefix.ends_with("```") || before.matches("\n```").count() % 2 == 1 {
+            return;
+        }
+        let Some(ch) = before.chars().last() else {
+            return;
+        };
+        if ch != self.slash_config.trigger_char {
+            return;
+        }
+        let prev = line_prefix.chars().rev().nth(1);
+        let allowed = prev.is_none() || prev == Some(' ') || line_prefix.ends_with("> /");
+        if !allowed {
+            return;
+        }
+
+        self.slash_menu.open = true;
+        self.slash_menu.trigger_char_index = cursor_char_index.saturating_sub(1);
+        self.slash_menu.trigger_line = cursor_line;
+        self.slash_menu.query.clear();
+        self.slash_menu.selected_idx = 0;
+        self.slash_menu.anchor = anchor;
+    }
+
+    fn slash_matches(&self, has_selection: bool) -> Vec<SlashMatch> {
+        let query = self.slash_menu.query.trim();
+        let mut out = Vec::new();
+        for (idx, cmd) in self.slash_commands.iter().enumerate() {
+            if let Some(needs_sel) = cmd.when_has_selection
+                && needs_sel != has_selection
+            {
+                continue;
+            }
+            let score = fuzzy_score(query, cmd.name)
+                .or_else(|| cmd.aliases.iter().find_map(|a| fuzzy_score(query, a)))
+                .or_else(|| cmd.keywords.iter().find_map(|k| fuzzy_score(query, k)));
+            if let Some(score) = score {
+                out.push(SlashMatch {
+                    cmd_idx: idx,
+                    score,
+                });
+            }
+        }
+        out.sort_by(|a, b| b.score.total_cmp(&a.score));
+        if query.is_empty() && self.slash_config.show_recently_used {
+            let recent = self
+                .slash_tracker
+                .top_recent(self.slash_config.recently_used_count);
+            out.sort_by_key(|m| {
+                recent
+                    .iter()
+                    .position(|id| id == self.slash_commands[m.cmd_idx].id)
+                    .unwrap_or(usize::MAX)
+            });
+        }
+        out
+    }
+
+    fn execute_slash_command(&mut self, cmd: &SlashCommand, cursor_char_index: usize) {
+        let settings = self.settings.clone();
+        let mut applied = false;
+        let trigger_char_index = self.slash_menu.trigger_char_index;
+        if let Some(doc) = self.active_document_mut() {
+            let start = Self::byte_index_from_char_index(&doc.raw_markdown, trigger_char_index);
+            let end = Self::byte_index_from_char_index(&doc.raw_markdown, cursor_char_index);
+            if start <= end && end <= doc.raw_markdown.len() {
+                doc.raw_markdown.replace_range(start..end, cmd.insert_text);
+                doc.record_edit(&settings, EditKind::Command);
+                applied = true;
+            }
+        }
+        if applied {
+            self.slash_tracker.record_use(cmd.id);
+        }
+        self.slash_menu.open = false;
+    }
+
+    fn show_inline_surface(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
+        let (doc_id, scroll_to_line) = {
+            let doc = &mut self.documents[self.active_doc];
+            (doc.id, doc.scroll_to_line.take())
+        };
+
+        egui::ScrollArea::vertical()
+            .id_salt(("inline", doc_id))
+            .show(ui, |ui| {
+                let mut changed = false;
+                let mut new_editing = self.inline_state.editing_line;
+                let doc = &mut self.documents[self.active_doc];
+                let mut lines: Vec<String> =
+                    doc.raw_markdown.lines().map(ToOwned::to_owned).collect();
+                if lines.is_empty() {
+                    lines.push(String::new());
+                }
+
+                for (idx, line) in lines.iter_mut().enumerate() {
+                    let is_editing = new_editing == Some(idx);
+                    let row_id = ui.make_persistent_id((doc.id, idx));
+                    let rendered_t = if self.settings.inline_render.animate_transitions {
+                        ctx.animate_bool(row_id, !is_editing)
+                    } else if is_editing {
+                        0.0
+                    } else {
+                        1.0
+                    };
+
+                    let resp = if is_editing {
+                        let resp = ui.add(
+                            egui::TextEdit::singleline(line)
+                                .id(inline_line_text_id(doc.id, idx))
+                                .desired_width(f32::INFINITY),
+                        );
+                        changed |= resp.changed();
+                        if resp.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                            new_editing = None;
+                        }
+                        resp
+                    } else {
+                        let rich = render_line_preview(line);
+                        let text = if rendered_t > 0.99 {
+                            rich
+                        } else {
+                            egui::RichText::new(line.as_str()).monospace()
+                        };
+                        let resp = ui.add(egui::Label::new(text).sense(egui::Sense::click()));
+                        if resp.clicked() {
+                            new_editing = Some(idx);
+                        }
+                        resp
+                    };
+                    if scroll_to_line == Some(idx) {
+                        resp.scroll_to_me(Some(egui::Align::TOP));
+                    }
+                }
+
+                if changed {
+                    let trailing_newline = doc.raw_markdown.ends_with('\n');
+                    doc.raw_markdown = lines.join("\n");
+                    if trailing_newline {
+                        doc.raw_markdown.push('\n');
+                    }
+                    doc.record_edit(&self.settings, EditKind::Typing);
+                }
+                self.inline_state.editing_line = new_editing;
+            });
+    }
+
+    fn show_editor_pane(&mut self, ui: &mut egui::Ui) -> EditorUiState {
+        let mut state = EditorUiState::default();
+        let doc_id = self.documents[self.active_doc].id;
+
+        let mut scroll_area = egui::ScrollArea::vertical().id_salt(("editor", doc_id));
+        if let Some(offset) = self.split_sync.editor_target.take() {
+            scroll_area = scroll_area.vertical_scroll_offset(offset);
+            state.scrolled_by_sync = true;
+        }
+        let scroll_output = scroll_area.show(ui, |ui| {
+            let origin = ui.min_rect().top();
+            let doc = &mut self.documents[self.active_doc];
+            let output = egui::TextEdit::multiline(&mut doc.raw_markdown)
+                .id(editor_text_id(doc_id))
+                .code_editor()
+                .desired_width(f32::INFINITY)
+                .desired_rows(12)
+                .show(ui);
+            self.split_sync.editor_layout =
+                editor_line_layout(&output.galley, output.galley_pos.y - origin);
+            state.has_focus = output.response.has_focus();
+            if let Some(range) = output.cursor_range {
+                state.cursor_char_index = Some(range.primary.index);
+                state.has_selection = range.primary.index != range.secondary.index;
+                let before = &doc.raw_markdown
+                    [..Self::byte_index_from_char_index(&doc.raw_markdown, range.primary.index)];
+                state.cursor_line = before.bytes().filter(|b| *b == b'\n').count();
+            }
+            if output.response.changed() {
+                doc.record_edit(&self.settings, EditKind::Typing);
+                if let Some(index) = state.cursor_char_index {
+                    state.open_slash_args = Some((
+                        index,
+                        state.cursor_line,
+                        doc.raw_markdown.clone(),
+                        output.response.rect.left_bottom(),
+                    ));
+                }
+            }
+        });
+        state.scroll_offset = scroll_output.state.offset.y;
+        state.viewport_height = scroll_output.inner_rect.height();
+
+        state
+    }
+
+    fn sync_preview_to_editor(&mut self, editor: &EditorUiState) {
+        let Some(doc) = self.documents.get(self.active_doc) else {
+            return;
+        };
+        let sync = &mut self.split_sync;
+        if sync.doc_id != Some(doc.id) {
+            *sync = SplitScrollSync {
+                doc_id: Some(doc.id),
+                editor_offset: Some(editor.scroll_offset),
+                editor_layout: std::mem::take(&mut sync.editor_layout),
+                ..Default::default()
+            };
+            return;
+        }
+
+        let user_scrolled = !editor.scrolled_by_sync
+            && sync
+                .editor_offset
+                .is_some_and(|last| (editor.scroll_offset - last).abs() > 0.5);
+        sync.editor_offset = Some(editor.scroll_offset);
+        sync.editor_height = editor.viewport_height;
+        if user_scrolled
+            && let Some(line) = sync.editor_layout.line_at(editor.scroll_offset)
+            && let Some(y) = doc.preview_layout.y_at(line)
+        {
+            sync.preview_target = Some(y);
+        }
+
+        if editor.has_focus && sync.cursor_line != Some(editor.cursor_line) {
+            sync.cursor_line = Some(editor.cursor_line);
+            let offset = sync
+                .preview_target
+                .or(sync.preview_offset)
+                .unwrap_or_default();
+            if let Some(y) = doc.preview_layout.y_for_line(editor.cursor_line)
+                && !(0.0..sync.preview_height).contains(&(y - offset))
+            {
+                sync.preview_target = Some((y - sync.preview_height / 3.0).max(0.0));
+            }
+        }
+    }
+
+    fn sync_editor_to_preview(
+        &mut self,
+        ctx: &egui::Context,
+        preview: &PreviewUiState,
+        scrolled_by_sync: bool,
+    ) {
+        let sync = &mut self.split_sync;
+        sync.preview_height = preview.viewport_height;
+        let user_scrolled = !scrolled_by_sync
+            && sync
+                .preview_offset
+                .is_some_and(|last| (preview.offset - last).abs() > 0.5);
+        sync.preview_offset = Some(preview.offset);
+        if user_scrolled
+            && let Some(doc) = self.documents.get(self.active_doc)
+            && let Some(line) = doc.preview_layout.line_at(preview.offset)
+            && let Some(y) = sync.editor_layout.y_at(line)
+        {
+            sync.editor_target = Some(y);
+        }
+
+        if let Some(line) = preview.clicked_line {
+            self.place_editor_cursor_at_line(ctx, line);
+        }
+    }
+
+    fn place_editor_cursor_at_line(&mut self, ctx: &egui::Context, line: usize) {
+        let Some(doc) = self.documents.get(self.active_doc) else {
+            return;
+        };
+        let byte = doc
+            .raw_markdown
+            .split_inclusive('\n')
+            .take(line)
+            .map(str::len)
+            .sum::<usize>();
+        let char_index = doc.raw_markdown[..byte].chars().count();
+        let id = editor_text_id(doc.id);
+
+        let mut state = egui::text_edit::TextEditState::load(ctx, id).unwrap_or_default();
+        state
+            .cursor
+            .set_char_range(Some(egui::text::CCursorRange::one(
+                egui::text::CCursor::new(char_index),
+            )));
+        state.store(ctx, id);
+        ctx.memory_mut(|m| m.request_focus(id));
+
+        // Don't bounce the preview back to the block that was just clicked.
+        self.split_sync.cursor_line = Some(line);
+        let sync = &mut self.split_sync;
+        if let Some(y) = sync.editor_layout.y_for_line(line)
+            && let Some(offset) = sync.editor_offset
+            && !(offset..offset + sync.editor_height).contains(&y)
+        {
+            sync.editor_target = Some((y - sync.editor_height / 3.0).max(0.0));
+        }
+    }
+
+    fn show_preview_pane(
+        &mut self,
+        ui: &mut egui::Ui,
+        scroll_offset: Option<f32>,
+    ) -> PreviewUiState {
+        let math_cache = self.math_cache.clone();
+        let math_pool = self.math_pool.clone();
+        let diagram_cache = self.diagram_cache.clone();
+        let diagram_tx = self.diagram_tx.clone();
+        let render_math_enabled = self.settings.render_math;
+        let render_diagrams_enabled = self.settings.render_diagrams;
+        let diagram_remote = self
+            .settings
+            .remote_diagrams
+            .then(|| self.settings.kroki_url.clone());
+        let theme = self.settings.theme;
+
+        let highlight = self.preview_find_highlight();
+        if highlight.as_ref().is_some_and(|h| h.reveal) {
+            self.find.reveal_selected = false;
+        }
+
+        let (doc_id, mut scroll_to_line) = {
+            let doc = &mut self.documents[self.active_doc];
+            (doc.id, doc.scroll_to_line.take())
+        };
+        let mut scroll_area = egui::ScrollArea::vertical().id_salt(("preview", doc_id));
+        if let Some(offset) = scroll_offset {
+            scroll_area = scroll_area.vertical_scroll_offset(offset);
+        }
+        let widget_took_click = click_taken_by_widget(ui.ctx());
+        let click = ui.input(|i| {
+            i.pointer
+                .primary_clicked()
+                .then(|| i.pointer.interact_pos())
+                .flatten()
+                .filter(|_| !widget_took_click)
+        });
+        let mut clicked_line = None::<usize>;
+
+        let scroll_output = scroll_area.show(ui, |ui| {
+            // Formulas and diagrams are numbered per block, so an edit is recognized as
+            // the same slot on the next frame.
+            let block_idx = Rc::new(Cell::new(0usize));
+            let math_idx = Rc::new(Cell::new(0usize));
+            let html_idx = Rc::new(Cell::new(0usize));
+            let next_slot = {
+                let block_idx = block_idx.clone();
+                move |counter: &Cell<usize>| {
+                    let index = counter.get();
+                    counter.set(index + 1);
+                    SvgSlotId {
+                        doc: doc_id,
+                        block: block_idx.get(),
+                        index,
+                    }
+                }
+            };
+            let render_math_fn = {
+                let (next_slot, math_idx) = (next_slot.clone(), math_idx.clone());
+                move |ui: &mut egui::Ui, tex: &str, inline: bool| {
+                    let slot = next_slot(&math_idx);
+                    render_math(ui, tex, inline, slot, &math_cache, &math_pool);
+                }
+            };
+            let render_html_fn = {
+                let html_idx = html_idx.clone();
+                move |ui: &mut egui::Ui, html: &str| {
+                    render_html(
+                        ui,
+                        html,
+                        render_diagrams_enabled,
+                        diagram_remote.as_deref(),
+                        next_slot(&html_idx),
+                        &diagram_cache,
+                        &diagram_tx,
+                    );
+                }
+            };
+
+            let alerts = callout_alert_bundle(theme, ui.visuals().dark_mode);
+            let new_viewer = || {
+                let mut viewer = CommonMarkViewer::new().alerts(alerts.clone());
+                if render_math_enabled {
+                    viewer = viewer.render_math_fn(Some(&render_math_fn));
+                }
+                if render_diagrams_enabled {
+                    viewer = viewer.render_html_fn(Some(&render_html_fn));
+                }
+                viewer
+            };
+
+            let row_height = ui.text_style_height(&TextStyle::Body).round();
+            let layer = ui.layer_id();
+            let origin = ui.min_rect().top();
+            let mut layout = LineLayout::default();
+            let doc = &mut self.documents[self.active_doc];
+            for (idx, block) in doc.preview_blocks.iter().enumerate() {
+                block_idx.set(idx);
+                math_idx.set(0);
+                html_idx.set(0);
+                if idx > 0 {
+                    let gap = preview_block_gap(&doc.preview_blocks, idx, row_height);
+                    ui.add_space(gap - ui.spacing().item_spacing.y);
+                }
+                let top = ui.cursor().top();
+                let first_shape = ui
+                    .ctx()
+                    .graphics(|layers| layers.get(layer).map_or(0, |list| list.next_idx().0));
+                let cache = &mut doc.commonmark_cache;
+                ui.push_id(("preview_block", idx), |ui| {
+                    if let Some(callout) = &block.callout {
+                        show_callout(
+                            ui,
+                            callout,
+                            theme,
+                            egui::Id::new(("callout", doc_id, idx)),
+                            |ui| {
+                                new_viewer().show(ui, cache, &callout.body);
+                            },
+                        );
+                    } else {
+                        new_viewer().show(ui, cache, &block.markdown);
+                    }
+                });
+                let bottom = ui.min_rect().bottom();
+                layout.blocks.push(BlockPosition {
+                    lines: block.lines.clone(),
+                    top: top - origin,
+                    bottom: bottom - origin,
+                });
+
+                if let Some(pos) = click
+                    && ui.clip_rect().contains(pos)
+                    && (top..bottom).contains(&pos.y)
+                {
+                    let fraction = (pos.y - top) / (bottom - top).max(1.0);
+                    clicked_line =
+                        Some(block.lines.start + (block.lines.len() as f32 * fraction) as usize);
+                }
+
+                if let Some(line) = scroll_to_line
+                    && line < block.lines.end
+                    && let Some(y) = layout.y_for_line(line)
+                {
+                    scroll_preview_to(ui, origin + y);
+                    scroll_to_line = None;
+                }
+
+                if !block.footnote_refs.is_empty() {
+                    let cache = &mut doc.commonmark_cache;
+                    show_footnote_previews(
+                        ui,
+                        layer,
+                        first_shape,
+                        &block.footnote_refs,
+                        &doc.footnotes,
+                        |ui, body| {
+                            new_viewer().show(ui, cache, body);
+                        },
+                    );
+                }
+                if let Some(highlight) = &highlight {
+                    paint_preview_matches(ui, layer, first_shape, highlight, idx);
+                }
+            }
+            if scroll_to_line.is_some()
+                && let Some(y) = layout.y_for_line(usize::MAX)
+            {
+                scroll_preview_to(ui, origin + y);
+            }
+            doc.preview_layout = layout;
+        });
+
+        let doc = &mut self.documents[self.active_doc];
+        let top = scroll_output.state.offset.y + ui.text_style_height(&TextStyle::Body);
+        doc.viewed_line = doc.preview_layout.line_at(top).map(|line| line as usize);
+
+        PreviewUiState {
+            offset: scroll_output.state.offset.y,
+            viewport_height: scroll_output.inner_rect.height(),
+            clicked_line,
+        }
+    }
+
+    fn dismiss_slash_menu(&mut self, remove_typed: bool, cursor_char_index: usize) {
+        let settings = self.settings.clone();
+        if remove_typed {
+            let trigger_char_index = self.slash_menu.trigger_char_index;
+            if let Some(doc) = self.active_document_mut() {
+                let start = Self::byte_index_from_char_index(&doc.raw_markdown, trigger_char_index);
+                let end = Self::byte_index_from_char_index(&doc.raw_markdown, cursor_char_index);
+                if start <= end && end <= doc.raw_markdown.len() {
+                    doc.raw_markdown.replace_range(start..end, "");
+                    doc.record_edit(&settings, EditKind::Command);
+                }
+            }
+        }
+        self.slash_menu.open = false;
+        self.slash_menu.query.clear();
+    }
+}
+
+impl eframe::App for MarkdownViewerApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.handle_close_request(ctx);
+
+        let dropped_paths: Vec<PathBuf> = ctx.input(|i| {
+            i.raw
+                .dropped_files
+                .iter()
+                .filter_map(|f| f.path.clone())
+                .collect()
+        });
+        let dropped_images: Vec<PendingImage> = ctx.input(|i| {
+            i.raw
+                .dropped_files
+                .iter()
+                .filter_map(|f| {
+                    let mime = f.mime.clone();
+                    let bytes = f.bytes.as_ref()?.to_vec();
+                    Some(PendingImage {
+                        name: if f.name.is_empty() {
+                            f.path
+                                .as_ref()
+                                .and_then(|p| {
+                                    p.file_name().map(|n| n.to_string_lossy().into_owned())
+                                })
+                                .unwrap_or_else(|| "image.png".to_string())
+                        } else {
+                            f.name.clone()
+                        },
+                        mime,
+                        bytes,
+                    })
+                })
+                .collect()
+        });
+
+        self.drop_zone_visible = ctx.input(|i| !i.raw.hovered_files.is_empty());
+        if ctx.input(|i| i.key_pressed(egui::Key::Escape)) {
+            self.drop_zone_visible = false;
+        }
+        if self.editor_has_focus {
+            self.process_images_for_active_doc(dropped_images);
+            for path in dropped_paths {
+                if path.is_file() {
+                    match fs::read(&path) {
+                        Ok(bytes) => {
+                            let mime = infer_mime_from_path(&path);
+                            self.process_images_for_active_doc(vec![PendingImage {
+                                name: path
+                                    .file_name()
+                                    .map(|n| n.to_string_lossy().into_owned())
+                                    .unwrap_or_else(|| "image.png".to_string()),
+                                mime,
+                                bytes,
+                            }]);
+                        }
+                        Err(err) => self.push_error(format!("Failed to read dropped file: {err}")),
+                    }
+                }
+            }
+        } else {
+            for path in dropped_paths {
+                let _ = self.open_file(path);
+            }
+        }
+
+        if ctx.input(|i| i.modifiers.command && i.modifiers.shift && i.key_pressed(egui::Key::I)) {
+            if let Some(files) = rfd::FileDialog::new()
+                .add_filter("Images", &["png", "jpg", "jpeg", "gif", "webp", "svg"])
+                .pick_files()
+            {
+                let mut pending = Vec::new();
+                for path in files {
+                    if let Ok(bytes) = fs::read(&path) {
+                        pending.push(PendingImage {
+                            name: path
+                                .file_name()
+                                .map(|n| n.to_string_lossy().into_owned())
+                                .unwrap_or_default(),
+                            mime: infer_mime_from_path(&path),
+                            bytes,
+                        });
+                    }
+                }
+                self.process_images_for_active_doc(pending);
+            }
+        }
+
+        self.handle_undo_shortcuts(ctx);
+
+        if ctx.input(|i| i.key_pressed(egui::Key::S) && i.modifiers.command) {
+            if ctx.input(|i| i.modifiers.shift) {
+                self.save_document_as(self.active_doc);
+            } else {
+                self.save_document(self.active_doc);
+            }
+        }
+
+        if ctx.input(|i| i.key_pressed(egui::Key::F) && i.modifiers.command && i.modifiers.shift) {
+            self.open_search_panel();
+        } else if ctx.input(|i| i.key_pressed(egui::Key::H) && i.modifiers.command) {
+            self.find.open = true;
+            self.find.replace_open = true;
+            self.find.focus_query = true;
+        } else if ctx.input(|i| i.key_pressed(egui::Key::F) && i.modifiers.command) {
+            self.find.open = true;
+            self.find.focus_query = true;
+        }
+        if ctx.input_mut(|i| {
+            i.consume_shortcut(&egui::KeyboardShortcut::new(
+                egui::Modifiers::COMMAND,
+                egui::Key::P,
+            ))
+        }) {
+            self.open_quick_open();
+        }
+        self.handle_quick_open_keys(ctx);
+        if self.find.open && ctx.input(|i| i.key_pressed(egui::Key::Escape)) {
+            self.find.open = false;
+        }
+
+        self.pump_watch_events();
+        if self.settings.auto_reload {
+            self.process_pending_reloads();
+        } else {
+            self.pending_reloads.clear();
+        }
+        self.process_workspace_rescan(ctx);
+        self.pump_search_events(ctx);
+
+        egui::TopBottomPanel::top("top_bar")
+            .frame(egui::Frame::new().inner_margin(egui::Margin::symmetric(8, 4)))
+            .show(ctx, |ui| {
+                ui.spacing_mut().item_spacing = egui::vec2(8.0, 6.0);
+                ui.vertical(|ui| {
+                    let mut auto_reload_changed = false;
+                    egui::menu::bar(ui, |ui| {
+                        ui.menu_button("File", |ui| {
+                            if ui
+                                .add(
+                                    egui::Button::new("Open…")
+                                        .shortcut_text("Ctrl+O"),
+                                )
+                                .clicked()
+                            {
+                                self.open_dialog();
+                                ui.close();
+                            }
+
+                            if ui
+                                .add(egui::Button::new("Quick Open…").shortcut_text("Ctrl+P"))
+                                .clicked()
+                            {
+                                self.open_quick_open();
+                                ui.close();
+                            }
+
+                            if ui.button("Open Folder…").clicked() {
+                                self.open_workspace_dialog();
+                                ui.close();
+                            }
+
+                            if ui
+                                .add_enabled(
+                                    self.workspace.is_some(),
+                                    egui::Button::new("Close Folder"),
+                                )
+                                .clicked()
+                            {
+                                self.close_workspace();
+                                ui.close();
+                            }
+
+                            ui.separator();
+
+                            if ui
+                                .add(egui::Button::new("Save").shortcut_text("Ctrl+S"))
+                                .clicked()
+                            {
+                                self.save_document(self.active_doc);
+                                ui.close();
+                            }
+
+                            if ui
+                                .add(egui::Button::new("Save As…").shortcut_text("Ctrl+Shift+S"))
+                                .clicked()
+                            {
+                                self.save_document_as(self.active_doc);
+                                ui.close();
+                            }
+
+                            ui.separator();
+
+                            if ui
+                                .add(
+                                    egui::Button::new("Upload image…")
+                                        .shortcut_text("Ctrl+Shift+I"),
+                                )
+                                .clicked()
+                            {
+                                if let Some(files) = rfd::FileDialog::new()
+                                    .add_filter(
+                                        "Images",
+                                        &["png", "jpg", "jpeg", "gif", "webp", "svg"],
+                                    )
+                                    .pick_files()
+                                {
+                                    let mut pending = Vec::new();
+                                    for path in files {
+                                        if let Ok(bytes) = fs::read(&path) {
+                                            pending.push(PendingImage {
+                                                name: path
+                                                    .file_name()
+                                                    .map(|n| n.to_string_lossy().into_owned())
+                                                    .unwrap_or_default(),
+                                                mime: infer_mime_from_path(&path),
+                                                bytes,
+                                            });
+                                        }
+                                    }
+                                    self.process_images_for_active_doc(pending);
+                                }
+                                ui.close();
+                            }
+
+                            ui.separator();
+
+                            ui.menu_button("Recent", |ui| {
+                                let recent = self.persisted.recent_files.clone();
+                                if recent.is_empty() {
+                                    ui.weak("No recent files");
+                                    return;
+                                }
+
+                                for path in recent {
+                                    let exists = path.is_file();
+                                    let label = path
+                                        .file_name()
+                                        .map(|n| n.to_string_lossy().into_owned())
+                                        .unwrap_or_else(|| path.display().to_string());
+
+                                    if ui
+                                        .add_enabled(exists, egui::Button::new(label))
+                                        .on_hover_text(path.display().to_string())
+                                        .clicked()
+                                    {
+                                        let _ = self.open_file(path);
+                                        ui.close();
+                                    }
+                                }
+
+                                ui.separator();
+                                if ui.button("Clear recent").clicked() {
+                                    self.persisted.recent_files.clear();
+                                    ui.close();
+                                }
+                            });
+
+                            ui.separator();
+
+                            let reload_enabled = self
+                                .active_document()
+                                .and_then(|d| d.file_path.as_ref())
+                                .is_some();
+                            if ui
+                                .add_enabled(reload_enabled, egui::Button::new("Reload"))
+                                .clicked()
+                            {
+                                self.reload_active();
+                                ui.close();
+                            }
+
+                            auto_reload_changed |= ui
+                                .checkbox(
+                                    &mut self.settings.auto_reload,
+                                    "Auto-reload changed files",
+                                )
+                                .changed();
+                        });
+
+                        ui.menu_button("Edit", |ui| {
+                            let (can_undo, can_redo) = self
+                                .active_document()
+                                .map(|d| (!d.history.undo.is_empty(), !d.history.redo.is_empty()))
+                                .unwrap_or_default();
+                            if ui
+                                .add_enabled(
+                                    can_undo,
+                                    egui::Button::new("Undo").shortcut_text("Ctrl+Z"),
+                                )
+                                .clicked()
+                            {
+                                self.undo_active();
+                                ui.close();
+                            }
+                            if ui
+                                .add_enabled(
+                                    can_redo,
+                                    egui::Button::new("Redo").shortcut_text("Ctrl+Y"),
+                                )
+                                .clicked()
+                            {
+                                self.redo_active();
+                                ui.close();
+                            }
+
+                            ui.separator();
+
+                            if ui
+                                .add(
+                                    egui::Button::new("Find…")
+                                        .shortcut_text("Ctrl+F"),
+                                )
+                                .clicked()
+                            {
+                                self.find.open = true;
+                                self.find.focus_query = true;
+                                ui.close();
+                            }
+
+                            if ui
+                                .add(egui::Button::new("Replace…").shortcut_text("Ctrl+H"))
+                                .clicked()
+                            {
+                                self.find.open = true;
+                                self.find.replace_open = true;
+                                self.find.focus_query = true;
+                                ui.close();
+                            }
+
+                            if ui
+                                .add(
+                                    egui::Button::new("Search in Files…")
+                                        .shortcut_text("Ctrl+Shift+F"),
+                                )
+                                .clicked()
+                            {
+                                self.open_search_panel();
+                                ui.close();
+                            }
+                        });
+
+                        ui.menu_button("View", |ui| {
+                            let view_mode_before = self.settings.view_mode;
+                            ui.selectable_value(
+                                &mut self.settings.view_mode,
+                                ViewMode::EditorOnly,
+                                "Editor only",
+                            );
+                            ui.selectable_value(
+                                &mut self.settings.view_mode,
+                                ViewMode::Split,
+                                "Split view",
+                            );
+                            ui.selectable_value(
+                                &mut self.settings.view_mode,
+                                ViewMode::PreviewOnly,
+                                "Preview only",
+                            );
+                            if view_mode_before != self.settings.view_mode {
+                                ui.close();
+                            }
+
+                            ui.separator();
+                            ui.checkbox(&mut self.settings.show_outline, "Show outline panel");
+                            ui.checkbox(&mut self.settings.show_workspace, "Show folder panel");
+                            ui.checkbox(
+                                &mut self.settings.sync_scroll,
+                                "Sync scrolling in split view",
+                            );
+                            ui.checkbox(
+                                &mut self.settings.inline_render.enabled,
+                                "Inline live rendering (experimental)",
+                            );
+
+                            ui.separator();
+                            ui.menu_button("Theme", |ui| {
+                                let theme_before = self.settings.theme;
+                                ui.selectable_value(
+                                    &mut self.settings.theme,
+                                    AppTheme::System,
+                                    "💻 System",
+                                )
+                                .on_hover_text("Follow the system theme");
+                                ui.selectable_value(
+                                    &mut self.settings.theme,
+                                    AppTheme::Dark,
+                                    "🌙 Dark",
+                                )
+                                .on_hover_text("Dark theme");
+                                ui.selectable_value(
+                                    &mut self.settings.theme,
+                                    AppTheme::Light,
+                                    "☀ Light",
+                                )
+                                .on_hover_text("Light theme");
+                                ui.selectable_value(
+                                    &mut self.settings.theme,
+                                    AppTheme::TerminalGreen,
+                                    egui::RichText::new("G> Terminal Green")
+                                        .monospace()
+                                        .color(egui::Color32::from_rgb(0x00, 0xff, 0x7a)),
+                                )
+                                .on_hover_text("Green terminal theme");
+                                ui.selectable_value(
+                                    &mut self.settings.theme,
+                                    AppTheme::TerminalAmber,
+                                    egui::RichText::new("A> Terminal Amber")
+                                        .monospace()
+                                        .color(egui::Color32::from_rgb(0xff, 0xb0, 0x36)),
+                                )
+                                .on_hover_text("Amber terminal theme");
+
+                                if theme_before != self.settings.theme {
+                                    apply_app_theme(ctx, self.settings.theme);
+                                    self.clear_render_caches();
+                                    ui.close();
+                                }
+                            });
+                        });
+
+                        ui.menu_button("Options", |ui| {
+                            let mut changed = false;
+                            ui.label(egui::RichText::new("Rendering").strong());
+                            changed |= ui
+                                .checkbox(
+                                    &mut self.settings.render_math,
+                                    "Render math ($...$ / $$...$$)",
+                                )
+                                .changed();
+                            ui.add_enabled_ui(self.settings.render_math, |ui| {
+                                ui.horizontal(|ui| {
+                                    ui.label("Math render threads");
+                                    let workers =
+                                        egui::DragValue::new(&mut self.settings.math_workers)
+                                            .range(1..=16);
+                                    if ui.add(workers).changed() {
+                                        self.math_pool.resize(self.settings.math_workers);
+                                        changed = true;
+                                    }
+                                });
+                            });
+                            changed |= ui
+                                .checkbox(
+                                    &mut self.settings.render_diagrams,
+                                    "Render diagrams (Mermaid, PlantUML, Graphviz, …)",
+                                )
+                                .changed();
+                            ui.checkbox(
+                                &mut self.settings.remote_diagrams,
+                                "Allow remote diagram rendering",
+                            )
+                            .on_hover_text(
+                                "Send diagrams that can't be drawn offline to this Kroki server",
+                            );
+                            ui.add_enabled_ui(self.settings.remote_diagrams, |ui| {
+                                let draft_id = ui.make_persistent_id("kroki_url_draft");
+                                let mut draft = ui
+                                    .data_mut(|d| d.get_temp::<String>(draft_id))
+                                    .unwrap_or_else(|| self.settings.kroki_url.clone());
+                                let response = ui.add(
+                                    egui::TextEdit::singleline(&mut draft)
+                                        .hint_text(DEFAULT_KROKI_URL)
+                                        .desired_width(220.0),
+                                );
+                                if response.lost_focus() {
+                                    let url = draft.trim().trim_end_matches('/');
+                                    self.settings.kroki_url = if url.is_empty() {
+                                        DEFAULT_KROKI_URL.to_string()
+                                    } else {
+                                        url.to_string()
+                                    };
+                                    ui.data_mut(|d| d.remove::<String>(draft_id));
+                                } else if response.has_focus() {
+                                    ui.data_mut(|d| d.insert_temp(draft_id, draft));
+                                }
+                            });
+                            let (renders, memory) = self.render_memory_usage();
+                            ui.horizontal(|ui| {
+                                ui.label("Render memory limit (MB)");
+                                let limit =
+                                    egui::DragValue::new(&mut self.settings.render_memory_limit_mb)
+                                        .range(16..=4096);
+                                if ui.add(limit).changed() {
+                                    self.apply_render_memory_limit();
+                                    changed = true;
+                                }
+                            });
+                            ui.weak(format!(
+                                "{renders} renders, {:.1} MB in memory",
+                                memory as f64 / (1024.0 * 1024.0)
+                            ));
+                            if let Some(disk) = self.render_disk_cache.clone() {
+                                let (files, bytes) = disk.usage();
+                                ui.horizontal(|ui| {
+                                    ui.label("Render cache limit (MB)");
+                                    let limit = egui::DragValue::new(
+                                        &mut self.settings.render_cache_limit_mb,
+                                    )
+                                    .range(16..=8192);
+                                    if ui.add(limit).changed() {
+                                        disk.set_limit(
+                                            self.settings.render_cache_limit_mb * 1024 * 1024,
+                                        );
+                                        changed = true;
+                                    }
+                                });
+                                ui.horizontal(|ui| {
+                                    ui.weak(format!(
+                                        "{files} SVGs, {:.1} MB",
+                                        bytes as f64 / (1024.0 * 1024.0)
+                                    ));
+                                    if ui.button("Clear render cache").clicked() {
+                                        disk.clear();
+                                        self.clear_render_caches();
+                                    }
+                                });
+                            }
+                            changed |= ui
+                                .checkbox(
+                                    &mut self.settings.auto_detect_code_lang,
+                                    "Auto-detect code languages",
+                                )
+                                .changed();
+
+                            ui.separator();
+                            ui.label(egui::RichText::new("Formatting").strong());
+                            changed |= ui
+                                .checkbox(&mut self.settings.autolink_urls, "Autolink plain URLs")
+                                .changed();
+                            changed |= ui
+                                .checkbox(
+                                    &mut self.settings.github_links,
+                                    "GitHub issue/PR links (#123)",
+                                )
+                                .changed();
+                            changed |= ui
+                                .checkbox(
+                                    &mut self.settings.replace_emoji,
+                                    "Emoji shortcodes (:smile:)",
+                                )
+                                .changed();
+                            changed |= ui
+                                .checkbox(
+                                    &mut self.settings.smart_typography,
+                                    "Smart typography (off by default)",
+                                )
+                                .changed();
+
+                            ui.separator();
+                            ui.label(egui::RichText::new("Images").strong());
+                            ui.label("Image storage mode");
+                            changed |= ui
+                                .selectable_value(
+                                    &mut self.image_config.storage_mode,
+                                    ImageStorageMode::Local,
+                                    "Local",
+                                )
+                                .changed();
+                            changed |= ui
+                                .selectable_value(
+                                    &mut self.image_config.storage_mode,
+                                    ImageStorageMode::Base64,
+                                    "Base64",
+                                )
+                                .changed();
+                            changed |= ui
+                                .selectable_value(
+                                    &mut self.image_config.storage_mode,
+                                    ImageStorageMode::Remote,
+                                    "Remote",
+                                )
+                                .changed();
+
+                            if changed {
+                                self.rebuild_all_markdown();
+                            }
+                        });
+
+                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                            match self
+                                .active_document()
+                                .and_then(|doc| doc.file_path.as_ref())
+                            {
+                                Some(path) => {
+                                    let full_path = path.display().to_string();
+                                    let truncated = left_truncate(&full_path, 72);
+                                    ui.label(truncated).on_hover_text(full_path);
+                                }
+                                None => {
+                                    ui.weak("No file loaded");
+                                }
+                            }
+                        });
+                    });
+                    if auto_reload_changed {
+                        self.update_watched_paths();
+                    }
+
+                    ui.horizontal(|ui| {
+                        ui.spacing_mut().item_spacing = egui::vec2(2.0, 0.0);
+                        if ui.button("📂").on_hover_text("Open file (Ctrl+O)").clicked() {
+                            self.open_dialog();
+                        }
+
+                        let save_enabled = self.active_document().is_some_and(|d| d.dirty);
+                        if ui
+                            .add_enabled(save_enabled, egui::Button::new("💾"))
+                            .on_hover_text("Save (Ctrl+S)")
+                            .clicked()
+                        {
+                            self.save_document(self.active_doc);
+                        }
+
+                        let reload_enabled = self
+                            .active_document()
+                            .and_then(|d| d.file_path.as_ref())
+                            .is_some();
+                        if ui
+                            .add_enabled(reload_enabled, egui::Button::new("🔄"))
+                            .on_hover_text("Reload file")
+                            .clicked()
+                        {
+                            self.reload_active();
+                        }
+
+                        if ui.button("🔍").on_hover_text("Find (Ctrl+F)").clicked() {
+                            self.find.open = true;
+                            self.find.focus_query = true;
+                        }
+
+                        ui.separator();
+
+                        ui.selectable_value(
+                            &mut self.settings.view_mode,
+                            ViewMode::EditorOnly,
+                            "Editor",
+                        );
+                        ui.selectable_value(&mut self.settings.view_mode, ViewMode::Split, "Split");
+                        ui.selectable_value(
+                            &mut self.settings.view_mode,
+                            ViewMode::PreviewOnly,
+                            "Preview",
+                        );
+
+                        ui.separator();
+
+                        let theme_before = self.settings.theme;
+                        ui.selectable_value(&mut self.settings.theme, AppTheme::System, "💻")
+                            .on_hover_text("System theme");
+                        ui.selectable_value(&mut self.settings.theme, AppTheme::Dark, "🌙")
+                            .on_hover_text("Dark theme");
+                        ui.selectable_value(&mut self.settings.theme, AppTheme::Light, "☀")
+                            .on_hover_text("Light theme");
+                        ui.selectable_value(
+                            &mut self.settings.theme,
+                            AppTheme::TerminalGreen,
+                            egui::RichText::new("G>")
+                                .monospace()
+                                .color(egui::Color32::from_rgb(0x00, 0xff, 0x7a)),
+                        )
+                        .on_hover_text("Green terminal theme");
+                        ui.selectable_value(
+                            &mut self.settings.theme,
+                            AppTheme::TerminalAmber,
+                            egui::RichText::new("A>")
+                                .monospace()
+                                .color(egui::Color32::from_rgb(0xff, 0xb0, 0x36)),
+                        )
+                        .on_hover_text("Amber terminal theme");
+                        if theme_before != self.settings.theme {
+                            apply_app_theme(ctx, self.settings.theme);
+                            self.clear_render_caches();
+                        }
+                    });
+
+                    ui.add_space(4.0);
+                    self.show_tab_bar(ui);
+                });
+            });
+
+        if self.settings.show_workspace {
+            self.show_workspace_panel(ctx);
+        }
+        self.show_search_panel(ctx);
+
+        if self.settings.show_outline {
+            self.show_outline_panel(ctx);
+        }
+
+        self.handle_smart_paste_shortcuts(ctx);
+
+        egui::CentralPanel::default()
+            .frame(egui::Frame::new().inner_margin(egui::Margin::symmetric(8, 6)))
+            .show(ctx, |ui| {
+                ui.spacing_mut().item_spacing = egui::vec2(8.0, 6.0);
+                if let Some(err) = &self.error {
+                    ui.colored_label(ui.visuals().error_fg_color, err);
+                    ui.separator();
+                }
+
+                if self.documents.get(self.active_doc).is_none() {
+                    ui.weak("No documents open");
+                    return;
+                }
+
+                self.show_conflict_banner(ui);
+
+                if self.settings.inline_render.enabled
+                    && matches!(self.settings.view_mode, ViewMode::EditorOnly)
+                {
+                    self.show_inline_surface(ui, ctx);
+                    return;
+                }
+
+                let show_editor = matches!(
+                    self.settings.view_mode,
+                    ViewMode::EditorOnly | ViewMode::Split
+                );
+                let mut editor_state = EditorUiState::default();
+
+                match self.settings.view_mode {
+                    ViewMode::EditorOnly => {
+                        editor_state = self.show_editor_pane(ui);
+                        if let Some(doc) = self.documents.get_mut(self.active_doc) {
+                            doc.viewed_line = Some(editor_state.cursor_line);
+                        }
+                    }
+                    ViewMode::Split => {
+                        let sync = self.settings.sync_scroll;
+                        if !sync {
+                            self.split_sync = SplitScrollSync::default();
+                        }
+                        ui.columns(2, |cols| {
+                            cols[0].spacing_mut().item_spacing = egui::vec2(8.0, 6.0);
+                            cols[1].spacing_mut().item_spacing = egui::vec2(8.0, 6.0);
+                            editor_state = self.show_editor_pane(&mut cols[0]);
+                            if sync {
+                                self.sync_preview_to_editor(&editor_state);
+                            }
+                            let target = self.split_sync.preview_target.take();
+                            let preview = self.show_preview_pane(&mut cols[1], target);
+                            if sync {
+                                self.sync_editor_to_preview(ctx, &preview, target.is_some());
+                            }
+                        });
+                    }
+                    ViewMode::PreviewOnly => {
+                        self.show_preview_pane(ui, None);
+                    }
+                }
+
+                self.editor_has_focus = show_editor && editor_state.has_focus;
+
+                if show_editor {
+                    if let Some((index, line, text, anchor)) = editor_state.open_slash_args {
+                        self.maybe_open_slash_menu(index, line, &text, anchor);
+                    }
+
+                    if self.slash_menu.open {
+                        if let Some(index) = editor_state.cursor_char_index {
+                            if editor_state.cursor_line != self.slash_menu.trigger_line {
+                                self.dismiss_slash_menu(false, index);
+                            } else if let Some(doc) = self.active_document() {
+                                let start = Self::byte_index_from_char_index(
+                                    &doc.raw_markdown,
+                                    self.slash_menu.trigger_char_index + 1,
+                                );
+                                let end =
+                                    Self::byte_index_from_char_index(&doc.raw_markdown, index);
+                                if start <= end && end <= doc.raw_markdown.len() {
+                                    self.slash_menu.query =
+                                        doc.raw_markdown[start..end].to_string();
+                                }
+                            }
+                        }
+                    }
+
+                    if self.slash_menu.open {
+                        let matches = self.slash_matches(editor_state.has_selection);
+                        if matches.len() == 1 {
+                            self.slash_menu.selected_idx = 0;
+                        } else if self.slash_menu.selected_idx >= matches.len() {
+                            self.slash_menu.selected_idx = 0;
+                        }
+
+                        let mut dismiss_escape = false;
+                        let mut execute = false;
+                        let mut move_next = false;
+                        let mut move_prev = false;
+                        let mut dismiss_on_space = false;
+                        ctx.input(|i| {
+                            dismiss_escape = i.key_pressed(egui::Key::Escape);
+                            execute = i.key_pressed(egui::Key::Enter);
+                            move_next = i.key_pressed(egui::Key::ArrowDown)
+                                || i.key_pressed(egui::Key::Tab) && !i.modifiers.shift;
+                            move_prev = i.key_pressed(egui::Key::ArrowUp)
+                                || (i.key_pressed(egui::Key::Tab) && i.modifiers.shift);
+                            dismiss_on_space =
+                                i.key_pressed(egui::Key::Space) && matches.is_empty();
+                        });
+                        if move_next && !matches.is_empty() {
+                            self.slash_menu.selected_idx =
+                                (self.slash_menu.selected_idx + 1) % matches.len();
+                        }
+                        if move_prev && !matches.is_empty() {
+                            self.slash_menu.selected_idx = if self.slash_menu.selected_idx == 0 {
+                                matches.len() - 1
+                            } else {
+                                self.slash_menu.selected_idx - 1
+                            };
+                        }
+                        if dismiss_escape {
+                            if let Some(index) = editor_state.cursor_char_index {
+                                self.dismiss_slash_menu(true, index);
+                            }
+                        } else if dismiss_on_space {
+                            self.slash_menu.open = false;
+                        } else if execute {
+                            if let (Some(index), Some(entry)) = (
+                                editor_state.cursor_char_index,
+                                matches.get(self.slash_menu.selected_idx),
+                            ) {
+                                let cmd = self.slash_commands[entry.cmd_idx].clone();
+                                self.execute_slash_command(&cmd, index);
+                            }
+                        }
+
+                        if self.slash_menu.open {
+                            let mut clicked_cmd: Option<SlashCommand> = None;
+                            let frame = egui::Frame::popup(ui.style())
+                                .corner_radius(egui::CornerRadius::same(4))
+                                .shadow(egui::epaint::Shadow {
+                                    offset: [0, 4],
+                                    blur: 12,
+                                    spread: 0,
+                                    color: egui::Color32::from_black_alpha(26),
+                                });
+                            egui::Area::new("slash_menu".into())
+                                .order(egui::Order::Foreground)
+                                .fixed_pos(self.slash_menu.anchor + egui::vec2(0.0, 4.0))
+                                .show(ctx, |ui| {
+                                    frame.show(ui, |ui| {
+                                        ui.set_min_width(280.0);
+                                        ui.set_max_width(360.0);
+                                        ui.set_max_height(340.0);
+                                        if matches.is_empty() {
+                                            ui.weak("No commands found");
+                                        } else {
+                                            egui::ScrollArea::vertical().max_height(340.0).show(
+                                                ui,
+                                                |ui| {
+                                                    for (i, m) in matches.iter().enumerate() {
+                                                        let cmd =
+                                                            self.slash_commands[m.cmd_idx].clone();
+                                                        let selected =
+                                                            i == self.slash_menu.selected_idx;
+                                                        let mut label =
+                                                            egui::RichText::new(cmd.name);
+                                                        if selected {
+                                                            label = label.strong();
+                                                        }
+                                                        let row =
+                                                            ui.selectable_label(selected, label);
+                                                        if row.clicked() {
+                                                            clicked_cmd = Some(cmd.clone());
+                                                        }
+                                                        if self.slash_config.show_descriptions {
+                                                            ui.small(
+                                                                egui::RichText::new(
+                                                                    cmd.description,
+                                                                )
+                                                                .weak(),
+                                                            );
+                                                        }
+                                                        ui.add_space(2.0);
+                                                    }
+                                                },
+                                            );
+                                        }
+                                    });
+                                });
+                            if let (Some(index), Some(cmd)) =
+                                (editor_state.cursor_char_index, clicked_cmd)
+                            {
+                                self.execute_slash_command(&cmd, index);
+                            }
+                        }
+                    }
+                } else {
+                    self.slash_menu.open = false;
+                    self.slash_menu.query.clear();
+                }
+
+                if self.drop_zone_visible && self.editor_has_focus {
+                    let rect = ui.max_rect();
+                    let painter = ui.painter();
+                    painter.rect_filled(
+                        rect,
+                        egui::CornerRadius::same(8),
+                        egui::Color32::from_rgba_unmultiplied(59, 130, 246, 20),
+                    );
+                    painter.rect_stroke(
+                        rect.shrink(4.0),
+                        egui::CornerRadius::same(8),
+                        egui::Stroke::new(2.0, egui::Color32::from_rgb(59, 130, 246)),
+                        egui::StrokeKind::Middle,
+                    );
+                    painter.text(
+                        rect.center(),
+                        egui::Align2::CENTER_CENTER,
+                        "🖼 Drop image here",
+                        egui::TextStyle::Heading.resolve(ui.style()),
+                        egui::Color32::from_rgb(59, 130, 246),
+                    );
+                }
+            });
+
+        self.show_find_window(ctx);
+        self.show_quick_open(ctx);
+        self.handle_internal_anchor_links(ctx);
+        self.show_notifications(ctx);
+        self.show_unsaved_changes_prompt(ctx);
+        self.show_recovery_prompt(ctx);
+        self.autosave_dirty_documents(ctx);
+        self.sweep_render_caches();
+    }
+
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        self.persisted.settings = self.settings.clone();
+        self.persisted.open_files = self
+            .documents
+            .iter()
+            .filter_map(|doc| doc.file_path.clone())
+            .collect();
+        self.persisted.active_file = self.active_document().and_then(|doc| doc.file_path.clone());
+        self.persisted.slash_tracker = self.slash_tracker.clone();
+        eframe::set_value(storage, STATE_KEY, &self.persisted);
+    }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        if let Some(autosave) = self.autosave.take() {
+            autosave.finish();
+        }
+    }
+}
+
+fn normalize_path(path: PathBuf) -> PathBuf {
+    std::fs::canonicalize(&path).unwrap_or(path)
+}
+
+fn infer_mime_from_path(path: &Path) -> String {
+    match path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+    .to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum AppTheme {
+    System,
+    Dark,
+    Light,
+    TerminalGreen,
+    TerminalAmber,
+}
+
+impl Default for AppTheme {
+    fn default() -> Self {
+        Self::System
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum ViewMode {
+    EditorOnly,
+    Split,
+    PreviewOnly,
+}
+
+impl Default for ViewMode {
+    fn default() -> Self {
+        Self::PreviewOnly
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TerminalHue {
+    Green,
+    Amber,
+}
+
+fn restore_default_visuals(ctx: &egui::Context) {
+    ctx.set_visuals_of(egui::Theme::Dark, egui::Visuals::dark());
+    ctx.set_visuals_of(egui::Theme::Light, egui::Visuals::light());
+}
+
+fn apply_app_theme(ctx: &egui::Context, theme: AppTheme) {
+    match theme {
+        AppTheme::System => {
+            restore_default_visuals(ctx);
+            ctx.set_theme(egui::ThemePreference::System);
+        }
+        AppTheme::Dark => {
+            restore_default_visuals(ctx);
+            ctx.set_theme(egui::ThemePreference::Dark);
+        }
+        AppTheme::Light => {
+            restore_default_visuals(ctx);
+            ctx.set_theme(egui::ThemePreference::Light);
+        }
+        AppTheme::TerminalGreen => {
+            ctx.set_theme(egui::ThemePreference::Dark);
+            ctx.set_visuals_of(egui::Theme::Dark, terminal_visuals(TerminalHue::Green));
+        }
+        AppTheme::TerminalAmber => {
+            ctx.set_theme(egui::ThemePreference::Dark);
+            ctx.set_visuals_of(egui::Theme::Dark, terminal_visuals(TerminalHue::Amber));
+        }
+    }
+}
+
+fn terminal_visuals(hue: TerminalHue) -> egui::Visuals {
+    let mut visuals = egui::Visuals::dark();
+
+    let bg = egui::Color32::from_rgb(0x07, 0x0b, 0x07);
+    let extreme_bg = egui::Color32::from_rgb(0x04, 0x06, 0x04);
+
+    let (accent, accent_dim, accent_bright) = match hue {
+        TerminalHue::Green => (
+            egui::Color32::from_rgb(0x00, 0xff, 0x7a),
+            egui::Color32::from_rgb(0x00, 0xc8, 0x60),
+            egui::Color32::from_rgb(0xc8, 0xff, 0xe8),
+        ),
+        TerminalHue::Amber => (
+            egui::Color32::from_rgb(0xff, 0xb0, 0x36),
+            egui::Color32::from_rgb(0xe6, 0x9a, 0x20),
+            egui::Color32::from_rgb(0xff, 0xf0, 0xd0),
+        ),
+    };
+
+    let button_bg = lerp_color(bg, accent, 0.12);
+    let hovered_bg = lerp_color(bg, accent, 0.18);
+    let active_bg = lerp_color(bg, accent, 0.24);
+
+    visuals.window_fill = bg;
+    visuals.panel_fill = bg;
+    visuals.window_stroke = egui::Stroke::new(1.0, with_alpha(accent_dim, 140));
+    visuals.faint_bg_color = lerp_color(bg, accent_dim, 0.06);
+    visuals.extreme_bg_color = extreme_bg;
+    visuals.text_edit_bg_color = Some(extreme_bg);
+    visuals.code_bg_color = lerp_color(bg, accent, 0.08);
+    visuals.hyperlink_color = egui::Color32::from_rgb(0x66, 0xd9, 0xef); // cyan
+    visuals.selection.bg_fill = lerp_color(bg, accent, 0.35);
+    visuals.selection.stroke = egui::Stroke::new(1.0, accent_bright);
+
+    visuals.widgets.noninteractive.bg_fill = bg;
+    visuals.widgets.noninteractive.weak_bg_fill = bg;
+    visuals.widgets.noninteractive.bg_stroke = egui::Stroke::new(1.0, with_alpha(accent_dim, 140));
+    visuals.widgets.noninteractive.fg_stroke = egui::Stroke::new(1.0, accent);
+
+    visuals.widgets.inactive.weak_bg_fill = button_bg;
+    visuals.widgets.inactive.bg_fill = button_bg;
+    visuals.widgets.inactive.bg_stroke = egui::Stroke::new(1.0, with_alpha(accent_dim, 110));
+    visuals.widgets.inactive.fg_stroke = egui::Stroke::new(1.0, accent);
+
+    visuals.widgets.hovered.weak_bg_fill = hovered_bg;
+    visuals.widgets.hovered.bg_fill = hovered_bg;
+    visuals.widgets.hovered.bg_stroke = egui::Stroke::new(1.0, with_alpha(accent_bright, 200));
+    visuals.widgets.hovered.fg_stroke = egui::Stroke::new(1.5, accent_bright);
+
+    visuals.widgets.active.weak_bg_fill = active_bg;
+    visuals.widgets.active.bg_fill = active_bg;
+    visuals.widgets.active.bg_stroke = egui::Stroke::new(1.0, accent_bright);
+    visuals.widgets.active.fg_stroke = egui::Stroke::new(2.0, accent_bright);
+
+    visuals.widgets.open.weak_bg_fill = hovered_bg;
+    visuals.widgets.open.bg_fill = bg;
+    visuals.widgets.open.bg_stroke = egui::Stroke::new(1.0, with_alpha(accent_dim, 140));
+    visuals.widgets.open.fg_stroke = egui::Stroke::new(1.0, accent_bright);
+
+    visuals.warn_fg_color = egui::Color32::from_rgb(0xff, 0xb0, 0x36);
+
+    visuals
+}
+
+fn read_markdown(path: &Path) -> Result<String> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("failed to read `{}`", path.display()))?;
+    let text = String::from_utf8_lossy(&bytes).into_owned();
+    Ok(text.replace("\r\n", "\n").replace('\r', "\n"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiffLine<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+    const MAX_LCS_CELLS: usize = 4_000_000;
+
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+
+    let prefix = old_lines
+        .iter()
+        .zip(&new_lines)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old_lines[prefix..]
+        .iter()
+        .rev()
+        .zip(new_lines[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let old_mid = &old_lines[prefix..old_lines.len() - suffix];
+    let new_mid = &new_lines[prefix..new_lines.len() - suffix];
+
+    let mut out: Vec<DiffLine<'a>> = old_lines[..prefix]
+        .iter()
+        .map(|line| DiffLine::Same(line))
+        .collect();
+
+    if old_mid.len().saturating_mul(new_mid.len()) > MAX_LCS_CELLS {
+        out.extend(old_mid.iter().map(|line| DiffLine::Removed(line)));
+        out.extend(new_mid.iter().map(|line| DiffLine::Added(line)));
+    } else {
+        let (n, m) = (old_mid.len(), new_mid.len());
+        let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i * (m + 1) + j] = if old_mid[i] == new_mid[j] {
+                    lcs[(i + 1) * (m + 1) + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * (m + 1) + j].max(lcs[i * (m + 1) + j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0usize, 0usize);
+        while i < n && j < m {
+            if old_mid[i] == new_mid[j] {
+                out.push(DiffLine::Same(old_mid[i]));
+                i += 1;
+                j += 1;
+            } else if lcs[(i + 1) * (m + 1) + j] >= lcs[i * (m + 1) + j + 1] {
+                out.push(DiffLine::Removed(old_mid[i]));
+                i += 1;
+            } else {
+                out.push(DiffLine::Added(new_mid[j]));
+                j += 1;
+            }
+        }
+        out.extend(old_mid[i..].iter().map(|line| DiffLine::Removed(line)));
+        out.extend(new_mid[j..].iter().map(|line| DiffLine::Added(line)));
+    }
+
+    out.extend(
+        old_lines[old_lines.len() - suffix..]
+            .iter()
+            .map(|line| DiffLine::Same(line)),
+    );
+    out
+}
+
+fn side_by_side_rows<'a>(diff: &[DiffLine<'a>]) -> Vec<(Option<&'a str>, Option<&'a str>)> {
+    fn flush<'a>(
+        rows: &mut Vec<(Option<&'a str>, Option<&'a str>)>,
+        removed: &mut Vec<&'a str>,
+        added: &mut Vec<&'a str>,
+    ) {
+        for idx in 0..removed.len().max(added.len()) {
+            rows.push((removed.get(idx).copied(), added.get(idx).copied()));
+        }
+        removed.clear();
+        added.clear();
+    }
+
+    let mut rows = Vec::with_capacity(diff.len());
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    for line in diff {
+        match *line {
+            DiffLine::Same(text) => {
+                flush(&mut rows, &mut removed, &mut added);
+                rows.push((Some(text), Some(text)));
+            }
+            DiffLine::Removed(text) => removed.push(text),
+            DiffLine::Added(text) => added.push(text),
+        }
+    }
+    flush(&mut rows, &mut removed, &mut added);
+    rows
+}
+
+fn show_side_by_side_diff(ui: &mut egui::Ui, mine: &str, theirs: &str) {
+    let rows = side_by_side_rows(&diff_lines(mine, theirs));
+    let removed_bg = egui::Color32::from_rgba_unmultiplied(220, 60, 60, 40);
+    let added_bg = egui::Color32::from_rgba_unmultiplied(60, 180, 90, 40);
+
+    let cell = |ui: &mut egui::Ui, text: Option<&str>, changed: bool, bg: egui::Color32| {
+        let text = egui::RichText::new(text.unwrap_or("")).monospace();
+        if changed {
+            egui::Frame::new().fill(bg).show(ui, |ui| ui.label(text));
+        } else {
+            ui.label(text);
+        }
+    };
+
+    egui::ScrollArea::both()
+        .id_salt("conflict_diff")
+        .max_height(260.0)
+        .show(ui, |ui| {
+            egui::Grid::new("conflict_diff_grid")
+                .num_columns(2)
+                .spacing(egui::vec2(16.0, 0.0))
+                .show(ui, |ui| {
+                    ui.strong("Mine (unsaved)");
+                    ui.strong("On disk");
+                    ui.end_row();
+                    for (left, right) in rows {
+                        let changed = left != right;
+                        cell(ui, left, changed, removed_bg);
+                        cell(ui, right, changed, added_bg);
+                        ui.end_row();
+                    }
+                });
+        });
+}
+
+#[derive(Debug, Clone)]
+struct GithubRepo {
+    base_url: String,
+}
+
+fn discover_github_repo(markdown_path: &Path) -> Option<GithubRepo> {
+    let dir = markdown_path.parent().unwrap_or(markdown_path);
+    let git_root = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "--show-toplevel"])
+        .output()
+        .ok()?;
+    if !git_root.status.success() {
+        return None;
+    }
+    let git_root = String::from_utf8_lossy(&git_root.stdout).trim().to_string();
+    if git_root.is_empty() {
+        return None;
+    }
+
+    let remote = Command::new("git")
+        .arg("-C")
+        .arg(&git_root)
+        .args(["config", "--get", "remote.origin.url"])
+        .output()
+        .ok()?;
+    if !remote.status.success() {
+        return None;
+    }
+    let remote = String::from_utf8_lossy(&remote.stdout).trim().to_string();
+    parse_github_remote(&remote).map(|base_url| GithubRepo { base_url })
+}
+
+fn parse_github_remote(remote: &str) -> Option<String> {
+    fn strip_git_suffix(s: &str) -> &str {
+        s.strip_suffix(".git").unwrap_or(s)
+    }
+
+    if let Some(rest) = remote.strip_prefix("https://github.com/") {
+        let rest = strip_git_suffix(rest);
+        let mut it = rest.split('/');
+        let owner = it.next()?.trim();
+        let repo = it.next()?.trim();
+        if owner.is_empty() || repo.is_empty() {
+            return None;
+        }
+        return Some(format!("https://github.com/{owner}/{repo}"));
+    }
+
+    if let Some(rest) = remote.strip_prefix("http://github.com/") {
+        let rest = strip_git_suffix(rest);
+        let mut it = rest.split('/');
+        let owner = it.next()?.trim();
+        let repo = it.next()?.trim();
+        if owner.is_empty() || repo.is_empty() {
+            return None;
+        }
+        return Some(format!("https://github.com/{owner}/{repo}"));
+    }
+
+    if let Some(rest) = remote.strip_prefix("git@github.com:") {
+        let rest = strip_git_suffix(rest);
+        let mut it = rest.split('/');
+        let owner = it.next()?.trim();
+        let repo = it.next()?.trim();
+        if owner.is_empty() || repo.is_empty() {
+            return None;
+        }
+        return Some(format!("https://github.com/{owner}/{repo}"));
+    }
+
+    if let Some(rest) = remote.strip_prefix("ssh://git@github.com/") {
+        let rest = strip_git_suffix(rest);
+        let mut it = rest.split('/');
+        let owner = it.next()?.trim();
+        let repo = it.next()?.trim();
+        if owner.is_empty() || repo.is_empty() {
+            return None;
+        }
+        return Some(format!("https://github.com/{owner}/{repo}"));
+    }
+
+    None
+}
+
+const DEFAULT_KROKI_URL: &str = "https://kroki.io";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct ViewerSettings {
+    theme: AppTheme,
+    view_mode: ViewMode,
+    render_math: bool,
+    math_workers: usize,
+    #[serde(alias = "render_mermaid")]
+    render_diagrams: bool,
+    remote_diagrams: bool,
+    kroki_url: String,
+    render_cache_limit_mb: u64,
+    render_memory_limit_mb: u64,
+    auto_detect_code_lang: bool,
+    autolink_urls: bool,
+    github_links: bool,
+    replace_emoji: bool,
+    smart_typography: bool,
+    show_outline: bool,
+    show_workspace: bool,
+    auto_reload: bool,
+    sync_scroll: bool,
+    inline_render: InlineRenderConfig,
+}
+
+impl Default for ViewerSettings {
+    fn default() -> Self {
+        Self {
+            theme: AppTheme::System,
+            view_mode: ViewMode::PreviewOnly,
+            render_math: true,
+            math_workers: default_math_workers(),
+            render_diagrams: true,
+            remote_diagrams: false,
+            kroki_url: DEFAULT_KROKI_URL.to_string(),
+            render_cache_limit_mb: DEFAULT_RENDER_CACHE_LIMIT_MB,
+            render_memory_limit_mb: DEFAULT_RENDER_MEMORY_LIMIT_MB,
+            auto_detect_code_lang: true,
+            autolink_urls: true,
+            github_links: true,
+            replace_emoji: true,
+            smart_typography: false,
+            show_outline: true,
+            show_workspace: true,
+            auto_reload: false,
+            sync_scroll: true,
+            inline_render: InlineRenderConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct InlineRenderConfig {
+    enabled: bool,
+    animate_transitions: bool,
+    spring_preset: SpringPreset,
+    max_concurrent_animations: usize,
+    proximity_reveal: bool,
+}
+
+impl Default for InlineRenderConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            animate_transitions: true,
+            spring_preset: SpringPreset::Smooth,
+            max_concurrent_animations: 200,
+            proximity_reveal: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum SpringPreset {
+    Snappy,
+    Smooth,
+    Gentle,
+    Bouncy,
+    Stiff,
+    None,
+}
+
+#[derive(Debug, Clone)]
+struct Spring {
+    stiffness: f64,
+    damping: f64,
+    mass: f64,
+    velocity: f64,
+    current: f64,
+    target: f64,
+    rest_threshold: f64,
+}
+
+impl Spring {
+    fn new(stiffness: f64, damping: f64, mass: f64) -> Self {
+        Self {
+            stiffness,
+            damping,
+            mass,
+            velocity: 0.0,
+            current: 0.0,
+            target: 0.0,
+            rest_threshold: 0.0001,
+        }
+    }
+
+    fn tick(&mut self, dt: f64) -> f64 {
+        let force = -self.stiffness * (self.current - self.target);
+        let damping_force = -self.damping * self.velocity;
+        let acceleration = (force + damping_force) / self.mass;
+        self.velocity += acceleration * dt;
+        self.current += self.velocity * dt;
+
+        if (self.current - self.target).abs() < self.rest_threshold
+            && self.velocity.abs() < self.rest_threshold
+        {
+            self.current = self.target;
+            self.velocity = 0.0;
+        }
+
+        self.current
+    }
+}
+
+struct AnimationPresets;
+
+impl AnimationPresets {
+    fn spring(preset: SpringPreset) -> Spring {
+        match preset {
+            SpringPreset::Snappy => Spring::new(400.0, 30.0, 1.0),
+            SpringPreset::Smooth => Spring::new(300.0, 25.0, 1.0),
+            SpringPreset::Gentle => Spring::new(200.0, 22.0, 1.0),
+            SpringPreset::Bouncy => Spring::new(400.0, 15.0, 1.0),
+            SpringPreset::Stiff => Spring::new(500.0, 35.0, 1.0),
+            SpringPreset::None => Spring::new(1_000_000.0, 10_000.0, 1.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct PositionMap {
+    rendered_to_source: Vec<(usize, usize)>,
+    source_to_rendered: Vec<(usize, usize)>,
+}
+
+impl PositionMap {
+    fn for_wrapped_delimiters(source: &str, left_delim: usize, right_delim: usize) -> Self {
+        let mut map = PositionMap::default();
+        let visible_start = left_delim.min(source.chars().count());
+        let visible_end = source.chars().count().saturating_sub(right_delim);
+        for rendered in 0..visible_end.saturating_sub(visible_start) {
+            let source_col = rendered + visible_start;
+            map.rendered_to_source.push((rendered, source_col));
+            map.source_to_rendered.push((source_col, rendered));
+        }
+        map
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct PersistedState {
+    settings: ViewerSettings,
+    recent_files: Vec<PathBuf>,
+    open_files: Vec<PathBuf>,
+    active_file: Option<PathBuf>,
+    workspace_root: Option<PathBuf>,
+    slash_tracker: SlashCommandTracker,
+}
+
+impl Default for PersistedState {
+    fn default() -> Self {
+        Self {
+            settings: ViewerSettings::default(),
+            recent_files: Vec::new(),
+            open_files: Vec::new(),
+            active_file: None,
+            workspace_root: None,
+            slash_tracker: SlashCommandTracker::default(),
+        }
+    }
+}
+
+impl PersistedState {
+    fn remember_file(&mut self, path: PathBuf) {
+        self.recent_files.retain(|p| p != &path);
+        self.recent_files.insert(0, path);
+        if self.recent_files.len() > MAX_RECENT_FILES {
+            self.recent_files.truncate(MAX_RECENT_FILES);
+        }
+    }
+}
+
+fn scroll_preview_to(ui: &egui::Ui, y: f32) {
+    let rect = egui::Rect::from_min_size(
+        egui::pos2(ui.min_rect().left(), y),
+        egui::vec2(1.0, ui.text_style_height(&TextStyle::Body)),
+    );
+    ui.scroll_to_rect(rect, Some(egui::Align::TOP));
+}
+
+fn paint_preview_matches(
+    ui: &mut egui::Ui,
+    layer: egui::LayerId,
+    first_shape: usize,
+    highlight: &PreviewHighlight,
+    block_idx: usize,
+) {
+    let occurrences = painted_text_matches(
+        ui.ctx(),
+        layer,
+        first_shape,
+        &highlight.regex,
+        highlight.whole_word,
+    );
+    let selected = highlight
+        .selected
+        .filter(|(block, _)| *block == block_idx)
+        .map(|(_, nth)| nth.min(occurrences.len().saturating_sub(1)));
+
+    let painter = ui.painter();
+    let match_fill = egui::Color32::from_rgba_unmultiplied(255, 210, 0, 70);
+    let selected_fill = egui::Color32::from_rgba_unmultiplied(255, 140, 0, 120);
+    for (idx, rects) in occurrences.iter().enumerate() {
+        let is_selected = selected == Some(idx);
+        for rect in rects {
+            painter.rect_filled(
+                rect.expand(1.0),
+                2.0,
+                if is_selected {
+                    selected_fill
+                } else {
+                    match_fill
+                },
+            );
+            if is_selected {
+                painter.rect_stroke(
+                    rect.expand(1.0),
+                    2.0,
+                    egui::Stroke::new(1.0, egui::Color32::from_rgb(255, 140, 0)),
+                    egui::StrokeKind::Outside,
+                );
+            }
+        }
+        if is_selected
+            && highlight.reveal
+            && let Some(first) = rects.first()
+        {
+            ui.scroll_to_rect(*first, Some(egui::Align::Center));
+        }
+    }
+}
+
+fn painted_text_matches(
+    ctx: &egui::Context,
+    layer: egui::LayerId,
+    first_shape: usize,
+    re: &Regex,
+    whole_word: bool,
+) -> Vec<Vec<egui::Rect>> {
+    painted_text_occurrences(ctx, layer, first_shape, re, whole_word)
+        .into_iter()
+        .map(|(_, rects)| rects)
+        .collect()
+}
+
+fn painted_text_occurrences(
+    ctx: &egui::Context,
+    layer: egui::LayerId,
+    first_shape: usize,
+    re: &Regex,
+    whole_word: bool,
+) -> Vec<(String, Vec<egui::Rect>)> {
+    let texts: Vec<(egui::Pos2, Arc<egui::Galley>)> = ctx.graphics(|layers| {
+        layers.get(layer).map_or_else(Vec::new, |list| {
+            list.all_entries()
+                .skip(first_shape)
+                .filter_map(|clipped| match &clipped.shape {
+                    egui::Shape::Text(text) => Some((text.pos, text.galley.clone())),
+                    _ => None,
+                })
+                .collect()
+        })
+    });
+
+    let mut occurrences = Vec::new();
+    for (pos, galley) in texts {
+        let text = galley.text();
+        for m in re.find_iter(text) {
+            if m.is_empty() || (whole_word && !is_whole_word(text, m.start(), m.end())) {
+                continue;
+            }
+            let start = text[..m.start()].chars().count();
+            let chars = start..start + m.as_str().chars().count();
+            occurrences.push((
+                m.as_str().to_string(),
+                galley_char_rects(&galley, chars)
+                    .into_iter()
+                    .map(|rect| rect.translate(pos.to_vec2()))
+                    .collect(),
+            ));
+        }
+    }
+    occurrences
+}
+
+fn galley_char_rects(galley: &egui::Galley, chars: std::ops::Range<usize>) -> Vec<egui::Rect> {
+    let mut rects = Vec::new();
+    let mut offset = 0usize;
+    for row in &galley.rows {
+        let mut rect = egui::Rect::NOTHING;
+        for (idx, glyph) in row.glyphs.iter().enumerate() {
+            if chars.contains(&(offset + idx)) {
+                rect = rect.union(glyph.logical_rect().translate(row.pos.to_vec2()));
+            }
+        }
+        if rect.is_positive() {
+            rects.push(rect);
+        }
+        offset += row.char_count_including_newline();
+        if offset >= chars.end {
+            break;
+        }
+    }
+    rects
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CalloutKind {
+    Note,
+    Tip,
+    Important,
+    Warning,
+    Caution,
+}
+
+impl CalloutKind {
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "NOTE" => Some(Self::Note),
+            "TIP" => Some(Self::Tip),
+            "IMPORTANT" => Some(Self::Important),
+            "WARNING" => Some(Self::Warning),
+            "CAUTION" => Some(Self::Caution),
+            _ => None,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Note => "Note",
+            Self::Tip => "Tip",
+            Self::Important => "Important",
+            Self::Warning => "Warning",
+            Self::Caution => "Caution",
+        }
+    }
+
+    fn icon(self) -> char {
+        match self {
+            Self::Note => '❕',
+            Self::Tip => '💡',
+            Self::Important => '💬',
+            Self::Warning => '⚠',
+            Self::Caution => '🔴',
+        }
+    }
+
+    fn accent(self, theme: AppTheme, dark_mode: bool) -> egui::Color32 {
+        let rgb = match (theme, self) {
+            (AppTheme::TerminalGreen, Self::Note) => (0x66, 0xd9, 0xef),
+            (AppTheme::TerminalGreen, Self::Tip) => (0x00, 0xff, 0x7a),
+            (AppTheme::TerminalGreen, Self::Important) => (0xc8, 0xff, 0xe8),
+            (AppTheme::TerminalGreen, Self::Warning) => (0xff, 0xd7, 0x00),
+            (AppTheme::TerminalGreen, Self::Caution) => (0xff, 0x55, 0x55),
+            (AppTheme::TerminalAmber, Self::Note) => (0xff, 0xd0, 0x80),
+            (AppTheme::TerminalAmber, Self::Tip) => (0xff, 0xb0, 0x36),
+            (AppTheme::TerminalAmber, Self::Important) => (0xff, 0xf0, 0xd0),
+            (AppTheme::TerminalAmber, Self::Warning) => (0xff, 0x8c, 0x00),
+            (AppTheme::TerminalAmber, Self::Caution) => (0xff, 0x50, 0x20),
+            (_, Self::Note) if dark_mode => (0x44, 0x93, 0xf8),
+            (_, Self::Tip) if dark_mode => (0x3f, 0xb9, 0x50),
+            (_, Self::Important) if dark_mode => (0xab, 0x7d, 0xf8),
+            (_, Self::Warning) if dark_mode => (0xd2, 0x99, 0x22),
+            (_, Self::Caution) if dark_mode => (0xf8, 0x51, 0x49),
+            (_, Self::Note) => (0x09, 0x69, 0xda),
+            (_, Self::Tip) => (0x1a, 0x7f, 0x37),
+            (_, Self::Important) => (0x82, 0x50, 0xdf),
+            (_, Self::Warning) => (0x9a, 0x67, 0x00),
+            (_, Self::Caution) => (0xd1, 0x24, 0x2f),
+        };
+        egui::Color32::from_rgb(rgb.0, rgb.1, rgb.2)
+    }
+}
+
+fn callout_alert_bundle(theme: AppTheme, dark_mode: bool) -> AlertBundle {
+    let kinds = [
+        CalloutKind::Note,
+        CalloutKind::Tip,
+        CalloutKind::Important,
+        CalloutKind::Warning,
+        CalloutKind::Caution,
+    ];
+    AlertBundle::from_alerts(
+        kinds
+            .into_iter()
+            .map(|kind| Alert {
+                accent_color: kind.accent(theme, dark_mode),
+                icon: kind.icon(),
+                identifier: kind.label().to_ascii_uppercase(),
+                identifier_rendered: kind.label().to_string(),
+            })
+            .collect(),
+    )
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Callout {
+    kind: CalloutKind,
+    title: Option<String>,
+    fold: Option<bool>,
+    body: String,
+}
+
+fn parse_callout(block: &str) -> Option<Callout> {
+    static MARKER: OnceLock<Regex> = OnceLock::new();
+    let marker = MARKER.get_or_init(|| {
+        Regex::new(r"^ {0,3}>[ \t]?[ \t]*\[!([A-Za-z]+)\]([+-]?)[ \t]*(.*?)\s*$")
+            .expect("valid callout regex")
+    });
+
+    let mut lines = block.split_inclusive('\n');
+    let caps = marker.captures(lines.next()?)?;
+    let kind = CalloutKind::parse(&caps[1])?;
+    let fold = match &caps[2] {
+        "-" => Some(false),
+        "+" => Some(true),
+        _ => None,
+    };
+    let title = Some(caps[3].to_string()).filter(|title| !title.is_empty());
+
+    let mut body = String::new();
+    for line in lines {
+        let unindented = line.trim_start_matches(' ');
+        let line = match unindented.strip_prefix('>') {
+            Some(rest) if line.len() - unindented.len() <= 3 => {
+                rest.strip_prefix([' ', '\t']).unwrap_or(rest)
+            }
+            _ => line,
+        };
+        body.push_str(line);
+    }
+    Some(Callout {
+        kind,
+        title,
+        fold,
+        body,
+    })
+}
+
+fn show_callout(
+    ui: &mut egui::Ui,
+    callout: &Callout,
+    theme: AppTheme,
+    id: egui::Id,
+    add_body: impl FnOnce(&mut egui::Ui),
+) {
+    let accent = callout.kind.accent(theme, ui.visuals().dark_mode);
+    let header = |ui: &mut egui::Ui| {
+        ui.label(egui::RichText::new(callout.kind.icon().to_string()).color(accent));
+        ui.label(
+            egui::RichText::new(callout.title.as_deref().unwrap_or(callout.kind.label()))
+                .strong()
+                .color(accent),
+        );
+    };
+
+    let frame = egui::Frame::new()
+        .fill(with_alpha(accent, 20))
+        .corner_radius(4)
+        .inner_margin(egui::Margin {
+            left: 14,
+            right: 8,
+            top: 6,
+            bottom: 6,
+        })
+        .show(ui, |ui| {
+            ui.set_width(ui.available_width());
+            let has_body = !callout.body.trim().is_empty();
+            match callout.fold {
+                Some(open) if has_body => {
+                    egui::collapsing_header::CollapsingState::load_with_default_open(
+                        ui.ctx(),
+                        id,
+                        open,
+                    )
+                    .show_header(ui, header)
+                    .body(add_body);
+                }
+                _ => {
+                    ui.horizontal(header);
+                    if has_body {
+                        add_body(ui);
+                    }
+                }
+            }
+        });
+
+    let rect = frame.response.rect;
+    let bar = egui::Rect::from_min_max(
+        rect.left_top(),
+        egui::pos2(rect.left() + 3.0, rect.bottom()),
+    );
+    ui.painter().rect_filled(
+        bar,
+        egui::CornerRadius {
+            nw: 4,
+            sw: 4,
+            ne: 0,
+            se: 0,
+        },
+        accent,
+    );
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Footnote {
+    label: String,
+    number: usize,
+    line: usize,
+    references: Vec<usize>,
+    body: String,
+}
+
+fn superscript_number(number: usize) -> String {
+    const DIGITS: [char; 10] = ['⁰', '¹', '²', '³', '⁴', '⁵', '⁶', '⁷', '⁸', '⁹'];
+    number
+        .to_string()
+        .bytes()
+        .map(|digit| DIGITS[(digit - b'0') as usize])
+        .collect()
+}
+
+fn link_footnotes(markdown: &str) -> (String, Vec<Footnote>) {
+    use pulldown_cmark::{Event, Parser, Tag, TagEnd};
+
+    let mut references: Vec<(String, std::ops::Range<usize>)> = Vec::new();
+    let mut definitions: Vec<(String, std::ops::Range<usize>)> = Vec::new();
+    let mut open_definition = None::<(String, usize)>;
+    for (event, range) in Parser::new_ext(markdown, markdown_parser_options()).into_offset_iter() {
+        match event {
+            Event::FootnoteReference(label) => references.push((label.to_string(), range)),
+            Event::Start(Tag::FootnoteDefinition(label)) => {
+                open_definition = Some((label.to_string(), range.start));
+            }
+            Event::End(TagEnd::FootnoteDefinition) => {
+                if let Some((label, start)) = open_definition.take() {
+                    definitions.push((label, start..range.end));
+                }
+            }
+            _ => {}
+        }
+    }
+    if references.is_empty() && definitions.is_empty() {
+        return (markdown.to_string(), Vec::new());
+    }
+
+    let line_of = |offset: usize| markdown[..offset].matches('\n').count();
+    let mut labels: Vec<&str> = Vec::new();
+    for (label, _) in references.iter().chain(&definitions) {
+        if !labels.contains(&label.as_str()) && definitions.iter().any(|(def, _)| def == label) {
+            labels.push(label);
+        }
+    }
+    let number_of = |label: &str| labels.iter().position(|l| *l == label).map(|idx| idx + 1);
+
+    let mut footnotes = Vec::with_capacity(labels.len());
+    let mut edits: Vec<(std::ops::Range<usize>, String)> = Vec::new();
+    for (label, range) in &definitions {
+        let Some(number) = number_of(label) else {
+            continue;
+        };
+        let source = &markdown[range.clone()];
+        let marker_len = source.find("]:").map_or(0, |idx| idx + 2);
+        let reference_count = references.iter().filter(|(l, _)| l == label).count();
+        let mut marker = format!("**{number}.**");
+        for k in 1..=reference_count {
+            let arrow = if k == 1 {
+                "↩".to_string()
+            } else {
+                format!("↩{}", superscript_number(k))
+            };
+            marker.push_str(&format!(" [{arrow}](#fnref-{number}-{k})"));
+        }
+        edits.push((range.start..range.start + marker_len, marker));
+
+        let mut body = String::new();
+        let mut offset = range.start;
+        for (idx, line) in source.split_inclusive('\n').enumerate() {
+            let text = if idx == 0 {
+                &line[marker_len..]
+            } else {
+                let indent = line.len() - line.trim_start_matches(' ').len();
+                if indent > 0 {
+                    edits.push((offset..offset + indent.min(4), String::new()));
+                }
+                &line[indent.min(4)..]
+            };
+            body.push_str(text.strip_prefix(' ').filter(|_| idx == 0).unwrap_or(text));
+            offset += line.len();
+        }
+        footnotes.push(Footnote {
+            label: label.clone(),
+            number,
+            line: line_of(range.start),
+            references: Vec::new(),
+            body: body.trim_end().to_string(),
+        });
+    }
+    for (label, range) in &references {
+        let Some(number) = number_of(label) else {
+            continue;
+        };
+        if let Some(footnote) = footnotes.iter_mut().find(|f| f.number == number) {
+            footnote.references.push(line_of(range.start));
+        }
+        edits.push((
+            range.clone(),
+            format!("[{}](#fn-{number})", superscript_number(number)),
+        ));
+    }
+    footnotes.sort_by_key(|footnote| footnote.number);
+
+    edits.sort_by_key(|(range, _)| (range.start, range.end));
+    let mut out = String::with_capacity(markdown.len() + edits.len() * 16);
+    let mut copied = 0usize;
+    for (range, replacement) in edits {
+        out.push_str(&markdown[copied..range.start]);
+        out.push_str(&replacement);
+        copied = range.end;
+    }
+    out.push_str(&markdown[copied..]);
+    (out, footnotes)
+}
+
+fn footnote_refs_in(markdown: &str) -> Vec<usize> {
+    static LINK: OnceLock<Regex> = OnceLock::new();
+    let link = LINK.get_or_init(|| {
+        Regex::new(r"\[[⁰¹²³⁴-⁹]+\]\(#fn-(\d+)\)").expect("valid footnote link regex")
+    });
+    link.captures_iter(markdown)
+        .filter_map(|caps| caps[1].parse().ok())
+        .collect()
+}
+
+fn footnote_line_for_fragment(footnotes: &[Footnote], fragment: &str) -> Option<usize> {
+    let find = |key: &str| {
+        footnotes.iter().find(|footnote| {
+            footnote.label.eq_ignore_ascii_case(key) || footnote.number.to_string() == key
+        })
+    };
+    let Some(key) = fragment.strip_prefix("fnref-") else {
+        return find(fragment.strip_prefix("fn-")?).map(|footnote| footnote.line);
+    };
+    if let Some(footnote) = find(key) {
+        return footnote.references.first().copied();
+    }
+    let (key, k) = key.rsplit_once('-')?;
+    let k: usize = k.parse().ok()?;
+    find(key)?.references.get(k.checked_sub(1)?).copied()
+}
+
+fn show_footnote_previews(
+    ui: &mut egui::Ui,
+    layer: egui::LayerId,
+    first_shape: usize,
+    refs: &[usize],
+    footnotes: &[Footnote],
+    mut show_body: impl FnMut(&mut egui::Ui, &str),
+) {
+    static SUPERSCRIPT: OnceLock<Regex> = OnceLock::new();
+    let superscript =
+        SUPERSCRIPT.get_or_init(|| Regex::new("[⁰¹²³⁴-⁹]+").expect("valid superscript regex"));
+    let Some(pointer) = ui.ctx().pointer_hover_pos() else {
+        return;
+    };
+    if !ui.clip_rect().contains(pointer) {
+        return;
+    }
+
+    // Pair painted superscripts with the block's references in order, skipping any that
+    // don't carry the expected number (superscripts in the text itself).
+    let mut refs = refs.iter().peekable();
+    for (text, rects) in painted_text_occurrences(ui.ctx(), layer, first_shape, superscript, false)
+    {
+        let Some(&&number) = refs.peek() else {
+            break;
+        };
+        if text != superscript_number(number) {
+            continue;
+        }
+        refs.next();
+        let Some(rect) = rects.iter().find(|rect| rect.expand(2.0).contains(pointer)) else {
+            continue;
+        };
+        if let Some(footnote) = footnotes.iter().find(|f| f.number == number) {
+            egui::Tooltip::always_open(
+                ui.ctx().clone(),
+                layer,
+                egui::Id::new(("footnote_preview", number)),
+                *rect,
+            )
+            .show(|ui| show_body(ui, &footnote.body));
+        }
+        break;
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PreviewBlock {
+    markdown: String,
+    lines: std::ops::Range<usize>,
+    heading: bool,
+    rule: bool,
+    ends_with_html: bool,
+    callout: Option<Callout>,
+    footnote_refs: Vec<usize>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct LineLayout {
+    blocks: Vec<BlockPosition>,
+}
+
+#[derive(Debug, Clone)]
+struct BlockPosition {
+    lines: std::ops::Range<usize>,
+    top: f32,
+    bottom: f32,
+}
+
+impl LineLayout {
+    fn y_for_line(&self, line: usize) -> Option<f32> {
+        self.y_at(line as f32)
+    }
+
+    fn y_at(&self, line: f32) -> Option<f32> {
+        let last = self.blocks.last()?;
+        let Some(block) = self.blocks.iter().find(|b| line < b.lines.end as f32) else {
+            return Some(last.bottom);
+        };
+        let start = block.lines.start as f32;
+        if line < start {
+            return Some(block.top);
+        }
+        let fraction = (line - start) / block.lines.len() as f32;
+        Some(block.top + (block.bottom - block.top) * fraction)
+    }
+
+    fn line_at(&self, y: f32) -> Option<f32> {
+        let last = self.blocks.last()?;
+        let Some(block) = self.blocks.iter().find(|b| y < b.bottom) else {
+            return Some(last.lines.end as f32);
+        };
+        let start = block.lines.start as f32;
+        if y < block.top {
+            return Some(start);
+        }
+        let fraction = (y - block.top) / (block.bottom - block.top).max(1.0);
+        Some(start + block.lines.len() as f32 * fraction)
+    }
+}
+
+fn document_undo_has_keys(
+    focused: Option<egui::Id>,
+    editors: &[egui::Id],
+    text_input_focused: bool,
+) -> bool {
+    focused.is_some_and(|id| editors.contains(&id)) || !text_input_focused
+}
+
+fn click_taken_by_widget(ctx: &egui::Context) -> bool {
+    ctx.viewport(|vp| vp.interact_widgets.clicked)
+        .and_then(|id| ctx.read_response(id))
+        .is_some_and(|response| response.sense.is_focusable())
+}
+
+fn editor_text_id(doc_id: u64) -> egui::Id {
+    egui::Id::new(("editor_text", doc_id))
+}
+
+fn inline_line_text_id(doc_id: u64, line: usize) -> egui::Id {
+    egui::Id::new(("inline_line_text", doc_id, line))
+}
+
+fn editor_line_layout(galley: &egui::Galley, top: f32) -> LineLayout {
+    let mut layout = LineLayout::default();
+    let mut line = 0usize;
+    let mut line_top = None::<f32>;
+    for row in &galley.rows {
+        let rect = row.rect();
+        let start = *line_top.get_or_insert(rect.top());
+        if row.ends_with_newline {
+            layout.blocks.push(BlockPosition {
+                lines: line..line + 1,
+                top: top + start,
+                bottom: top + rect.bottom(),
+            });
+            line += 1;
+            line_top = None;
+        }
+    }
+    if let (Some(start), Some(last)) = (line_top, galley.rows.last()) {
+        layout.blocks.push(BlockPosition {
+            lines: line..line + 1,
+            top: top + start,
+            bottom: top + last.rect().bottom(),
+        });
+    }
+    layout
+}
+
+#[derive(Debug, Default)]
+struct SplitScrollSync {
+    doc_id: Option<u64>,
+    editor_offset: Option<f32>,
+    preview_offset: Option<f32>,
+    editor_target: Option<f32>,
+    preview_target: Option<f32>,
+    cursor_line: Option<usize>,
+    editor_layout: LineLayout,
+    editor_height: f32,
+    preview_height: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct OutlineDrag(usize);
+
+#[derive(Debug, Clone, Copy)]
+enum OutlineEdit {
+    Move { idx: usize, dest_line: usize },
+    Shift { idx: usize, delta: isize },
+}
+
+#[derive(Debug, Default)]
+struct OutlinePanelState {
+    filter: String,
+    revealed: Option<(u64, usize)>,
+}
+
+#[derive(Debug, Default)]
+struct PreviewUiState {
+    offset: f32,
+    viewport_height: f32,
+    clicked_line: Option<usize>,
+}
+
+fn split_preview_blocks(markdown: &str) -> Vec<PreviewBlock> {
+    use pulldown_cmark::{BrokenLink, Event, Parser, Tag, TagEnd};
+
+    let options = markdown_parser_options();
+
+    let mut spans: Vec<(std::ops::Range<usize>, PreviewEdges)> = Vec::new();
+    let mut depth = 0usize;
+    let mut block_start = 0usize;
+    let mut heading = false;
+    let mut events = Parser::new_ext(markdown, options).into_offset_iter();
+    for (event, range) in events.by_ref() {
+        match event {
+            Event::Start(tag) => {
+                if depth == 0 {
+                    block_start = range.start;
+                    heading = matches!(tag, Tag::Heading { .. });
+                }
+                depth += 1;
+            }
+            Event::End(tag) => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    let edges = PreviewEdges {
+                        heading,
+                        ends_with_html: matches!(tag, TagEnd::HtmlBlock),
+                        ..Default::default()
+                    };
+                    spans.push((block_start..range.end, edges));
+                }
+            }
+            Event::Rule if depth == 0 => {
+                let edges = PreviewEdges {
+                    rule: true,
+                    ..Default::default()
+                };
+                spans.push((range, edges));
+            }
+            _ if depth == 0 => spans.push((range, PreviewEdges::default())),
+            _ => {}
+        }
+    }
+    let normalize_label = |label: &str| {
+        label
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase()
+    };
+    let mut definitions: Vec<(String, std::ops::Range<usize>)> = events
+        .reference_definitions()
+        .iter()
+        .map(|(label, definition)| (normalize_label(label), definition.span.clone()))
+        .collect();
+    definitions.sort_by_key(|(_, span)| span.start);
+
+    let line_start = |offset: usize| markdown[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = |offset: usize| {
+        markdown[offset..]
+            .find('\n')
+            .map_or(markdown.len(), |i| offset + i + 1)
+    };
+    let mut merged: Vec<(std::ops::Range<usize>, PreviewEdges)> = Vec::new();
+    for (range, edges) in spans {
+        let range = line_start(range.start)..line_end(range.end.saturating_sub(1).max(range.start));
+        match merged.last_mut() {
+            Some((last, last_edges)) if range.start < last.end => {
+                last.end = last.end.max(range.end);
+                last_edges.ends_with_html = edges.ends_with_html;
+            }
+            _ => merged.push((range, edges)),
+        }
+    }
+
+    // Link reference definitions produce no events. Those between blocks are appended to
+    // each block that references their label; ones inside a block already travel with it.
+    definitions.retain(|(_, span)| !merged.iter().any(|(range, _)| range.contains(&span.start)));
+    let definitions: Vec<(String, &str)> = definitions
+        .into_iter()
+        .map(|(label, span)| (label, markdown[span].trim()))
+        .collect();
+
+    let mut blocks = Vec::with_capacity(merged.len());
+    let mut line = 0usize;
+    let mut counted = 0usize;
+    for (range, edges) in merged {
+        line += markdown[counted..range.start].matches('\n').count();
+        let line_count = markdown[range.clone()].matches('\n').count().max(1);
+        counted = range.start;
+
+        let with_definitions = |text: &str| {
+            let mut text = text.to_string();
+            if definitions.is_empty() || !text.contains(']') {
+                return text;
+            }
+            // Without its definitions, every reference the block makes is a broken link.
+            let mut used = HashSet::new();
+            Parser::new_with_broken_link_callback(
+                &text,
+                options,
+                Some(|link: BrokenLink| {
+                    used.insert(normalize_label(&link.reference));
+                    None
+                }),
+            )
+            .for_each(drop);
+            let used: Vec<&str> = definitions
+                .iter()
+                .filter(|(label, _)| used.contains(label))
+                .map(|(_, definition)| *definition)
+                .collect();
+            if !used.is_empty() {
+                if !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                text.push('\n');
+                text.push_str(&used.join("\n"));
+            }
+            text
+        };
+        let callout = parse_callout(&markdown[range.clone()]).map(|callout| Callout {
+            body: with_definitions(&callout.body),
+            ..callout
+        });
+        blocks.push(PreviewBlock {
+            footnote_refs: footnote_refs_in(&markdown[range.clone()]),
+            markdown: with_definitions(&markdown[range]),
+            lines: line..line + line_count,
+            heading: edges.heading,
+            rule: edges.rule,
+            ends_with_html: edges.ends_with_html,
+            callout,
+        });
+    }
+    blocks
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct PreviewEdges {
+    heading: bool,
+    rule: bool,
+    ends_with_html: bool,
+}
+
+fn preview_block_gap(blocks: &[PreviewBlock], idx: usize, row_height: f32) -> f32 {
+    // Separator::default() spacing; a rule drawn last in its block is only this tall,
+    // while within a document it fills a whole row.
+    const RULE_HEIGHT: f32 = 6.0;
+    let (prev, block) = (&blocks[idx - 1], &blocks[idx]);
+    // Every block but an HTML one ends with a newline and every block starts with one,
+    // leaving a blank row; a heading's leading newline is already inside its block.
+    let mut gap = if prev.ends_with_html { 0.0 } else { row_height };
+    if block.heading {
+        gap -= row_height;
+    }
+    if block.rule || (idx == 1 && prev.rule) {
+        gap += row_height - RULE_HEIGHT;
+    }
+    gap
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TocOptions {
+    min_depth: usize,
+    max_depth: usize,
+}
+
+impl Default for TocOptions {
+    fn default() -> Self {
+        Self {
+            min_depth: 1,
+            max_depth: 6,
+        }
+    }
+}
+
+fn parse_toc_marker(line: &str) -> Option<TocOptions> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+    let line = line.trim();
+    let inner = line
+        .strip_prefix("[[")
+        .and_then(|rest| rest.strip_suffix("]]"))
+        .or_else(|| {
+            line.strip_prefix('[')
+                .and_then(|rest| rest.strip_suffix(']'))
+        })?;
+    let mut words = inner.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("toc") {
+        return None;
+    }
+
+    let mut options = TocOptions::default();
+    for word in words {
+        let (key, value) = word.split_once('=')?;
+        let value: usize = value.parse().ok().filter(|v| (1..=6).contains(v))?;
+        match key.to_ascii_lowercase().as_str() {
+            "min" => options.min_depth = value,
+            "max" => options.max_depth = value,
+            _ => return None,
+        }
+    }
+    Some(options)
+}
+
+fn toc_markdown(outline: &[OutlineItem], options: TocOptions) -> String {
+    let mut out = String::new();
+    let mut parents: Vec<usize> = Vec::new();
+    for item in outline
+        .iter()
+        .filter(|item| (options.min_depth..=options.max_depth).contains(&item.level))
+    {
+        while parents.last().is_some_and(|&level| level >= item.level) {
+            parents.pop();
+        }
+        let mut title = String::with_capacity(item.title.len());
+        for ch in item.title.chars() {
+            if matches!(ch, '\\' | '[' | ']' | '*' | '_' | '`' | '<' | '>') {
+                title.push('\\');
+            }
+            title.push(ch);
+        }
+        out.push_str(&"  ".repeat(parents.len()));
+        out.push_str(&format!("- [{title}](#{})\n", item.slug));
+        parents.push(item.level);
+    }
+    out
+}
+
+fn expand_toc_markers(blocks: &mut [PreviewBlock], outline: &[OutlineItem]) {
+    for block in blocks {
+        if block.lines.len() != 1 {
+            continue;
+        }
+        let first_line = block.markdown.lines().next().unwrap_or_default();
+        if let Some(options) = parse_toc_marker(first_line) {
+            block.markdown = toc_markdown(outline, options);
+        }
+    }
+}
+
+fn preprocess_markdown(
+    input: &str,
+    settings: &ViewerSettings,
+    github_repo: Option<&GithubRepo>,
+) -> String {
+    let mut out = String::with_capacity(input.len() + 256);
+    let mut fence: Option<FenceState> = None;
+
+    // Show YAML front matter as a code block rather than letting its closing `---` turn
+    // the last key into a setext heading. The line count stays the same.
+    let mut input = input;
+    if let Some(end) = front_matter_len(input) {
+        let block = &input[..end];
+        let mut lines = block.split_inclusive('\n');
+        lines.next();
+        let body: Vec<&str> = lines.collect();
+        let body = &body[..body.len() - 1];
+        // Longer than any tilde run inside, so a `~~~` line in the YAML can't close it.
+        let longest_run = body
+            .iter()
+            .flat_map(|line| line.split(|c| c != '~'))
+            .map(str::len)
+            .max()
+            .unwrap_or(0);
+        let fence = "~".repeat(longest_run.max(2) + 1);
+        out.push_str(&fence);
+        out.push_str("yaml\n");
+        for line in body {
+            out.push_str(line);
+        }
+        out.push_str(&fence);
+        out.push('\n');
+        input = &input[end..];
+    }
+
+    for chunk in input.split_inclusive('\n') {
+        if let Some(state) = &mut fence {
+            if is_fence_closing_line(chunk, state) {
+                flush_fence(&mut out, state, chunk, settings);
+                fence = None;
+            } else {
+                state.content.push_str(chunk);
+            }
+            continue;
+        }
+
+        if let Some(state) = parse_fence_opening_line(chunk) {
+            fence = Some(state);
+            continue;
+        }
+
+        out.push_str(&process_inline_line(chunk, settings, github_repo));
+    }
+
+    if let Some(state) = fence {
+        out.push_str(&format!(
+            "{indent}{fence}{info}\n{content}",
+            indent = state.indent,
+            fence = state.fence(),
+            info = state.info,
+            content = state.content
+        ));
+    }
+
+    out
+}
+
+fn front_matter_len(input: &str) -> Option<usize> {
+    let mut lines = input.split_inclusive('\n');
+    let first = lines.next()?;
+    if first.trim_end() != "---" {
+        return None;
+    }
+    let mut len = first.len();
+    let mut lines = lines.peekable();
+    if lines.peek().is_none_or(|line| line.trim().is_empty()) {
+        return None;
+    }
+    for line in lines {
+        len += line.len();
+        if matches!(line.trim_end(), "---" | "...") {
+            return Some(len);
+        }
+    }
+    None
+}
+
+#[derive(Debug, Clone)]
+struct FenceState {
+    indent: String,
+    marker: char,
+    marker_len: usize,
+    info: String,
+    content: String,
+}
+
+impl FenceState {
+    fn fence(&self) -> String {
+        std::iter::repeat(self.marker)
+            .take(self.marker_len)
+            .collect()
+    }
+}
+
+fn parse_fence_opening_line(chunk: &str) -> Option<FenceState> {
+    let line = chunk.strip_suffix('\n').unwrap_or(chunk);
+    let (indent, rest) = line.split_at(line.len() - line.trim_start_matches([' ', '\t']).len());
+    let mut chars = rest.chars();
+    let marker = chars.next()?;
+    if marker != '`' && marker != '~' {
+        return None;
+    }
+
+    let marker_len = rest.chars().take_while(|&c| c == marker).count();
+    if marker_len < 3 {
+        return None;
+    }
+
+    let after_markers = &rest[marker_len..];
+    let info = after_markers.trim().to_string();
+
+    Some(FenceState {
+        indent: indent.to_string(),
+        marker,
+        marker_len,
+        info,
+        content: String::new(),
+    })
+}
+
+fn is_fence_closing_line(chunk: &str, state: &FenceState) -> bool {
+    let line = chunk.strip_suffix('\n').unwrap_or(chunk);
+    let rest = line.trim_start_matches([' ', '\t']);
+    if !rest.starts_with(state.marker) {
+        return false;
+    }
+    let run_len = rest.chars().take_while(|&c| c == state.marker).count();
+    run_len >= state.marker_len
+}
+
+fn flush_fence(
+    out: &mut String,
+    state: &FenceState,
+    closing_line: &str,
+    settings: &ViewerSettings,
+) {
+    let raw_lang = state.info.split_whitespace().next().unwrap_or("");
+    if settings.render_diagrams
+        && let Some(engine) = DiagramEngine::from_fence_lang(raw_lang)
+    {
+        out.push_str(&state.indent);
+        out.push_str(&format!(
+            "<pre class=\"diagram\" data-engine=\"{}\">\n",
+            engine.kroki_name()
+        ));
+        out.push_str(&state.content);
+        if !state.content.ends_with('\n') {
+            out.push('\n');
+        }
+        out.push_str(&state.indent);
+        out.push_str("</pre>\n");
+        return;
+    }
+
+    let mut info = raw_lang.to_string();
+    info = normalize_fence_language(&info).unwrap_or(info);
+
+    if info.is_empty() && settings.auto_detect_code_lang {
+        if let Some(guess) = guess_code_language(&state.content) {
+            info = guess.to_string();
+        }
+    }
+
+    out.push_str(&state.indent);
+    out.push_str(&state.fence());
+    out.push_str(&info);
+    out.push('\n');
+    out.push_str(&state.content);
+    out.push_str(closing_line);
+}
+
+fn normalize_fence_language(lang: &str) -> Option<String> {
+    let lang = lang.trim();
+    if lang.is_empty() {
+        return None;
+    }
+    let lang = lang
+        .trim_matches(|c| c == '{' || c == '}' || c == '.')
+        .strip_prefix("language-")
+        .unwrap_or(lang)
+        .trim();
+    let lang_lc = lang.to_ascii_lowercase();
+    let mapped = match lang_lc.as_str() {
+        // Core languages
+        "rust" => "rs",
+        "python" => "py",
+        "javascript" | "js" => "js",
+        "typescript" | "ts" => "ts",
+        "tsx" => "tsx",
+        "jsx" => "jsx",
+        "bash" | "sh" | "shell" => "sh",
+        "zsh" | "fish" => "sh",
+        "powershell" | "pwsh" => "ps1",
+        "csharp" | "cs" => "cs",
+        "cpp" | "c++" => "cpp",
+        "c" => "c",
+        "h" => "h",
+        "h++" | "hpp" => "hpp",
+        "go" | "golang" => "go",
+        "java" => "java",
+        "kotlin" | "kt" | "kts" => "kt",
+        "swift" => "swift",
+        "scala" => "scala",
+        "ruby" | "rb" => "rb",
+        "php" => "php",
+        "perl" | "pl" => "pl",
+        "lua" => "lua",
+        "r" => "r",
+        "julia" | "jl" => "jl",
+        "dart" => "dart",
+        "elixir" | "ex" | "exs" => "ex",
+        "erlang" | "erl" => "erl",
+        "haskell" | "hs" => "hs",
+        "ocaml" | "ml" => "ml",
+        "nim" => "nim",
+        "zig" => "zig",
+        "solidity" | "sol" => "sol",
+        // Web + data formats
+        "json" => "json",
+        "json5" => "json5",
+        "toml" => "toml",
+        "ini" => "ini",
+        "yaml" | "yml" => "yml",
+        "markdown" | "md" => "md",
+        "graphql" | "gql" => "graphql",
+        "css" => "css",
+        "scss" => "scss",
+        "less" => "less",
+        "html" => "html",
+        "xml" => "xml",
+        "svg" => "svg",
+        "csv" => "csv",
+        "sql" => "sql",
+        "proto" | "protobuf" => "proto",
+        // Tooling / config
+        "docker" | "dockerfile" => "dockerfile",
+        "make" | "makefile" => "makefile",
+        "cmake" => "cmake",
+        "nix" => "nix",
+        "diff" | "patch" => "diff",
+        "gitignore" => "gitignore",
+        "editorconfig" => "editorconfig",
+        "tex" | "latex" => "tex",
+        "plaintext" | "text" | "txt" => "txt",
+        other => other,
+    };
+    Some(mapped.to_string())
+}
+
+fn guess_code_language(code: &str) -> Option<&'static str> {
+    let mut first_non_empty = None;
+    for line in code.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        first_non_empty = Some(trimmed);
+        break;
+    }
+    let first = first_non_empty?;
+
+    if let Some(shebang) = first.strip_prefix("#!") {
+        let shebang = shebang.to_ascii_lowercase();
+        if shebang.contains("python") {
+            return Some("py");
+        }
+        if shebang.contains("bash") || shebang.contains("sh") {
+            return Some("sh");
+        }
+        if shebang.contains("node") {
+            return Some("js");
+        }
+        return Some("sh");
+    }
+
+    if first.starts_with("<?xml") {
+        return Some("xml");
+    }
+    if first.starts_with("<!DOCTYPE") || first.starts_with("<html") {
+        return Some("html");
+    }
+    if first.starts_with('{') || first.starts_with('[') {
+        return Some("json");
+    }
+    if first.starts_with("SELECT") || first.starts_with("select") {
+        return Some("sql");
+    }
+
+    let hay = code;
+    if hay.contains("fn main") || hay.contains("println!") || hay.contains("use ") {
+        return Some("rs");
+    }
+    if hay.contains("def ") || hay.contains("import ") {
+        return Some("py");
+    }
+    if hay.contains("console.log") || hay.contains("function ") || hay.contains("=>") {
+        return Some("js");
+    }
+    if hay.contains("using System") || hay.contains("namespace ") {
+        return Some("cs");
+    }
+    if hay.contains("#include") {
+        if hay.contains("<iostream>") {
+            return Some("cpp");
+        }
+        return Some("c");
+    }
+
+    None
+}
+
+fn process_inline_line(
+    chunk: &str,
+    settings: &ViewerSettings,
+    github_repo: Option<&GithubRepo>,
+) -> String {
+    let (line, line_ending) = chunk
+        .strip_suffix('\n')
+        .map(|l| (l, "\n"))
+        .unwrap_or((chunk, ""));
+
+    let processed = process_inline_text(line, settings, github_repo);
+    let mut out = String::with_capacity(processed.len() + line_ending.len());
+    out.push_str(&processed);
+    out.push_str(line_ending);
+    out
+}
+
+fn process_inline_text(
+    text: &str,
+    settings: &ViewerSettings,
+    github_repo: Option<&GithubRepo>,
+) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    let mut in_code = false;
+    let mut code_delim_len = 0usize;
+
+    while i < text.len() {
+        let Some(rel) = text[i..].find('`') else {
+            let tail = &text[i..];
+            if in_code {
+                out.push_str(tail);
+            } else {
+                out.push_str(&apply_text_transforms(tail, settings, github_repo));
+            }
+            break;
+        };
+        let start = i + rel;
+        let before = &text[i..start];
+        if in_code {
+            out.push_str(before);
+        } else {
+            out.push_str(&apply_text_transforms(before, settings, github_repo));
+        }
+
+        let run_len = text[start..].bytes().take_while(|b| *b == b'`').count();
+        let run = &text[start..start + run_len];
+        out.push_str(run);
+
+        if !in_code {
+            in_code = true;
+            code_delim_len = run_len;
+        } else if run_len == code_delim_len {
+            in_code = false;
+            code_delim_len = 0;
+        }
+
+        i = start + run_len;
+    }
+
+    out
+}
+
+fn apply_text_transforms(
+    text: &str,
+    settings: &ViewerSettings,
+    github_repo: Option<&GithubRepo>,
+) -> String {
+    let mut out = text.to_string();
+
+    if settings.github_links {
+        out = linkify_github_references(&out, github_repo);
+    }
+    if settings.autolink_urls {
+        out = autolink_plain_urls(&out);
+    }
+    if settings.replace_emoji {
+        out = replace_emoji_shortcodes(&out);
+    }
+    if settings.smart_typography {
+        out = smart_typography(&out);
+    }
+
+    out
+}
+
+fn linkify_github_references(text: &str, github_repo: Option<&GithubRepo>) -> String {
+    static RE_CROSS_REPO: OnceLock<Regex> = OnceLock::new();
+    static RE_PR: OnceLock<Regex> = OnceLock::new();
+    static RE_ISSUE: OnceLock<Regex> = OnceLock::new();
+
+    let re_cross_repo = RE_CROSS_REPO.get_or_init(|| {
+        Regex::new(r"(?P<owner>[A-Za-z0-9_.-]+)/(?P<repo>[A-Za-z0-9_.-]+)#(?P<num>[0-9]+)")
+            .expect("valid regex")
+    });
+    let mut out = re_cross_repo
+        .replace_all(text, |caps: &Captures| {
+            let owner = &caps["owner"];
+            let repo = &caps["repo"];
+            let num = &caps["num"];
+            format!("[{owner}/{repo}#{num}](https://github.com/{owner}/{repo}/issues/{num})")
+        })
+        .into_owned();
+
+    let Some(repo) = github_repo else {
+        return out;
+    };
+
+    let re_pr = RE_PR.get_or_init(|| {
+        Regex::new(r"(?i)(?P<prefix>^|[^A-Za-z0-9_])PR\s*#(?P<num>[0-9]+)").expect("valid regex")
+    });
+    out = re_pr
+        .replace_all(&out, |caps: &Captures| {
+            let prefix = caps.name("prefix").map_or("", |m| m.as_str());
+            let num = &caps["num"];
+            format!("{prefix}[PR#{num}]({}/pull/{num})", repo.base_url)
+        })
+        .into_owned();
+
+    let re_issue = RE_ISSUE.get_or_init(|| {
+        Regex::new(r"(?P<prefix>^|[^A-Za-z0-9_])#(?P<num>[0-9]+)").expect("valid regex")
+    });
+    out = re_issue
+        .replace_all(&out, |caps: &Captures| {
+            let prefix = caps.name("prefix").map_or("", |m| m.as_str());
+            let num = &caps["num"];
+            format!("{prefix}[#{num}]({}/issues/{num})", repo.base_url)
+        })
+        .into_owned();
+
+    out
+}
+
+fn autolink_plain_urls(text: &str) -> String {
+    let mut finder = linkify::LinkFinder::new();
+    finder.kinds(&[linkify::LinkKind::Url]);
+
+    let links: Vec<linkify::Link<'_>> = finder.links(text).collect();
+    if links.is_empty() {
+        return text.to_string();
+    }
+
+    let mut out = String::with_capacity(text.len() + links.len() * 2);
+    let mut last = 0usize;
+
+    for link in links {
+        let start = link.start();
+        let end = link.end();
+        out.push_str(&text[last..start]);
+
+        let before = start
+            .checked_sub(1)
+            .and_then(|i| text.as_bytes().get(i))
+            .copied();
+        let after = text.as_bytes().get(end).copied();
+        if before == Some(b'<') && after == Some(b'>') {
+            out.push_str(&text[start..end]);
+        } else {
+            out.push('<');
+            out.push_str(&normalize_autolink_url(link.as_str()));
+            out.push('>');
+        }
+
+        last = end;
+    }
+
+    out.push_str(&text[last..]);
+    out
+}
+
+fn normalize_autolink_url(url: &str) -> String {
+    if url.contains("://") {
+        return url.to_string();
+    }
+    if url.starts_with("mailto:") {
+        return url.to_string();
+    }
+    format!("https://{url}")
+}
+
+fn replace_emoji_shortcodes(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+
+    while let Some(colon1_rel) = text[i..].find(':') {
+        let colon1 = i + colon1_rel;
+        out.push_str(&text[i..colon1]);
+
+        let rest = &text[colon1 + 1..];
+        let Some(colon2_rel) = rest.find(':') else {
+            out.push_str(&text[colon1..]);
+            return out;
+        };
+        let name = &rest[..colon2_rel];
+        if is_valid_emoji_shortcode(name) {
+            if let Some(emoji) = emojis::get_by_shortcode(name) {
+                out.push_str(emoji.as_str());
+                i = colon1 + 1 + colon2_rel + 1;
+                continue;
+            }
+        }
+        out.push(':');
+        out.push_str(name);
+        out.push(':');
+
+        i = colon1 + 1 + colon2_rel + 1;
+    }
+
+    out.push_str(&text[i..]);
+    out
+}
+
+fn is_valid_emoji_shortcode(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= 64
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-'))
+}
+
+fn smart_typography(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+
+    let mut chars = text.chars().peekable();
+    let mut prev_char: Option<char> = None;
+    let mut double_open = true;
+    let mut single_open = true;
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '.' => {
+                let mut dots = 1;
+                while dots < 3 && chars.peek().copied() == Some('.') {
+                    dots += 1;
+                    chars.next();
+                }
+                if dots == 3 {
+                    out.push('…');
+                    prev_char = Some('…');
+                    continue;
+                }
+                for _ in 0..dots {
+                    out.push('.');
+                }
+                prev_char = Some('.');
+            }
+            '-' => {
+                if chars.peek().copied() == Some('-') {
+                    chars.next();
+                    out.push('—');
+                    prev_char = Some('—');
+                    continue;
+                }
+                out.push('-');
+                prev_char = Some('-');
+            }
+            '"' => {
+                out.push(if double_open { '“' } else { '”' });
+                double_open = !double_open;
+                prev_char = Some('"');
+            }
+            '\'' => {
+                let next = chars.peek().copied();
+                let prev_is_word = prev_char.is_some_and(|c| c.is_alphanumeric());
+                let next_is_word = next.is_some_and(|c| c.is_alphanumeric());
+                if prev_is_word && next_is_word {
+                    out.push('’');
+                } else {
+                    out.push(if single_open { '‘' } else { '’' });
+                    single_open = !single_open;
                 }
-            });
-
-        self.show_find_window(ctx);
-        self.handle_internal_anchor_links(ctx);
-        self.show_notifications(ctx);
+                prev_char = Some('\'');
+            }
+            _ => {
+                out.push(ch);
+                prev_char = Some(ch);
+            }
+        }
     }
 
-    fn save(&mut self, storage: &mut dyn eframe::Storage) {
-        self.persisted.settings = self.settings.clone();
-        self.persisted.open_files = self
-            .documents
-            .iter()
-            .filter_map(|doc| doc.file_path.clone())
-            .collect();
-        self.persisted.active_file = self.active_document().and_then(|doc| doc.file_path.clone());
-        self.persisted.slash_tracker = self.slash_tracker.clone();
-        eframe::set_value(storage, STATE_KEY, &self.persisted);
-    }
+    out
 }
 
-fn normalize_path(path: PathBuf) -> PathBuf {
-    std::fs::canonicalize(&path).unwrap_or(path)
+const MATH_RENDERER_VERSION: &str = "mathjax_svg-3";
+const OFFLINE_DIAGRAM_RENDERER_VERSION: &str = "offline-1";
+const DEFAULT_RENDER_CACHE_LIMIT_MB: u64 = 256;
+
+fn render_cache_dir() -> Option<PathBuf> {
+    eframe::storage_dir(APP_NAME).map(|dir| dir.join("render-cache"))
 }
 
-fn infer_mime_from_path(path: &Path) -> String {
-    match path
-        .extension()
-        .and_then(|e| e.to_str())
-        .unwrap_or_default()
-        .to_ascii_lowercase()
-        .as_str()
-    {
-        "png" => "image/png",
-        "jpg" | "jpeg" => "image/jpeg",
-        "gif" => "image/gif",
-        "webp" => "image/webp",
-        "svg" => "image/svg+xml",
-        _ => "application/octet-stream",
+fn stable_hash(parts: &[&str]) -> u128 {
+    const OFFSET: u128 = 0x6c62_272e_07bb_0142_62b8_2175_6295_c58d;
+    const PRIME: u128 = 0x0000_0000_0100_0000_0000_0000_0000_013b;
+    let mut hash = OFFSET;
+    for part in parts {
+        // Length-prefix each part so ("ab", "c") and ("a", "bc") differ.
+        for byte in (part.len() as u64)
+            .to_le_bytes()
+            .iter()
+            .chain(part.as_bytes())
+        {
+            hash ^= u128::from(*byte);
+            hash = hash.wrapping_mul(PRIME);
+        }
     }
-    .to_string()
+    hash
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-enum AppTheme {
-    System,
-    Dark,
-    Light,
-    TerminalGreen,
-    TerminalAmber,
+#[derive(Debug, Default)]
+struct RenderCacheIndex {
+    entries: HashMap<String, (u64, u64)>,
+    total_bytes: u64,
+    tick: u64,
 }
 
-impl Default for AppTheme {
-    fn default() -> Self {
-        Self::System
+impl RenderCacheIndex {
+    fn touch(&mut self, name: String, size: u64) {
+        self.tick += 1;
+        if let Some((previous, _)) = self.entries.insert(name, (size, self.tick)) {
+            self.total_bytes -= previous;
+        }
+        self.total_bytes += size;
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-enum ViewMode {
-    EditorOnly,
-    Split,
-    PreviewOnly,
+#[derive(Debug)]
+struct RenderDiskCache {
+    dir: PathBuf,
+    limit_bytes: AtomicU64,
+    index: Mutex<Option<RenderCacheIndex>>,
 }
 
-impl Default for ViewMode {
-    fn default() -> Self {
-        Self::PreviewOnly
+impl RenderDiskCache {
+    const EXTENSION: &str = "svg";
+
+    fn new(dir: PathBuf, limit_bytes: u64) -> Self {
+        Self {
+            dir,
+            limit_bytes: AtomicU64::new(limit_bytes),
+            index: Mutex::new(None),
+        }
     }
-}
 
-#[derive(Debug, Clone, Copy)]
-enum TerminalHue {
-    Green,
-    Amber,
-}
+    fn file_name(key: &[&str]) -> String {
+        format!("{:032x}.{}", stable_hash(key), Self::EXTENSION)
+    }
 
-fn restore_default_visuals(ctx: &egui::Context) {
-    ctx.set_visuals_of(egui::Theme::Dark, egui::Visuals::dark());
-    ctx.set_visuals_of(egui::Theme::Light, egui::Visuals::light());
-}
+    fn set_limit(&self, limit_bytes: u64) {
+        self.limit_bytes.store(limit_bytes, Ordering::Relaxed);
+        self.with_index(|index| self.evict(index));
+    }
 
-fn apply_app_theme(ctx: &egui::Context, theme: AppTheme) {
-    match theme {
-        AppTheme::System => {
-            restore_default_visuals(ctx);
-            ctx.set_theme(egui::ThemePreference::System);
-        }
-        AppTheme::Dark => {
-            restore_default_visuals(ctx);
-            ctx.set_theme(egui::ThemePreference::Dark);
-        }
-        AppTheme::Light => {
-            restore_default_visuals(ctx);
-            ctx.set_theme(egui::ThemePreference::Light);
+    fn usage(&self) -> (usize, u64) {
+        self.with_index(|index| (index.entries.len(), index.total_bytes))
+    }
+
+    fn get(&self, key: &[&str]) -> Option<Arc<[u8]>> {
+        let name = Self::file_name(key);
+        let path = self.dir.join(&name);
+        let bytes = fs::read(&path).ok()?;
+        // The file's modification time doubles as its last use across sessions.
+        if let Ok(file) = fs::File::options().write(true).open(&path) {
+            let _ = file.set_modified(std::time::SystemTime::now());
         }
-        AppTheme::TerminalGreen => {
-            ctx.set_theme(egui::ThemePreference::Dark);
-            ctx.set_visuals_of(egui::Theme::Dark, terminal_visuals(TerminalHue::Green));
+        self.with_index(|index| index.touch(name, bytes.len() as u64));
+        Some(Arc::from(bytes))
+    }
+
+    fn put(&self, key: &[&str], bytes: &[u8]) {
+        if let Err(err) = fs::create_dir_all(&self.dir) {
+            eprintln!("render cache disabled: {err}");
+            return;
         }
-        AppTheme::TerminalAmber => {
-            ctx.set_theme(egui::ThemePreference::Dark);
-            ctx.set_visuals_of(egui::Theme::Dark, terminal_visuals(TerminalHue::Amber));
+        let name = Self::file_name(key);
+        let tmp = self.dir.join(format!("{name}.tmp"));
+        let written = fs::write(&tmp, bytes).and_then(|()| fs::rename(&tmp, self.dir.join(&name)));
+        if let Err(err) = written {
+            eprintln!("failed to cache rendered SVG: {err}");
+            let _ = fs::remove_file(&tmp);
+            return;
         }
+        self.with_index(|index| {
+            index.touch(name, bytes.len() as u64);
+            self.evict(index);
+        });
     }
-}
 
-fn terminal_visuals(hue: TerminalHue) -> egui::Visuals {
-    let mut visuals = egui::Visuals::dark();
+    fn clear(&self) {
+        self.with_index(|index| {
+            for name in index.entries.keys() {
+                let _ = fs::remove_file(self.dir.join(name));
+            }
+            *index = RenderCacheIndex::default();
+        });
+    }
 
-    let bg = egui::Color32::from_rgb(0x07, 0x0b, 0x07);
-    let extreme_bg = egui::Color32::from_rgb(0x04, 0x06, 0x04);
+    fn with_index<R>(&self, f: impl FnOnce(&mut RenderCacheIndex) -> R) -> R {
+        let mut guard = self.index.lock().unwrap_or_else(|e| e.into_inner());
+        let index = guard.get_or_insert_with(|| self.scan());
+        f(index)
+    }
+
+    fn scan(&self) -> RenderCacheIndex {
+        let mut files: Vec<(String, u64, std::time::SystemTime)> = fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let meta = entry.metadata().ok()?;
+                let ours =
+                    Path::new(&name).extension().and_then(|e| e.to_str()) == Some(Self::EXTENSION);
+                if !ours || !meta.is_file() {
+                    return None;
+                }
+                Some((name, meta.len(), meta.modified().ok()?))
+            })
+            .collect();
+        files.sort_by_key(|(_, _, modified)| *modified);
 
-    let (accent, accent_dim, accent_bright) = match hue {
-        TerminalHue::Green => (
-            egui::Color32::from_rgb(0x00, 0xff, 0x7a),
-            egui::Color32::from_rgb(0x00, 0xc8, 0x60),
-            egui::Color32::from_rgb(0xc8, 0xff, 0xe8),
-        ),
-        TerminalHue::Amber => (
-            egui::Color32::from_rgb(0xff, 0xb0, 0x36),
-            egui::Color32::from_rgb(0xe6, 0x9a, 0x20),
-            egui::Color32::from_rgb(0xff, 0xf0, 0xd0),
-        ),
-    };
+        let mut index = RenderCacheIndex::default();
+        for (name, size, _) in files {
+            index.touch(name, size);
+        }
+        self.evict(&mut index);
+        index
+    }
 
-    let button_bg = lerp_color(bg, accent, 0.12);
-    let hovered_bg = lerp_color(bg, accent, 0.18);
-    let active_bg = lerp_color(bg, accent, 0.24);
+    fn evict(&self, index: &mut RenderCacheIndex) {
+        let limit = self.limit_bytes.load(Ordering::Relaxed);
+        if index.total_bytes <= limit {
+            return;
+        }
+        let mut by_age: Vec<(u64, String, u64)> = index
+            .entries
+            .iter()
+            .map(|(name, &(size, tick))| (tick, name.clone(), size))
+            .collect();
+        by_age.sort_unstable();
+        for (_, name, size) in by_age {
+            if index.total_bytes <= limit {
+                break;
+            }
+            let _ = fs::remove_file(self.dir.join(&name));
+            index.entries.remove(&name);
+            index.total_bytes -= size;
+        }
+    }
+}
 
-    visuals.window_fill = bg;
-    visuals.panel_fill = bg;
-    visuals.window_stroke = egui::Stroke::new(1.0, with_alpha(accent_dim, 140));
-    visuals.faint_bg_color = lerp_color(bg, accent_dim, 0.06);
-    visuals.extreme_bg_color = extreme_bg;
-    visuals.text_edit_bg_color = Some(extreme_bg);
-    visuals.code_bg_color = lerp_color(bg, accent, 0.08);
-    visuals.hyperlink_color = egui::Color32::from_rgb(0x66, 0xd9, 0xef); // cyan
-    visuals.selection.bg_fill = lerp_color(bg, accent, 0.35);
-    visuals.selection.stroke = egui::Stroke::new(1.0, accent_bright);
+fn cached_render(
+    disk: Option<&RenderDiskCache>,
+    key: &[&str],
+    render: impl FnOnce() -> std::result::Result<Arc<[u8]>, String>,
+) -> std::result::Result<Arc<[u8]>, String> {
+    if let Some(bytes) = disk.and_then(|disk| disk.get(key)) {
+        return Ok(bytes);
+    }
+    let bytes = render()?;
+    if let Some(disk) = disk {
+        disk.put(key, &bytes);
+    }
+    Ok(bytes)
+}
 
-    visuals.widgets.noninteractive.bg_fill = bg;
-    visuals.widgets.noninteractive.weak_bg_fill = bg;
-    visuals.widgets.noninteractive.bg_stroke = egui::Stroke::new(1.0, with_alpha(accent_dim, 140));
-    visuals.widgets.noninteractive.fg_stroke = egui::Stroke::new(1.0, accent);
+fn default_math_workers() -> usize {
+    thread::available_parallelism().map_or(2, |n| n.get().clamp(1, 4))
+}
 
-    visuals.widgets.inactive.weak_bg_fill = button_bg;
-    visuals.widgets.inactive.bg_fill = button_bg;
-    visuals.widgets.inactive.bg_stroke = egui::Stroke::new(1.0, with_alpha(accent_dim, 110));
-    visuals.widgets.inactive.fg_stroke = egui::Stroke::new(1.0, accent);
+struct MathPool {
+    ctx: egui::Context,
+    cache: Arc<Mutex<SvgCache<MathKey>>>,
+    disk: Option<Arc<RenderDiskCache>>,
+    queue: Mutex<MathQueue>,
+    available: std::sync::Condvar,
+}
 
-    visuals.widgets.hovered.weak_bg_fill = hovered_bg;
-    visuals.widgets.hovered.bg_fill = hovered_bg;
-    visuals.widgets.hovered.bg_stroke = egui::Stroke::new(1.0, with_alpha(accent_bright, 200));
-    visuals.widgets.hovered.fg_stroke = egui::Stroke::new(1.5, accent_bright);
+#[derive(Default)]
+struct MathQueue {
+    visible: VecDeque<MathKey>,
+    background: VecDeque<MathKey>,
+    in_background: HashSet<MathKey>,
+    workers: usize,
+    running: Vec<bool>,
+}
 
-    visuals.widgets.active.weak_bg_fill = active_bg;
-    visuals.widgets.active.bg_fill = active_bg;
-    visuals.widgets.active.bg_stroke = egui::Stroke::new(1.0, accent_bright);
-    visuals.widgets.active.fg_stroke = egui::Stroke::new(2.0, accent_bright);
+impl MathPool {
+    fn start(
+        ctx: egui::Context,
+        cache: Arc<Mutex<SvgCache<MathKey>>>,
+        disk: Option<Arc<RenderDiskCache>>,
+        workers: usize,
+    ) -> Arc<Self> {
+        let pool = Arc::new(Self {
+            ctx,
+            cache,
+            disk,
+            queue: Mutex::new(MathQueue::default()),
+            available: std::sync::Condvar::new(),
+        });
+        pool.resize(workers);
+        pool
+    }
 
-    visuals.widgets.open.weak_bg_fill = hovered_bg;
-    visuals.widgets.open.bg_fill = bg;
-    visuals.widgets.open.bg_stroke = egui::Stroke::new(1.0, with_alpha(accent_dim, 140));
-    visuals.widgets.open.fg_stroke = egui::Stroke::new(1.0, accent_bright);
+    fn resize(self: &Arc<Self>, workers: usize) {
+        let workers = workers.max(1);
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.workers = workers;
+        if queue.running.len() < workers {
+            queue.running.resize(workers, false);
+        }
+        for index in 0..workers {
+            if !queue.running[index] {
+                queue.running[index] = true;
+                spawn_math_worker(self.clone(), index);
+            }
+        }
+        self.available.notify_all();
+    }
 
-    visuals.warn_fg_color = egui::Color32::from_rgb(0xff, 0xb0, 0x36);
+    fn push(&self, key: MathKey, visible: bool) {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        if visible {
+            queue.visible.push_back(key);
+        } else {
+            queue.in_background.insert(key.clone());
+            queue.background.push_back(key);
+        }
+        self.available.notify_one();
+    }
 
-    visuals
-}
+    fn promote(&self, key: &MathKey) {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        if !queue.in_background.remove(key) {
+            return;
+        }
+        if let Some(index) = queue.background.iter().position(|queued| queued == key)
+            && let Some(key) = queue.background.remove(index)
+        {
+            queue.visible.push_back(key);
+        }
+    }
 
-fn read_markdown(path: &Path) -> Result<String> {
-    let bytes =
-        std::fs::read(path).with_context(|| format!("failed to read `{}`", path.display()))?;
-    let text = String::from_utf8_lossy(&bytes).into_owned();
-    Ok(text.replace("\r\n", "\n").replace('\r', "\n"))
+    fn next_job(&self, index: usize) -> Option<MathKey> {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if index >= queue.workers {
+                queue.running[index] = false;
+                return None;
+            }
+            if let Some(key) = queue.visible.pop_front() {
+                return Some(key);
+            }
+            if let Some(key) = queue.background.pop_front() {
+                queue.in_background.remove(&key);
+                return Some(key);
+            }
+            queue = self
+                .available
+                .wait(queue)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
-struct GithubRepo {
-    base_url: String,
+fn spawn_math_worker(pool: Arc<MathPool>, index: usize) {
+    thread::spawn(move || {
+        // Every thread gets its own MathJax instance, but the shared V8 platform must be
+        // initialized by one of them before the others start.
+        static MATHJAX_READY: OnceLock<()> = OnceLock::new();
+        MATHJAX_READY.get_or_init(|| {
+            let _ = mathjax_svg::convert_to_svg_inline("x");
+        });
+
+        while let Some(key) = pool.next_job(index) {
+            if !pool.cache.lock().is_ok_and(|cache| cache.wants(&key)) {
+                continue;
+            }
+            let color = key.color.to_hex();
+            let mode = if key.inline { "inline" } else { "display" };
+            let disk_key = ["math", MATH_RENDERER_VERSION, mode, &color, &key.tex];
+            let rendered = cached_render(pool.disk.as_deref(), &disk_key, || render_math_svg(&key));
+            let next_state = match rendered {
+                Ok(svg_bytes) => SvgState::Ready(svg_bytes),
+                Err(err) => SvgState::Error(err),
+            };
+
+            if let Ok(mut cache) = pool.cache.lock() {
+                cache.finish(&key, next_state);
+            }
+            pool.ctx.request_repaint();
+        }
+    });
 }
 
-fn discover_github_repo(markdown_path: &Path) -> Option<GithubRepo> {
-    let dir = markdown_path.parent().unwrap_or(markdown_path);
-    let git_root = Command::new("git")
-        .arg("-C")
-        .arg(dir)
-        .args(["rev-parse", "--show-toplevel"])
-        .output()
-        .ok()?;
-    if !git_root.status.success() {
-        return None;
-    }
-    let git_root = String::from_utf8_lossy(&git_root.stdout).trim().to_string();
-    if git_root.is_empty() {
-        return None;
+fn render_math_svg(key: &MathKey) -> std::result::Result<Arc<[u8]>, String> {
+    let svg = if key.inline {
+        mathjax_svg::convert_to_svg_inline(&key.tex)
+    } else {
+        mathjax_svg::convert_to_svg(&key.tex)
     }
+    .map_err(|e| e.to_string())?;
 
-    let remote = Command::new("git")
-        .arg("-C")
-        .arg(&git_root)
-        .args(["config", "--get", "remote.origin.url"])
-        .output()
-        .ok()?;
-    if !remote.status.success() {
-        return None;
-    }
-    let remote = String::from_utf8_lossy(&remote.stdout).trim().to_string();
-    parse_github_remote(&remote).map(|base_url| GithubRepo { base_url })
+    let svg = apply_svg_text_color(&svg, key.color);
+    Ok(Arc::<[u8]>::from(svg.into_bytes()))
 }
 
-fn parse_github_remote(remote: &str) -> Option<String> {
-    fn strip_git_suffix(s: &str) -> &str {
-        s.strip_suffix(".git").unwrap_or(s)
-    }
+fn apply_svg_text_color(svg: &str, color: egui::Color32) -> String {
+    let [r, g, b, a] = color.to_srgba_unmultiplied();
+    let css = if a == 255 {
+        format!("color: rgb({r}, {g}, {b});")
+    } else {
+        format!("color: rgba({r}, {g}, {b}, {:.3});", (a as f32) / 255.0)
+    };
 
-    if let Some(rest) = remote.strip_prefix("https://github.com/") {
-        let rest = strip_git_suffix(rest);
-        let mut it = rest.split('/');
-        let owner = it.next()?.trim();
-        let repo = it.next()?.trim();
-        if owner.is_empty() || repo.is_empty() {
-            return None;
+    let Some(svg_start) = svg.find("<svg") else {
+        return svg.to_owned();
+    };
+    let Some(tag_end_rel) = svg[svg_start..].find('>') else {
+        return svg.to_owned();
+    };
+    let tag_end = svg_start + tag_end_rel;
+    let start_tag = &svg[svg_start..tag_end];
+
+    if let Some(style_pos_rel) = start_tag.find("style=\"") {
+        let value_start = svg_start + style_pos_rel + "style=\"".len();
+        let Some(value_end_rel) = svg[value_start..].find('"') else {
+            return svg.to_owned();
+        };
+        let value_end = value_start + value_end_rel;
+        let existing = &svg[value_start..value_end];
+
+        let mut out = String::with_capacity(svg.len() + css.len() + 2);
+        out.push_str(&svg[..value_end]);
+        if !existing.trim().is_empty() && !existing.trim_end().ends_with(';') {
+            out.push(';');
         }
-        return Some(format!("https://github.com/{owner}/{repo}"));
+        out.push_str(&css);
+        out.push_str(&svg[value_end..]);
+        return out;
     }
 
-    if let Some(rest) = remote.strip_prefix("http://github.com/") {
-        let rest = strip_git_suffix(rest);
-        let mut it = rest.split('/');
-        let owner = it.next()?.trim();
-        let repo = it.next()?.trim();
-        if owner.is_empty() || repo.is_empty() {
-            return None;
+    let mut out = String::with_capacity(svg.len() + css.len() + 16);
+    out.push_str(&svg[..tag_end]);
+    out.push_str(" style=\"");
+    out.push_str(&css);
+    out.push('"');
+    out.push_str(&svg[tag_end..]);
+    out
+}
+
+fn search_sources(documents: &[Document], workspace: Option<&Workspace>) -> Vec<SearchSource> {
+    let mut sources: Vec<SearchSource> = documents
+        .iter()
+        .map(|doc| SearchSource {
+            doc_id: Some(doc.id),
+            path: doc.file_path.clone(),
+            title: doc.display_name(),
+            text: Some(doc.raw_markdown.clone()),
+        })
+        .collect();
+    if let Some(workspace) = workspace {
+        let open: HashSet<PathBuf> = documents
+            .iter()
+            .filter_map(|doc| doc.file_path.clone())
+            .collect();
+        for path in workspace.markdown_files() {
+            if open.contains(&path) {
+                continue;
+            }
+            let title = path
+                .strip_prefix(&workspace.root)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .replace('\\', "/");
+            sources.push(SearchSource {
+                doc_id: None,
+                path: Some(path),
+                title,
+                text: None,
+            });
         }
-        return Some(format!("https://github.com/{owner}/{repo}"));
     }
+    sources
+}
 
-    if let Some(rest) = remote.strip_prefix("git@github.com:") {
-        let rest = strip_git_suffix(rest);
-        let mut it = rest.split('/');
-        let owner = it.next()?.trim();
-        let repo = it.next()?.trim();
-        if owner.is_empty() || repo.is_empty() {
-            return None;
+fn spawn_search_worker(
+    ctx: egui::Context,
+    current_generation: Arc<AtomicU64>,
+    rx: mpsc::Receiver<SearchRequest>,
+    tx: mpsc::Sender<SearchEvent>,
+) {
+    thread::spawn(move || {
+        'requests: while let Ok(request) = rx.recv() {
+            let mut files_searched = 0;
+            for source in request.sources {
+                if current_generation.load(Ordering::Relaxed) != request.generation {
+                    continue 'requests;
+                }
+                let text = match (source.text, &source.path) {
+                    (Some(text), _) => text,
+                    (None, Some(path)) => match fs::read_to_string(path) {
+                        Ok(text) => text,
+                        Err(_) => continue,
+                    },
+                    (None, None) => continue,
+                };
+                files_searched += 1;
+
+                let matches = find_matches(&text, &request.regex, request.whole_word);
+                if matches.is_empty() {
+                    continue;
+                }
+                let result = SearchFileResult {
+                    doc_id: source.doc_id,
+                    path: source.path,
+                    title: source.title,
+                    matches,
+                };
+                if tx
+                    .send(SearchEvent::File {
+                        generation: request.generation,
+                        result,
+                    })
+                    .is_err()
+                {
+                    return;
+                }
+                ctx.request_repaint();
+            }
+            let _ = tx.send(SearchEvent::Done {
+                generation: request.generation,
+                files_searched,
+            });
+            ctx.request_repaint();
         }
-        return Some(format!("https://github.com/{owner}/{repo}"));
+    });
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+struct DiagramPalette {
+    text: egui::Color32,
+    line: egui::Color32,
+    fill: egui::Color32,
+    note: egui::Color32,
+    background: egui::Color32,
+}
+
+impl DiagramPalette {
+    fn cache_key(&self) -> String {
+        [self.text, self.line, self.fill, self.note, self.background]
+            .map(|color| color.to_hex())
+            .join(" ")
     }
 
-    if let Some(rest) = remote.strip_prefix("ssh://git@github.com/") {
-        let rest = strip_git_suffix(rest);
-        let mut it = rest.split('/');
-        let owner = it.next()?.trim();
-        let repo = it.next()?.trim();
-        if owner.is_empty() || repo.is_empty() {
-            return None;
+    fn from_visuals(visuals: &egui::Visuals) -> Self {
+        let background = visuals.panel_fill;
+        let text = visuals.text_color();
+        Self {
+            text,
+            line: lerp_color(text, background, 0.25),
+            fill: lerp_color(background, visuals.selection.bg_fill, 0.35),
+            note: lerp_color(background, visuals.warn_fg_color, 0.18),
+            background,
         }
-        return Some(format!("https://github.com/{owner}/{repo}"));
     }
+}
 
-    None
+// Spelled out because resvg maps `sans-serif` to Arial, which most Linux systems lack.
+const DIAGRAM_FONT: &str =
+    "Segoe UI, Helvetica, Arial, DejaVu Sans, Noto Sans, Liberation Sans, sans-serif";
+const DIAGRAM_FONT_SIZE: f32 = 14.0;
+const DIAGRAM_LINE_HEIGHT: f32 = 18.0;
+
+fn diagram_text_width(text: &str) -> f32 {
+    text.chars()
+        .map(|ch| match ch {
+            'i' | 'l' | 'j' | 't' | 'f' | 'r' | '.' | ',' | ':' | ';' | '|' | '!' | '\'' => 0.32,
+            'm' | 'w' | 'M' | 'W' => 0.85,
+            ch if ch.is_ascii_uppercase() => 0.68,
+            ch if ch.is_ascii() => 0.55,
+            ch if ch >= '\u{2e80}' => 1.0,
+            _ => 0.65,
+        })
+        .sum::<f32>()
+        * DIAGRAM_FONT_SIZE
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(default)]
-struct ViewerSettings {
-    theme: AppTheme,
-    view_mode: ViewMode,
-    render_math: bool,
-    render_mermaid: bool,
-    auto_detect_code_lang: bool,
-    autolink_urls: bool,
-    github_links: bool,
-    replace_emoji: bool,
-    smart_typography: bool,
-    show_outline: bool,
-    auto_reload: bool,
-    inline_render: InlineRenderConfig,
+fn diagram_text_size(lines: &[String]) -> egui::Vec2 {
+    let width = lines
+        .iter()
+        .map(|line| diagram_text_width(line))
+        .fold(0.0, f32::max);
+    egui::vec2(width, lines.len().max(1) as f32 * DIAGRAM_LINE_HEIGHT)
 }
 
-impl Default for ViewerSettings {
-    fn default() -> Self {
-        Self {
-            theme: AppTheme::System,
-            view_mode: ViewMode::PreviewOnly,
-            render_math: true,
-            render_mermaid: true,
-            auto_detect_code_lang: true,
-            autolink_urls: true,
-            github_links: true,
-            replace_emoji: true,
-            smart_typography: false,
-            show_outline: true,
-            auto_reload: false,
-            inline_render: InlineRenderConfig::default(),
+fn diagram_label_lines(label: &str) -> Vec<String> {
+    static BREAK: OnceLock<Regex> = OnceLock::new();
+    let re = BREAK.get_or_init(|| Regex::new(r"(?i)<br\s*/?>|\\n").expect("valid break regex"));
+    re.split(label.trim())
+        .map(|line| line.trim().to_string())
+        .collect()
+}
+
+fn xml_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(ch),
         }
     }
+    out
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(default)]
-struct InlineRenderConfig {
-    enabled: bool,
-    animate_transitions: bool,
-    spring_preset: SpringPreset,
-    max_concurrent_animations: usize,
-    proximity_reveal: bool,
+fn svg_color(color: egui::Color32) -> String {
+    let [r, g, b, a] = color.to_srgba_unmultiplied();
+    if a == 255 {
+        format!("#{r:02x}{g:02x}{b:02x}")
+    } else {
+        format!("rgba({r},{g},{b},{:.3})", a as f32 / 255.0)
+    }
 }
 
-impl Default for InlineRenderConfig {
-    fn default() -> Self {
-        Self {
-            enabled: true,
-            animate_transitions: true,
-            spring_preset: SpringPreset::Smooth,
-            max_concurrent_animations: 200,
-            proximity_reveal: false,
-        }
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineStyle {
+    Solid,
+    Dashed,
+    Dotted,
+    Thick,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
-enum SpringPreset {
-    Snappy,
-    Smooth,
-    Gentle,
-    Bouncy,
-    Stiff,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArrowHead {
     None,
+    Filled,
+    Open,
+    Cross,
+    Circle,
+    Triangle,
+    Diamond,
+    FilledDiamond,
 }
 
-#[derive(Debug, Clone)]
-struct Spring {
-    stiffness: f64,
-    damping: f64,
-    mass: f64,
-    velocity: f64,
-    current: f64,
-    target: f64,
-    rest_threshold: f64,
+struct SvgBuilder {
+    body: String,
+    palette: DiagramPalette,
 }
 
-impl Spring {
-    fn new(stiffness: f64, damping: f64, mass: f64) -> Self {
+impl SvgBuilder {
+    fn new(palette: DiagramPalette) -> Self {
         Self {
-            stiffness,
-            damping,
-            mass,
-            velocity: 0.0,
-            current: 0.0,
-            target: 0.0,
-            rest_threshold: 0.0001,
+            body: String::new(),
+            palette,
         }
     }
 
-    fn tick(&mut self, dt: f64) -> f64 {
-        let force = -self.stiffness * (self.current - self.target);
-        let damping_force = -self.damping * self.velocity;
-        let acceleration = (force + damping_force) / self.mass;
-        self.velocity += acceleration * dt;
-        self.current += self.velocity * dt;
+    fn finish(self, bounds: egui::Rect) -> String {
+        let (x, y) = (bounds.min.x.floor(), bounds.min.y.floor());
+        let (w, h) = ((bounds.max.x - x).ceil(), (bounds.max.y - y).ceil());
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"{x} {y} {w} {h}\">\n{}</svg>\n",
+            self.body
+        )
+    }
 
-        if (self.current - self.target).abs() < self.rest_threshold
-            && self.velocity.abs() < self.rest_threshold
-        {
-            self.current = self.target;
-            self.velocity = 0.0;
+    fn stroke_attrs(&self, color: egui::Color32, style: LineStyle) -> String {
+        let (width, dash) = match style {
+            LineStyle::Solid => (1.3, ""),
+            LineStyle::Thick => (2.6, ""),
+            LineStyle::Dashed => (1.3, " stroke-dasharray=\"6 4\""),
+            LineStyle::Dotted => (1.3, " stroke-dasharray=\"2 3\""),
+        };
+        format!(
+            "stroke=\"{}\" stroke-width=\"{width}\"{dash}",
+            svg_color(color)
+        )
+    }
+
+    fn rect(&mut self, rect: egui::Rect, radius: f32, fill: egui::Color32, style: LineStyle) {
+        let stroke = self.stroke_attrs(self.palette.line, style);
+        self.body.push_str(&format!(
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" rx=\"{radius:.1}\" fill=\"{}\" {stroke}/>\n",
+            rect.min.x,
+            rect.min.y,
+            rect.width(),
+            rect.height(),
+            svg_color(fill),
+        ));
+    }
+
+    fn ellipse(&mut self, center: egui::Pos2, radius: egui::Vec2, fill: egui::Color32) {
+        let stroke = self.stroke_attrs(self.palette.line, LineStyle::Solid);
+        self.body.push_str(&format!(
+            "<ellipse cx=\"{:.1}\" cy=\"{:.1}\" rx=\"{:.1}\" ry=\"{:.1}\" fill=\"{}\" {stroke}/>\n",
+            center.x,
+            center.y,
+            radius.x,
+            radius.y,
+            svg_color(fill),
+        ));
+    }
+
+    fn polygon(&mut self, points: &[egui::Pos2], fill: egui::Color32, style: LineStyle) {
+        let stroke = self.stroke_attrs(self.palette.line, style);
+        let points: Vec<String> = points
+            .iter()
+            .map(|p| format!("{:.1},{:.1}", p.x, p.y))
+            .collect();
+        self.body.push_str(&format!(
+            "<polygon points=\"{}\" fill=\"{}\" stroke-linejoin=\"round\" {stroke}/>\n",
+            points.join(" "),
+            svg_color(fill),
+        ));
+    }
+
+    fn polyline(&mut self, points: &[egui::Pos2], color: egui::Color32, style: LineStyle) {
+        if points.len() < 2 {
+            return;
+        }
+        let stroke = self.stroke_attrs(color, style);
+        let points: Vec<String> = points
+            .iter()
+            .map(|p| format!("{:.1},{:.1}", p.x, p.y))
+            .collect();
+        self.body.push_str(&format!(
+            "<polyline points=\"{}\" fill=\"none\" stroke-linejoin=\"round\" {stroke}/>\n",
+            points.join(" "),
+        ));
+    }
+
+    fn text(&mut self, pos: egui::Pos2, lines: &[String], anchor: &str, bold: bool) {
+        let top = pos.y - lines.len() as f32 * DIAGRAM_LINE_HEIGHT / 2.0;
+        let weight = if bold { " font-weight=\"bold\"" } else { "" };
+        for (idx, line) in lines.iter().enumerate() {
+            let baseline =
+                top + (idx as f32 + 0.5) * DIAGRAM_LINE_HEIGHT + DIAGRAM_FONT_SIZE * 0.35;
+            self.body.push_str(&format!(
+                "<text x=\"{:.1}\" y=\"{baseline:.1}\" font-family=\"{DIAGRAM_FONT}\" font-size=\"{DIAGRAM_FONT_SIZE}\" fill=\"{}\" text-anchor=\"{anchor}\"{weight}>{}</text>\n",
+                pos.x,
+                svg_color(self.palette.text),
+                xml_escape(line),
+            ));
+        }
+    }
+
+    fn label(&mut self, center: egui::Pos2, lines: &[String]) {
+        let size = diagram_text_size(lines) + egui::vec2(8.0, 2.0);
+        let plate = egui::Rect::from_center_size(center, size);
+        self.body.push_str(&format!(
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"{}\" fill-opacity=\"0.85\"/>\n",
+            plate.min.x,
+            plate.min.y,
+            plate.width(),
+            plate.height(),
+            svg_color(self.palette.background),
+        ));
+        self.text(center, lines, "middle", false);
+    }
+
+    fn edge(&mut self, points: &[egui::Pos2], style: LineStyle, start: ArrowHead, end: ArrowHead) {
+        if points.len() < 2 {
+            return;
+        }
+        let mut line = points.to_vec();
+        let last = line.len() - 1;
+        line[0] = self.arrow_head(points[1], points[0], start);
+        line[last] = self.arrow_head(points[last - 1], points[last], end);
+        self.polyline(&line, self.palette.line, style);
+    }
+
+    fn arrow_head(&mut self, from: egui::Pos2, tip: egui::Pos2, head: ArrowHead) -> egui::Pos2 {
+        let dir = (tip - from).normalized();
+        if head == ArrowHead::None || !dir.x.is_finite() {
+            return tip;
+        }
+        let normal = egui::vec2(-dir.y, dir.x);
+        let line = self.palette.line;
+        let background = self.palette.background;
+        match head {
+            ArrowHead::None => tip,
+            ArrowHead::Filled => {
+                let base = tip - dir * 10.0;
+                self.polygon(
+                    &[tip, base + normal * 4.5, base - normal * 4.5],
+                    line,
+                    LineStyle::Solid,
+                );
+                base
+            }
+            ArrowHead::Open => {
+                let base = tip - dir * 10.0;
+                self.polyline(
+                    &[base + normal * 5.0, tip, base - normal * 5.0],
+                    line,
+                    LineStyle::Solid,
+                );
+                tip
+            }
+            ArrowHead::Cross => {
+                let center = tip - dir * 6.0;
+                let (a, b) = (dir + normal, dir - normal);
+                self.polyline(
+                    &[center - a * 4.0, center + a * 4.0],
+                    line,
+                    LineStyle::Solid,
+                );
+                self.polyline(
+                    &[center - b * 4.0, center + b * 4.0],
+                    line,
+                    LineStyle::Solid,
+                );
+                tip
+            }
+            ArrowHead::Circle => {
+                let center = tip - dir * 5.0;
+                self.ellipse(center, egui::vec2(4.0, 4.0), background);
+                center - dir * 4.0
+            }
+            ArrowHead::Triangle => {
+                let base = tip - dir * 14.0;
+                self.polygon(
+                    &[tip, base + normal * 7.0, base - normal * 7.0],
+                    background,
+                    LineStyle::Solid,
+                );
+                base
+            }
+            ArrowHead::Diamond | ArrowHead::FilledDiamond => {
+                let mid = tip - dir * 9.0;
+                let back = tip - dir * 18.0;
+                let fill = if head == ArrowHead::Diamond {
+                    background
+                } else {
+                    line
+                };
+                self.polygon(
+                    &[tip, mid + normal * 5.5, back, mid - normal * 5.5],
+                    fill,
+                    LineStyle::Solid,
+                );
+                back
+            }
         }
-
-        self.current
     }
 }
 
-struct AnimationPresets;
+fn clip_to_shape(rect: egui::Rect, toward: egui::Pos2, shape: NodeShape) -> egui::Pos2 {
+    let center = rect.center();
+    let d = toward - center;
+    if d.length_sq() < 1e-3 {
+        return center;
+    }
+    let (hw, hh) = (rect.width() / 2.0, rect.height() / 2.0);
+    let t = match shape {
+        NodeShape::Circle => hw.min(hh) / d.length(),
+        NodeShape::Diamond => 1.0 / (d.x.abs() / hw + d.y.abs() / hh),
+        NodeShape::Ellipse => 1.0 / ((d.x / hw).powi(2) + (d.y / hh).powi(2)).sqrt(),
+        _ => (hw / d.x.abs()).min(hh / d.y.abs()),
+    };
+    center + d * t.min(1.0)
+}
 
-impl AnimationPresets {
-    fn spring(preset: SpringPreset) -> Spring {
-        match preset {
-            SpringPreset::Snappy => Spring::new(400.0, 30.0, 1.0),
-            SpringPreset::Smooth => Spring::new(300.0, 25.0, 1.0),
-            SpringPreset::Gentle => Spring::new(200.0, 22.0, 1.0),
-            SpringPreset::Bouncy => Spring::new(400.0, 15.0, 1.0),
-            SpringPreset::Stiff => Spring::new(500.0, 35.0, 1.0),
-            SpringPreset::None => Spring::new(1_000_000.0, 10_000.0, 1.0),
-        }
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NodeShape {
+    Rect,
+    Round,
+    Stadium,
+    Circle,
+    Diamond,
+    Hexagon,
+    Parallelogram,
+    Cylinder,
+    Subroutine,
+    Flag,
+    Ellipse,
+    Plain,
 }
 
-#[derive(Debug, Clone, Default)]
-struct PositionMap {
-    rendered_to_source: Vec<(usize, usize)>,
-    source_to_rendered: Vec<(usize, usize)>,
+fn node_size(shape: NodeShape, text: egui::Vec2) -> egui::Vec2 {
+    let padded = text + egui::vec2(30.0, 16.0);
+    match shape {
+        NodeShape::Circle => {
+            let d = text.length() + 16.0;
+            egui::vec2(d, d)
+        }
+        NodeShape::Diamond => egui::vec2(text.x * 1.4 + 28.0, text.y * 1.6 + 26.0),
+        NodeShape::Hexagon | NodeShape::Parallelogram | NodeShape::Flag => {
+            padded + egui::vec2(24.0, 0.0)
+        }
+        NodeShape::Cylinder => padded + egui::vec2(0.0, 14.0),
+        NodeShape::Ellipse => text * 1.25 + egui::vec2(24.0, 14.0),
+        NodeShape::Plain => text + egui::vec2(8.0, 4.0),
+        _ => padded,
+    }
 }
 
-impl PositionMap {
-    fn for_wrapped_delimiters(source: &str, left_delim: usize, right_delim: usize) -> Self {
-        let mut map = PositionMap::default();
-        let visible_start = left_delim.min(source.chars().count());
-        let visible_end = source.chars().count().saturating_sub(right_delim);
-        for rendered in 0..visible_end.saturating_sub(visible_start) {
-            let source_col = rendered + visible_start;
-            map.rendered_to_source.push((rendered, source_col));
-            map.source_to_rendered.push((source_col, rendered));
+fn draw_node(svg: &mut SvgBuilder, rect: egui::Rect, shape: NodeShape, lines: &[String]) {
+    let fill = svg.palette.fill;
+    let (l, r, t, b) = (rect.left(), rect.right(), rect.top(), rect.bottom());
+    let c = rect.center();
+    let p = egui::pos2;
+    match shape {
+        NodeShape::Rect => svg.rect(rect, 2.0, fill, LineStyle::Solid),
+        NodeShape::Round => svg.rect(rect, 8.0, fill, LineStyle::Solid),
+        NodeShape::Stadium => svg.rect(rect, rect.height() / 2.0, fill, LineStyle::Solid),
+        NodeShape::Ellipse => svg.ellipse(c, rect.size() / 2.0, fill),
+        NodeShape::Plain => {}
+        NodeShape::Circle => {
+            let radius = rect.width().min(rect.height()) / 2.0;
+            svg.ellipse(c, egui::vec2(radius, radius), fill);
+        }
+        NodeShape::Diamond => svg.polygon(
+            &[p(c.x, t), p(r, c.y), p(c.x, b), p(l, c.y)],
+            fill,
+            LineStyle::Solid,
+        ),
+        NodeShape::Hexagon => svg.polygon(
+            &[
+                p(l + 12.0, t),
+                p(r - 12.0, t),
+                p(r, c.y),
+                p(r - 12.0, b),
+                p(l + 12.0, b),
+                p(l, c.y),
+            ],
+            fill,
+            LineStyle::Solid,
+        ),
+        NodeShape::Parallelogram => svg.polygon(
+            &[p(l + 12.0, t), p(r, t), p(r - 12.0, b), p(l, b)],
+            fill,
+            LineStyle::Solid,
+        ),
+        NodeShape::Flag => svg.polygon(
+            &[p(l, t), p(r, t), p(r, b), p(l, b), p(l + 12.0, c.y)],
+            fill,
+            LineStyle::Solid,
+        ),
+        NodeShape::Cylinder => {
+            let ry = 7.0;
+            svg.rect(
+                egui::Rect::from_min_max(p(l, t + ry), p(r, b - ry)),
+                0.0,
+                fill,
+                LineStyle::Solid,
+            );
+            svg.ellipse(p(c.x, b - ry), egui::vec2(rect.width() / 2.0, ry), fill);
+            svg.rect(
+                egui::Rect::from_min_max(p(l + 0.7, t + ry), p(r - 0.7, b - ry)),
+                0.0,
+                fill,
+                LineStyle::Solid,
+            );
+            svg.ellipse(p(c.x, t + ry), egui::vec2(rect.width() / 2.0, ry), fill);
+        }
+        NodeShape::Subroutine => {
+            svg.rect(rect, 0.0, fill, LineStyle::Solid);
+            let line = svg.palette.line;
+            svg.polyline(&[p(l + 8.0, t), p(l + 8.0, b)], line, LineStyle::Solid);
+            svg.polyline(&[p(r - 8.0, t), p(r - 8.0, b)], line, LineStyle::Solid);
         }
-        map
     }
+    svg.text(c, lines, "middle", false);
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(default)]
-struct PersistedState {
-    settings: ViewerSettings,
-    recent_files: Vec<PathBuf>,
-    open_files: Vec<PathBuf>,
-    active_file: Option<PathBuf>,
-    slash_tracker: SlashCommandTracker,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FlowDirection {
+    TopDown,
+    BottomUp,
+    LeftRight,
+    RightLeft,
 }
 
-impl Default for PersistedState {
-    fn default() -> Self {
-        Self {
-            settings: ViewerSettings::default(),
-            recent_files: Vec::new(),
-            open_files: Vec::new(),
-            active_file: None,
-            slash_tracker: SlashCommandTracker::default(),
+impl FlowDirection {
+    fn parse(text: &str) -> Option<Self> {
+        match text.trim().to_ascii_uppercase().as_str() {
+            "TB" | "TD" => Some(Self::TopDown),
+            "BT" => Some(Self::BottomUp),
+            "LR" => Some(Self::LeftRight),
+            "RL" => Some(Self::RightLeft),
+            _ => None,
         }
     }
-}
 
-impl PersistedState {
-    fn remember_file(&mut self, path: PathBuf) {
-        self.recent_files.retain(|p| p != &path);
-        self.recent_files.insert(0, path);
-        if self.recent_files.len() > MAX_RECENT_FILES {
-            self.recent_files.truncate(MAX_RECENT_FILES);
-        }
+    fn horizontal(self) -> bool {
+        matches!(self, Self::LeftRight | Self::RightLeft)
     }
 }
 
-fn preprocess_markdown(
-    input: &str,
-    settings: &ViewerSettings,
-    github_repo: Option<&GithubRepo>,
-) -> String {
-    let mut out = String::with_capacity(input.len() + 256);
-    let mut fence: Option<FenceState> = None;
+#[derive(Debug, Clone)]
+struct LayoutEdge {
+    from: usize,
+    to: usize,
+    label: Option<egui::Vec2>,
+}
 
-    for chunk in input.split_inclusive('\n') {
-        if let Some(state) = &mut fence {
-            if is_fence_closing_line(chunk, state) {
-                flush_fence(&mut out, state, chunk, settings);
-                fence = None;
+#[derive(Debug, Clone, Default)]
+struct GraphLayout {
+    nodes: Vec<egui::Rect>,
+    edges: Vec<Vec<egui::Pos2>>,
+    labels: Vec<Option<egui::Pos2>>,
+    size: egui::Vec2,
+}
+
+fn layout_graph(
+    sizes: &[egui::Vec2],
+    edges: &[LayoutEdge],
+    direction: FlowDirection,
+) -> GraphLayout {
+    const NODE_GAP: f32 = 28.0;
+    const DUMMY_GAP: f32 = 14.0;
+    const MARGIN: f32 = 12.0;
+
+    let n = sizes.len();
+    let horizontal = direction.horizontal();
+    let cross_of = |size: egui::Vec2| if horizontal { size.y } else { size.x };
+    let main_of = |size: egui::Vec2| if horizontal { size.x } else { size.y };
+    let rank_gap: f32 = if horizontal { 56.0 } else { 44.0 };
+
+    // Reverse edges that close a cycle, found by depth-first search.
+    let mut outgoing = vec![Vec::new(); n];
+    for (idx, edge) in edges.iter().enumerate() {
+        if edge.from != edge.to {
+            outgoing[edge.from].push(idx);
+        }
+    }
+    let mut reversed = vec![false; edges.len()];
+    let mut state = vec![0u8; n];
+    for root in 0..n {
+        if state[root] != 0 {
+            continue;
+        }
+        let mut stack = vec![(root, 0usize)];
+        state[root] = 1;
+        while let Some((node, next)) = stack.last_mut() {
+            if let Some(&edge) = outgoing[*node].get(*next) {
+                *next += 1;
+                let target = edges[edge].to;
+                match state[target] {
+                    0 => {
+                        state[target] = 1;
+                        stack.push((target, 0));
+                    }
+                    1 => reversed[edge] = true,
+                    _ => {}
+                }
             } else {
-                state.content.push_str(chunk);
+                state[*node] = 2;
+                stack.pop();
             }
-            continue;
         }
+    }
+    let oriented = |idx: usize| {
+        let edge = &edges[idx];
+        if reversed[idx] {
+            (edge.to, edge.from)
+        } else {
+            (edge.from, edge.to)
+        }
+    };
 
-        if let Some(state) = parse_fence_opening_line(chunk) {
-            fence = Some(state);
+    let mut indegree = vec![0usize; n];
+    let mut succ = vec![Vec::new(); n];
+    for idx in 0..edges.len() {
+        let (from, to) = oriented(idx);
+        if from != to {
+            indegree[to] += 1;
+            succ[from].push(to);
+        }
+    }
+    let mut rank = vec![0usize; n];
+    let mut queue: VecDeque<usize> = (0..n).filter(|&v| indegree[v] == 0).collect();
+    while let Some(v) = queue.pop_front() {
+        for &w in &succ[v] {
+            rank[w] = rank[w].max(rank[v] + 1);
+            indegree[w] -= 1;
+            if indegree[w] == 0 {
+                queue.push_back(w);
+            }
+        }
+    }
+    let rank_count = rank.iter().max().map_or(0, |r| r + 1);
+
+    // Layered graph: real nodes first, then one dummy per rank crossed by a long edge.
+    let mut node_rank = rank.clone();
+    let mut node_cross: Vec<f32> = sizes.iter().map(|&s| cross_of(s)).collect();
+    let mut chains: Vec<Vec<usize>> = Vec::with_capacity(edges.len());
+    let mut gaps = vec![rank_gap; rank_count];
+    for (idx, edge) in edges.iter().enumerate() {
+        let (from, to) = oriented(idx);
+        if from == to {
+            chains.push(Vec::new());
             continue;
         }
+        let mut chain = vec![from];
+        for r in rank[from] + 1..rank[to] {
+            node_rank.push(r);
+            node_cross.push(edge.label.map_or(0.0, cross_of).max(2.0));
+            chain.push(node_rank.len() - 1);
+        }
+        chain.push(to);
+        if let Some(label) = edge.label {
+            let r = rank[from];
+            gaps[r] = gaps[r].max(main_of(label) + 24.0);
+        }
+        chains.push(chain);
+    }
+    let total = node_rank.len();
+    let mut preds = vec![Vec::new(); total];
+    let mut succs = vec![Vec::new(); total];
+    for chain in &chains {
+        for pair in chain.windows(2) {
+            succs[pair[0]].push(pair[1]);
+            preds[pair[1]].push(pair[0]);
+        }
+    }
 
-        out.push_str(&process_inline_line(chunk, settings, github_repo));
+    // Order within ranks: barycenter sweeps, keeping the ordering with fewest crossings.
+    let mut layers: Vec<Vec<usize>> = vec![Vec::new(); rank_count];
+    for v in 0..total {
+        layers[node_rank[v]].push(v);
+    }
+    let positions = |layers: &[Vec<usize>]| {
+        let mut pos = vec![0usize; total];
+        for layer in layers {
+            for (i, &v) in layer.iter().enumerate() {
+                pos[v] = i;
+            }
+        }
+        pos
+    };
+    let crossings = |layers: &[Vec<usize>]| {
+        let pos = positions(layers);
+        let mut count = 0usize;
+        for layer in layers {
+            let segments: Vec<(usize, usize)> = layer
+                .iter()
+                .flat_map(|&v| succs[v].iter().map(move |&w| (v, w)))
+                .map(|(v, w)| (pos[v], pos[w]))
+                .collect();
+            for (i, a) in segments.iter().enumerate() {
+                for b in &segments[i + 1..] {
+                    if (a.0 < b.0 && a.1 > b.1) || (a.0 > b.0 && a.1 < b.1) {
+                        count += 1;
+                    }
+                }
+            }
+        }
+        count
+    };
+    let mut best = layers.clone();
+    let mut best_crossings = crossings(&layers);
+    for iteration in 0..12 {
+        let pos = positions(&layers);
+        let downward = iteration % 2 == 0;
+        let range: Vec<usize> = if downward {
+            (1..rank_count).collect()
+        } else {
+            (0..rank_count.saturating_sub(1)).rev().collect()
+        };
+        let mut pos = pos;
+        for r in range {
+            let neighbours = if downward { &preds } else { &succs };
+            let mut keyed: Vec<(f32, usize)> = layers[r]
+                .iter()
+                .map(|&v| {
+                    let adjacent = &neighbours[v];
+                    let key = if adjacent.is_empty() {
+                        pos[v] as f32
+                    } else {
+                        adjacent.iter().map(|&u| pos[u] as f32).sum::<f32>() / adjacent.len() as f32
+                    };
+                    (key, v)
+                })
+                .collect();
+            keyed.sort_by(|a, b| a.0.total_cmp(&b.0));
+            layers[r] = keyed.into_iter().map(|(_, v)| v).collect();
+            for (i, &v) in layers[r].iter().enumerate() {
+                pos[v] = i;
+            }
+        }
+        let count = crossings(&layers);
+        if count < best_crossings {
+            best_crossings = count;
+            best = layers.clone();
+        }
     }
+    let layers = best;
 
-    if let Some(state) = fence {
-        out.push_str(&format!(
-            "{indent}{fence}{info}\n{content}",
-            indent = state.indent,
-            fence = state.fence(),
-            info = state.info,
-            content = state.content
-        ));
+    let mut rank_size = vec![0.0f32; rank_count];
+    for v in 0..n {
+        rank_size[rank[v]] = rank_size[rank[v]].max(main_of(sizes[v]));
+    }
+    let mut rank_center = vec![0.0f32; rank_count];
+    let mut cursor = 0.0;
+    for r in 0..rank_count {
+        rank_center[r] = cursor + rank_size[r] / 2.0;
+        cursor += rank_size[r] + gaps[r];
     }
 
-    out
+    // Cross-axis placement: pack, then pull nodes towards their neighbours while keeping
+    // the order and minimum gaps.
+    let gap_after = |v: usize, w: usize| {
+        let gap = if v >= n || w >= n {
+            DUMMY_GAP
+        } else {
+            NODE_GAP
+        };
+        (node_cross[v] + node_cross[w]) / 2.0 + gap
+    };
+    let mut cross = vec![0.0f32; total];
+    for layer in &layers {
+        let mut at = 0.0;
+        for (i, &v) in layer.iter().enumerate() {
+            if i > 0 {
+                at += gap_after(layer[i - 1], v);
+            }
+            cross[v] = at;
+        }
+    }
+    for iteration in 0..16 {
+        let downward = iteration % 2 == 0;
+        let order: Vec<usize> = if downward {
+            (0..rank_count).collect()
+        } else {
+            (0..rank_count).rev().collect()
+        };
+        for r in order {
+            let layer = &layers[r];
+            let desired: Vec<f32> = layer
+                .iter()
+                .map(|&v| {
+                    let adjacent: Vec<usize> = preds[v].iter().chain(&succs[v]).copied().collect();
+                    if adjacent.is_empty() {
+                        cross[v]
+                    } else {
+                        adjacent.iter().map(|&u| cross[u]).sum::<f32>() / adjacent.len() as f32
+                    }
+                })
+                .collect();
+            let mut forward = desired.clone();
+            for i in 1..layer.len() {
+                forward[i] = forward[i].max(forward[i - 1] + gap_after(layer[i - 1], layer[i]));
+            }
+            let mut backward = desired;
+            for i in (0..layer.len().saturating_sub(1)).rev() {
+                backward[i] = backward[i].min(backward[i + 1] - gap_after(layer[i], layer[i + 1]));
+            }
+            for (i, &v) in layer.iter().enumerate() {
+                cross[v] = (forward[i] + backward[i]) / 2.0;
+            }
+        }
+    }
+
+    let total_main = cursor - gaps.last().copied().unwrap_or(0.0);
+    let to_point = |main: f32, cross: f32| match direction {
+        FlowDirection::TopDown => egui::pos2(cross, main),
+        FlowDirection::BottomUp => egui::pos2(cross, total_main - main),
+        FlowDirection::LeftRight => egui::pos2(main, cross),
+        FlowDirection::RightLeft => egui::pos2(total_main - main, cross),
+    };
+    let center_of = |v: usize| to_point(rank_center[node_rank[v]], cross[v]);
+
+    let mut layout = GraphLayout {
+        nodes: (0..n)
+            .map(|v| egui::Rect::from_center_size(center_of(v), sizes[v]))
+            .collect(),
+        edges: Vec::with_capacity(edges.len()),
+        labels: Vec::with_capacity(edges.len()),
+        size: egui::Vec2::ZERO,
+    };
+    for (idx, chain) in chains.iter().enumerate() {
+        let edge = &edges[idx];
+        if chain.is_empty() {
+            let rect = layout.nodes[edge.from];
+            let (a, b, out) = if horizontal {
+                let out = egui::vec2(0.0, 18.0);
+                (
+                    rect.center_bottom() - egui::vec2(8.0, 0.0),
+                    rect.center_bottom() + egui::vec2(8.0, 0.0),
+                    out,
+                )
+            } else {
+                let out = egui::vec2(18.0, 0.0);
+                (
+                    rect.right_center() - egui::vec2(0.0, 8.0),
+                    rect.right_center() + egui::vec2(0.0, 8.0),
+                    out,
+                )
+            };
+            layout.edges.push(vec![a, a + out, b + out, b]);
+            layout.labels.push(edge.label.map(|label| {
+                (a + out).lerp(b + out, 0.5)
+                    + out.normalized() * (cross_of(label).max(main_of(label)) / 2.0 + 4.0)
+            }));
+            continue;
+        }
+        let mut points: Vec<egui::Pos2> = chain.iter().map(|&v| center_of(v)).collect();
+        let label = edge.label.map(|_| {
+            let r = node_rank[chain[0]];
+            let mid_main = rank_center[r] + rank_size[r] / 2.0 + gaps[r] / 2.0;
+            let (a, b) = (chain[0], chain[1]);
+            let span = rank_center[node_rank[b]] - rank_center[r];
+            let t = ((mid_main - rank_center[r]) / span.max(1.0)).clamp(0.0, 1.0);
+            to_point(mid_main, cross[a] + (cross[b] - cross[a]) * t)
+        });
+        if reversed[idx] {
+            points.reverse();
+        }
+        layout.edges.push(points);
+        layout.labels.push(label);
+    }
+
+    let mut bounds = egui::Rect::NOTHING;
+    for rect in &layout.nodes {
+        bounds = bounds.union(*rect);
+    }
+    for point in layout.edges.iter().flatten() {
+        bounds.extend_with(*point);
+    }
+    for (label, edge) in layout.labels.iter().zip(edges) {
+        if let (Some(pos), Some(size)) = (label, edge.label) {
+            bounds = bounds.union(egui::Rect::from_center_size(
+                *pos,
+                size + egui::vec2(8.0, 2.0),
+            ));
+        }
+    }
+    if !bounds.is_positive() {
+        bounds = egui::Rect::from_min_size(egui::Pos2::ZERO, egui::Vec2::ZERO);
+    }
+    let shift = egui::vec2(MARGIN, MARGIN) - bounds.min.to_vec2();
+    for rect in &mut layout.nodes {
+        *rect = rect.translate(shift);
+    }
+    for point in layout.edges.iter_mut().flatten() {
+        *point += shift;
+    }
+    for pos in layout.labels.iter_mut().flatten() {
+        *pos += shift;
+    }
+    layout.size = bounds.size() + egui::vec2(2.0 * MARGIN, 2.0 * MARGIN);
+    layout
 }
 
-#[derive(Debug, Clone)]
-struct FenceState {
-    indent: String,
-    marker: char,
-    marker_len: usize,
-    info: String,
-    content: String,
+fn clip_route(
+    mut points: Vec<egui::Pos2>,
+    from: (egui::Rect, NodeShape),
+    to: (egui::Rect, NodeShape),
+) -> Vec<egui::Pos2> {
+    if points.len() < 2 || from.0 == to.0 {
+        return points;
+    }
+    let last = points.len() - 1;
+    points[0] = clip_to_shape(from.0, points[1], from.1);
+    points[last] = clip_to_shape(to.0, points[last - 1], to.1);
+    points
 }
 
-impl FenceState {
-    fn fence(&self) -> String {
-        std::iter::repeat(self.marker)
-            .take(self.marker_len)
-            .collect()
+fn render_mermaid_offline(
+    source: &str,
+    palette: DiagramPalette,
+) -> std::result::Result<String, String> {
+    let lines = mermaid_statement_lines(source);
+    let Some((_, header)) = lines.first() else {
+        return Err("empty diagram".to_string());
+    };
+    let kind = header.split_whitespace().next().unwrap_or_default();
+    match kind {
+        "graph" | "flowchart" | "flowchart-elk" => {
+            let chart = parse_flowchart(&lines)?;
+            Ok(render_flowchart(&chart, palette))
+        }
+        "sequenceDiagram" => {
+            let diagram = parse_sequence_diagram(&lines[1..])?;
+            Ok(render_sequence_diagram(&diagram, palette))
+        }
+        "classDiagram" | "classDiagram-v2" => {
+            let diagram = parse_class_diagram(&lines[1..])?;
+            Ok(render_class_diagram(&diagram, palette))
+        }
+        _ => Err(format!("`{kind}` diagrams can't be rendered offline")),
     }
 }
 
-fn parse_fence_opening_line(chunk: &str) -> Option<FenceState> {
-    let line = chunk.strip_suffix('\n').unwrap_or(chunk);
-    let (indent, rest) = line.split_at(line.len() - line.trim_start_matches([' ', '\t']).len());
-    let mut chars = rest.chars();
-    let marker = chars.next()?;
-    if marker != '`' && marker != '~' {
-        return None;
+fn mermaid_statement_lines(source: &str) -> Vec<(usize, String)> {
+    let mut out = Vec::new();
+    let mut in_front_matter = false;
+    for (idx, line) in source.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed == "---" && (in_front_matter || out.is_empty()) {
+            in_front_matter = !in_front_matter;
+            continue;
+        }
+        if in_front_matter || trimmed.is_empty() || trimmed.starts_with("%%") {
+            continue;
+        }
+        out.push((idx + 1, trimmed.to_string()));
     }
+    out
+}
 
-    let marker_len = rest.chars().take_while(|&c| c == marker).count();
-    if marker_len < 3 {
-        return None;
-    }
+#[derive(Debug, Clone)]
+struct FlowNode {
+    id: String,
+    label: Vec<String>,
+    shape: NodeShape,
+    hidden: bool,
+}
 
-    let after_markers = &rest[marker_len..];
-    let info = after_markers.trim().to_string();
+#[derive(Debug, Clone)]
+struct FlowEdge {
+    from: usize,
+    to: usize,
+    label: Option<Vec<String>>,
+    style: LineStyle,
+    start: ArrowHead,
+    end: ArrowHead,
+    hidden: bool,
+}
 
-    Some(FenceState {
-        indent: indent.to_string(),
-        marker,
-        marker_len,
-        info,
-        content: String::new(),
-    })
+#[derive(Debug, Clone)]
+struct FlowSubgraph {
+    title: Vec<String>,
+    members: Vec<usize>,
 }
 
-fn is_fence_closing_line(chunk: &str, state: &FenceState) -> bool {
-    let line = chunk.strip_suffix('\n').unwrap_or(chunk);
-    let rest = line.trim_start_matches([' ', '\t']);
-    if !rest.starts_with(state.marker) {
-        return false;
+#[derive(Debug, Clone)]
+struct Flowchart {
+    direction: FlowDirection,
+    nodes: Vec<FlowNode>,
+    edges: Vec<FlowEdge>,
+    subgraphs: Vec<FlowSubgraph>,
+}
+
+impl Flowchart {
+    fn node(&mut self, id: &str) -> usize {
+        if let Some(idx) = self.nodes.iter().position(|node| node.id == id) {
+            return idx;
+        }
+        self.nodes.push(FlowNode {
+            id: id.to_string(),
+            label: vec![id.to_string()],
+            shape: NodeShape::Rect,
+            hidden: false,
+        });
+        self.nodes.len() - 1
     }
-    let run_len = rest.chars().take_while(|&c| c == state.marker).count();
-    run_len >= state.marker_len
 }
 
-fn flush_fence(
-    out: &mut String,
-    state: &FenceState,
-    closing_line: &str,
-    settings: &ViewerSettings,
-) {
-    let raw_lang = state.info.split_whitespace().next().unwrap_or("");
-    if settings.render_mermaid && raw_lang.eq_ignore_ascii_case("mermaid") {
-        out.push_str(&state.indent);
-        out.push_str("<div class=\"mermaid\">\n");
-        out.push_str(&state.content);
-        if !state.content.ends_with('\n') {
-            out.push('\n');
+fn parse_flowchart(lines: &[(usize, String)]) -> std::result::Result<Flowchart, String> {
+    // `graph LR; A-->B` puts statements on the header line too.
+    let (first_line, first) = &lines[0];
+    let (header, first_statements) = first.split_once(';').unwrap_or((first, ""));
+    let direction = header
+        .split_whitespace()
+        .nth(1)
+        .map(|dir| FlowDirection::parse(dir).ok_or(format!("unknown direction `{dir}`")))
+        .transpose()?
+        .unwrap_or(FlowDirection::TopDown);
+    let mut chart = Flowchart {
+        direction,
+        nodes: Vec::new(),
+        edges: Vec::new(),
+        subgraphs: Vec::new(),
+    };
+    let mut open_subgraphs: Vec<usize> = Vec::new();
+
+    let statements = std::iter::once((first_line, first_statements)).chain(
+        lines[1..]
+            .iter()
+            .map(|(line_no, line)| (line_no, line.as_str())),
+    );
+    for (line_no, line) in statements {
+        for statement in split_outside_quotes(line, ';') {
+            let statement = statement.trim();
+            if statement.is_empty() {
+                continue;
+            }
+            let keyword = statement.split_whitespace().next().unwrap_or_default();
+            match keyword {
+                "subgraph" => {
+                    let rest = statement["subgraph".len()..].trim();
+                    let title = match rest.find(['[', '"']) {
+                        Some(open) => {
+                            unquote(rest[open..].trim_start_matches('[').trim_end_matches(']'))
+                        }
+                        None => rest,
+                    };
+                    chart.subgraphs.push(FlowSubgraph {
+                        title: diagram_label_lines(title),
+                        members: Vec::new(),
+                    });
+                    open_subgraphs.push(chart.subgraphs.len() - 1);
+                    continue;
+                }
+                "end" => {
+                    if let (Some(inner), Some(&outer)) =
+                        (open_subgraphs.pop(), open_subgraphs.last())
+                    {
+                        let members = chart.subgraphs[inner].members.clone();
+                        chart.subgraphs[outer].members.extend(members);
+                    }
+                    continue;
+                }
+                "direction" | "style" | "classDef" | "class" | "click" | "linkStyle" => {
+                    continue;
+                }
+                _ => {}
+            }
+            let before = chart.nodes.len();
+            parse_flow_statement(&mut chart, statement)
+                .map_err(|err| format!("line {line_no}: {err}"))?;
+            if let Some(&subgraph) = open_subgraphs.last() {
+                chart.subgraphs[subgraph]
+                    .members
+                    .extend(before..chart.nodes.len());
+            }
+        }
+    }
+    Ok(chart)
+}
+
+fn split_outside_quotes(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (idx, ch) in text.char_indices() {
+        if ch == '"' {
+            in_quotes = !in_quotes;
+        } else if ch == separator && !in_quotes {
+            parts.push(&text[start..idx]);
+            start = idx + ch.len_utf8();
         }
-        out.push_str(&state.indent);
-        out.push_str("</div>\n");
-        return;
     }
+    parts.push(&text[start..]);
+    parts
+}
 
-    let mut info = raw_lang.to_string();
-    info = normalize_fence_language(&info).unwrap_or(info);
+fn unquote(text: &str) -> &str {
+    let text = text.trim();
+    text.strip_prefix('"')
+        .and_then(|t| t.strip_suffix('"'))
+        .unwrap_or(text)
+}
 
-    if info.is_empty() && settings.auto_detect_code_lang {
-        if let Some(guess) = guess_code_language(&state.content) {
-            info = guess.to_string();
+fn parse_flow_statement(chart: &mut Flowchart, statement: &str) -> std::result::Result<(), String> {
+    let mut rest = statement;
+    let mut previous = parse_flow_node_group(chart, &mut rest)?;
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return Ok(());
+        }
+        let Some(link) = parse_flow_link(&mut rest) else {
+            return Err(format!("unexpected `{rest}`"));
+        };
+        rest = rest.trim_start();
+        let next = parse_flow_node_group(chart, &mut rest)?;
+        for &from in &previous {
+            for &to in &next {
+                chart.edges.push(FlowEdge {
+                    from,
+                    to,
+                    ..link.clone()
+                });
+            }
         }
+        previous = next;
     }
+}
 
-    out.push_str(&state.indent);
-    out.push_str(&state.fence());
-    out.push_str(&info);
-    out.push('\n');
-    out.push_str(&state.content);
-    out.push_str(closing_line);
+fn parse_flow_node_group(
+    chart: &mut Flowchart,
+    rest: &mut &str,
+) -> std::result::Result<Vec<usize>, String> {
+    let mut group = vec![parse_flow_node(chart, rest)?];
+    loop {
+        let trimmed = rest.trim_start();
+        let Some(after) = trimmed.strip_prefix('&') else {
+            return Ok(group);
+        };
+        *rest = after.trim_start();
+        group.push(parse_flow_node(chart, rest)?);
+    }
 }
 
-fn normalize_fence_language(lang: &str) -> Option<String> {
-    let lang = lang.trim();
-    if lang.is_empty() {
-        return None;
+fn parse_flow_node(chart: &mut Flowchart, rest: &mut &str) -> std::result::Result<usize, String> {
+    let input = *rest;
+    let id_len = input
+        .char_indices()
+        .find(|&(_, ch)| !(ch.is_alphanumeric() || ch == '_'))
+        .map_or(rest.len(), |(idx, _)| idx);
+    if id_len == 0 {
+        return Err(format!("expected a node id at `{rest}`"));
+    }
+    let id = &input[..id_len];
+    *rest = &input[id_len..];
+    let idx = chart.node(id);
+
+    const SHAPES: &[(&str, &str, NodeShape)] = &[
+        ("(((", ")))", NodeShape::Circle),
+        ("((", "))", NodeShape::Circle),
+        ("([", "])", NodeShape::Stadium),
+        ("(", ")", NodeShape::Round),
+        ("[[", "]]", NodeShape::Subroutine),
+        ("[(", ")]", NodeShape::Cylinder),
+        ("[/", "]", NodeShape::Parallelogram),
+        ("[\\", "]", NodeShape::Parallelogram),
+        ("[", "]", NodeShape::Rect),
+        ("{{", "}}", NodeShape::Hexagon),
+        ("{", "}", NodeShape::Diamond),
+        (">", "]", NodeShape::Flag),
+    ];
+    if let Some(&(open, close, shape)) = SHAPES.iter().find(|(open, ..)| rest.starts_with(open)) {
+        let body = &rest[open.len()..];
+        let end = if body.trim_start().starts_with('"') {
+            let quote = body.find('"').unwrap_or(0);
+            body[quote + 1..]
+                .find('"')
+                .and_then(|q| body[quote + q + 2..].find(close).map(|c| quote + q + 2 + c))
+        } else {
+            body.find(close)
+        };
+        let Some(end) = end else {
+            return Err(format!("unclosed `{open}` after `{id}`"));
+        };
+        let mut text = unquote(&body[..end]);
+        if matches!(open, "[/" | "[\\") {
+            text = text.trim_end_matches(['/', '\\']);
+        }
+        chart.nodes[idx].label = diagram_label_lines(unquote(text));
+        chart.nodes[idx].shape = shape;
+        *rest = &body[end + close.len()..];
     }
-    let lang = lang
-        .trim_matches(|c| c == '{' || c == '}' || c == '.')
-        .strip_prefix("language-")
-        .unwrap_or(lang)
-        .trim();
-    let lang_lc = lang.to_ascii_lowercase();
-    let mapped = match lang_lc.as_str() {
-        // Core languages
-        "rust" => "rs",
-        "python" => "py",
-        "javascript" | "js" => "js",
-        "typescript" | "ts" => "ts",
-        "tsx" => "tsx",
-        "jsx" => "jsx",
-        "bash" | "sh" | "shell" => "sh",
-        "zsh" | "fish" => "sh",
-        "powershell" | "pwsh" => "ps1",
-        "csharp" | "cs" => "cs",
-        "cpp" | "c++" => "cpp",
-        "c" => "c",
-        "h" => "h",
-        "h++" | "hpp" => "hpp",
-        "go" | "golang" => "go",
-        "java" => "java",
-        "kotlin" | "kt" | "kts" => "kt",
-        "swift" => "swift",
-        "scala" => "scala",
-        "ruby" | "rb" => "rb",
-        "php" => "php",
-        "perl" | "pl" => "pl",
-        "lua" => "lua",
-        "r" => "r",
-        "julia" | "jl" => "jl",
-        "dart" => "dart",
-        "elixir" | "ex" | "exs" => "ex",
-        "erlang" | "erl" => "erl",
-        "haskell" | "hs" => "hs",
-        "ocaml" | "ml" => "ml",
-        "nim" => "nim",
-        "zig" => "zig",
-        "solidity" | "sol" => "sol",
-        // Web + data formats
-        "json" => "json",
-        "json5" => "json5",
-        "toml" => "toml",
-        "ini" => "ini",
-        "yaml" | "yml" => "yml",
-        "markdown" | "md" => "md",
-        "graphql" | "gql" => "graphql",
-        "css" => "css",
-        "scss" => "scss",
-        "less" => "less",
-        "html" => "html",
-        "xml" => "xml",
-        "svg" => "svg",
-        "csv" => "csv",
-        "sql" => "sql",
-        "proto" | "protobuf" => "proto",
-        // Tooling / config
-        "docker" | "dockerfile" => "dockerfile",
-        "make" | "makefile" => "makefile",
-        "cmake" => "cmake",
-        "nix" => "nix",
-        "diff" | "patch" => "diff",
-        "gitignore" => "gitignore",
-        "editorconfig" => "editorconfig",
-        "tex" | "latex" => "tex",
-        "plaintext" | "text" | "txt" => "txt",
-        other => other,
+    if let Some(after) = rest.strip_prefix(":::") {
+        let class_len = after
+            .find(|ch: char| !(ch.is_alphanumeric() || ch == '_' || ch == '-'))
+            .unwrap_or(after.len());
+        *rest = &after[class_len..];
+    }
+    Ok(idx)
+}
+
+fn parse_flow_link(rest: &mut &str) -> Option<FlowEdge> {
+    static TEXT_LINK: OnceLock<Regex> = OnceLock::new();
+    static LINK: OnceLock<Regex> = OnceLock::new();
+    static PIPE_TEXT: OnceLock<Regex> = OnceLock::new();
+    let text_link = TEXT_LINK.get_or_init(|| {
+        Regex::new(r"^([<xo])?(--|==|-\.)\s+(.+?)\s*(-{2,}|={2,}|\.+-)([>xo])?")
+            .expect("valid flowchart text link regex")
+    });
+    let link = LINK.get_or_init(|| {
+        Regex::new(r"^([<xo])?(-\.+-|-{2,}|={2,}|~{3,})([>xo])?")
+            .expect("valid flowchart link regex")
+    });
+    let pipe_text = PIPE_TEXT
+        .get_or_init(|| Regex::new(r"^\s*\|([^|]*)\|").expect("valid flowchart link label regex"));
+
+    let input = *rest;
+    let (caps, body, mut label) = if let Some(caps) = text_link.captures(input) {
+        let label = caps.get(3).map(|m| unquote(m.as_str()).to_string());
+        (caps, 4, label)
+    } else {
+        (link.captures(input)?, 2, None)
     };
-    Some(mapped.to_string())
+    let mut end = caps.get(0)?.end();
+    let head = |marker: Option<regex::Match>| match marker.map(|m| m.as_str()) {
+        Some("<" | ">") => ArrowHead::Filled,
+        Some("x") => ArrowHead::Cross,
+        Some("o") => ArrowHead::Circle,
+        _ => ArrowHead::None,
+    };
+    // `x` and `o` only end a link when they aren't the start of the next node id.
+    let mut end_marker = caps.get(body + 1);
+    if let Some(marker) = end_marker
+        && marker.as_str() != ">"
+        && input[marker.end()..].starts_with(|ch: char| ch.is_alphanumeric() || ch == '_')
+    {
+        end = marker.start();
+        end_marker = None;
+    }
+    let shaft = caps.get(body)?.as_str();
+    let style = if shaft.starts_with('=') {
+        LineStyle::Thick
+    } else if shaft.contains('.') {
+        LineStyle::Dotted
+    } else {
+        LineStyle::Solid
+    };
+    *rest = &input[end..];
+    if let Some(pipe) = pipe_text.captures(&input[end..]) {
+        label = Some(unquote(&pipe[1]).to_string());
+        *rest = &input[end + pipe[0].len()..];
+    }
+    Some(FlowEdge {
+        from: 0,
+        to: 0,
+        label: label
+            .filter(|label| !label.is_empty())
+            .map(|label| diagram_label_lines(&label)),
+        style,
+        start: head(caps.get(1)),
+        end: head(end_marker),
+        hidden: shaft.starts_with('~'),
+    })
 }
 
-fn guess_code_language(code: &str) -> Option<&'static str> {
-    let mut first_non_empty = None;
-    for line in code.lines() {
-        let trimmed = line.trim();
-        if trimmed.is_empty() {
+fn render_flowchart(chart: &Flowchart, palette: DiagramPalette) -> String {
+    let sizes: Vec<egui::Vec2> = chart
+        .nodes
+        .iter()
+        .map(|node| node_size(node.shape, diagram_text_size(&node.label)))
+        .collect();
+    let edges: Vec<LayoutEdge> = chart
+        .edges
+        .iter()
+        .map(|edge| LayoutEdge {
+            from: edge.from,
+            to: edge.to,
+            label: edge.label.as_deref().map(diagram_text_size),
+        })
+        .collect();
+    let layout = layout_graph(&sizes, &edges, chart.direction);
+    let mut bounds = egui::Rect::from_min_size(egui::Pos2::ZERO, layout.size);
+    let mut svg = SvgBuilder::new(palette);
+
+    // Subgraphs are drawn as clusters only when no other node ends up inside their box;
+    // the layout doesn't keep members together.
+    for subgraph in &chart.subgraphs {
+        let Some(members) = subgraph
+            .members
+            .iter()
+            .map(|&idx| layout.nodes[idx])
+            .reduce(|a, b| a.union(b))
+        else {
+            continue;
+        };
+        let title = diagram_text_size(&subgraph.title);
+        let mut cluster = members.expand(12.0);
+        cluster.min.y -= title.y + 4.0;
+        cluster.max.x = cluster.max.x.max(cluster.min.x + title.x + 16.0);
+        let overlaps = layout
+            .nodes
+            .iter()
+            .enumerate()
+            .any(|(idx, rect)| !subgraph.members.contains(&idx) && rect.intersects(cluster));
+        if overlaps {
             continue;
         }
-        first_non_empty = Some(trimmed);
-        break;
+        svg.rect(
+            cluster,
+            4.0,
+            with_alpha(palette.fill, 90),
+            LineStyle::Dashed,
+        );
+        svg.text(
+            egui::pos2(cluster.min.x + 8.0, cluster.min.y + 4.0 + title.y / 2.0),
+            &subgraph.title,
+            "start",
+            true,
+        );
+        bounds = bounds.union(cluster.expand(4.0));
     }
-    let first = first_non_empty?;
 
-    if let Some(shebang) = first.strip_prefix("#!") {
-        let shebang = shebang.to_ascii_lowercase();
-        if shebang.contains("python") {
-            return Some("py");
-        }
-        if shebang.contains("bash") || shebang.contains("sh") {
-            return Some("sh");
-        }
-        if shebang.contains("node") {
-            return Some("js");
+    for (idx, edge) in chart.edges.iter().enumerate() {
+        if edge.hidden {
+            continue;
         }
-        return Some("sh");
-    }
-
-    if first.starts_with("<?xml") {
-        return Some("xml");
-    }
-    if first.starts_with("<!DOCTYPE") || first.starts_with("<html") {
-        return Some("html");
-    }
-    if first.starts_with('{') || first.starts_with('[') {
-        return Some("json");
-    }
-    if first.starts_with("SELECT") || first.starts_with("select") {
-        return Some("sql");
-    }
-
-    let hay = code;
-    if hay.contains("fn main") || hay.contains("println!") || hay.contains("use ") {
-        return Some("rs");
-    }
-    if hay.contains("def ") || hay.contains("import ") {
-        return Some("py");
-    }
-    if hay.contains("console.log") || hay.contains("function ") || hay.contains("=>") {
-        return Some("js");
+        let from = (layout.nodes[edge.from], chart.nodes[edge.from].shape);
+        let to = (layout.nodes[edge.to], chart.nodes[edge.to].shape);
+        let route = clip_route(layout.edges[idx].clone(), from, to);
+        svg.edge(&route, edge.style, edge.start, edge.end);
     }
-    if hay.contains("using System") || hay.contains("namespace ") {
-        return Some("cs");
+    for (node, rect) in chart.nodes.iter().zip(&layout.nodes) {
+        if !node.hidden {
+            draw_node(&mut svg, *rect, node.shape, &node.label);
+        }
     }
-    if hay.contains("#include") {
-        if hay.contains("<iostream>") {
-            return Some("cpp");
+    for (edge, pos) in chart.edges.iter().zip(&layout.labels) {
+        if let (Some(label), Some(pos), false) = (&edge.label, pos, edge.hidden) {
+            svg.label(*pos, label);
         }
-        return Some("c");
     }
+    svg.finish(bounds)
+}
 
-    None
+#[derive(Debug, Clone)]
+struct SeqParticipant {
+    id: String,
+    label: Vec<String>,
+    actor: bool,
 }
 
-fn process_inline_line(
-    chunk: &str,
-    settings: &ViewerSettings,
-    github_repo: Option<&GithubRepo>,
-) -> String {
-    let (line, line_ending) = chunk
-        .strip_suffix('\n')
-        .map(|l| (l, "\n"))
-        .unwrap_or((chunk, ""));
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NotePlacement {
+    LeftOf,
+    RightOf,
+    Over,
+}
 
-    let processed = process_inline_text(line, settings, github_repo);
-    let mut out = String::with_capacity(processed.len() + line_ending.len());
-    out.push_str(&processed);
-    out.push_str(line_ending);
-    out
+#[derive(Debug, Clone)]
+enum SeqEvent {
+    Message {
+        from: usize,
+        to: usize,
+        text: Vec<String>,
+        style: LineStyle,
+        head: ArrowHead,
+        both_ends: bool,
+        number: Option<usize>,
+    },
+    Note {
+        first: usize,
+        last: usize,
+        placement: NotePlacement,
+        text: Vec<String>,
+    },
+    Activate(usize),
+    Deactivate(usize),
+    FrameStart {
+        kind: String,
+        label: String,
+    },
+    FrameSection {
+        label: String,
+    },
+    FrameEnd,
 }
 
-fn process_inline_text(
-    text: &str,
-    settings: &ViewerSettings,
-    github_repo: Option<&GithubRepo>,
-) -> String {
-    let mut out = String::with_capacity(text.len());
-    let mut i = 0;
-    let mut in_code = false;
-    let mut code_delim_len = 0usize;
+#[derive(Debug, Clone, Default)]
+struct SequenceDiagram {
+    participants: Vec<SeqParticipant>,
+    events: Vec<SeqEvent>,
+}
 
-    while i < text.len() {
-        let Some(rel) = text[i..].find('`') else {
-            let tail = &text[i..];
-            if in_code {
-                out.push_str(tail);
-            } else {
-                out.push_str(&apply_text_transforms(tail, settings, github_repo));
-            }
-            break;
-        };
-        let start = i + rel;
-        let before = &text[i..start];
-        if in_code {
-            out.push_str(before);
-        } else {
-            out.push_str(&apply_text_transforms(before, settings, github_repo));
+impl SequenceDiagram {
+    fn participant(&mut self, id: &str) -> usize {
+        if let Some(idx) = self.participants.iter().position(|p| p.id == id) {
+            return idx;
         }
+        self.participants.push(SeqParticipant {
+            id: id.to_string(),
+            label: vec![id.to_string()],
+            actor: false,
+        });
+        self.participants.len() - 1
+    }
+}
 
-        let run_len = text[start..].bytes().take_while(|b| *b == b'`').count();
-        let run = &text[start..start + run_len];
-        out.push_str(run);
+fn parse_sequence_diagram(
+    lines: &[(usize, String)],
+) -> std::result::Result<SequenceDiagram, String> {
+    static PARTICIPANT: OnceLock<Regex> = OnceLock::new();
+    static MESSAGE: OnceLock<Regex> = OnceLock::new();
+    static NOTE: OnceLock<Regex> = OnceLock::new();
+    let participant_re = PARTICIPANT.get_or_init(|| {
+        Regex::new(r"^(participant|actor)\s+(\S+?)(?:\s+as\s+(.+))?$")
+            .expect("valid participant regex")
+    });
+    let message_re = MESSAGE.get_or_init(|| {
+        Regex::new(
+            r"^([^\s:+<>-]+)\s*(<<-->>|<<->>|-->>|->>|--x|-x|--\)|-\)|-->|->)\s*([+-]?)\s*([^\s:+<>-]+)\s*(?::\s*(.*))?$",
+        )
+        .expect("valid message regex")
+    });
+    let note_re = NOTE.get_or_init(|| {
+        Regex::new(r"(?i)^note\s+(left of|right of|over)\s+([^:]+?)\s*:\s*(.*)$")
+            .expect("valid note regex")
+    });
 
-        if !in_code {
-            in_code = true;
-            code_delim_len = run_len;
-        } else if run_len == code_delim_len {
-            in_code = false;
-            code_delim_len = 0;
+    let mut diagram = SequenceDiagram::default();
+    let mut numbering: Option<usize> = None;
+    let mut open_blocks: Vec<bool> = Vec::new();
+    for (line_no, line) in lines {
+        let (keyword, rest) = line
+            .split_once(char::is_whitespace)
+            .map_or((line.as_str(), ""), |(k, r)| (k, r.trim()));
+        match keyword {
+            "participant" | "actor" => {
+                let caps = participant_re
+                    .captures(line)
+                    .ok_or(format!("line {line_no}: expected a participant name"))?;
+                let idx = diagram.participant(&caps[2]);
+                let participant = &mut diagram.participants[idx];
+                participant.actor = keyword == "actor";
+                if let Some(label) = caps.get(3) {
+                    participant.label = diagram_label_lines(label.as_str());
+                }
+            }
+            "autonumber" => numbering = Some(rest.parse().unwrap_or(1)),
+            "activate" | "deactivate" => {
+                let idx = diagram.participant(rest);
+                diagram.events.push(if keyword == "activate" {
+                    SeqEvent::Activate(idx)
+                } else {
+                    SeqEvent::Deactivate(idx)
+                });
+            }
+            "loop" | "alt" | "opt" | "par" | "critical" | "break" | "rect" => {
+                let label = if keyword == "rect" { "" } else { rest };
+                diagram.events.push(SeqEvent::FrameStart {
+                    kind: keyword.to_string(),
+                    label: label.to_string(),
+                });
+                open_blocks.push(true);
+            }
+            "box" => open_blocks.push(false),
+            "else" | "and" | "option" => diagram.events.push(SeqEvent::FrameSection {
+                label: rest.to_string(),
+            }),
+            "end" => match open_blocks.pop() {
+                Some(true) => diagram.events.push(SeqEvent::FrameEnd),
+                Some(false) => {}
+                None => return Err(format!("line {line_no}: `end` without a block")),
+            },
+            "title" | "links" | "link" | "properties" | "details" | "create" | "destroy" => {}
+            _ if keyword.eq_ignore_ascii_case("note") => {
+                let caps = note_re
+                    .captures(line)
+                    .ok_or(format!("line {line_no}: malformed note"))?;
+                let placement = match caps[1].to_ascii_lowercase().as_str() {
+                    "left of" => NotePlacement::LeftOf,
+                    "right of" => NotePlacement::RightOf,
+                    _ => NotePlacement::Over,
+                };
+                let mut ids = caps[2].split(',').map(str::trim);
+                let first = diagram.participant(ids.next().unwrap_or_default());
+                let last = ids.next().map_or(first, |id| diagram.participant(id));
+                diagram.events.push(SeqEvent::Note {
+                    first: first.min(last),
+                    last: first.max(last),
+                    placement,
+                    text: diagram_label_lines(&caps[3]),
+                });
+            }
+            _ => {
+                let caps = message_re
+                    .captures(line)
+                    .ok_or(format!("line {line_no}: could not parse `{line}`"))?;
+                let from = diagram.participant(&caps[1]);
+                let to = diagram.participant(&caps[4]);
+                let arrow = &caps[2];
+                let head = if arrow.ends_with('x') {
+                    ArrowHead::Cross
+                } else if arrow.ends_with(')') {
+                    ArrowHead::Open
+                } else if arrow.ends_with(">>") {
+                    ArrowHead::Filled
+                } else {
+                    ArrowHead::None
+                };
+                diagram.events.push(SeqEvent::Message {
+                    from,
+                    to,
+                    text: caps
+                        .get(5)
+                        .map(|m| diagram_label_lines(m.as_str()))
+                        .unwrap_or_default(),
+                    style: if arrow.contains("--") {
+                        LineStyle::Dashed
+                    } else {
+                        LineStyle::Solid
+                    },
+                    head,
+                    both_ends: arrow.starts_with("<<"),
+                    number: numbering,
+                });
+                if let Some(n) = &mut numbering {
+                    *n += 1;
+                }
+                match &caps[3] {
+                    "+" => diagram.events.push(SeqEvent::Activate(to)),
+                    "-" => diagram.events.push(SeqEvent::Deactivate(from)),
+                    _ => {}
+                }
+            }
         }
-
-        i = start + run_len;
     }
-
-    out
+    if diagram.participants.is_empty() {
+        return Err("the diagram has no participants".to_string());
+    }
+    Ok(diagram)
 }
 
-fn apply_text_transforms(
-    text: &str,
-    settings: &ViewerSettings,
-    github_repo: Option<&GithubRepo>,
-) -> String {
-    let mut out = text.to_string();
+fn render_sequence_diagram(diagram: &SequenceDiagram, palette: DiagramPalette) -> String {
+    const GAP: f32 = 40.0;
+    const ACTIVATION_WIDTH: f32 = 10.0;
+
+    let count = diagram.participants.len();
+    let box_sizes: Vec<egui::Vec2> = diagram
+        .participants
+        .iter()
+        .map(|p| {
+            let text = diagram_text_size(&p.label);
+            if p.actor {
+                egui::vec2(text.x.max(40.0) + 8.0, text.y + 44.0)
+            } else {
+                egui::vec2((text.x + 24.0).max(80.0), text.y + 16.0)
+            }
+        })
+        .collect();
+    let mut x = vec![0.0f32; count];
+    for i in 1..count {
+        x[i] = x[i - 1] + (box_sizes[i - 1].x + box_sizes[i].x) / 2.0 + GAP;
+    }
+    let widen = |x: &mut Vec<f32>, left: usize, right: usize, needed: f32| {
+        let missing = needed - (x[right] - x[left]);
+        if missing > 0.0 {
+            for column in &mut x[right..] {
+                *column += missing;
+            }
+        }
+    };
+    for event in &diagram.events {
+        match event {
+            SeqEvent::Message { from, to, text, .. } if from != to => {
+                let width = diagram_text_size(text).x + 24.0;
+                widen(&mut x, *from.min(to), *from.max(to), width);
+            }
+            SeqEvent::Message { from, text, .. } if from + 1 < count => {
+                let width = diagram_text_size(text).x + 48.0;
+                widen(&mut x, *from, from + 1, width);
+            }
+            SeqEvent::Note {
+                first,
+                placement: NotePlacement::RightOf,
+                text,
+                ..
+            } if first + 1 < count => {
+                let width = diagram_text_size(text).x + 40.0 + box_sizes[first + 1].x / 2.0;
+                widen(&mut x, *first, first + 1, width);
+            }
+            SeqEvent::Note {
+                first,
+                placement: NotePlacement::LeftOf,
+                text,
+                ..
+            } if *first > 0 => {
+                let width = diagram_text_size(text).x + 40.0 + box_sizes[first - 1].x / 2.0;
+                widen(&mut x, first - 1, *first, width);
+            }
+            _ => {}
+        }
+    }
+
+    struct Frame {
+        kind: String,
+        label: String,
+        top: f32,
+        left: f32,
+        right: f32,
+        sections: Vec<(f32, String)>,
+    }
+    struct DrawnFrame {
+        rect: egui::Rect,
+        kind: String,
+        label: String,
+        sections: Vec<(f32, String)>,
+    }
+
+    let header_height = box_sizes.iter().map(|s| s.y).fold(0.0, f32::max);
+    let mut y = header_height + 24.0;
+    let mut bounds = egui::Rect::NOTHING;
+    let mut frames: Vec<Frame> = Vec::new();
+    let mut drawn_frames: Vec<DrawnFrame> = Vec::new();
+    let mut active: Vec<Vec<f32>> = vec![Vec::new(); count];
+    let mut bars: Vec<(usize, usize, f32, f32)> = Vec::new();
+    let mut overlay = SvgBuilder::new(palette);
+    // Messages attach to the edge of the outermost activation bar facing their peer.
+    let lane = |idx: usize, active: &[Vec<f32>], toward: f32| match active[idx].len() {
+        0 => x[idx],
+        depth => {
+            x[idx] + (depth - 1) as f32 * ACTIVATION_WIDTH * 0.5 + ACTIVATION_WIDTH / 2.0 * toward
+        }
+    };
+    let touch = |frames: &mut Vec<Frame>, left: f32, right: f32| {
+        if let Some(frame) = frames.last_mut() {
+            frame.left = frame.left.min(left);
+            frame.right = frame.right.max(right);
+        }
+    };
 
-    if settings.github_links {
-        out = linkify_github_references(&out, github_repo);
+    for event in &diagram.events {
+        match event {
+            SeqEvent::Message {
+                from,
+                to,
+                text,
+                style,
+                head,
+                both_ends,
+                number,
+            } => {
+                let text_size = diagram_text_size(text);
+                let text = if text.iter().all(String::is_empty) {
+                    &[][..]
+                } else {
+                    &text[..]
+                };
+                if from == to {
+                    let start = lane(*from, &active, 1.0);
+                    y += text_size.y.max(DIAGRAM_LINE_HEIGHT) / 2.0 + 4.0;
+                    let label_y = y;
+                    y += text_size.y / 2.0 + 4.0;
+                    let points = [
+                        egui::pos2(start, y),
+                        egui::pos2(start + 30.0, y),
+                        egui::pos2(start + 30.0, y + 20.0),
+                        egui::pos2(start, y + 20.0),
+                    ];
+                    overlay.edge(&points, *style, ArrowHead::None, *head);
+                    overlay.text(egui::pos2(start + 36.0, label_y), text, "start", false);
+                    touch(&mut frames, x[*from] - 20.0, start + 40.0 + text_size.x);
+                    bounds.extend_with(egui::pos2(start + 40.0 + text_size.x, y));
+                    if let Some(n) = number {
+                        sequence_number(&mut overlay, egui::pos2(start, y), *n);
+                    }
+                    y += 34.0;
+                } else {
+                    let dir = (x[*to] - x[*from]).signum();
+                    let (start, end) = (lane(*from, &active, dir), lane(*to, &active, -dir));
+                    y += text_size.y + 6.0;
+                    overlay.text(
+                        egui::pos2((start + end) / 2.0, y - text_size.y / 2.0 - 3.0),
+                        text,
+                        "middle",
+                        false,
+                    );
+                    let tail = if *both_ends { *head } else { ArrowHead::None };
+                    overlay.edge(
+                        &[egui::pos2(start, y), egui::pos2(end, y)],
+                        *style,
+                        tail,
+                        *head,
+                    );
+                    if let Some(n) = number {
+                        sequence_number(&mut overlay, egui::pos2(start, y), *n);
+                    }
+                    touch(&mut frames, start.min(end) - 20.0, start.max(end) + 20.0);
+                    y += 18.0;
+                }
+            }
+            SeqEvent::Note {
+                first,
+                last,
+                placement,
+                text,
+            } => {
+                let text_size = diagram_text_size(text);
+                let width = text_size.x + 20.0;
+                let (left, right) = match placement {
+                    NotePlacement::LeftOf => (x[*first] - 12.0 - width, x[*first] - 12.0),
+                    NotePlacement::RightOf => (x[*first] + 12.0, x[*first] + 12.0 + width),
+                    NotePlacement::Over => {
+                        let center = (x[*first] + x[*last]) / 2.0;
+                        let half = (width.max(x[*last] - x[*first] + 40.0)) / 2.0;
+                        (center - half, center + half)
+                    }
+                };
+                let rect = egui::Rect::from_min_max(
+                    egui::pos2(left, y),
+                    egui::pos2(right, y + text_size.y + 12.0),
+                );
+                overlay.rect(rect, 0.0, palette.note, LineStyle::Solid);
+                overlay.text(rect.center(), text, "middle", false);
+                touch(&mut frames, left - 10.0, right + 10.0);
+                bounds = bounds.union(rect);
+                y = rect.bottom() + 12.0;
+            }
+            SeqEvent::Activate(idx) => {
+                active[*idx].push(y - 4.0);
+            }
+            SeqEvent::Deactivate(idx) => {
+                if let Some(start) = active[*idx].pop() {
+                    bars.push((*idx, active[*idx].len(), start, y + 4.0));
+                }
+            }
+            SeqEvent::FrameStart { kind, label } => {
+                y += 10.0;
+                frames.push(Frame {
+                    kind: kind.clone(),
+                    label: label.clone(),
+                    top: y,
+                    left: f32::INFINITY,
+                    right: f32::NEG_INFINITY,
+                    sections: Vec::new(),
+                });
+                y += DIAGRAM_LINE_HEIGHT + 10.0;
+            }
+            SeqEvent::FrameSection { label } => {
+                if let Some(frame) = frames.last_mut() {
+                    y += 6.0;
+                    frame.sections.push((y, label.clone()));
+                    y += DIAGRAM_LINE_HEIGHT + 6.0;
+                }
+            }
+            SeqEvent::FrameEnd => {
+                let Some(frame) = frames.pop() else { continue };
+                y += 6.0;
+                let (left, right) = if frame.left <= frame.right {
+                    (frame.left, frame.right)
+                } else {
+                    (x[0] - 20.0, x[count - 1] + 20.0)
+                };
+                let label_width =
+                    diagram_text_width(&frame.kind) + diagram_text_width(&frame.label) + 60.0;
+                let rect = egui::Rect::from_min_max(
+                    egui::pos2(left, frame.top),
+                    egui::pos2(right.max(left + label_width), y),
+                );
+                touch(&mut frames, rect.left() - 8.0, rect.right() + 8.0);
+                bounds = bounds.union(rect);
+                drawn_frames.push(DrawnFrame {
+                    rect,
+                    kind: frame.kind,
+                    label: frame.label,
+                    sections: frame.sections,
+                });
+                y += 10.0;
+            }
+        }
     }
-    if settings.autolink_urls {
-        out = autolink_plain_urls(&out);
+    for (idx, starts) in active.iter().enumerate() {
+        for (depth, start) in starts.iter().enumerate() {
+            bars.push((idx, depth, *start, y));
+        }
     }
-    if settings.replace_emoji {
-        out = replace_emoji_shortcodes(&out);
+    y += 12.0;
+
+    let mut svg = SvgBuilder::new(palette);
+    for frame in &drawn_frames {
+        let fill = if frame.kind == "rect" {
+            with_alpha(palette.fill, 110)
+        } else {
+            egui::Color32::TRANSPARENT
+        };
+        svg.rect(frame.rect, 0.0, fill, LineStyle::Solid);
     }
-    if settings.smart_typography {
-        out = smart_typography(&out);
+    for (idx, participant) in diagram.participants.iter().enumerate() {
+        let top = egui::Rect::from_center_size(
+            egui::pos2(x[idx], header_height - box_sizes[idx].y / 2.0),
+            box_sizes[idx],
+        );
+        let bottom = top.translate(egui::vec2(0.0, y - top.top()));
+        svg.polyline(
+            &[
+                egui::pos2(x[idx], top.bottom()),
+                egui::pos2(x[idx], bottom.top()),
+            ],
+            palette.line,
+            LineStyle::Dashed,
+        );
+        for rect in [top, bottom] {
+            draw_participant(&mut svg, rect, participant);
+            bounds = bounds.union(rect);
+        }
+    }
+    for &(idx, depth, start, end) in &bars {
+        let center = x[idx] + depth as f32 * ACTIVATION_WIDTH * 0.5;
+        svg.rect(
+            egui::Rect::from_min_max(
+                egui::pos2(center - ACTIVATION_WIDTH / 2.0, start),
+                egui::pos2(center + ACTIVATION_WIDTH / 2.0, end),
+            ),
+            0.0,
+            palette.fill,
+            LineStyle::Solid,
+        );
     }
-
-    out
+    svg.body.push_str(&overlay.body);
+    for frame in &drawn_frames {
+        let rect = frame.rect;
+        for (section_y, label) in &frame.sections {
+            svg.polyline(
+                &[
+                    egui::pos2(rect.left(), *section_y),
+                    egui::pos2(rect.right(), *section_y),
+                ],
+                palette.line,
+                LineStyle::Dashed,
+            );
+            if !label.is_empty() {
+                svg.text(
+                    egui::pos2(rect.center().x, section_y + DIAGRAM_LINE_HEIGHT / 2.0 + 2.0),
+                    &[format!("[{label}]")],
+                    "middle",
+                    false,
+                );
+            }
+        }
+        if frame.kind == "rect" {
+            continue;
+        }
+        let tag_width = diagram_text_width(&frame.kind) + 16.0;
+        let (l, t) = (rect.left(), rect.top());
+        let h = DIAGRAM_LINE_HEIGHT + 4.0;
+        svg.polygon(
+            &[
+                egui::pos2(l, t),
+                egui::pos2(l + tag_width + 8.0, t),
+                egui::pos2(l + tag_width + 8.0, t + h - 6.0),
+                egui::pos2(l + tag_width + 2.0, t + h),
+                egui::pos2(l, t + h),
+            ],
+            palette.fill,
+            LineStyle::Solid,
+        );
+        svg.text(
+            egui::pos2(l + 6.0, t + h / 2.0),
+            std::slice::from_ref(&frame.kind),
+            "start",
+            true,
+        );
+        if !frame.label.is_empty() {
+            svg.text(
+                egui::pos2(l + tag_width + 16.0, t + h / 2.0),
+                &[format!("[{}]", frame.label)],
+                "start",
+                false,
+            );
+        }
+    }
+    svg.finish(bounds.expand(12.0))
 }
 
-fn linkify_github_references(text: &str, github_repo: Option<&GithubRepo>) -> String {
-    static RE_CROSS_REPO: OnceLock<Regex> = OnceLock::new();
-    static RE_PR: OnceLock<Regex> = OnceLock::new();
-    static RE_ISSUE: OnceLock<Regex> = OnceLock::new();
-
-    let re_cross_repo = RE_CROSS_REPO.get_or_init(|| {
-        Regex::new(r"(?P<owner>[A-Za-z0-9_.-]+)/(?P<repo>[A-Za-z0-9_.-]+)#(?P<num>[0-9]+)")
-            .expect("valid regex")
-    });
-    let mut out = re_cross_repo
-        .replace_all(text, |caps: &Captures| {
-            let owner = &caps["owner"];
-            let repo = &caps["repo"];
-            let num = &caps["num"];
-            format!("[{owner}/{repo}#{num}](https://github.com/{owner}/{repo}/issues/{num})")
-        })
-        .into_owned();
-
-    let Some(repo) = github_repo else {
-        return out;
-    };
+fn draw_participant(svg: &mut SvgBuilder, rect: egui::Rect, participant: &SeqParticipant) {
+    if !participant.actor {
+        svg.rect(rect, 3.0, svg.palette.fill, LineStyle::Solid);
+        svg.text(rect.center(), &participant.label, "middle", false);
+        return;
+    }
+    let line = svg.palette.line;
+    let c = rect.center().x;
+    let t = rect.top();
+    let p = egui::pos2;
+    svg.ellipse(p(c, t + 7.0), egui::vec2(7.0, 7.0), svg.palette.fill);
+    svg.polyline(&[p(c, t + 14.0), p(c, t + 28.0)], line, LineStyle::Solid);
+    svg.polyline(
+        &[p(c - 11.0, t + 19.0), p(c + 11.0, t + 19.0)],
+        line,
+        LineStyle::Solid,
+    );
+    svg.polyline(
+        &[p(c - 9.0, t + 38.0), p(c, t + 28.0), p(c + 9.0, t + 38.0)],
+        line,
+        LineStyle::Solid,
+    );
+    let text_top = t + 44.0;
+    svg.text(
+        p(c, (text_top + rect.bottom()) / 2.0),
+        &participant.label,
+        "middle",
+        false,
+    );
+}
 
-    let re_pr = RE_PR.get_or_init(|| {
-        Regex::new(r"(?i)(?P<prefix>^|[^A-Za-z0-9_])PR\s*#(?P<num>[0-9]+)").expect("valid regex")
-    });
-    out = re_pr
-        .replace_all(&out, |caps: &Captures| {
-            let prefix = caps.name("prefix").map_or("", |m| m.as_str());
-            let num = &caps["num"];
-            format!("{prefix}[PR#{num}]({}/pull/{num})", repo.base_url)
-        })
-        .into_owned();
+fn sequence_number(svg: &mut SvgBuilder, center: egui::Pos2, number: usize) {
+    svg.ellipse(center, egui::vec2(9.0, 9.0), svg.palette.background);
+    svg.text(center, &[number.to_string()], "middle", false);
+}
 
-    let re_issue = RE_ISSUE.get_or_init(|| {
-        Regex::new(r"(?P<prefix>^|[^A-Za-z0-9_])#(?P<num>[0-9]+)").expect("valid regex")
-    });
-    out = re_issue
-        .replace_all(&out, |caps: &Captures| {
-            let prefix = caps.name("prefix").map_or("", |m| m.as_str());
-            let num = &caps["num"];
-            format!("{prefix}[#{num}]({}/issues/{num})", repo.base_url)
-        })
-        .into_owned();
+#[derive(Debug, Clone, Default)]
+struct ClassBox {
+    id: String,
+    name: String,
+    annotations: Vec<String>,
+    attributes: Vec<String>,
+    methods: Vec<String>,
+}
 
-    out
+#[derive(Debug, Clone)]
+struct ClassRelation {
+    from: usize,
+    to: usize,
+    start: ArrowHead,
+    end: ArrowHead,
+    style: LineStyle,
+    label: Option<String>,
+    from_cardinality: Option<String>,
+    to_cardinality: Option<String>,
 }
 
-fn autolink_plain_urls(text: &str) -> String {
-    let mut finder = linkify::LinkFinder::new();
-    finder.kinds(&[linkify::LinkKind::Url]);
+#[derive(Debug, Clone)]
+struct ClassDiagram {
+    direction: FlowDirection,
+    classes: Vec<ClassBox>,
+    relations: Vec<ClassRelation>,
+}
 
-    let links: Vec<linkify::Link<'_>> = finder.links(text).collect();
-    if links.is_empty() {
-        return text.to_string();
+impl ClassDiagram {
+    fn class(&mut self, name: &str) -> usize {
+        let id = name.split('~').next().unwrap_or(name);
+        let idx = match self.classes.iter().position(|class| class.id == id) {
+            Some(idx) => idx,
+            None => {
+                self.classes.push(ClassBox {
+                    id: id.to_string(),
+                    name: id.to_string(),
+                    ..ClassBox::default()
+                });
+                self.classes.len() - 1
+            }
+        };
+        if name.contains('~') {
+            self.classes[idx].name = mermaid_generics(name);
+        }
+        idx
     }
 
-    let mut out = String::with_capacity(text.len() + links.len() * 2);
-    let mut last = 0usize;
-
-    for link in links {
-        let start = link.start();
-        let end = link.end();
-        out.push_str(&text[last..start]);
-
-        let before = start
-            .checked_sub(1)
-            .and_then(|i| text.as_bytes().get(i))
-            .copied();
-        let after = text.as_bytes().get(end).copied();
-        if before == Some(b'<') && after == Some(b'>') {
-            out.push_str(&text[start..end]);
+    fn add_member(&mut self, idx: usize, member: &str) {
+        let member = member.trim();
+        if member.is_empty() {
+            return;
+        }
+        if let Some(annotation) = member.strip_prefix("<<").and_then(|m| m.strip_suffix(">>")) {
+            self.classes[idx]
+                .annotations
+                .push(format!("«{annotation}»"));
+            return;
+        }
+        let display = mermaid_generics(member.trim_end_matches(['$', '*']));
+        if member.contains('(') {
+            self.classes[idx].methods.push(display);
         } else {
-            out.push('<');
-            out.push_str(&normalize_autolink_url(link.as_str()));
-            out.push('>');
+            self.classes[idx].attributes.push(display);
         }
-
-        last = end;
     }
+}
 
-    out.push_str(&text[last..]);
+fn mermaid_generics(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch == '~' {
+            let opens = chars
+                .peek()
+                .is_some_and(|next| next.is_alphanumeric() || *next == '_');
+            out.push(if opens { '<' } else { '>' });
+        } else {
+            out.push(ch);
+        }
+    }
     out
 }
 
-fn normalize_autolink_url(url: &str) -> String {
-    if url.contains("://") {
-        return url.to_string();
+fn parse_class_diagram(lines: &[(usize, String)]) -> std::result::Result<ClassDiagram, String> {
+    static RELATION: OnceLock<Regex> = OnceLock::new();
+    static MEMBER: OnceLock<Regex> = OnceLock::new();
+    let relation_re = RELATION.get_or_init(|| {
+        Regex::new(
+            r#"^([^\s"]+)\s*(?:"([^"]*)"\s*)?(<\||[<*o])?(--|\.\.)(\|>|[>*o])?\s*(?:"([^"]*)"\s*)?([^\s:"]+)\s*(?::\s*(.*))?$"#,
+        )
+        .expect("valid class relation regex")
+    });
+    let member_re = MEMBER
+        .get_or_init(|| Regex::new(r"^([\w~]+)\s*:\s*(.+)$").expect("valid class member regex"));
+
+    let mut diagram = ClassDiagram {
+        direction: FlowDirection::TopDown,
+        classes: Vec::new(),
+        relations: Vec::new(),
+    };
+    let mut open_class: Option<usize> = None;
+    for (line_no, line) in lines {
+        if let Some(idx) = open_class {
+            if line == "}" {
+                open_class = None;
+            } else {
+                diagram.add_member(idx, line);
+            }
+            continue;
+        }
+        let (keyword, rest) = line
+            .split_once(char::is_whitespace)
+            .map_or((line.as_str(), ""), |(k, r)| (k, r.trim()));
+        match keyword {
+            "direction" => {
+                diagram.direction = FlowDirection::parse(rest)
+                    .ok_or(format!("line {line_no}: unknown direction `{rest}`"))?;
+            }
+            "class" => {
+                let body = rest.trim_end_matches('{').trim();
+                let name = body.split([':', '[', ' ']).next().unwrap_or_default();
+                let idx = diagram.class(name);
+                if rest.ends_with('{') {
+                    open_class = Some(idx);
+                }
+            }
+            "}" | "namespace" | "note" | "style" | "classDef" | "cssClass" | "click" | "link"
+            | "callback" | "title" => {}
+            _ if line.starts_with("<<") => {
+                let Some((annotation, name)) = line[2..].split_once(">>") else {
+                    return Err(format!("line {line_no}: malformed annotation"));
+                };
+                let idx = diagram.class(name.trim());
+                diagram.classes[idx]
+                    .annotations
+                    .push(format!("«{}»", annotation.trim()));
+            }
+            _ => {
+                if let Some(caps) = relation_re.captures(line) {
+                    let marker = |text: Option<regex::Match>| match text.map(|m| m.as_str()) {
+                        Some("<|" | "|>") => ArrowHead::Triangle,
+                        Some("*") => ArrowHead::FilledDiamond,
+                        Some("o") => ArrowHead::Diamond,
+                        Some("<" | ">") => ArrowHead::Open,
+                        _ => ArrowHead::None,
+                    };
+                    let from = diagram.class(&caps[1]);
+                    let to = diagram.class(&caps[7]);
+                    let text = |idx: usize| caps.get(idx).map(|m| m.as_str().trim().to_string());
+                    diagram.relations.push(ClassRelation {
+                        from,
+                        to,
+                        start: marker(caps.get(3)),
+                        end: marker(caps.get(5)),
+                        style: if &caps[4] == ".." {
+                            LineStyle::Dashed
+                        } else {
+                            LineStyle::Solid
+                        },
+                        label: text(8).filter(|label| !label.is_empty()),
+                        from_cardinality: text(2),
+                        to_cardinality: text(6),
+                    });
+                } else if let Some(caps) = member_re.captures(line) {
+                    let idx = diagram.class(&caps[1]);
+                    diagram.add_member(idx, &caps[2]);
+                } else {
+                    return Err(format!("line {line_no}: could not parse `{line}`"));
+                }
+            }
+        }
     }
-    if url.starts_with("mailto:") {
-        return url.to_string();
+    if let Some(idx) = open_class {
+        return Err(format!(
+            "class `{}` is missing its closing `}}`",
+            diagram.classes[idx].id
+        ));
     }
-    format!("https://{url}")
+    Ok(diagram)
 }
 
-fn replace_emoji_shortcodes(text: &str) -> String {
-    let mut out = String::with_capacity(text.len());
-    let mut i = 0;
-
-    while let Some(colon1_rel) = text[i..].find(':') {
-        let colon1 = i + colon1_rel;
-        out.push_str(&text[i..colon1]);
-
-        let rest = &text[colon1 + 1..];
-        let Some(colon2_rel) = rest.find(':') else {
-            out.push_str(&text[colon1..]);
-            return out;
-        };
-        let name = &rest[..colon2_rel];
-        if is_valid_emoji_shortcode(name) {
-            if let Some(emoji) = emojis::get_by_shortcode(name) {
-                out.push_str(emoji.as_str());
-                i = colon1 + 1 + colon2_rel + 1;
-                continue;
+fn render_class_diagram(diagram: &ClassDiagram, palette: DiagramPalette) -> String {
+    const PADDING: egui::Vec2 = egui::vec2(10.0, 5.0);
+    let compartments = |class: &ClassBox| -> [Vec<String>; 3] {
+        let mut header = class.annotations.clone();
+        header.push(class.name.clone());
+        [header, class.attributes.clone(), class.methods.clone()]
+    };
+    let heights = |parts: &[Vec<String>; 3]| {
+        parts
+            .each_ref()
+            .map(|lines| lines.len() as f32 * DIAGRAM_LINE_HEIGHT + 2.0 * PADDING.y)
+    };
+    let sizes: Vec<egui::Vec2> = diagram
+        .classes
+        .iter()
+        .map(|class| {
+            let parts = compartments(class);
+            let width = parts
+                .iter()
+                .map(|lines| diagram_text_size(lines).x)
+                .fold(60.0, f32::max);
+            egui::vec2(width + 2.0 * PADDING.x, heights(&parts).iter().sum())
+        })
+        .collect();
+    let edges: Vec<LayoutEdge> = diagram
+        .relations
+        .iter()
+        .map(|relation| {
+            let cardinalities = [&relation.from_cardinality, &relation.to_cardinality]
+                .into_iter()
+                .flatten()
+                .map(|text| diagram_text_width(text) + 8.0)
+                .fold(None, |width: Option<f32>, w| {
+                    Some(width.unwrap_or(0.0).max(w))
+                });
+            let label = relation
+                .label
+                .as_ref()
+                .map(|label| diagram_text_size(std::slice::from_ref(label)));
+            let label = match (label, cardinalities) {
+                (label, Some(width)) => Some(
+                    label.unwrap_or_default() + egui::vec2(2.0 * width, 2.0 * DIAGRAM_LINE_HEIGHT),
+                ),
+                (label, None) => label,
+            };
+            LayoutEdge {
+                from: relation.from,
+                to: relation.to,
+                label,
+            }
+        })
+        .collect();
+    let layout = layout_graph(&sizes, &edges, diagram.direction);
+    let mut bounds = egui::Rect::from_min_size(egui::Pos2::ZERO, layout.size);
+    let mut svg = SvgBuilder::new(palette);
+
+    for (idx, relation) in diagram.relations.iter().enumerate() {
+        let from = (layout.nodes[relation.from], NodeShape::Rect);
+        let to = (layout.nodes[relation.to], NodeShape::Rect);
+        let route = clip_route(layout.edges[idx].clone(), from, to);
+        svg.edge(&route, relation.style, relation.start, relation.end);
+        let ends = [
+            (&relation.from_cardinality, route.first(), route.get(1)),
+            (
+                &relation.to_cardinality,
+                route.last(),
+                route.iter().rev().nth(1),
+            ),
+        ];
+        for (text, end, next) in ends {
+            if let (Some(text), Some(&end), Some(&next)) = (text, end, next) {
+                let dir = (next - end).normalized();
+                let pos = end + dir * 14.0 + egui::vec2(-dir.y, dir.x) * 14.0;
+                svg.text(pos, std::slice::from_ref(text), "middle", false);
+                bounds.extend_with(pos + egui::vec2(diagram_text_width(text) / 2.0 + 4.0, 0.0));
+                bounds.extend_with(pos - egui::vec2(diagram_text_width(text) / 2.0 + 4.0, 0.0));
+            }
+        }
+    }
+    for (class, rect) in diagram.classes.iter().zip(&layout.nodes) {
+        let parts = compartments(class);
+        svg.rect(*rect, 2.0, palette.fill, LineStyle::Solid);
+        let mut top = rect.top();
+        for (part, (lines, height)) in parts.iter().zip(heights(&parts)).enumerate() {
+            if part > 0 {
+                svg.polyline(
+                    &[egui::pos2(rect.left(), top), egui::pos2(rect.right(), top)],
+                    palette.line,
+                    LineStyle::Solid,
+                );
+            }
+            let center_y = top + height / 2.0;
+            if part == 0 {
+                let (annotations, name) = lines.split_at(lines.len() - 1);
+                let text_top = center_y - lines.len() as f32 * DIAGRAM_LINE_HEIGHT / 2.0;
+                let annotations_height = annotations.len() as f32 * DIAGRAM_LINE_HEIGHT;
+                svg.text(
+                    egui::pos2(rect.center().x, text_top + annotations_height / 2.0),
+                    annotations,
+                    "middle",
+                    false,
+                );
+                svg.text(
+                    egui::pos2(
+                        rect.center().x,
+                        text_top + annotations_height + DIAGRAM_LINE_HEIGHT / 2.0,
+                    ),
+                    name,
+                    "middle",
+                    true,
+                );
+            } else {
+                svg.text(
+                    egui::pos2(rect.left() + PADDING.x, center_y),
+                    lines,
+                    "start",
+                    false,
+                );
             }
+            top += height;
         }
-        out.push(':');
-        out.push_str(name);
-        out.push(':');
-
-        i = colon1 + 1 + colon2_rel + 1;
     }
-
-    out.push_str(&text[i..]);
-    out
+    for (relation, pos) in diagram.relations.iter().zip(&layout.labels) {
+        if let (Some(label), Some(pos)) = (&relation.label, pos) {
+            svg.label(*pos, std::slice::from_ref(label));
+        }
+    }
+    svg.finish(bounds)
 }
 
-fn is_valid_emoji_shortcode(s: &str) -> bool {
-    !s.is_empty()
-        && s.len() <= 64
-        && s.chars()
-            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-'))
+fn render_dot_offline(
+    source: &str,
+    palette: DiagramPalette,
+) -> std::result::Result<String, String> {
+    let chart = parse_dot(source)?;
+    Ok(render_flowchart(&chart, palette))
 }
 
-fn smart_typography(text: &str) -> String {
-    let mut out = String::with_capacity(text.len());
-
-    let mut chars = text.chars().peekable();
-    let mut prev_char: Option<char> = None;
-    let mut double_open = true;
-    let mut single_open = true;
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DotToken {
+    Id(String),
+    Punct(char),
+    EdgeOp,
+}
 
+fn tokenize_dot(source: &str) -> std::result::Result<Vec<DotToken>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    let mut line_start = true;
     while let Some(ch) = chars.next() {
         match ch {
-            '.' => {
-                let mut dots = 1;
-                while dots < 3 && chars.peek().copied() == Some('.') {
-                    dots += 1;
-                    chars.next();
-                }
-                if dots == 3 {
-                    out.push('…');
-                    prev_char = Some('…');
-                    continue;
-                }
-                for _ in 0..dots {
-                    out.push('.');
-                }
-                prev_char = Some('.');
+            '\n' => {
+                line_start = true;
+                continue;
             }
-            '-' => {
-                if chars.peek().copied() == Some('-') {
-                    chars.next();
-                    out.push('—');
-                    prev_char = Some('—');
-                    continue;
+            ch if ch.is_whitespace() => continue,
+            // `#` lines are C preprocessor output, which Graphviz skips too.
+            '#' if line_start => {
+                chars.by_ref().take_while(|&c| c != '\n').for_each(drop);
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                chars.by_ref().take_while(|&c| c != '\n').for_each(drop);
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
                 }
-                out.push('-');
-                prev_char = Some('-');
+            }
+            '-' if matches!(chars.peek(), Some('>' | '-')) => {
+                chars.next();
+                tokens.push(DotToken::EdgeOp);
             }
             '"' => {
-                out.push(if double_open { '“' } else { '”' });
-                double_open = !double_open;
-                prev_char = Some('"');
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err("unterminated string".to_string()),
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('"') => text.push('"'),
+                            Some('\n') => {}
+                            Some(c) => {
+                                text.push('\\');
+                                text.push(c);
+                            }
+                            None => return Err("unterminated string".to_string()),
+                        },
+                        Some(c) => text.push(c),
+                    }
+                }
+                if let Some(DotToken::Punct('+')) = tokens.last() {
+                    tokens.pop();
+                    if let Some(DotToken::Id(previous)) = tokens.last_mut() {
+                        previous.push_str(&text);
+                        line_start = false;
+                        continue;
+                    }
+                }
+                tokens.push(DotToken::Id(text));
             }
-            '\'' => {
-                let next = chars.peek().copied();
-                let prev_is_word = prev_char.is_some_and(|c| c.is_alphanumeric());
-                let next_is_word = next.is_some_and(|c| c.is_alphanumeric());
-                if prev_is_word && next_is_word {
-                    out.push('’');
-                } else {
-                    out.push(if single_open { '‘' } else { '’' });
-                    single_open = !single_open;
+            '<' => {
+                let mut depth = 1;
+                let mut text = String::new();
+                for c in chars.by_ref() {
+                    match c {
+                        '<' => depth += 1,
+                        '>' => depth -= 1,
+                        _ => {}
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                    text.push(c);
                 }
-                prev_char = Some('\'');
+                if depth != 0 {
+                    return Err("unterminated HTML label".to_string());
+                }
+                tokens.push(DotToken::Id(dot_html_text(&text)));
             }
-            _ => {
-                out.push(ch);
-                prev_char = Some(ch);
+            '{' | '}' | '[' | ']' | ';' | ',' | '=' | ':' | '+' => tokens.push(DotToken::Punct(ch)),
+            ch if ch.is_alphanumeric() || ch == '_' || ch == '.' || ch == '-' => {
+                let mut text = ch.to_string();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_' || c == '.') {
+                        break;
+                    }
+                    text.push(c);
+                    chars.next();
+                }
+                tokens.push(DotToken::Id(text));
             }
+            other => return Err(format!("unexpected `{other}`")),
         }
+        line_start = false;
     }
-
-    out
+    Ok(tokens)
 }
 
-fn spawn_math_worker(
-    ctx: egui::Context,
-    cache: Arc<Mutex<HashMap<MathKey, SvgState>>>,
-    rx: mpsc::Receiver<MathKey>,
-) {
-    thread::spawn(move || {
-        while let Ok(key) = rx.recv() {
-            let next_state = match render_math_svg(&key) {
-                Ok(svg_bytes) => SvgState::Ready(svg_bytes),
-                Err(err) => SvgState::Error(err),
-            };
-
-            if let Ok(mut map) = cache.lock() {
-                map.insert(key, next_state);
-            }
-            ctx.request_repaint();
+fn dot_html_text(html: &str) -> String {
+    static TAG: OnceLock<Regex> = OnceLock::new();
+    let tag = TAG.get_or_init(|| Regex::new(r"(?i)<(/?)(\w+)[^>]*>").expect("valid tag regex"));
+    let text = tag.replace_all(html, |caps: &Captures| {
+        if caps[2].eq_ignore_ascii_case("br")
+            || (&caps[1] == "/" && caps[2].eq_ignore_ascii_case("tr"))
+        {
+            "\\n"
+        } else {
+            ""
         }
     });
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
 }
 
-fn render_math_svg(key: &MathKey) -> std::result::Result<Arc<[u8]>, String> {
-    let svg = if key.inline {
-        mathjax_svg::convert_to_svg_inline(&key.tex)
-    } else {
-        mathjax_svg::convert_to_svg(&key.tex)
-    }
-    .map_err(|e| e.to_string())?;
+#[derive(Debug, Clone, Default)]
+struct DotScope {
+    graph: HashMap<String, String>,
+    node: HashMap<String, String>,
+    edge: HashMap<String, String>,
+}
 
-    let svg = apply_svg_text_color(&svg, key.color);
-    Ok(Arc::<[u8]>::from(svg.into_bytes()))
+struct DotParser {
+    tokens: Vec<DotToken>,
+    pos: usize,
+    directed: bool,
+    chart: Flowchart,
 }
 
-fn apply_svg_text_color(svg: &str, color: egui::Color32) -> String {
-    let [r, g, b, a] = color.to_srgba_unmultiplied();
-    let css = if a == 255 {
-        format!("color: rgb({r}, {g}, {b});")
-    } else {
-        format!("color: rgba({r}, {g}, {b}, {:.3});", (a as f32) / 255.0)
+fn parse_dot(source: &str) -> std::result::Result<Flowchart, String> {
+    let mut parser = DotParser {
+        tokens: tokenize_dot(source)?,
+        pos: 0,
+        directed: false,
+        chart: Flowchart {
+            direction: FlowDirection::TopDown,
+            nodes: Vec::new(),
+            edges: Vec::new(),
+            subgraphs: Vec::new(),
+        },
     };
+    parser.keyword("strict");
+    if parser.keyword("digraph") {
+        parser.directed = true;
+    } else if !parser.keyword("graph") {
+        return Err("expected `graph` or `digraph`".to_string());
+    }
+    if let Some(DotToken::Id(_)) = parser.peek() {
+        parser.pos += 1;
+    }
+    parser.expect('{')?;
+    let mut scope = DotScope::default();
+    let mut members = Vec::new();
+    parser.statements(&mut scope, &mut members)?;
+    parser.expect('}')?;
+    if let Some(rankdir) = scope.graph.get("rankdir") {
+        parser.chart.direction = FlowDirection::parse(rankdir).unwrap_or(FlowDirection::TopDown);
+    }
+    Ok(parser.chart)
+}
 
-    let Some(svg_start) = svg.find("<svg") else {
-        return svg.to_owned();
-    };
-    let Some(tag_end_rel) = svg[svg_start..].find('>') else {
-        return svg.to_owned();
-    };
-    let tag_end = svg_start + tag_end_rel;
-    let start_tag = &svg[svg_start..tag_end];
+impl DotParser {
+    fn peek(&self) -> Option<&DotToken> {
+        self.tokens.get(self.pos)
+    }
 
-    if let Some(style_pos_rel) = start_tag.find("style=\"") {
-        let value_start = svg_start + style_pos_rel + "style=\"".len();
-        let Some(value_end_rel) = svg[value_start..].find('"') else {
-            return svg.to_owned();
+    fn punct(&mut self, ch: char) -> bool {
+        let found = self.peek() == Some(&DotToken::Punct(ch));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, ch: char) -> std::result::Result<(), String> {
+        if self.punct(ch) {
+            return Ok(());
+        }
+        Err(match self.peek() {
+            Some(DotToken::Id(id)) => format!("expected `{ch}` before `{id}`"),
+            Some(DotToken::Punct(found)) => format!("expected `{ch}` before `{found}`"),
+            Some(DotToken::EdgeOp) => format!("expected `{ch}` before an edge"),
+            None => format!("expected `{ch}` at the end"),
+        })
+    }
+
+    fn keyword(&mut self, word: &str) -> bool {
+        let found = matches!(self.peek(), Some(DotToken::Id(id)) if id.eq_ignore_ascii_case(word));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn id(&mut self) -> std::result::Result<String, String> {
+        match self.tokens.get(self.pos) {
+            Some(DotToken::Id(id)) => {
+                self.pos += 1;
+                Ok(id.clone())
+            }
+            _ => Err("expected an identifier".to_string()),
+        }
+    }
+
+    fn statements(
+        &mut self,
+        scope: &mut DotScope,
+        members: &mut Vec<usize>,
+    ) -> std::result::Result<(), String> {
+        while !matches!(self.peek(), Some(DotToken::Punct('}')) | None) {
+            self.statement(scope, members)?;
+            while self.punct(';') || self.punct(',') {}
+        }
+        Ok(())
+    }
+
+    fn statement(
+        &mut self,
+        scope: &mut DotScope,
+        members: &mut Vec<usize>,
+    ) -> std::result::Result<(), String> {
+        let is_attr_target = |p: &Self| p.tokens.get(p.pos + 1) == Some(&DotToken::Punct('['));
+        for word in ["graph", "node", "edge"] {
+            if is_attr_target(self) && self.keyword(word) {
+                let attrs = self.attributes()?;
+                match word {
+                    "graph" => scope.graph.extend(attrs),
+                    "node" => scope.node.extend(attrs),
+                    _ => scope.edge.extend(attrs),
+                }
+                return Ok(());
+            }
+        }
+        if let (Some(DotToken::Id(key)), Some(DotToken::Punct('='))) =
+            (self.peek(), self.tokens.get(self.pos + 1))
+        {
+            let key = key.clone();
+            self.pos += 2;
+            let value = self.id()?;
+            scope.graph.insert(key, value);
+            return Ok(());
+        }
+
+        let (first, is_node) = self.operand(scope, members)?;
+        let mut operands = vec![first];
+        while self.peek() == Some(&DotToken::EdgeOp) {
+            self.pos += 1;
+            operands.push(self.operand(scope, members)?.0);
+        }
+        let attrs = if self.peek() == Some(&DotToken::Punct('[')) {
+            self.attributes()?
+        } else {
+            Vec::new()
         };
-        let value_end = value_start + value_end_rel;
-        let existing = &svg[value_start..value_end];
+        if operands.len() == 1 {
+            if is_node {
+                self.apply_node_attrs(operands[0][0], &attrs);
+            }
+            return Ok(());
+        }
+        let mut edge_attrs = scope.edge.clone();
+        edge_attrs.extend(attrs);
+        let template = self.edge_template(&edge_attrs);
+        for pair in operands.windows(2) {
+            for &from in &pair[0] {
+                for &to in &pair[1] {
+                    self.chart.edges.push(FlowEdge {
+                        from,
+                        to,
+                        ..template.clone()
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let mut out = String::with_capacity(svg.len() + css.len() + 2);
-        out.push_str(&svg[..value_end]);
-        if !existing.trim().is_empty() && !existing.trim_end().ends_with(';') {
-            out.push(';');
+    fn operand(
+        &mut self,
+        scope: &mut DotScope,
+        members: &mut Vec<usize>,
+    ) -> std::result::Result<(Vec<usize>, bool), String> {
+        if self.keyword("subgraph") || self.peek() == Some(&DotToken::Punct('{')) {
+            let name = match self.peek() {
+                Some(DotToken::Id(_)) => self.id()?,
+                _ => String::new(),
+            };
+            self.expect('{')?;
+            let mut inner = DotScope {
+                graph: HashMap::new(),
+                ..scope.clone()
+            };
+            let mut inner_members = Vec::new();
+            self.statements(&mut inner, &mut inner_members)?;
+            self.expect('}')?;
+            if name.starts_with("cluster") {
+                let title = inner.graph.get("label").cloned().unwrap_or_default();
+                self.chart.subgraphs.push(FlowSubgraph {
+                    title: dot_label_lines(&title),
+                    members: inner_members.clone(),
+                });
+            }
+            for &idx in &inner_members {
+                if !members.contains(&idx) {
+                    members.push(idx);
+                }
+            }
+            return Ok((inner_members, false));
         }
-        out.push_str(&css);
-        out.push_str(&svg[value_end..]);
-        return out;
+
+        let id = self.id()?;
+        // Ports (`node:port:compass`) only matter to Graphviz's own router.
+        while self.punct(':') {
+            self.id()?;
+        }
+        let before = self.chart.nodes.len();
+        let idx = self.chart.node(&id);
+        if idx == before {
+            self.chart.nodes[idx].shape = NodeShape::Ellipse;
+            let defaults: Vec<(String, String)> = scope.node.clone().into_iter().collect();
+            self.apply_node_attrs(idx, &defaults);
+        }
+        if !members.contains(&idx) {
+            members.push(idx);
+        }
+        Ok((vec![idx], true))
     }
 
-    let mut out = String::with_capacity(svg.len() + css.len() + 16);
-    out.push_str(&svg[..tag_end]);
-    out.push_str(" style=\"");
-    out.push_str(&css);
-    out.push('"');
-    out.push_str(&svg[tag_end..]);
-    out
+    fn attributes(&mut self) -> std::result::Result<Vec<(String, String)>, String> {
+        let mut attrs = Vec::new();
+        while self.punct('[') {
+            while !self.punct(']') {
+                let key = self.id()?;
+                let value = if self.punct('=') {
+                    self.id()?
+                } else {
+                    "true".to_string()
+                };
+                attrs.push((key, value));
+                while self.punct(',') || self.punct(';') {}
+            }
+        }
+        Ok(attrs)
+    }
+
+    fn apply_node_attrs(&mut self, idx: usize, attrs: &[(String, String)]) {
+        // Graphviz ignores attribute order, but records and `style=rounded` depend on
+        // the shape, so it goes first whichever order the attributes were written in.
+        let mut attrs: Vec<&(String, String)> = attrs.iter().collect();
+        attrs.sort_by_key(|(key, _)| match key.as_str() {
+            "shape" => 0,
+            "label" => 1,
+            _ => 2,
+        });
+        let node = &mut self.chart.nodes[idx];
+        for (key, value) in attrs {
+            match key.as_str() {
+                "label" => {
+                    let label = value.replace("\\N", &node.id);
+                    node.label = if node.shape == NodeShape::Rect && label.contains(['|', '{']) {
+                        dot_record_lines(&label)
+                    } else {
+                        dot_label_lines(&label)
+                    };
+                }
+                "shape" => {
+                    node.shape = match value.to_ascii_lowercase().as_str() {
+                        "box" | "rect" | "rectangle" | "square" | "record" | "note" | "tab"
+                        | "folder" | "component" | "box3d" => NodeShape::Rect,
+                        "mrecord" => NodeShape::Round,
+                        "circle" | "doublecircle" | "point" => NodeShape::Circle,
+                        "diamond" | "mdiamond" => NodeShape::Diamond,
+                        "hexagon" | "octagon" | "doubleoctagon" => NodeShape::Hexagon,
+                        "parallelogram" => NodeShape::Parallelogram,
+                        "cylinder" => NodeShape::Cylinder,
+                        "plaintext" | "plain" | "none" => NodeShape::Plain,
+                        _ => NodeShape::Ellipse,
+                    };
+                    if matches!(value.as_str(), "record" | "Mrecord") {
+                        let label = node.label.join("\\n");
+                        node.label = dot_record_lines(&label);
+                    }
+                }
+                "style" => {
+                    let styles: Vec<&str> = value.split(',').map(str::trim).collect();
+                    node.hidden = styles.contains(&"invis");
+                    if styles.contains(&"rounded") && node.shape == NodeShape::Rect {
+                        node.shape = NodeShape::Round;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn edge_template(&self, attrs: &HashMap<String, String>) -> FlowEdge {
+        let attr = |key: &str| attrs.get(key).map(String::as_str);
+        let head = |name: Option<&str>| match name.unwrap_or("normal") {
+            "none" => ArrowHead::None,
+            "vee" | "open" => ArrowHead::Open,
+            "empty" | "onormal" => ArrowHead::Triangle,
+            "diamond" => ArrowHead::FilledDiamond,
+            "odiamond" | "ediamond" => ArrowHead::Diamond,
+            "dot" | "odot" => ArrowHead::Circle,
+            "tee" | "box" | "obox" | "crow" => ArrowHead::Cross,
+            _ => ArrowHead::Filled,
+        };
+        let default_dir = if self.directed { "forward" } else { "none" };
+        let (start, end) = match attr("dir").unwrap_or(default_dir) {
+            "forward" => (ArrowHead::None, head(attr("arrowhead"))),
+            "back" => (head(attr("arrowtail")), ArrowHead::None),
+            "both" => (head(attr("arrowtail")), head(attr("arrowhead"))),
+            _ => (ArrowHead::None, ArrowHead::None),
+        };
+        let styles: Vec<&str> = attr("style")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .collect();
+        let style = if styles.contains(&"dashed") {
+            LineStyle::Dashed
+        } else if styles.contains(&"dotted") {
+            LineStyle::Dotted
+        } else if styles.contains(&"bold")
+            || attr("penwidth").is_some_and(|w| w.parse::<f32>().is_ok_and(|w| w >= 2.0))
+        {
+            LineStyle::Thick
+        } else {
+            LineStyle::Solid
+        };
+        FlowEdge {
+            from: 0,
+            to: 0,
+            label: attr("label")
+                .or(attr("xlabel"))
+                .filter(|label| !label.is_empty())
+                .map(dot_label_lines),
+            style,
+            start,
+            end,
+            hidden: styles.contains(&"invis"),
+        }
+    }
+}
+
+fn dot_label_lines(label: &str) -> Vec<String> {
+    let mut lines: Vec<String> = label
+        .split("\\n")
+        .flat_map(|part| part.split("\\l"))
+        .flat_map(|part| part.split("\\r"))
+        .map(|line| line.replace("\\\\", "\\"))
+        .collect();
+    while lines.len() > 1 && lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+    }
+    lines
+}
+
+fn dot_record_lines(label: &str) -> Vec<String> {
+    static PORT: OnceLock<Regex> = OnceLock::new();
+    let port = PORT.get_or_init(|| Regex::new(r"<[^>]*>").expect("valid record port regex"));
+    let label = port.replace_all(label, "");
+    label
+        .split(['|', '{', '}'])
+        .flat_map(dot_label_lines)
+        .map(|field| field.trim().to_string())
+        .filter(|field| !field.is_empty())
+        .collect()
 }
 
-fn spawn_mermaid_worker(
+fn spawn_diagram_worker(
     ctx: egui::Context,
-    cache: Arc<Mutex<HashMap<MermaidKey, SvgState>>>,
-    rx: mpsc::Receiver<MermaidKey>,
+    cache: Arc<Mutex<SvgCache<DiagramKey>>>,
+    disk: Option<Arc<RenderDiskCache>>,
+    rx: mpsc::Receiver<DiagramKey>,
 ) {
     thread::spawn(move || {
-        let client = reqwest::blocking::Client::builder()
-            .user_agent("markdownviewer")
-            .build()
-            .unwrap_or_else(|_| reqwest::blocking::Client::new());
+        let mut client = None;
 
         while let Ok(key) = rx.recv() {
-            let next_state = match render_mermaid_svg(&client, &key.source) {
+            if !cache.lock().is_ok_and(|cache| cache.wants(&key)) {
+                continue;
+            }
+            let engine = key.engine.kroki_name();
+            let palette = key.palette.cache_key();
+            let offline_key = [
+                "diagram",
+                engine,
+                OFFLINE_DIAGRAM_RENDERER_VERSION,
+                &palette,
+                &key.source,
+            ];
+            let offline = cached_render(disk.as_deref(), &offline_key, || {
+                render_diagram_offline(key.engine, &key.source, key.palette)
+                    .map(|svg| Arc::from(svg.into_bytes()))
+            });
+            let next_state = match offline {
                 Ok(svg_bytes) => SvgState::Ready(svg_bytes),
-                Err(err) => SvgState::Error(err),
+                // Only diagrams the offline renderer can't handle ever leave the machine,
+                // and only when the user opted in. Kroki ignores the theme, so its SVGs
+                // are cached per service rather than per palette.
+                Err(err) => match &key.remote {
+                    Some(url) => {
+                        let service = format!("kroki {url}");
+                        let remote_key = ["diagram", engine, &service, &key.source];
+                        let rendered = cached_render(disk.as_deref(), &remote_key, || {
+                            let client = client.get_or_insert_with(|| {
+                                reqwest::blocking::Client::builder()
+                                    .user_agent("markdownviewer")
+                                    .build()
+                                    .unwrap_or_else(|_| reqwest::blocking::Client::new())
+                            });
+                            render_kroki_svg(client, url, key.engine, &key.source)
+                        });
+                        match rendered {
+                            Ok(svg_bytes) => SvgState::Ready(svg_bytes),
+                            Err(remote_err) => SvgState::Error(format!("{err}; {remote_err}")),
+                        }
+                    }
+                    None => SvgState::Error(format!(
+                        "{err}. Enable remote diagram rendering in Options to use a diagram service."
+                    )),
+                },
             };
 
-            if let Ok(mut map) = cache.lock() {
-                map.insert(key, next_state);
+            if let Ok(mut cache) = cache.lock() {
+                cache.finish(&key, next_state);
             }
             ctx.request_repaint();
         }
     });
 }
 
-fn render_mermaid_svg(
+fn render_diagram_offline(
+    engine: DiagramEngine,
+    source: &str,
+    palette: DiagramPalette,
+) -> std::result::Result<String, String> {
+    match engine {
+        DiagramEngine::Mermaid => render_mermaid_offline(source, palette),
+        DiagramEngine::Graphviz => render_dot_offline(source, palette),
+        _ => Err(format!(
+            "{} diagrams can't be rendered offline",
+            engine.label()
+        )),
+    }
+}
+
+fn render_kroki_svg(
     client: &reqwest::blocking::Client,
+    kroki_url: &str,
+    engine: DiagramEngine,
     source: &str,
 ) -> std::result::Result<Arc<[u8]>, String> {
-    const KROKI_URL: &str = "https://kroki.io/mermaid/svg";
+    let url = format!(
+        "{}/{}/svg",
+        kroki_url.trim_end_matches('/'),
+        engine.kroki_name()
+    );
 
     let response = client
-        .post(KROKI_URL)
+        .post(url)
         .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
         .body(source.to_owned())
         .send()
@@ -4243,12 +12016,34 @@ fn render_mermaid_svg(
     Ok(Arc::<[u8]>::from(body.to_vec()))
 }
 
+fn request_svg<K: Clone + Eq + std::hash::Hash>(
+    ui: &egui::Ui,
+    slot: SvgSlotId,
+    key: K,
+    key_bytes: usize,
+    uri: String,
+    cache: &Arc<Mutex<SvgCache<K>>>,
+    request: impl FnOnce(K),
+) -> (String, SvgState) {
+    let now = ui.input(|i| i.time);
+    let (uri, state, wait) = cache
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .show_slot(slot, &key, key_bytes, &uri, now, request);
+    if let Some(wait) = wait {
+        ui.ctx()
+            .request_repaint_after(Duration::from_secs_f64(wait));
+    }
+    (uri, state)
+}
+
 fn render_math(
     ui: &mut egui::Ui,
     tex: &str,
     inline: bool,
-    cache: &Arc<Mutex<HashMap<MathKey, SvgState>>>,
-    tx: &mpsc::Sender<MathKey>,
+    slot: SvgSlotId,
+    cache: &Arc<Mutex<SvgCache<MathKey>>>,
+    pool: &MathPool,
 ) {
     let key = MathKey {
         tex: tex.to_owned(),
@@ -4256,23 +12051,15 @@ fn render_math(
         color: ui.visuals().text_color(),
     };
     let uri = format!("math-{}.svg", egui::Id::new(&key).value());
-
-    let mut should_request = false;
-    let state = {
-        let mut map = cache.lock().unwrap_or_else(|e| e.into_inner());
-        match map.get(&key) {
-            Some(existing) => existing.clone(),
-            None => {
-                should_request = true;
-                map.insert(key.clone(), SvgState::Pending);
-                SvgState::Pending
-            }
-        }
-    };
-
-    if should_request {
-        let _ = tx.send(key);
-    }
+    let key_bytes = key.tex.len();
+    let line = egui::vec2(ui.available_width(), ui.text_style_height(&TextStyle::Body));
+    let visible = ui.is_rect_visible(egui::Rect::from_min_size(ui.cursor().min, line));
+    if visible {
+        pool.promote(&key);
+    }
+    let (uri, state) = request_svg(ui, slot, key, key_bytes, uri, cache, |key| {
+        pool.push(key, visible)
+    });
 
     match state {
         SvgState::Ready(bytes) => {
@@ -4311,15 +12098,15 @@ fn render_math(
 fn render_html(
     ui: &mut egui::Ui,
     html: &str,
-    render_mermaid_enabled: bool,
-    cache: &Arc<Mutex<HashMap<MermaidKey, SvgState>>>,
-    tx: &mpsc::Sender<MermaidKey>,
+    render_diagrams_enabled: bool,
+    remote: Option<&str>,
+    slot: SvgSlotId,
+    cache: &Arc<Mutex<SvgCache<DiagramKey>>>,
+    tx: &mpsc::Sender<DiagramKey>,
 ) {
-    if render_mermaid_enabled {
-        if let Some(source) = extract_mermaid_source(html) {
-            render_mermaid(ui, &source, cache, tx);
-            return;
-        }
+    if render_diagrams_enabled && let Some((engine, source)) = extract_diagram_source(html) {
+        render_diagram(ui, engine, &source, remote, slot, cache, tx);
+        return;
     }
 
     let mut html_text = html;
@@ -4331,33 +12118,31 @@ fn render_html(
     );
 }
 
-fn render_mermaid(
+fn render_diagram(
     ui: &mut egui::Ui,
+    engine: DiagramEngine,
     source: &str,
-    cache: &Arc<Mutex<HashMap<MermaidKey, SvgState>>>,
-    tx: &mpsc::Sender<MermaidKey>,
+    remote: Option<&str>,
+    slot: SvgSlotId,
+    cache: &Arc<Mutex<SvgCache<DiagramKey>>>,
+    tx: &mpsc::Sender<DiagramKey>,
 ) {
-    let key = MermaidKey {
-        source: source.to_owned(),
-    };
-    let uri = format!("mermaid-{}.svg", egui::Id::new(&key).value());
-
-    let mut should_request = false;
-    let state = {
-        let mut map = cache.lock().unwrap_or_else(|e| e.into_inner());
-        match map.get(&key) {
-            Some(existing) => existing.clone(),
-            None => {
-                should_request = true;
-                map.insert(key.clone(), SvgState::Pending);
-                SvgState::Pending
-            }
-        }
+    let key = DiagramKey {
+        engine,
+        source: source.to_owned(),
+        palette: DiagramPalette::from_visuals(ui.visuals()),
+        remote: remote.map(str::to_owned),
     };
+    let uri = format!(
+        "{}-{}.svg",
+        engine.kroki_name(),
+        egui::Id::new(&key).value()
+    );
 
-    if should_request {
+    let key_bytes = key.source.len() + key.remote.as_ref().map_or(0, String::len);
+    let (uri, state) = request_svg(ui, slot, key, key_bytes, uri, cache, |key| {
         let _ = tx.send(key);
-    }
+    });
 
     match state {
         SvgState::Ready(bytes) => {
@@ -4373,13 +12158,16 @@ fn render_mermaid(
         SvgState::Pending => {
             ui.horizontal(|ui| {
                 ui.add(egui::Spinner::new());
-                ui.weak("Rendering Mermaid…");
+                ui.weak(format!("Rendering {}…", engine.label()));
             });
         }
         SvgState::Error(err) => {
-            ui.colored_label(ui.visuals().error_fg_color, format!("Mermaid error: {err}"));
+            ui.colored_label(
+                ui.visuals().error_fg_color,
+                format!("{} error: {err}", engine.label()),
+            );
             ui.add_space(4.0);
-            ui.collapsing("Show Mermaid source", |ui| {
+            ui.collapsing(format!("Show {} source", engine.label()), |ui| {
                 let mut src = source;
                 ui.add(
                     egui::TextEdit::multiline(&mut src)
@@ -4392,22 +12180,32 @@ fn render_mermaid(
     }
 }
 
-fn extract_mermaid_source(html: &str) -> Option<String> {
+fn extract_diagram_source(html: &str) -> Option<(DiagramEngine, String)> {
+    static PRE: OnceLock<Regex> = OnceLock::new();
     let html = html.trim();
 
-    let start = if html.starts_with("<div class=\"mermaid\">") {
-        "<div class=\"mermaid\">"
-    } else if html.starts_with("<div class='mermaid'>") {
-        "<div class='mermaid'>"
+    let pre = PRE.get_or_init(|| {
+        Regex::new(r#"^<pre class="diagram" data-engine="([a-z0-9-]+)">"#)
+            .expect("valid diagram block regex")
+    });
+    let (engine, inner, close) = if let Some(caps) = pre.captures(html) {
+        let engine = DiagramEngine::ALL
+            .into_iter()
+            .find(|engine| engine.kroki_name() == &caps[1])?;
+        (engine, &html[caps[0].len()..], "</pre>")
+    } else if let Some(inner) = html
+        .strip_prefix("<div class=\"mermaid\">")
+        .or_else(|| html.strip_prefix("<div class='mermaid'>"))
+    {
+        (DiagramEngine::Mermaid, inner, "</div>")
     } else {
         return None;
     };
 
-    let inner = html.strip_prefix(start)?;
-    let end = inner.rfind("</div>")?;
+    let end = inner.rfind(close)?;
     let inner = inner[..end].trim_matches(['\n', '\r', ' ', '\t']);
 
-    Some(dedent_block(inner))
+    Some((engine, dedent_block(inner)))
 }
 
 fn dedent_block(text: &str) -> String {
@@ -4456,6 +12254,31 @@ fn dedent_block(text: &str) -> String {
 mod tests {
     use super::*;
 
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
     #[test]
     fn converts_basic_formatting() {
         let md = convert_html_to_markdown(
@@ -4474,6 +12297,1072 @@ mod tests {
         assert!(md.contains("- One"));
         assert!(md.contains("[Two](https://example.com?a=1)"));
     }
+
+    #[test]
+    fn recovery_snapshot_round_trips() {
+        let snapshot = RecoverySnapshot {
+            file_path: Some(PathBuf::from("/tmp/notes.md")),
+            saved_at: 1_700_000_000,
+            content: "# Notes\n\nsaved 12\npath x\n".to_string(),
+        };
+        let decoded = decode_recovery_snapshot(&encode_recovery_snapshot(&snapshot));
+        assert_eq!(decoded, Some(snapshot));
+    }
+
+    #[test]
+    fn saving_clears_dirty_and_pending_closes_ask_about_unsaved_documents() {
+        let settings = ViewerSettings::default();
+        let dir = TempDir::new("save-test");
+        let mut documents: Vec<Document> = (1..=3)
+            .map(|id| Document::from_content(id, "# Draft\n".to_string(), None, &settings))
+            .collect();
+        for doc in &mut documents[1..] {
+            doc.raw_markdown.push_str("more\n");
+            doc.mark_edited(&settings);
+        }
+        assert!(!documents[0].dirty && documents[1].dirty);
+
+        assert!(PendingClose::Tab(1).dirty_documents(&documents).is_empty());
+        assert_eq!(PendingClose::Tab(2).dirty_documents(&documents), [1]);
+        assert_eq!(PendingClose::Exit.dirty_documents(&documents), [1, 2]);
+
+        let path = dir.join("draft.md");
+        documents[1].save_to(path.clone(), &settings).unwrap();
+        assert!(!documents[1].dirty);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "# Draft\nmore\n");
+        assert_eq!(documents[1].file_path, Some(normalize_path(path)));
+        assert!(PendingClose::Tab(2).dirty_documents(&documents).is_empty());
+        assert_eq!(PendingClose::Exit.dirty_documents(&documents), [2]);
+
+        documents[2].raw_markdown = "# Draft\n".to_string();
+        documents[2].mark_edited(&settings);
+        assert!(PendingClose::Exit.dirty_documents(&documents).is_empty());
+    }
+
+    #[test]
+    fn searches_open_buffers_before_disk_and_drops_stale_requests() {
+        let settings = ViewerSettings::default();
+        let root = TempDir::new("search-test");
+        let (open_path, closed_path) = (root.join("open.md"), root.join("closed.md"));
+        fs::write(&open_path, "needle on disk\n").unwrap();
+        fs::write(&closed_path, "a\nneedle\n").unwrap();
+
+        let mut doc = Document::from_path(7, open_path.clone(), &settings).unwrap();
+        doc.raw_markdown = "edited\n\nneedle in buffer\n".to_string();
+        doc.mark_edited(&settings);
+        let mut workspace = Workspace::new(root.to_path_buf());
+        workspace.set_tree(scan_workspace_dir(&root, 0));
+
+        let generation = Arc::new(AtomicU64::new(2));
+        let (tx, rx) = mpsc::channel();
+        let (event_tx, event_rx) = mpsc::channel();
+        spawn_search_worker(egui::Context::default(), generation, rx, event_tx);
+        let request = |generation| SearchRequest {
+            generation,
+            regex: build_find_regex("needle", FindOptions::default()).unwrap(),
+            whole_word: false,
+            sources: search_sources(std::slice::from_ref(&doc), Some(&workspace)),
+        };
+        tx.send(request(1)).unwrap();
+        tx.send(request(2)).unwrap();
+
+        let mut results = Vec::new();
+        loop {
+            match event_rx.recv_timeout(Duration::from_secs(10)).unwrap() {
+                SearchEvent::File { generation, result } => {
+                    assert_eq!(generation, 2);
+                    results.push(result);
+                }
+                SearchEvent::Done {
+                    generation,
+                    files_searched,
+                } => {
+                    assert_eq!((generation, files_searched), (2, 2));
+                    break;
+                }
+            }
+        }
+        let found: Vec<_> = results
+            .iter()
+            .map(|result| (result.doc_id, result.title.as_str(), result.matches[0].line))
+            .collect();
+        assert_eq!(found, [(Some(7), "open.md", 2), (None, "closed.md", 1)]);
+    }
+
+    #[test]
+    fn recovery_skips_sessions_that_are_still_running() {
+        let dir = TempDir::new("recovery-test");
+        let snapshot = RecoverySnapshot {
+            file_path: None,
+            saved_at: 1,
+            content: "unsaved".to_string(),
+        };
+        let session = "1-42";
+        let file = dir.join(format!("{session}-7.{RECOVERY_EXTENSION}"));
+        fs::write(&file, encode_recovery_snapshot(&snapshot)).unwrap();
+
+        let lock = fs::File::create(dir.join(recovery_lock_name(session))).unwrap();
+        lock.try_lock().unwrap();
+        assert!(load_recovered_buffers(&dir).is_empty());
+
+        drop(lock);
+        let recovered = load_recovered_buffers(&dir);
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].recovery_file, file);
+        assert!(!dir.join(recovery_lock_name(session)).exists());
+    }
+
+    #[test]
+    fn scans_workspace_for_markdown_files() {
+        assert!(is_markdown_path(Path::new("notes/README.MD")));
+        assert!(is_markdown_path(Path::new("a.mkdn")));
+        assert!(!is_markdown_path(Path::new("a.txt")));
+        assert!(!is_markdown_path(Path::new("md")));
+
+        let root = TempDir::new("workspace-test");
+        for dir in [
+            "docs/guide",
+            ".git",
+            "node_modules/pkg",
+            "target/doc",
+            "assets",
+        ] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "b.md",
+            "A.markdown",
+            "notes.txt",
+            "docs/guide/intro.md",
+            ".git/HEAD.md",
+            "node_modules/pkg/readme.md",
+            "target/doc/index.md",
+            "assets/logo.svg",
+        ] {
+            fs::write(root.join(file), "").unwrap();
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let (scan_tx, scan_rx) = mpsc::channel();
+        spawn_workspace_scanner(egui::Context::default(), rx, scan_tx);
+        tx.send(root.to_path_buf()).unwrap();
+        let (scanned, tree) = scan_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(scanned, *root);
+
+        let mut files = Vec::new();
+        tree.collect_files(&mut files);
+        let files: Vec<_> = files
+            .iter()
+            .map(|path| path.strip_prefix(&*root).unwrap().to_path_buf())
+            .collect();
+        assert_eq!(
+            files,
+            ["A.markdown", "b.md", "docs/guide/intro.md"].map(PathBuf::from)
+        );
+        let dirs: Vec<_> = tree.dirs.iter().map(|dir| dir.name.as_str()).collect();
+        assert_eq!(dirs, ["docs"]);
+    }
+
+    #[test]
+    fn preview_clicks_on_links_are_not_line_jumps() {
+        let ctx = egui::Context::default();
+        let frame = |events: Vec<egui::Event>| {
+            let input = egui::RawInput {
+                events,
+                screen_rect: Some(egui::Rect::from_min_size(
+                    egui::Pos2::ZERO,
+                    egui::vec2(400.0, 300.0),
+                )),
+                ..Default::default()
+            };
+            let (mut taken, mut rects) = (false, Vec::new());
+            let _ = ctx.run(input, |ctx| {
+                taken = click_taken_by_widget(ctx);
+                egui::CentralPanel::default().show(ctx, |ui| {
+                    rects = vec![
+                        ui.label("plain text").rect,
+                        ui.hyperlink_to("a link", "https://example.com").rect,
+                    ];
+                });
+            });
+            (taken, rects)
+        };
+        let click = |pos: egui::Pos2| {
+            let button = |pressed| egui::Event::PointerButton {
+                pos,
+                button: egui::PointerButton::Primary,
+                pressed,
+                modifiers: egui::Modifiers::NONE,
+            };
+            vec![egui::Event::PointerMoved(pos), button(true), button(false)]
+        };
+
+        let (_, rects) = frame(Vec::new());
+        assert!(!frame(click(rects[0].center())).0);
+        assert!(frame(click(rects[1].center())).0);
+    }
+
+    #[test]
+    fn routes_undo_shortcuts_to_the_focused_field() {
+        let editors = [editor_text_id(1), inline_line_text_id(1, 4)];
+        let find = Some(egui::Id::new("find_query"));
+        assert!(document_undo_has_keys(Some(editors[0]), &editors, true));
+        assert!(document_undo_has_keys(Some(editors[1]), &editors, true));
+        assert!(document_undo_has_keys(None, &editors, false));
+        assert!(!document_undo_has_keys(find, &editors, true));
+        assert!(!document_undo_has_keys(
+            Some(inline_line_text_id(2, 4)),
+            &editors,
+            true
+        ));
+        assert!(!document_undo_has_keys(None, &[], true));
+    }
+
+    #[test]
+    fn edit_history_undoes_and_redoes_across_edit_kinds() {
+        let mut history = EditHistory::new("a");
+        assert!(history.record("ab", EditKind::Typing));
+        assert!(history.record("abc", EditKind::Typing));
+        assert!(history.record("abc\n---\n", EditKind::Command));
+        assert!(!history.record("abc\n---\n", EditKind::Command));
+
+        assert_eq!(history.undo().as_deref(), Some("abc"));
+        assert_eq!(history.undo().as_deref(), Some("a"));
+        assert_eq!(history.undo(), None);
+        assert_eq!(history.redo().as_deref(), Some("abc"));
+        assert_eq!(history.redo().as_deref(), Some("abc\n---\n"));
+        assert_eq!(history.redo(), None);
+    }
+
+    #[test]
+    fn diffs_changed_lines() {
+        let diff = diff_lines("a\nb\nc\nd", "a\nc\nx\nd");
+        assert_eq!(
+            diff,
+            vec![
+                DiffLine::Same("a"),
+                DiffLine::Removed("b"),
+                DiffLine::Same("c"),
+                DiffLine::Added("x"),
+                DiffLine::Same("d"),
+            ]
+        );
+    }
+
+    #[test]
+    fn fuzzy_match_prefers_contiguous_and_boundary_matches() {
+        let spread = fuzzy_score("read", "r_e_a_d.md").unwrap();
+        let contiguous = fuzzy_score("read", "readme.md").unwrap();
+        assert!(contiguous > spread);
+
+        let m = fuzzy_match("dg", "docs/design-guide.md").unwrap();
+        assert_eq!(m.positions, vec![5, 12]);
+        assert!(fuzzy_match("xyz", "readme.md").is_none());
+    }
+
+    #[test]
+    fn find_supports_regex_captures_and_whole_words() {
+        let text = "TODO(alice): fix\nsee todo(bob)\nTODOS later";
+        let regex = FindOptions {
+            regex: true,
+            ..Default::default()
+        };
+        let matches = search_text(text, r"TODO\((\w+)\)", regex).unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[1].line, 1);
+        assert_eq!(matches[1].col, 4);
+        assert_eq!(matches[1].captures, vec!["bob".to_string()]);
+        assert_eq!(
+            &matches[1].preview[matches[1].highlight.clone()],
+            "todo(bob)"
+        );
+
+        let whole_word = FindOptions {
+            whole_word: true,
+            ..Default::default()
+        };
+        assert_eq!(search_text(text, "todo", whole_word).unwrap().len(), 2);
+        assert!(search_text(text, "TODO(", regex).is_err());
+    }
+
+    #[test]
+    fn replaces_with_capture_groups() {
+        let text = "TODO(alice) and TODO(bob)\r\nTODO(carol)\n";
+        let options = FindOptions {
+            regex: true,
+            ..Default::default()
+        };
+        let re = build_find_regex(r"TODO\((\w+)\)", options).unwrap();
+        let matches = find_matches(text, &re, false);
+        let replacements = expand_replacements(text, &matches, &re, "DONE[$1]", true);
+        assert_eq!(
+            apply_replacements(text, &matches, &replacements),
+            "DONE[alice] and DONE[bob]\r\nDONE[carol]\n"
+        );
+
+        let preview = replacement_preview(text, &matches, &replacements);
+        assert_eq!(preview.len(), 2);
+        assert_eq!(preview[1], (1, "TODO(carol)".into(), "DONE[carol]".into()));
+
+        let long = "x ".repeat(FIND_MAX_MATCHES + 20);
+        let re = build_find_regex("x", FindOptions::default()).unwrap();
+        assert_eq!(find_matches(&long, &re, true).len(), FIND_MAX_MATCHES);
+        assert_eq!(count_find_matches(&long, &re, true), FIND_MAX_MATCHES + 20);
+    }
+
+    #[test]
+    fn splits_preview_into_source_line_blocks() {
+        let markdown = "# Title\n\nSee [docs][d].\n\n```sh\n# not a heading\n```\n\n[d]: https://example.com\n- a\n- b\n";
+        let blocks = split_preview_blocks(markdown);
+        let lines: Vec<_> = blocks.iter().map(|b| b.lines.clone()).collect();
+        assert_eq!(lines, vec![0..1, 2..3, 4..7, 9..11]);
+        assert!(blocks[0].heading && !blocks[2].heading);
+        assert!(blocks[1].markdown.ends_with("[d]: https://example.com"));
+        assert!(!blocks[2].markdown.contains("[d]:"));
+
+        let markdown =
+            "- [x] done\n\n[Later] and [unused][U].\n\n[later]: /later\n[other]: /other\n";
+        let blocks = split_preview_blocks(markdown);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].markdown, "- [x] done\n\n");
+        assert_eq!(
+            blocks[1].markdown,
+            "[Later] and [unused][U].\n\n[later]: /later"
+        );
+        let earlier = "[next]:\n  /next\n\n# Next\n\nSee [the next part][Next].\n";
+        let blocks = split_preview_blocks(earlier);
+        assert!(!blocks[0].markdown.contains("/next"));
+        assert!(blocks[1].markdown.ends_with("[next]:\n  /next"));
+    }
+
+    #[test]
+    fn preview_blocks_render_where_one_viewer_puts_them() {
+        let ctx = egui::Context::default();
+        let painted = |show: &mut dyn FnMut(&mut egui::Ui)| {
+            let input = egui::RawInput {
+                screen_rect: Some(egui::Rect::from_min_size(
+                    egui::Pos2::ZERO,
+                    egui::vec2(600.0, 2000.0),
+                )),
+                ..Default::default()
+            };
+            let mut output = None;
+            // Tables settle their column widths over a couple of frames.
+            for _ in 0..3 {
+                output = Some(ctx.run(input.clone(), |ctx| {
+                    egui::CentralPanel::default().show(ctx, |ui| {
+                        egui::ScrollArea::vertical().show(ui, |ui| show(ui));
+                    });
+                }));
+            }
+            let mut shapes = Vec::new();
+            for clipped in output.unwrap().shapes {
+                match clipped.shape {
+                    egui::Shape::Text(text) if !text.galley.text().trim().is_empty() => {
+                        shapes.push((text.galley.text().to_string(), text.pos));
+                    }
+                    egui::Shape::LineSegment { points, .. } => {
+                        shapes.push(("line".to_string(), points[0]));
+                    }
+                    _ => {}
+                }
+            }
+            shapes
+        };
+
+        for markdown in [
+            "# Title\n\nPara one\n\n## Sub\n\n- tight\n- list\n\nAfter list\n",
+            "Para\n\n- loose\n\n- list\n\n1. other\n2. kind\n\nEnd\n",
+            "```\ncode\n```\n\n    indented\n\n> quote\n> more\n\nAfter\n",
+            "Para\n\n<div>html</div>\n\nText\n\n<!-- c -->\n\n# After html\n",
+            "---\n\n# T\n\nPara\n\n---\n\n***\n\nTerm\n: Definition\n",
+            // Each table keeps its own column widths.
+            "| a | b |\n|---|---|\n| 1 | 2 |\n\n| wide header | x | y |\n|---|---|---|\n| 1 | 2 | 3 |\n",
+        ] {
+            let mut cache = CommonMarkCache::default();
+            let single = painted(&mut |ui| {
+                CommonMarkViewer::new().show(ui, &mut cache, markdown);
+            });
+            let blocks = split_preview_blocks(markdown);
+            let mut cache = CommonMarkCache::default();
+            let split = painted(&mut |ui| {
+                let row_height = ui.text_style_height(&TextStyle::Body).round();
+                for (idx, block) in blocks.iter().enumerate() {
+                    if idx > 0 {
+                        let gap = preview_block_gap(&blocks, idx, row_height);
+                        ui.add_space(gap - ui.spacing().item_spacing.y);
+                    }
+                    ui.push_id(("preview_block", idx), |ui| {
+                        CommonMarkViewer::new().show(ui, &mut cache, &block.markdown);
+                    });
+                }
+            });
+            assert_eq!(split, single, "{markdown:?}");
+        }
+    }
+
+    #[test]
+    fn outline_skips_front_matter_and_fenced_comments() {
+        let markdown = "---\ntitle: Spec\n---\n# Setup `cargo` via [rustup](https://rustup.rs)\n\n```sh\n# install\n```\n\nUsage\n-----\n";
+        let outline = build_outline(markdown);
+        let items: Vec<_> = outline
+            .iter()
+            .map(|item| {
+                (
+                    item.level,
+                    item.title.as_str(),
+                    item.slug.as_str(),
+                    item.line,
+                )
+            })
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                (1, "Setup cargo via rustup", "setup-cargo-via-rustup", 3),
+                (2, "Usage", "usage", 9),
+            ]
+        );
+
+        let preprocessed = preprocess_markdown(markdown, &ViewerSettings::default(), None);
+        assert!(preprocessed.starts_with("~~~yaml\ntitle: Spec\n~~~\n# Setup"));
+        assert_eq!(preprocessed.lines().count(), markdown.lines().count());
+        let tildes = "---\nnote: |\n  ~~~~\n---\n# Body\n";
+        let preprocessed = preprocess_markdown(tildes, &ViewerSettings::default(), None);
+        assert!(preprocessed.starts_with("~~~~~yaml\nnote: |\n  ~~~~\n~~~~~\n# Body"));
+    }
+
+    #[test]
+    fn outline_rows_follow_collapse_and_filter() {
+        let outline = build_outline("# A\n## B\n### C\n## D\n# E\n## Fig\n");
+        assert_eq!(outline_section_at(&outline, 2), Some(2));
+        assert_eq!(outline_section_at(&outline, 0), Some(0));
+
+        let collapsed = outline_collapsed_to_level(&outline, 2);
+        assert_eq!(collapsed, HashSet::from(["b".to_string()]));
+        assert_eq!(
+            outline_visible_rows(&outline, &collapsed, ""),
+            vec![0, 1, 3, 4, 5]
+        );
+
+        let collapsed = outline_collapsed_to_level(&outline, 1);
+        assert_eq!(outline_visible_rows(&outline, &collapsed, ""), vec![0, 4]);
+        assert_eq!(
+            outline_visible_rows(&outline, &collapsed, "c"),
+            vec![0, 1, 2]
+        );
+        assert_eq!(outline_visible_rows(&outline, &collapsed, "F"), vec![4, 5]);
+    }
+
+    #[test]
+    fn moves_and_shifts_outline_sections() {
+        let markdown = "# A\na\n## B\nb\n# C\nc";
+        let outline = build_outline(markdown);
+        assert_eq!(outline_section_lines(&outline, 0, 6), 0..4);
+
+        let moved = move_outline_section(markdown, &outline, 2, 0).unwrap();
+        assert_eq!(moved, "# C\nc\n# A\na\n## B\nb");
+        let back = move_outline_section(&moved, &build_outline(&moved), 0, 6).unwrap();
+        assert_eq!(back, markdown);
+        assert_eq!(move_outline_section(markdown, &outline, 0, 2), None);
+
+        assert_eq!(
+            shift_outline_section(markdown, &outline, 0, 1).unwrap(),
+            "## A\na\n### B\nb\n# C\nc"
+        );
+        assert_eq!(shift_outline_section(markdown, &outline, 0, -1), None);
+
+        let setext = "Title\n=====\n\nPart\n----\n";
+        let outline = build_outline(setext);
+        assert_eq!(
+            shift_outline_section(setext, &outline, 0, 1).unwrap(),
+            "Title\n-----\n\n### Part\n"
+        );
+
+        let quoted = "> # A\n> text\n>\n> ## B\n";
+        let outline = build_outline(quoted);
+        assert_eq!(
+            shift_outline_section(quoted, &outline, 0, 1).unwrap(),
+            "> ## A\n> text\n>\n> ### B\n"
+        );
+        let listed = "- #   A\n";
+        let outline = build_outline(listed);
+        assert_eq!(
+            shift_outline_section(listed, &outline, 0, 1).unwrap(),
+            "- ## A\n"
+        );
+        let multiline = "Long\ntitle\n=====\n\nQuoted\n\n> Part\n> two\n> ---\n";
+        let outline = build_outline(multiline);
+        assert_eq!(
+            shift_outline_section(multiline, &outline, 0, 1).unwrap(),
+            "Long\ntitle\n-----\n\nQuoted\n\n> ### Part two\n"
+        );
+    }
+
+    #[test]
+    fn expands_toc_markers_in_place() {
+        let markdown = "# Guide\n\n[[toc max=2]]\n\n## Install `cli`\n### Linux\n## FAQ [draft]\n";
+        let outline = build_outline(markdown);
+        let mut blocks = split_preview_blocks(markdown);
+        expand_toc_markers(&mut blocks, &outline);
+        assert_eq!(blocks[1].lines, 2..3);
+        assert_eq!(
+            blocks[1].markdown,
+            "- [Guide](#guide)\n  - [Install cli](#install-cli)\n  - [FAQ \\[draft\\]](#faq-draft)\n"
+        );
+
+        assert_eq!(
+            parse_toc_marker("[TOC min=2]"),
+            Some(TocOptions {
+                min_depth: 2,
+                max_depth: 6
+            })
+        );
+        assert_eq!(parse_toc_marker("[[toc depth=2]]"), None);
+        assert_eq!(parse_toc_marker("    [[toc]]"), None);
+    }
+
+    #[test]
+    fn parses_github_alerts_into_callouts() {
+        let markdown =
+            "> [!WARNING]- Breaking change\n> Run `migrate` first.\nlazy line\n\n> plain quote\n";
+        let blocks = split_preview_blocks(markdown);
+        assert_eq!(
+            blocks[0].callout,
+            Some(Callout {
+                kind: CalloutKind::Warning,
+                title: Some("Breaking change".into()),
+                fold: Some(false),
+                body: "Run `migrate` first.\nlazy line\n".into(),
+            })
+        );
+        assert_eq!(blocks[1].callout, None);
+
+        let note = parse_callout(">[!note]\n>\n> Body").unwrap();
+        assert_eq!(
+            (note.kind, note.title, note.fold),
+            (CalloutKind::Note, None, None)
+        );
+        assert_eq!(note.body, "\nBody");
+        assert_eq!(parse_callout("> [!UNKNOWN]\n> x"), None);
+    }
+
+    #[test]
+    fn links_footnotes_both_ways() {
+        let markdown = "Claim[^b] and `[^a]` then[^a].\n\n[^a]: First\n    continued.\n\n[^b]: Second.\n\nAgain[^b].\n";
+        let (linked, footnotes) = link_footnotes(markdown);
+        assert_eq!(
+            linked,
+            "Claim[¹](#fn-1) and `[^a]` then[²](#fn-2).\n\n**2.** [↩](#fnref-2-1) First\ncontinued.\n\n**1.** [↩](#fnref-1-1) [↩²](#fnref-1-2) Second.\n\nAgain[¹](#fn-1).\n"
+        );
+        assert_eq!(linked.lines().count(), markdown.lines().count());
+        assert_eq!(footnote_refs_in(&linked), vec![1, 2, 1]);
+
+        assert_eq!(footnotes.len(), 2);
+        assert_eq!((footnotes[1].label.as_str(), footnotes[1].line), ("a", 2));
+        assert_eq!(footnotes[1].body, "First\ncontinued.");
+        assert_eq!(footnote_line_for_fragment(&footnotes, "fn-b"), Some(5));
+        assert_eq!(footnote_line_for_fragment(&footnotes, "fnref-2"), Some(0));
+        assert_eq!(footnote_line_for_fragment(&footnotes, "fnref-1-2"), Some(7));
+        assert_eq!(footnote_line_for_fragment(&footnotes, "fnref-b-1"), Some(0));
+        assert_eq!(footnote_line_for_fragment(&footnotes, "fnref-1-3"), None);
+        assert_eq!(footnote_line_for_fragment(&footnotes, "fn-9"), None);
+    }
+
+    #[test]
+    fn line_layout_maps_lines_and_offsets_both_ways() {
+        let layout = LineLayout {
+            blocks: vec![
+                BlockPosition {
+                    lines: 0..1,
+                    top: 0.0,
+                    bottom: 30.0,
+                },
+                BlockPosition {
+                    lines: 2..6,
+                    top: 50.0,
+                    bottom: 250.0,
+                },
+            ],
+        };
+        assert_eq!(layout.y_for_line(0), Some(0.0));
+        assert_eq!(layout.y_for_line(1), Some(50.0));
+        assert_eq!(layout.y_for_line(4), Some(150.0));
+        assert_eq!(layout.y_for_line(40), Some(250.0));
+        assert_eq!(LineLayout::default().y_for_line(0), None);
+
+        assert_eq!(layout.line_at(150.0), Some(4.0));
+        assert_eq!(layout.line_at(40.0), Some(2.0));
+        assert_eq!(layout.line_at(900.0), Some(6.0));
+    }
+
+    #[test]
+    fn fuzzy_match_folds_unicode_case() {
+        let m = fuzzy_match("ÜBER", "notes/über-uns.md").unwrap();
+        assert_eq!(m.positions, vec![6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn routes_diagram_fences_to_their_engine() {
+        let markdown = "```plantuml\n@startuml\n\nA -> B\n@enduml\n```\n\n```dot\ndigraph { a -> b }\n```\n\n```text\nplain\n```\n";
+        let preprocessed = preprocess_markdown(markdown, &ViewerSettings::default(), None);
+        assert_eq!(preprocessed.lines().count(), markdown.lines().count());
+
+        let mut html_blocks = Vec::new();
+        for event in pulldown_cmark::Parser::new_ext(&preprocessed, markdown_parser_options()) {
+            match event {
+                pulldown_cmark::Event::Start(pulldown_cmark::Tag::HtmlBlock) => {
+                    html_blocks.push(String::new())
+                }
+                pulldown_cmark::Event::Html(html) => {
+                    html_blocks.last_mut().unwrap().push_str(&html)
+                }
+                _ => {}
+            }
+        }
+        let diagrams: Vec<_> = html_blocks
+            .iter()
+            .filter_map(|html| extract_diagram_source(html))
+            .collect();
+        assert_eq!(
+            diagrams,
+            [
+                (
+                    DiagramEngine::PlantUml,
+                    "@startuml\n\nA -> B\n@enduml".to_string()
+                ),
+                (DiagramEngine::Graphviz, "digraph { a -> b }".to_string()),
+            ]
+        );
+        assert_eq!(
+            extract_diagram_source("<div class=\"mermaid\">\ngraph TD\nA-->B\n</div>"),
+            Some((DiagramEngine::Mermaid, "graph TD\nA-->B".to_string()))
+        );
+    }
+
+    #[test]
+    fn parses_and_lays_out_mermaid_flowcharts() {
+        let lines = mermaid_statement_lines(
+            "%% comment\ngraph LR; A[Start] -->|go| B{Check}\n  B -- no --> C((Stop)) & A\n  C -.-> D\n",
+        );
+        let chart = parse_flowchart(&lines).unwrap();
+        assert_eq!(chart.direction, FlowDirection::LeftRight);
+        let ids: Vec<&str> = chart.nodes.iter().map(|n| n.id.as_str()).collect();
+        assert_eq!(ids, ["A", "B", "C", "D"]);
+        assert_eq!(chart.nodes[1].shape, NodeShape::Diamond);
+        assert_eq!(chart.nodes[2].label, ["Stop"]);
+        assert_eq!(chart.edges.len(), 4);
+        assert_eq!(
+            chart.edges[1].label.as_deref(),
+            Some(&["no".to_string()][..])
+        );
+        assert_eq!(chart.edges[3].style, LineStyle::Dotted);
+
+        let sizes = vec![egui::vec2(60.0, 30.0); 4];
+        let edges: Vec<LayoutEdge> = chart
+            .edges
+            .iter()
+            .map(|e| LayoutEdge {
+                from: e.from,
+                to: e.to,
+                label: None,
+            })
+            .collect();
+        let layout = layout_graph(&sizes, &edges, chart.direction);
+        assert!(layout.nodes[0].right() < layout.nodes[1].left());
+        assert!(layout.nodes[1].right() < layout.nodes[2].left());
+        assert!(layout.nodes[2].right() < layout.nodes[3].left());
+        assert_eq!(layout.edges[2].first(), Some(&layout.nodes[1].center()));
+
+        assert!(parse_flowchart(&mermaid_statement_lines("graph TD\nA --> [oops]")).is_err());
+    }
+
+    #[test]
+    fn svg_cache_evicts_stale_and_unreferenced_renders() {
+        let ctx = egui::Context::default();
+        let next_frame = || {
+            let _ = ctx.run(egui::RawInput::default(), |_| {});
+        };
+        let key = |tex: &str| MathKey {
+            tex: tex.to_string(),
+            inline: true,
+            color: egui::Color32::BLACK,
+        };
+        let ready = |len: usize| SvgState::Ready(Arc::from(vec![0u8; len]));
+        let mut cache = SvgCache::new(ctx.clone(), 250);
+
+        for tex in ["a", "b"] {
+            cache.insert_pending(&key(tex), 1, "");
+            cache.finish(&key(tex), ready(100));
+            next_frame();
+        }
+        next_frame();
+        assert!(cache.get(&key("a")).is_some());
+        cache.insert_pending(&key("c"), 1, "");
+        cache.finish(&key("c"), ready(100));
+        assert_eq!(cache.usage(), (2, 202));
+        assert!(cache.get(&key("b")).is_none());
+
+        cache.insert_pending(&key("b"), 1, "");
+        assert!(cache.wants(&key("b")));
+        cache.cancel(&key("b"));
+        cache.finish(&key("b"), ready(100));
+        assert!(!cache.wants(&key("b")) && cache.get(&key("b")).is_none());
+        cache.insert_pending(&key("b"), 1, "");
+
+        let references = RenderReferences::new(["Only $c$ is left, and $b+$."]);
+        cache.retain(|key| references.has_math(&key.tex));
+        assert_eq!(cache.usage(), (1, 101));
+
+        cache.get(&key("c"));
+        next_frame();
+        cache.set_limit(0);
+        assert_eq!(cache.usage(), (1, 101));
+        next_frame();
+        next_frame();
+        cache.set_limit(0);
+        assert_eq!(cache.usage(), (0, 0));
+
+        let diagram = "graph TD\n  A --> B\n";
+        assert!(
+            RenderReferences::new(["> ```mermaid\n> graph TD\n>   A --> B\n> ```"])
+                .has_diagram(diagram)
+        );
+        assert!(
+            !RenderReferences::new(["```mermaid\ngraph TD\n  A --> Bx\n```"]).has_diagram(diagram)
+        );
+    }
+
+    #[test]
+    fn request_svg_debounces_edits_and_keeps_the_last_render() {
+        let ctx = egui::Context::default();
+        let cache = Arc::new(Mutex::new(SvgCache::new(ctx.clone(), usize::MAX)));
+        let slot = SvgSlotId {
+            doc: 1,
+            block: 0,
+            index: 0,
+        };
+        let key = |tex: &str| MathKey {
+            tex: tex.to_string(),
+            inline: true,
+            color: egui::Color32::BLACK,
+        };
+        let frame = |time: f64, tex: &str| {
+            let mut shown = String::new();
+            let mut sent = Vec::new();
+            let input = egui::RawInput {
+                time: Some(time),
+                ..Default::default()
+            };
+            let _ = ctx.run(input, |ctx| {
+                egui::CentralPanel::default().show(ctx, |ui| {
+                    let (uri, _) =
+                        request_svg(ui, slot, key(tex), 1, format!("{tex}.svg"), &cache, |key| {
+                            sent.push(key.tex)
+                        });
+                    shown = uri;
+                });
+            });
+            (shown, sent)
+        };
+        let finish = |tex: &str| {
+            let bytes = SvgState::Ready(Arc::from(vec![0u8; 10]));
+            cache.lock().unwrap().finish(&key(tex), bytes);
+        };
+
+        assert_eq!(frame(0.0, "a").1, ["a"]);
+        finish("a");
+        assert_eq!(frame(0.1, "a"), ("a.svg".to_owned(), vec![]));
+
+        assert_eq!(frame(0.2, "ab"), ("a.svg".to_owned(), vec![]));
+        assert_eq!(frame(0.3, "abc"), ("a.svg".to_owned(), vec![]));
+        assert_eq!(
+            frame(0.7, "abc"),
+            ("a.svg".to_owned(), vec!["abc".to_owned()])
+        );
+
+        assert_eq!(frame(0.75, "abcd").1, Vec::<String>::new());
+        assert!(!cache.lock().unwrap().wants(&key("abc")));
+        assert_eq!(frame(1.1, "abcd").1, ["abcd"]);
+        finish("abcd");
+        assert_eq!(frame(1.2, "abcd"), ("abcd.svg".to_owned(), vec![]));
+
+        cache.lock().unwrap().retain(|key| key.tex == "abcd");
+        assert_eq!(cache.lock().unwrap().usage().0, 1);
+        for _ in 0..2 {
+            let _ = ctx.run(egui::RawInput::default(), |_| {});
+        }
+        cache.lock().unwrap().set_limit(0);
+        assert_eq!(cache.lock().unwrap().usage(), (0, 0));
+    }
+
+    #[test]
+    fn math_pool_renders_visible_formulas_first() {
+        let ctx = egui::Context::default();
+        let pool = MathPool {
+            cache: Arc::new(Mutex::new(SvgCache::new(ctx.clone(), usize::MAX))),
+            ctx,
+            disk: None,
+            queue: Mutex::new(MathQueue {
+                workers: 2,
+                running: vec![true, true],
+                ..MathQueue::default()
+            }),
+            available: std::sync::Condvar::new(),
+        };
+        let key = |tex: &str| MathKey {
+            tex: tex.to_string(),
+            inline: false,
+            color: egui::Color32::BLACK,
+        };
+
+        for tex in ["a", "b", "c"] {
+            pool.push(key(tex), false);
+        }
+        pool.push(key("d"), true);
+        pool.promote(&key("c"));
+        pool.promote(&key("c"));
+        pool.promote(&key("d"));
+        let order: Vec<String> = (0..4)
+            .filter_map(|_| pool.next_job(0))
+            .map(|key| key.tex)
+            .collect();
+        assert_eq!(order, ["d", "c", "a", "b"]);
+        assert!(pool.queue.lock().unwrap().in_background.is_empty());
+
+        pool.queue.lock().unwrap().workers = 1;
+        assert!(pool.next_job(1).is_none());
+        assert_eq!(pool.queue.lock().unwrap().running, [true, false]);
+    }
+
+    #[test]
+    fn disk_render_cache_evicts_least_recently_used() {
+        let dir = TempDir::new("render-cache-test");
+        let cache = RenderDiskCache::new(dir.to_path_buf(), 250);
+
+        cache.put(&["math", "a"], &[b'a'; 100]);
+        cache.put(&["math", "b"], &[b'b'; 100]);
+        assert_eq!(cache.get(&["math", "a"]).as_deref(), Some(&[b'a'; 100][..]));
+        cache.put(&["math", "c"], &[b'c'; 100]);
+        assert!(cache.get(&["math", "b"]).is_none());
+        assert!(cache.get(&["math", "a"]).is_some());
+        assert_eq!(cache.usage(), (2, 200));
+
+        let reopened = RenderDiskCache::new(dir.to_path_buf(), 250);
+        assert_eq!(reopened.usage(), (2, 200));
+        reopened.clear();
+        assert_eq!(reopened.usage(), (0, 0));
+        assert!(reopened.get(&["math", "c"]).is_none());
+    }
+
+    #[test]
+    fn parses_graphviz_dot() {
+        let chart = parse_dot(
+            r#"/* services */ digraph "deps" {
+                rankdir = LR
+                node [shape=box]
+                subgraph cluster_a { label="Tier\nA"; web -> api [label="REST", style=dashed] }
+                api -> {db cache} [dir=both, arrowtail=odiamond]
+                db [shape=cylinder, label=<Main<br/>DB>]
+                hidden [style=invis]; api:out -> hidden
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(chart.direction, FlowDirection::LeftRight);
+        let ids: Vec<&str> = chart.nodes.iter().map(|n| n.id.as_str()).collect();
+        assert_eq!(ids, ["web", "api", "db", "cache", "hidden"]);
+        assert_eq!(chart.nodes[0].shape, NodeShape::Rect);
+        assert_eq!(chart.nodes[2].shape, NodeShape::Cylinder);
+        assert_eq!(chart.nodes[2].label, ["Main", "DB"]);
+        assert!(chart.nodes[4].hidden);
+
+        assert_eq!(chart.edges.len(), 4);
+        let rest = &chart.edges[0];
+        assert_eq!(rest.label.as_deref(), Some(&["REST".to_string()][..]));
+        assert_eq!(
+            (rest.style, rest.end),
+            (LineStyle::Dashed, ArrowHead::Filled)
+        );
+        assert_eq!(
+            (chart.edges[2].to, chart.edges[2].start),
+            (3, ArrowHead::Diamond)
+        );
+
+        assert_eq!(chart.subgraphs.len(), 1);
+        assert_eq!(chart.subgraphs[0].title, ["Tier", "A"]);
+        assert_eq!(chart.subgraphs[0].members, [0, 1]);
+
+        let undirected = parse_dot("graph { a -- b [label=\"x\"] }").unwrap();
+        assert_eq!(undirected.edges[0].end, ArrowHead::None);
+        assert_eq!(undirected.nodes[0].shape, NodeShape::Ellipse);
+        assert!(parse_dot("digraph { a -> }").is_err());
+
+        let ordered = parse_dot(
+            r#"digraph { node [style=rounded, label="\N!", shape=box]; a; b [label="x|y", shape=record] }"#,
+        )
+        .unwrap();
+        assert_eq!(ordered.nodes[0].shape, NodeShape::Round);
+        assert_eq!(ordered.nodes[0].label, ["a!"]);
+        assert_eq!(ordered.nodes[1].label, dot_record_lines("x|y"));
+    }
+
+    #[test]
+    fn renders_sequence_and_class_diagrams_offline() {
+        let palette = DiagramPalette::from_visuals(&egui::Visuals::dark());
+        let svg = render_mermaid_offline(
+            "sequenceDiagram\n  participant A as Alice\n  A->>+B: Hello <b>\n  loop retry\n    B-->>-A: ok\n  end",
+            palette,
+        )
+        .unwrap();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains(">Alice</text>") && svg.contains("Hello &lt;b&gt;"));
+        assert!(svg.contains("[retry]"));
+
+        let diagram = parse_class_diagram(&mermaid_statement_lines(
+            "class Box~T~ {\n  <<interface>>\n  +List~Item~ items\n  +open() bool\n}\nBox \"1\" *-- \"n\" Item : holds",
+        ))
+        .unwrap();
+        let class = &diagram.classes[0];
+        assert_eq!(class.name, "Box<T>");
+        assert_eq!(class.annotations, ["«interface»"]);
+        assert_eq!(class.attributes, ["+List<Item> items"]);
+        assert_eq!(class.methods, ["+open() bool"]);
+        let relation = &diagram.relations[0];
+        assert_eq!(relation.start, ArrowHead::FilledDiamond);
+        assert_eq!(relation.to_cardinality.as_deref(), Some("n"));
+        assert_eq!(relation.label.as_deref(), Some("holds"));
+
+        let err = render_mermaid_offline("gantt\n  title Plan", palette).unwrap_err();
+        assert!(err.contains("gantt"));
+    }
+
+    #[test]
+    fn parses_sequence_frames_notes_and_activations() {
+        let diagram = parse_sequence_diagram(&mermaid_statement_lines(
+            "autonumber\nactor U as User\nparticipant S\nU->>+S: login\nalt ok\n  S-->>U: token\nelse failed\n  S--xU: denied\nend\nloop every minute\n  Note over U,S: heartbeat\nend\nnote left of U: waits\ndeactivate S",
+        ))
+        .unwrap();
+        assert!(diagram.participants[0].actor && !diagram.participants[1].actor);
+        assert_eq!(diagram.participants[0].label, ["User"]);
+
+        let events: Vec<String> = diagram
+            .events
+            .iter()
+            .map(|event| match event {
+                SeqEvent::Message {
+                    from,
+                    to,
+                    style,
+                    head,
+                    number,
+                    ..
+                } => format!("{from}->{to} {style:?} {head:?} {number:?}"),
+                SeqEvent::Note {
+                    first,
+                    last,
+                    placement,
+                    text,
+                } => format!("note {placement:?} {first}..{last} {}", text.join(" ")),
+                SeqEvent::Activate(idx) => format!("+{idx}"),
+                SeqEvent::Deactivate(idx) => format!("-{idx}"),
+                SeqEvent::FrameStart { kind, label } => format!("{kind} {label}"),
+                SeqEvent::FrameSection { label } => format!("else {label}"),
+                SeqEvent::FrameEnd => "end".to_string(),
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "0->1 Solid Filled Some(1)",
+                "+1",
+                "alt ok",
+                "1->0 Dashed Filled Some(2)",
+                "else failed",
+                "1->0 Dashed Cross Some(3)",
+                "end",
+                "loop every minute",
+                "note Over 0..1 heartbeat",
+                "end",
+                "note LeftOf 0..0 waits",
+                "-1",
+            ]
+        );
+
+        let unbalanced = parse_sequence_diagram(&mermaid_statement_lines("A->>B: hi\nend"));
+        assert!(unbalanced.unwrap_err().contains("line 2"));
+        assert!(parse_sequence_diagram(&mermaid_statement_lines("Note over A")).is_err());
+    }
+
+    #[test]
+    fn parses_class_members_annotations_and_cardinalities() {
+        let diagram = parse_class_diagram(&mermaid_statement_lines(
+            "class Animal {\n  <<abstract>>\n  +String name\n  +speak()$\n}\n<<interface>> Pet\nAnimal <|-- Dog\nPerson \"1\" o-- \"0..*\" Pet : owns\nDog ..> Bone\nDog : +fetch() bool",
+        ))
+        .unwrap();
+        let names: Vec<&str> = diagram.classes.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["Animal", "Pet", "Dog", "Person", "Bone"]);
+        let animal = &diagram.classes[0];
+        assert_eq!(animal.annotations, ["«abstract»"]);
+        assert_eq!(animal.attributes, ["+String name"]);
+        assert_eq!(animal.methods, ["+speak()"]);
+        assert_eq!(diagram.classes[1].annotations, ["«interface»"]);
+        assert_eq!(diagram.classes[2].methods, ["+fetch() bool"]);
+
+        let relations: Vec<_> = diagram
+            .relations
+            .iter()
+            .map(|r| {
+                (
+                    r.from,
+                    r.to,
+                    r.start,
+                    r.end,
+                    r.style,
+                    r.from_cardinality.as_deref(),
+                    r.to_cardinality.as_deref(),
+                    r.label.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            relations,
+            [
+                (
+                    0,
+                    2,
+                    ArrowHead::Triangle,
+                    ArrowHead::None,
+                    LineStyle::Solid,
+                    None,
+                    None,
+                    None
+                ),
+                (
+                    3,
+                    1,
+                    ArrowHead::Diamond,
+                    ArrowHead::None,
+                    LineStyle::Solid,
+                    Some("1"),
+                    Some("0..*"),
+                    Some("owns"),
+                ),
+                (
+                    2,
+                    4,
+                    ArrowHead::None,
+                    ArrowHead::Open,
+                    LineStyle::Dashed,
+                    None,
+                    None,
+                    None
+                ),
+            ]
+        );
+
+        let unclosed = parse_class_diagram(&mermaid_statement_lines("class Open {\n  +x"));
+        assert!(unclosed.unwrap_err().contains("`Open`"));
+    }
 }
 
 #[cfg(test)]